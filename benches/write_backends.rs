@@ -0,0 +1,36 @@
+//! Compares the write backends on a batch of small files, through the same
+//! write path downloads take.
+//!
+//! Run with `cargo bench [--features io-uring] -- [files] [file_size]`.
+
+use fast_download::{benchmark_writes, WriteBackend};
+use std::env;
+
+#[tokio::main]
+async fn main() {
+    let mut args = env::args().skip(1).filter(|arg| arg != "--bench");
+    let n_files = args
+        .next()
+        .map_or(5000, |s| s.parse().expect("invalid file count"));
+    let file_size = args
+        .next()
+        .map_or(16 * 1024, |s| s.parse().expect("invalid file size"));
+    let root = env::temp_dir().join(format!("fast_download_bench_{}", std::process::id()));
+
+    for backend in WriteBackend::ALL
+        .into_iter()
+        .filter(|backend| backend.is_available())
+    {
+        let elapsed = benchmark_writes(backend, &root, n_files, file_size, 64)
+            .await
+            .unwrap();
+        println!(
+            "{:>8}: {:>8.1?} ({:.0} files/s)",
+            backend.name(),
+            elapsed,
+            n_files as f64 / elapsed.as_secs_f64()
+        );
+    }
+
+    std::fs::remove_dir_all(&root).unwrap();
+}
@@ -0,0 +1,58 @@
+/* C API of fast_download, built with `cargo build --release --features ffi`. */
+
+#ifndef FAST_DOWNLOAD_H
+#define FAST_DOWNLOAD_H
+
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+typedef struct FastDownloadManager FastDownloadManager;
+
+typedef enum {
+    FAST_DOWNLOAD_STARTED = 0,
+    FAST_DOWNLOAD_CHUNK = 1,
+    FAST_DOWNLOAD_FINISHED = 2,
+    FAST_DOWNLOAD_FAILED = 3,
+} FastDownloadEventKind;
+
+typedef struct {
+    FastDownloadEventKind kind;
+    uint64_t id;
+    /* chunk size for FAST_DOWNLOAD_CHUNK */
+    uint64_t bytes;
+    /* expected file size for FAST_DOWNLOAD_CHUNK, -1 when unknown */
+    int64_t total;
+    /* set on FAST_DOWNLOAD_FINISHED when the file already existed */
+    bool skipped;
+    uint32_t attempt;
+    bool will_retry;
+    /* url for FAST_DOWNLOAD_STARTED, error for FAST_DOWNLOAD_FAILED, NULL
+       otherwise; valid until the next fast_download_poll */
+    const char *message;
+} FastDownloadEvent;
+
+/* Returns NULL if the manager's runtime can't be started. */
+FastDownloadManager *fast_download_new(size_t max_concurrent_downloads, uint32_t retries);
+
+/* Returns the id of the download, or -1 if url or path isn't valid UTF-8. */
+int64_t fast_download_enqueue(FastDownloadManager *manager, const char *url, const char *path);
+
+/* Returns 0 if the download isn't running. */
+int fast_download_cancel(FastDownloadManager *manager, uint64_t id);
+
+/* Returns 1 and fills event, or 0 without blocking when there is no event. */
+int fast_download_poll(FastDownloadManager *manager, FastDownloadEvent *event);
+
+/* Cancels all downloads and frees the manager. */
+void fast_download_destroy(FastDownloadManager *manager);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
@@ -0,0 +1,127 @@
+//! `--aria2 <file>`: reads the input files of aria2 instead of url file lines,
+//! so existing aria2 jobs run without converting them.
+//!
+//! An item is a line of urls for the same file, separated by tabs, the rest
+//! being mirrors, followed by indented `key=value` option lines. `out`, `dir`,
+//! `checksum=sha-256=<hex>`, `header`, `referer` and `user-agent` are used;
+//! aria2's other options, and checksums of other types, are ignored.
+
+use fast_download::Image;
+
+/// The items of `text`, each with the line of its urls, or why it can't be
+/// one.
+pub fn parse(text: &str) -> Vec<(usize, Result<Image, String>)> {
+    let mut items = Vec::new();
+    let mut current = None;
+    for (number, line) in text.lines().enumerate() {
+        if line.trim().is_empty() || line.trim_start().starts_with('#') {
+            continue;
+        }
+        if line.starts_with([' ', '\t']) {
+            match &mut current {
+                Some((_, Ok(item))) => {
+                    if let Err(err) = option(item, line.trim()) {
+                        current = Some((number + 1, Err(err)));
+                    }
+                }
+                // options of an item that is already invalid
+                Some((_, Err(_))) => {}
+                None => items.push((number + 1, Err("options before any url".to_string()))),
+            }
+            continue;
+        }
+        items.extend(current.take());
+        let mut urls = line
+            .split('\t')
+            .map(str::trim)
+            .filter(|url| !url.is_empty());
+        let url = urls.next().expect("the line isn't empty");
+        let item = Item {
+            url: url.to_string(),
+            mirrors: urls.map(str::to_string).collect(),
+            ..Item::default()
+        };
+        current = Some((number + 1, Ok(item)));
+    }
+    items.extend(current);
+    items
+        .into_iter()
+        .map(|(number, item)| (number, item.and_then(Item::image)))
+        .collect()
+}
+
+#[derive(Default)]
+struct Item {
+    url: String,
+    mirrors: Vec<String>,
+    out: Option<String>,
+    dir: Option<String>,
+    sha256: Option<String>,
+    headers: Vec<(String, String)>,
+}
+
+impl Item {
+    fn image(self) -> Result<Image, String> {
+        let name = match self.out {
+            Some(out) => out,
+            None => file_name(&self.url).ok_or_else(|| format!("no out= for {}", self.url))?,
+        };
+        let path = match self.dir {
+            Some(dir) => format!("{}/{}", dir.trim_end_matches('/'), name),
+            None => name,
+        };
+        let mut image = Image::new(self.url, path);
+        image.mirrors = self.mirrors;
+        image.sha256 = self.sha256;
+        image.headers = self.headers;
+        Ok(image)
+    }
+}
+
+/// Applies the option line `line` to `item`.
+fn option(item: &mut Item, line: &str) -> Result<(), String> {
+    let (key, value) = line
+        .split_once('=')
+        .ok_or_else(|| format!("invalid option line: {}", line))?;
+    match key.trim() {
+        "out" => item.out = Some(value.to_string()),
+        "dir" => item.dir = Some(value.to_string()),
+        "checksum" => {
+            let (kind, hex) = value
+                .split_once('=')
+                .ok_or_else(|| format!("invalid checksum: {}", value))?;
+            if kind.eq_ignore_ascii_case("sha-256") {
+                if hex.len() != 64 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+                    return Err(format!("invalid checksum: {}", value));
+                }
+                item.sha256 = Some(hex.to_ascii_lowercase());
+            }
+        }
+        "header" => {
+            let (name, value) = value
+                .split_once(':')
+                .ok_or_else(|| format!("invalid header: {}", value))?;
+            item.headers
+                .push((name.trim().to_string(), value.trim().to_string()));
+        }
+        "referer" => item
+            .headers
+            .push(("Referer".to_string(), value.to_string())),
+        "user-agent" => item
+            .headers
+            .push(("User-Agent".to_string(), value.to_string())),
+        _ => {}
+    }
+    Ok(())
+}
+
+/// The last segment of the path of `url`, decoded, as aria2 names files
+/// without an `out=`.
+fn file_name(url: &str) -> Option<String> {
+    let url = reqwest::Url::parse(url).ok()?;
+    let name = url.path_segments()?.next_back()?;
+    let name = percent_encoding::percent_decode_str(name)
+        .decode_utf8()
+        .ok()?;
+    (!name.is_empty() && name != "..").then(|| name.into_owned())
+}
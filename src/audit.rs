@@ -0,0 +1,314 @@
+//! `fast_download audit <url_file_name>`: a report on a url file before any of
+//! it is downloaded, to gate large jobs on: how many items there are and on
+//! which hosts, urls listed more than once, paths more than one item writes,
+//! how large it all is, and the hosts that don't answer.
+//!
+//! Sizes are the `size=` of the url file where it has one, and what a HEAD
+//! request reports otherwise. Every host gets at least one request, so the
+//! unreachable ones are found even when all sizes are known.
+
+use crate::check;
+use fast_download::{Image, Options};
+use futures::{stream, StreamExt};
+use indicatif::HumanBytes;
+use reqwest::Url;
+use serde_json::json;
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    time::Duration,
+};
+
+/// How long a host gets to accept a connection before it counts as
+/// unreachable.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Default)]
+struct Host {
+    items: usize,
+    /// The sum of the sizes that are known.
+    size: u64,
+    /// Items of neither a `size=` nor a reported size.
+    unknown: usize,
+    requests: usize,
+    responses: usize,
+    /// The error of a request without a response.
+    error: Option<String>,
+}
+
+impl Host {
+    fn unreachable(&self) -> bool {
+        self.requests > 0 && self.responses == 0
+    }
+}
+
+/// Prints the report on `images` and returns how many problems it found:
+/// duplicate urls, colliding paths, urls answering with an error status and
+/// unreachable hosts. Requests go out like the downloads of `options` would.
+pub async fn run(images: &[Image], options: &Options, json: bool) -> Result<usize, String> {
+    let client = check::Client::new(&Options {
+        connect_timeout: options.connect_timeout.or(Some(CONNECT_TIMEOUT)),
+        ..options.clone()
+    })?;
+    let max_concurrent = options.max_concurrent_downloads;
+    let host_of = |image: &Image| {
+        let url = Url::parse(&image.url).ok();
+        match url
+            .as_ref()
+            .and_then(|url| Some((url.host_str()?, url.port())))
+        {
+            Some((host, Some(port))) => format!("{}:{}", host, port),
+            Some((host, None)) => host.to_string(),
+            None => "-".to_string(),
+        }
+    };
+    // without a size= the server is asked, and else the first item of a host
+    let mut asked = HashSet::new();
+    let probed = images
+        .iter()
+        .filter(|image| asked.insert(host_of(image)) | image.size.is_none())
+        .collect::<Vec<_>>();
+    let checked = stream::iter(probed)
+        .map(|image| check::check(&client, image))
+        .buffered(max_concurrent.max(1))
+        .collect::<Vec<_>>()
+        .await;
+    let reported = checked
+        .iter()
+        .filter_map(|checked| Some((&checked.image.url, checked.size.filter(|_| checked.ok())?)))
+        .collect::<HashMap<_, _>>();
+
+    let mut hosts = BTreeMap::<String, Host>::new();
+    let (mut from_url_file, mut from_servers) = (0, 0);
+    for image in images {
+        let host = hosts.entry(host_of(image)).or_default();
+        host.items += 1;
+        match (image.size, reported.get(&image.url)) {
+            (Some(size), _) => {
+                host.size += size;
+                from_url_file += 1;
+            }
+            (None, Some(size)) => {
+                host.size += size;
+                from_servers += 1;
+            }
+            (None, None) => host.unknown += 1,
+        }
+    }
+    for checked in &checked {
+        let host = hosts
+            .get_mut(&host_of(checked.image))
+            .expect("counted above");
+        host.requests += 1;
+        match &checked.error {
+            Some(error) => host.error = Some(error.clone()),
+            None => host.responses += 1,
+        }
+    }
+    let failed = checked
+        .iter()
+        .filter(|checked| checked.status.is_some_and(|status| !status.is_success()))
+        .collect::<Vec<_>>();
+    let unreachable = hosts
+        .iter()
+        .filter(|(_, host)| host.unreachable())
+        .collect::<Vec<_>>();
+
+    let mut by_url = HashMap::<&str, Vec<&Image>>::new();
+    for image in images {
+        by_url.entry(&image.url).or_default().push(image);
+    }
+    let mut duplicates = by_url
+        .into_values()
+        .filter(|images| images.len() > 1)
+        .collect::<Vec<_>>();
+    duplicates.sort_by_key(|images| images[0].source.clone());
+    let collisions = collisions(images);
+
+    let total = hosts.values().map(|host| host.size).sum::<u64>();
+    let unknown = images.len() - from_url_file - from_servers;
+    let known = from_url_file + from_servers;
+    // the unknown sizes counted at the average of the known ones
+    let estimate = match known {
+        0 => None,
+        known => Some(total + total / known as u64 * unknown as u64),
+    };
+    let sources = |images: &[&Image]| {
+        images
+            .iter()
+            .map(|image| image.source.clone().unwrap_or_default())
+            .collect::<Vec<_>>()
+    };
+
+    if json {
+        let report = json!({
+            "items": images.len(),
+            "size": {
+                "total": total,
+                "from_url_file": from_url_file,
+                "from_servers": from_servers,
+                "unknown": unknown,
+                "estimate": estimate,
+            },
+            "hosts": hosts
+                .iter()
+                .map(|(name, host)| json!({
+                    "host": name,
+                    "items": host.items,
+                    "size": host.size,
+                    "unknown": host.unknown,
+                    "unreachable": host.unreachable(),
+                    "error": host.error,
+                }))
+                .collect::<Vec<_>>(),
+            "duplicate_urls": duplicates
+                .iter()
+                .map(|images| json!({"url": images[0].url, "sources": sources(images)}))
+                .collect::<Vec<_>>(),
+            "collisions": collisions
+                .iter()
+                .map(|collision| json!({
+                    "path": collision.path,
+                    "reason": collision.reason,
+                    "sources": sources(&collision.images),
+                }))
+                .collect::<Vec<_>>(),
+            "failed": failed
+                .iter()
+                .map(|checked| json!({
+                    "url": checked.image.url,
+                    "source": checked.image.source,
+                    "status": checked.status.map(|status| status.as_u16()),
+                }))
+                .collect::<Vec<_>>(),
+        });
+        println!("{}", serde_json::to_string_pretty(&report).unwrap());
+    } else {
+        println!("{} items on {} hosts", images.len(), hosts.len());
+        let mut size = format!(
+            "{} in total: {} sizes from the url file, {} from servers, {} unknown",
+            HumanBytes(total),
+            from_url_file,
+            from_servers,
+            unknown
+        );
+        if let (Some(estimate), 1..) = (estimate, unknown) {
+            size.push_str(&format!(", about {} counting those", HumanBytes(estimate)));
+        }
+        println!("{}", size);
+        println!();
+        println!(
+            "{:<32} {:>8} {:>10} {:>8}  REACHABLE",
+            "HOST", "ITEMS", "SIZE", "UNKNOWN"
+        );
+        for (name, host) in &hosts {
+            println!(
+                "{:<32} {:>8} {:>10} {:>8}  {}",
+                name,
+                host.items,
+                HumanBytes(host.size).to_string(),
+                host.unknown,
+                if host.unreachable() { "no" } else { "yes" }
+            );
+        }
+        if !duplicates.is_empty() {
+            println!();
+            println!("{} urls listed more than once:", duplicates.len());
+            for images in &duplicates {
+                println!("  {} at {}", images[0].url, sources(images).join(", "));
+            }
+        }
+        if !collisions.is_empty() {
+            println!();
+            println!("{} paths written by more than one item:", collisions.len());
+            for collision in &collisions {
+                println!(
+                    "  {} ({}) at {}",
+                    collision.path,
+                    collision.reason,
+                    sources(&collision.images).join(", ")
+                );
+            }
+        }
+        if !failed.is_empty() {
+            println!();
+            println!("{} urls answered with an error status:", failed.len());
+            for checked in &failed {
+                println!(
+                    "  {} {} at {}",
+                    checked.status.expect("filtered").as_u16(),
+                    checked.image.url,
+                    checked.image.source.as_deref().unwrap_or("-")
+                );
+            }
+        }
+        if !unreachable.is_empty() {
+            println!();
+            println!("{} hosts unreachable:", unreachable.len());
+            for (name, host) in &unreachable {
+                println!("  {} ({})", name, host.error.as_deref().unwrap_or("-"));
+            }
+        }
+    }
+    Ok(duplicates.len() + collisions.len() + failed.len() + unreachable.len())
+}
+
+struct Collision<'a> {
+    path: String,
+    reason: &'static str,
+    images: Vec<&'a Image>,
+}
+
+/// Paths that more than one item writes, also counting `copies=`, paths that
+/// only differ in case, which are the same file on macOS and Windows, and
+/// files at the path of another item's directory.
+fn collisions(images: &[Image]) -> Vec<Collision<'_>> {
+    let mut by_path = BTreeMap::<String, Vec<(&str, &Image)>>::new();
+    for image in images {
+        for path in std::iter::once(&image.file_name).chain(&image.copies) {
+            by_path
+                .entry(path.to_lowercase())
+                .or_default()
+                .push((path, image));
+        }
+    }
+    let mut collisions = Vec::new();
+    for writers in by_path.values() {
+        // the same url at the same path twice is a duplicate url instead
+        let distinct = writers
+            .iter()
+            .map(|(path, image)| (*path, image.url.as_str()))
+            .collect::<HashSet<_>>();
+        if distinct.len() < 2 {
+            continue;
+        }
+        let same_path = writers.iter().all(|(path, _)| *path == writers[0].0);
+        collisions.push(Collision {
+            path: writers[0].0.to_string(),
+            reason: if same_path {
+                "same path"
+            } else {
+                "paths differ only in case"
+            },
+            images: writers.iter().map(|(_, image)| *image).collect(),
+        });
+    }
+    // the first item under each directory
+    let mut dirs = HashMap::new();
+    for (path, writers) in &by_path {
+        let mut parent = path.as_str();
+        while let Some((dir, _)) = parent.rsplit_once('/') {
+            dirs.entry(dir).or_insert(writers[0].1);
+            parent = dir;
+        }
+    }
+    for (path, writers) in &by_path {
+        if let Some(inside) = dirs.get(path.as_str()) {
+            collisions.push(Collision {
+                path: writers[0].0.to_string(),
+                reason: "a file where another item needs a directory",
+                images: vec![writers[0].1, *inside],
+            });
+        }
+    }
+    collisions
+}
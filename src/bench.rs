@@ -0,0 +1,91 @@
+//! `fast_download bench`: downloads one url at increasing concurrency to find
+//! the setting with the best throughput. Downloads aren't split into segments,
+//! so concurrency is the only setting measured.
+
+use fast_download::{Downloader, Image, Options};
+use indicatif::HumanBytes;
+use std::{
+    env, process,
+    time::{Duration, Instant},
+};
+use tokio::fs;
+
+/// Settings within this share of the best throughput count as just as fast,
+/// so the recommendation doesn't open connections for noise.
+const GOOD_ENOUGH: f64 = 0.95;
+
+pub async fn run(url: &str, options: &Options) -> Result<(), String> {
+    let dir = env::temp_dir().join(format!("fast_download-bench-{}", process::id()));
+    let result = bench(url, options, &dir.to_string_lossy()).await;
+    let _ = fs::remove_dir_all(&dir).await;
+    result
+}
+
+async fn bench(url: &str, options: &Options, dir: &str) -> Result<(), String> {
+    let max = options.max_concurrent_downloads.max(1);
+    let mut levels = (0..)
+        .map(|shift| 1 << shift)
+        .take_while(|&level| level < max)
+        .collect::<Vec<usize>>();
+    levels.push(max);
+    // the first download pays for DNS and connection setup, leave it out
+    measure(url, options, dir, 1).await?;
+    println!(
+        "{:>11}  {:>12}  {:>10}",
+        "CONCURRENCY", "THROUGHPUT", "TIME"
+    );
+    let mut results = Vec::new();
+    for level in levels {
+        let (bytes, elapsed) = measure(url, options, dir, level).await?;
+        let throughput = bytes as f64 / elapsed.as_secs_f64().max(f64::EPSILON);
+        println!(
+            "{:>11}  {:>10}/s  {:>10.2?}",
+            level,
+            HumanBytes(throughput as u64),
+            elapsed
+        );
+        results.push((level, throughput));
+    }
+    let best = results
+        .iter()
+        .map(|(_, throughput)| *throughput)
+        .fold(0.0, f64::max);
+    let (recommended, _) = results
+        .iter()
+        .find(|(_, throughput)| *throughput >= best * GOOD_ENOUGH)
+        .expect("the best level is good enough");
+    println!("recommended: -c{}", recommended);
+    Ok(())
+}
+
+/// Downloads `concurrency` copies of `url` at once, returning the bytes
+/// received and how long it took.
+async fn measure(
+    url: &str,
+    options: &Options,
+    dir: &str,
+    concurrency: usize,
+) -> Result<(u64, Duration), String> {
+    let downloader = Downloader::new(Options {
+        max_concurrent_downloads: concurrency,
+        force_redownload: true,
+        record_requests: false,
+        ..options.clone()
+    })
+    .map_err(|err| err.to_string())?;
+    let images = (0..concurrency)
+        .map(|i| Image::new(url, format!("{}/{}-{}", dir, concurrency, i)))
+        .collect::<Vec<_>>();
+    let start = Instant::now();
+    let results = downloader.download_all(&images).await;
+    let elapsed = start.elapsed();
+    let mut bytes = 0;
+    for (image, result) in images.iter().zip(results) {
+        result.map_err(|err| err.to_string())?;
+        if let Ok(metadata) = fs::metadata(&image.file_name).await {
+            bytes += metadata.len();
+        }
+        let _ = fs::remove_file(&image.file_name).await;
+    }
+    Ok((bytes, elapsed))
+}
@@ -0,0 +1,150 @@
+//! What browsers export as url files: a HAR file saved from the network panel
+//! of the devtools, or the JSON list of downloads extensions export from
+//! `chrome.downloads`, so a captured session is fetched again at full speed.
+//!
+//! Entries keep the `Cookie`, `Referer` and `User-Agent` they were requested
+//! with, which sites that check the session or where links come from need.
+
+use fast_download::Image;
+use reqwest::Url;
+use serde_json::Value;
+use std::collections::HashSet;
+
+/// The request headers taken along from a HAR.
+const KEPT_HEADERS: &[&str] = &["cookie", "referer", "user-agent"];
+
+/// Whether `text` is JSON rather than url file lines, which can't start with
+/// a brace or bracket.
+pub fn is_export(text: &str) -> bool {
+    text.trim_start().starts_with(['{', '['])
+}
+
+/// The entries of a HAR or downloads list, each with the index it has there.
+pub fn parse(text: &str) -> Result<Vec<(usize, Image)>, String> {
+    let json = serde_json::from_str::<Value>(text).map_err(|err| err.to_string())?;
+    let images = match &json {
+        Value::Object(har) => {
+            let entries = har
+                .get("log")
+                .and_then(|log| log.get("entries"))
+                .and_then(Value::as_array)
+                .ok_or("expected a HAR with log.entries")?;
+            entries
+                .iter()
+                .enumerate()
+                .filter_map(|(index, entry)| Some((index, from_har(entry)?)))
+                .collect::<Vec<_>>()
+        }
+        Value::Array(downloads) => downloads
+            .iter()
+            .enumerate()
+            .filter_map(|(index, item)| Some((index, from_download(item)?)))
+            .collect(),
+        _ => return Err("expected a HAR or an array of downloads".to_string()),
+    };
+    // pages load the same url many times, and different urls can save to the
+    // same path
+    let mut paths = HashSet::new();
+    Ok(images
+        .into_iter()
+        .filter(|(_, image)| paths.insert(image.file_name.clone()))
+        .collect())
+}
+
+/// A successful GET of a HAR, with its cookies, referer and user agent.
+fn from_har(entry: &Value) -> Option<Image> {
+    let request = entry.get("request")?;
+    if request.get("method")?.as_str()? != "GET" {
+        return None;
+    }
+    let status = entry.get("response")?.get("status")?.as_u64()?;
+    if !(200..300).contains(&status) && status != 304 {
+        return None;
+    }
+    let url = request.get("url")?.as_str()?;
+    let mut image = Image::new(url, path_of(url)?);
+    let fields = |value: Option<&Value>| {
+        value
+            .and_then(Value::as_array)
+            .into_iter()
+            .flatten()
+            .filter_map(|field| {
+                let name = field.get("name")?.as_str()?;
+                let value = field.get("value")?.as_str()?;
+                Some((name.to_string(), value.to_string()))
+            })
+            .collect::<Vec<_>>()
+    };
+    // HTTP/2 requests list pseudo-headers and leave out the cookie header
+    for (name, value) in fields(request.get("headers")) {
+        if KEPT_HEADERS.contains(&name.to_ascii_lowercase().as_str()) {
+            image.headers.push((name, value));
+        }
+    }
+    let cookies = fields(request.get("cookies"));
+    let has_cookie = image
+        .headers
+        .iter()
+        .any(|(name, _)| name.eq_ignore_ascii_case("cookie"));
+    if !has_cookie && !cookies.is_empty() {
+        let cookie = cookies
+            .iter()
+            .map(|(name, value)| format!("{}={}", name, value))
+            .collect::<Vec<_>>()
+            .join("; ");
+        image.headers.push(("Cookie".to_string(), cookie));
+    }
+    Some(image)
+}
+
+/// An item of a `chrome.downloads` export: its final url, saved under the
+/// name it had, with its referrer and size.
+fn from_download(item: &Value) -> Option<Image> {
+    let text = |key| {
+        item.get(key)
+            .and_then(Value::as_str)
+            .filter(|s| !s.is_empty())
+    };
+    let url = text("finalUrl").or_else(|| text("url"))?;
+    let name = match text("filename") {
+        // the browser's own path, of which only the name is kept
+        Some(filename) => filename.rsplit(['/', '\\']).next()?.to_string(),
+        None => path_of(url)?,
+    };
+    let mut image = Image::new(url, name);
+    if let Some(referrer) = text("referrer") {
+        image
+            .headers
+            .push(("Referer".to_string(), referrer.to_string()));
+    }
+    image.size = item
+        .get("totalBytes")
+        .or_else(|| item.get("fileSize"))
+        .and_then(Value::as_u64)
+        .filter(|&size| size > 0);
+    Some(image)
+}
+
+/// Where a url is saved: its host and path, like `wget -x`, with
+/// `index.html` for directories.
+pub fn path_of(url: &str) -> Option<String> {
+    let url = Url::parse(url).ok()?;
+    if !matches!(url.scheme(), "http" | "https") {
+        return None;
+    }
+    let mut path = vec![url.host_str()?.to_string()];
+    for segment in url.path_segments()? {
+        let segment = percent_encoding::percent_decode_str(segment)
+            .decode_utf8()
+            .ok()?;
+        if segment == ".." || segment.contains('\\') {
+            return None;
+        }
+        path.push(segment.into_owned());
+    }
+    if path.last().is_some_and(|last| last.is_empty()) {
+        path.pop();
+        path.push("index.html".to_string());
+    }
+    Some(path.join("/"))
+}
@@ -0,0 +1,163 @@
+//! A content-addressable cache of HTTP responses on disk.
+//!
+//! Bodies are stored once per checksum as `blobs/<ab>/<sha256>`, and every
+//! cached url has an entry `urls/<sha256 of the url>.json` naming its blob and
+//! the response headers worth replaying. Files only appear under their final
+//! names once complete, so concurrent readers never see partial data.
+
+use fast_download::sigv4::hex;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    io,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicU64, Ordering},
+};
+use tokio::{fs, io::AsyncWriteExt};
+
+/// Distinguishes the temp files of writes running at the same time.
+static NEXT_TEMP: AtomicU64 = AtomicU64::new(0);
+
+pub struct Cache {
+    dir: PathBuf,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Entry {
+    pub url: String,
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub sha256: String,
+    pub size: u64,
+    /// Until when, in seconds since the epoch, the response may be served
+    /// without asking the origin; `None` once it has to be revalidated.
+    #[serde(default)]
+    pub fresh_until: Option<u64>,
+}
+
+/// A body being written to the cache, see [`Cache::writer`].
+pub struct Writer {
+    temp: PathBuf,
+    file: fs::File,
+    hasher: Sha256,
+    size: u64,
+}
+
+impl Cache {
+    pub async fn open(dir: &Path) -> io::Result<Cache> {
+        for sub in ["blobs", "urls", "tmp"] {
+            fs::create_dir_all(dir.join(sub)).await?;
+        }
+        Ok(Cache {
+            dir: dir.to_path_buf(),
+        })
+    }
+
+    /// The entry for `url` if its blob is still there.
+    pub async fn get(&self, url: &str) -> Option<(Entry, PathBuf)> {
+        let json = fs::read(self.entry_path(url)).await.ok()?;
+        let entry = serde_json::from_slice::<Entry>(&json).ok()?;
+        let blob = self.blob_path(&entry.sha256);
+        fs::try_exists(&blob)
+            .await
+            .unwrap_or(false)
+            .then_some((entry, blob))
+    }
+
+    pub async fn writer(&self) -> io::Result<Writer> {
+        let temp = self.dir.join("tmp").join(format!(
+            "{}.{}",
+            std::process::id(),
+            NEXT_TEMP.fetch_add(1, Ordering::Relaxed)
+        ));
+        Ok(Writer {
+            file: fs::File::create(&temp).await?,
+            temp,
+            hasher: Sha256::new(),
+            size: 0,
+        })
+    }
+
+    /// Stores the body of `writer` as the response for `url`.
+    pub async fn commit(
+        &self,
+        writer: Writer,
+        url: &str,
+        status: u16,
+        headers: Vec<(String, String)>,
+        fresh_until: Option<u64>,
+    ) -> io::Result<Entry> {
+        let Writer {
+            temp,
+            mut file,
+            hasher,
+            size,
+        } = writer;
+        file.flush().await?;
+        drop(file);
+        let sha256 = hex(&hasher.finalize());
+        let blob = self.blob_path(&sha256);
+        if let Some(dir) = blob.parent() {
+            fs::create_dir_all(dir).await?;
+        }
+        // a blob with this checksum already has these contents
+        if fs::try_exists(&blob).await.unwrap_or(false) {
+            fs::remove_file(&temp).await?;
+        } else {
+            fs::rename(&temp, &blob).await?;
+        }
+        let entry = Entry {
+            url: url.to_string(),
+            status,
+            headers,
+            sha256,
+            size,
+            fresh_until,
+        };
+        self.save(&entry).await?;
+        Ok(entry)
+    }
+
+    /// Writes `entry` over the one of its url, like after a revalidation
+    /// renewed its freshness.
+    pub async fn save(&self, entry: &Entry) -> io::Result<()> {
+        let json = serde_json::to_vec_pretty(entry).expect("entries serialize");
+        let path = self.entry_path(&entry.url);
+        let staged = path.with_extension("json.part");
+        fs::write(&staged, json).await?;
+        fs::rename(&staged, &path).await
+    }
+
+    fn entry_path(&self, url: &str) -> PathBuf {
+        self.dir
+            .join("urls")
+            .join(format!("{}.json", hex(&Sha256::digest(url))))
+    }
+
+    fn blob_path(&self, sha256: &str) -> PathBuf {
+        self.dir.join("blobs").join(&sha256[..2]).join(sha256)
+    }
+}
+
+impl Writer {
+    pub async fn write(&mut self, chunk: &[u8]) -> io::Result<()> {
+        self.hasher.update(chunk);
+        self.size += chunk.len() as u64;
+        self.file.write_all(chunk).await
+    }
+
+    /// Drops an incomplete body.
+    pub async fn discard(self) {
+        drop(self.file);
+        let _ = fs::remove_file(&self.temp).await;
+    }
+}
+
+/// `$XDG_CACHE_HOME/fast_download` or its equivalent.
+pub fn default_dir() -> Option<PathBuf> {
+    let dir = std::env::var_os("XDG_CACHE_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("LOCALAPPDATA").map(PathBuf::from))
+        .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".cache")))?;
+    Some(dir.join("fast_download"))
+}
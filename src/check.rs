@@ -0,0 +1,197 @@
+//! `fast_download check`: HEADs every url of a url file to triage it before
+//! downloading. The same requests estimate the batch size for `--confirm-over`.
+
+use fast_download::{Image, Options, UrlPolicy};
+use futures::{stream, StreamExt};
+use indicatif::{HumanBytes, ProgressBar};
+use reqwest::{header, Method, StatusCode};
+use serde_json::json;
+
+/// What the requests of `check`, `audit`, `crawl`, `--repair` and
+/// `--discover-sums` go through: the client of the downloads, and their url policy.
+pub struct Client {
+    http: reqwest::Client,
+    policy: Option<UrlPolicy>,
+}
+
+impl Client {
+    pub fn new(options: &Options) -> Result<Client, String> {
+        Ok(Client {
+            http: options.client().map_err(|err| err.to_string())?,
+            policy: options.url_policy.clone(),
+        })
+    }
+
+    /// A `method` request for `url`, unless the policy blocks the url.
+    pub fn request(&self, method: Method, url: &str) -> Result<reqwest::RequestBuilder, String> {
+        if let Some(policy) = &self.policy {
+            policy.check(url)?;
+        }
+        Ok(self.http.request(method, url))
+    }
+
+    /// Sends a `method` request for `url` with `headers`, unless the policy
+    /// blocks the url.
+    pub async fn send(
+        &self,
+        method: Method,
+        url: &str,
+        headers: &[(String, String)],
+    ) -> Result<reqwest::Response, String> {
+        let mut request = self.request(method, url)?;
+        for (name, value) in headers {
+            request = request.header(name, value);
+        }
+        request.send().await.map_err(|err| err.to_string())
+    }
+}
+
+pub struct Checked<'a> {
+    pub image: &'a Image,
+    pub status: Option<StatusCode>,
+    pub size: Option<u64>,
+    pub content_type: Option<String>,
+    /// The final url when the server redirected.
+    pub redirect: Option<String>,
+    /// Why there was no response.
+    pub error: Option<String>,
+}
+
+impl Checked<'_> {
+    pub fn ok(&self) -> bool {
+        self.status.is_some_and(|status| status.is_success())
+    }
+}
+
+/// Prints a line per url in url file order and returns how many failed.
+pub async fn run(images: &[Image], client: &Client, max_concurrent: usize, json: bool) -> usize {
+    let checked = stream::iter(images)
+        .map(|image| check(client, image))
+        .buffered(max_concurrent.max(1))
+        .collect::<Vec<_>>()
+        .await;
+    if json {
+        let items = checked
+            .iter()
+            .map(|checked| {
+                json!({
+                    "url": checked.image.url,
+                    "path": checked.image.file_name,
+                    "status": checked.status.map(|status| status.as_u16()),
+                    "size": checked.size,
+                    "content_type": checked.content_type,
+                    "redirect": checked.redirect,
+                    "error": checked.error,
+                })
+            })
+            .collect::<Vec<_>>();
+        println!("{}", serde_json::to_string_pretty(&items).unwrap());
+    } else {
+        println!("{:<6} {:>10}  {:<24}  URL", "STATUS", "SIZE", "TYPE");
+        for checked in &checked {
+            let status = checked
+                .status
+                .map_or("-".to_string(), |status| status.as_u16().to_string());
+            let size = checked
+                .size
+                .map_or("-".to_string(), |size| HumanBytes(size).to_string());
+            let mut line = format!(
+                "{:<6} {:>10}  {:<24}  {}",
+                status,
+                size,
+                checked.content_type.as_deref().unwrap_or("-"),
+                checked.image.url
+            );
+            if let Some(redirect) = &checked.redirect {
+                line.push_str(&format!(" -> {}", redirect));
+            }
+            if let Some(error) = &checked.error {
+                line.push_str(&format!(" ({})", error));
+            }
+            println!("{}", line);
+        }
+    }
+    let failed = checked.iter().filter(|checked| !checked.ok()).count();
+    let total = checked
+        .iter()
+        .filter(|checked| checked.ok())
+        .filter_map(|checked| checked.size)
+        .sum::<u64>();
+    eprintln!(
+        "{} ok, {} failed, {} in total",
+        checked.len() - failed,
+        failed,
+        HumanBytes(total)
+    );
+    failed
+}
+
+/// Sums the sizes servers report for `images`, returning the total and how many
+/// urls didn't report one.
+pub async fn total_size(
+    images: &[Image],
+    client: &Client,
+    max_concurrent: usize,
+    progress: &ProgressBar,
+) -> (u64, usize) {
+    progress.set_length(images.len() as u64);
+    stream::iter(images)
+        .map(|image| check(client, image))
+        .buffer_unordered(max_concurrent.max(1))
+        .inspect(|_| progress.inc(1))
+        .fold((0, 0), |(total, unknown), checked| async move {
+            match checked.size.filter(|_| checked.ok()) {
+                Some(size) => (total + size, unknown),
+                None => (total, unknown + 1),
+            }
+        })
+        .await
+}
+
+/// The size the server reports for `image`, when it answers with a 2xx status.
+pub async fn remote_size(client: &Client, image: &Image) -> Option<u64> {
+    let checked = check(client, image).await;
+    checked.size.filter(|_| checked.ok())
+}
+
+/// HEADs `image`, or asks for the headers of a GET where HEAD isn't allowed.
+pub async fn check<'a>(client: &Client, image: &'a Image) -> Checked<'a> {
+    let mut checked = Checked {
+        image,
+        status: None,
+        size: None,
+        content_type: None,
+        redirect: None,
+        error: None,
+    };
+    let request = |method| client.send(method, &image.url, &image.headers);
+    let mut response = request(Method::HEAD).await;
+    // some servers don't implement HEAD, ask for the headers of a GET instead
+    if let Ok(head) = &response {
+        if matches!(
+            head.status(),
+            StatusCode::METHOD_NOT_ALLOWED | StatusCode::NOT_IMPLEMENTED
+        ) {
+            response = request(Method::GET).await;
+        }
+    }
+    match response {
+        Ok(response) => {
+            checked.status = Some(response.status());
+            checked.size = response
+                .headers()
+                .get(header::CONTENT_LENGTH)
+                .and_then(|len| len.to_str().ok()?.parse().ok());
+            checked.content_type = response
+                .headers()
+                .get(header::CONTENT_TYPE)
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_string);
+            if reqwest::Url::parse(&image.url).ok().as_ref() != Some(response.url()) {
+                checked.redirect = Some(response.url().to_string());
+            }
+        }
+        Err(err) => checked.error = Some(err),
+    }
+    checked
+}
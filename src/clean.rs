@@ -0,0 +1,164 @@
+//! `fast_download clean [<dir>]`: keeps a long-lived download directory tidy.
+//! It removes the `.part` files of downloads that stopped long ago, under
+//! `<dir>` and `--temp-dir`, `.lock` files left by url files that are gone,
+//! entries quarantined long ago in `--quarantine`, and the runs of the history
+//! whose url file no longer exists.
+//!
+//! Long ago is `--older-than`, a week by default, so parts a running download
+//! writes, or `--resume` could still continue tomorrow, stay.
+
+use crate::{history, sync};
+use chrono::DateTime;
+use std::{
+    collections::HashSet,
+    fs::{self, File},
+    io,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+pub const DEFAULT_AGE: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// Removes what is stale, or with `dry_run` prints what would be, and prints
+/// a summary.
+pub fn run(
+    dir: &Path,
+    temp_dir: Option<&Path>,
+    quarantine: Option<&Path>,
+    history: Option<&Path>,
+    older_than: Duration,
+    dry_run: bool,
+    verbose: bool,
+) -> Result<(), String> {
+    let remove = |root: &Path, files: &[PathBuf]| {
+        for file in files {
+            match (dry_run, verbose) {
+                (true, _) => println!("delete: {}", file.display()),
+                (false, true) => println!("deleted: {}", file.display()),
+                (false, false) => {}
+            }
+        }
+        match dry_run {
+            true => Ok(()),
+            false => sync::remove(root, files).map_err(fail(root)),
+        }
+    };
+
+    let mut seen = HashSet::new();
+    let (mut parts, mut locks) = (0, 0);
+    for root in std::iter::once(dir).chain(temp_dir) {
+        let files = sync::extraneous(root, &HashSet::new()).map_err(fail(root))?;
+        let mut stale = Vec::new();
+        for file in files {
+            if !seen.insert(file.clone()) {
+                continue;
+            }
+            let name = file.file_name().unwrap_or_default().to_string_lossy();
+            if name.ends_with(".part") && age(&file) >= older_than {
+                stale.push(file);
+                parts += 1;
+            } else if name.ends_with(".lock") && orphaned_lock(&file) {
+                stale.push(file);
+                locks += 1;
+            }
+        }
+        remove(root, &stale)?;
+    }
+
+    let mut quarantined = 0;
+    if let Some(root) = quarantine.filter(|root| root.is_dir()) {
+        let files = sync::extraneous(root, &HashSet::new()).map_err(fail(root))?;
+        let present = files.iter().cloned().collect::<HashSet<_>>();
+        let mut stale = Vec::new();
+        for file in &files {
+            let name = file.file_name().unwrap_or_default().to_string_lossy();
+            if let Some(file_name) = name.strip_suffix(".reason") {
+                // a reason goes with its file, or alone if that was removed
+                if !present.contains(&file.with_file_name(file_name)) && age(file) >= older_than {
+                    stale.push(file.clone());
+                }
+                continue;
+            }
+            let reason = reason_path(file);
+            if quarantined_for(file, &reason) >= older_than {
+                stale.push(file.clone());
+                quarantined += 1;
+                if present.contains(&reason) {
+                    stale.push(reason);
+                }
+            }
+        }
+        remove(root, &stale)?;
+    }
+
+    let mut runs = 0;
+    if let Some(path) = history {
+        for (url_file, count) in history::forget_missing(path, dry_run)? {
+            match (dry_run, verbose) {
+                (true, _) => println!("forget: {} runs of {}", count, url_file.display()),
+                (false, true) => println!("forgot: {} runs of {}", count, url_file.display()),
+                (false, false) => {}
+            }
+            runs += count;
+        }
+    }
+
+    let (deleted, forgot) = match dry_run {
+        true => ("would delete", "forget"),
+        false => ("deleted", "forgot"),
+    };
+    eprintln!(
+        "clean: {} {} part files, {} lock files and {} quarantined files, {} {} runs of missing url files",
+        deleted, parts, locks, quarantined, forgot, runs
+    );
+    Ok(())
+}
+
+fn fail(dir: &Path) -> impl Fn(io::Error) -> String + '_ {
+    move |err| format!("{}: {}", dir.display(), err)
+}
+
+/// How long ago `path` was last modified.
+fn age(path: &Path) -> Duration {
+    fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+        .and_then(|modified| SystemTime::now().duration_since(modified).ok())
+        .unwrap_or_default()
+}
+
+/// Whether `lock` is the lock file of a url file that no longer exists and no
+/// download holds it. Those hold a pid, unlike the lock files of package
+/// managers, which are left alone.
+fn orphaned_lock(lock: &Path) -> bool {
+    if lock.with_extension("").exists() {
+        return false;
+    }
+    let pid = fs::read_to_string(lock).unwrap_or_default();
+    if !pid.trim().chars().all(|c| c.is_ascii_digit()) {
+        return false;
+    }
+    let file = File::options().write(true).open(lock);
+    matches!(file.map(|file| file.try_lock()), Ok(Ok(())))
+}
+
+fn reason_path(file: &Path) -> PathBuf {
+    let mut path = file.as_os_str().to_owned();
+    path.push(".reason");
+    PathBuf::from(path)
+}
+
+/// How long ago `file` was quarantined: the `time:` of its reason, or when it
+/// was last modified without one.
+fn quarantined_for(file: &Path, reason: &Path) -> Duration {
+    let time = fs::read_to_string(reason).ok().and_then(|text| {
+        let time = text.lines().find_map(|line| line.strip_prefix("time: "))?;
+        DateTime::parse_from_rfc3339(time.trim()).ok()
+    });
+    match time {
+        Some(time) => SystemTime::now()
+            .duration_since(SystemTime::from(time))
+            .unwrap_or_default(),
+        None => age(file),
+    }
+}
@@ -0,0 +1,159 @@
+//! `fast_download completions <shell>`: completion scripts for bash, zsh, fish
+//! and PowerShell.
+//!
+//! The flags are read from the usage text, so every flag documented there is
+//! completed without keeping a second list in sync.
+
+pub const SHELLS: &[&str] = &["bash", "zsh", "fish", "powershell"];
+
+const SUBCOMMANDS: &[&str] = &[
+    "check",
+    "audit",
+    "verify",
+    "bench",
+    "bench-io",
+    "completions",
+    "serve-files",
+    "proxy",
+    "coordinator",
+    "worker",
+    "daemon",
+    "self-update",
+    "history",
+    "clean",
+    "expand",
+    "crawl",
+    "export",
+];
+
+struct Flag {
+    /// Like `-q` or `--quiet`.
+    names: Vec<String>,
+    takes_value: bool,
+    help: String,
+}
+
+pub fn script(shell: &str, usage: &str) -> Option<String> {
+    let flags = flags(usage);
+    let names = flags
+        .iter()
+        .flat_map(|flag| flag.names.iter().cloned())
+        .collect::<Vec<_>>()
+        .join(" ");
+    let subcommands = SUBCOMMANDS.join(" ");
+    let script = match shell {
+        "bash" => format!(
+            r#"_fast_download() {{
+    local cur=${{COMP_WORDS[COMP_CWORD]}}
+    if [[ $cur == -* ]]; then
+        COMPREPLY=($(compgen -W "{names}" -- "$cur"))
+        return
+    fi
+    if [[ $COMP_CWORD -eq 2 && ${{COMP_WORDS[1]}} == completions ]]; then
+        COMPREPLY=($(compgen -W "{shells}" -- "$cur"))
+        return
+    fi
+    COMPREPLY=($(compgen -f -- "$cur"))
+    if [[ $COMP_CWORD -eq 1 ]]; then
+        COMPREPLY+=($(compgen -W "{subcommands}" -- "$cur"))
+    fi
+}}
+complete -o filenames -F _fast_download fast_download
+"#,
+            shells = SHELLS.join(" "),
+        ),
+        "zsh" => format!(
+            r#"#compdef fast_download
+
+if [[ $PREFIX == -* ]]; then
+    compadd -- {names}
+elif (( CURRENT == 3 )) && [[ $words[2] == completions ]]; then
+    compadd -- {shells}
+else
+    (( CURRENT == 2 )) && compadd -- {subcommands}
+    _files
+fi
+"#,
+            shells = SHELLS.join(" "),
+        ),
+        "fish" => {
+            let mut script = format!(
+                "complete -c fast_download -n __fish_use_subcommand -a '{}'\n\
+                 complete -c fast_download -n '__fish_seen_subcommand_from completions' -x -a '{}'\n",
+                subcommands,
+                SHELLS.join(" ")
+            );
+            for flag in &flags {
+                let mut line = "complete -c fast_download".to_string();
+                for name in &flag.names {
+                    match name.strip_prefix("--") {
+                        Some(long) => line.push_str(&format!(" -l {}", long)),
+                        None => line.push_str(&format!(" -s {}", &name[1..])),
+                    }
+                }
+                if flag.takes_value {
+                    line.push_str(" -r");
+                }
+                line.push_str(&format!(" -d '{}'", flag.help.replace('\'', "\\'")));
+                script.push_str(&line);
+                script.push('\n');
+            }
+            script
+        }
+        "powershell" => {
+            let words = SUBCOMMANDS
+                .iter()
+                .map(|word| word.to_string())
+                .chain(flags.iter().flat_map(|flag| flag.names.iter().cloned()))
+                .map(|word| format!("'{}'", word))
+                .collect::<Vec<_>>()
+                .join(", ");
+            format!(
+                r#"Register-ArgumentCompleter -Native -CommandName fast_download -ScriptBlock {{
+    param($wordToComplete, $commandAst, $cursorPosition)
+    @({words}) | Where-Object {{ $_ -like "$wordToComplete*" }} | ForEach-Object {{
+        [System.Management.Automation.CompletionResult]::new($_, $_, 'ParameterValue', $_)
+    }}
+}}
+"#
+            )
+        }
+        _ => return None,
+    };
+    Some(script)
+}
+
+/// Collects the flags of the `options:` section of the usage text, where each
+/// flag starts a line and its help follows on the same or the next line.
+fn flags(usage: &str) -> Vec<Flag> {
+    let mut flags = Vec::<Flag>::new();
+    let options = usage
+        .split_once("options:\n")
+        .map_or("", |(_, options)| options);
+    for line in options.lines() {
+        let trimmed = line.trim_start();
+        if !trimmed.starts_with('-') {
+            if let Some(flag) = flags.last_mut().filter(|flag| flag.help.is_empty()) {
+                flag.help = trimmed.to_string();
+            }
+            continue;
+        }
+        let (spec, help) = trimmed.split_once("  ").unwrap_or((trimmed, ""));
+        let mut takes_value = false;
+        let names = spec
+            .split(", ")
+            .map(|name| {
+                // `-c<number>` and `--temp-dir <dir>` both take a value
+                let end = name.find([' ', '<']).unwrap_or(name.len());
+                takes_value |= end < name.len();
+                name[..end].to_string()
+            })
+            .collect();
+        flags.push(Flag {
+            names,
+            takes_value,
+            help: help.trim().to_string(),
+        });
+    }
+    flags
+}
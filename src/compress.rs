@@ -0,0 +1,114 @@
+//! [`Options::compress`](crate::Options::compress): compressing downloads as
+//! they are written.
+
+use crate::{sigv4::hex, tree_hash::BlockingTreeHasher};
+use bytes::Bytes;
+use sha2::{Digest, Sha256};
+use std::{
+    io::{self, Write},
+    path::Path,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    Zstd,
+}
+
+impl Compression {
+    pub fn name(self) -> &'static str {
+        match self {
+            Compression::Zstd => "zstd",
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<Compression> {
+        match name {
+            "zstd" => Some(Compression::Zstd),
+            _ => None,
+        }
+    }
+
+    /// The extension added to the file names of compressed files.
+    pub fn extension(self) -> &'static str {
+        match self {
+            Compression::Zstd => ".zst",
+        }
+    }
+}
+
+/// Compresses a download chunk by chunk.
+pub(crate) struct Compressor {
+    encoder: zstd::stream::write::Encoder<'static, Vec<u8>>,
+}
+
+impl Compressor {
+    pub fn new(compression: Compression) -> io::Result<Compressor> {
+        match compression {
+            Compression::Zstd => Ok(Compressor {
+                encoder: zstd::stream::write::Encoder::new(
+                    Vec::new(),
+                    zstd::DEFAULT_COMPRESSION_LEVEL,
+                )?,
+            }),
+        }
+    }
+
+    /// The compressed data `chunk` adds, often none until the encoder has
+    /// collected enough.
+    pub fn compress(&mut self, chunk: &[u8]) -> io::Result<Bytes> {
+        self.encoder.write_all(chunk)?;
+        Ok(Bytes::from(std::mem::take(self.encoder.get_mut())))
+    }
+
+    /// The rest of the compressed data, once the download is complete.
+    pub fn finish(self) -> io::Result<Bytes> {
+        self.encoder.finish().map(Bytes::from)
+    }
+}
+
+/// Size, SHA-256 and with `tree` the SHA-256 tree hash, in lowercase hex, of
+/// what the compressed file at `path` holds, to compare against the ones of
+/// the download.
+pub async fn decompressed_digest(
+    path: &Path,
+    compression: Compression,
+    tree: bool,
+) -> io::Result<(u64, String, Option<String>)> {
+    let path = path.to_path_buf();
+    tokio::task::spawn_blocking(move || {
+        let file = std::fs::File::open(path)?;
+        let mut digests = Digests {
+            sha256: Sha256::new(),
+            tree: tree.then(BlockingTreeHasher::new),
+        };
+        let size = match compression {
+            Compression::Zstd => {
+                io::copy(&mut zstd::stream::read::Decoder::new(file)?, &mut digests)?
+            }
+        };
+        let sha256 = hex(&digests.sha256.finalize());
+        Ok((size, sha256, digests.tree.map(BlockingTreeHasher::finish)))
+    })
+    .await?
+}
+
+/// Writes data to both hashers of [`decompressed_digest`].
+struct Digests {
+    sha256: Sha256,
+    tree: Option<BlockingTreeHasher>,
+}
+
+impl Write for Digests {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        let data = match &mut self.tree {
+            Some(tree) => &data[..tree.write(data)?],
+            None => data,
+        };
+        self.sha256.update(data);
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,123 @@
+//! The optional TOML config file.
+//!
+//! It's read from `--config`, or from `fast_download/config.toml` in the user's
+//! config directory when that exists.
+
+use serde::Deserialize;
+use std::{
+    collections::HashMap,
+    env,
+    path::{Path, PathBuf},
+};
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub rewrite: RewriteConfig,
+    /// Url files `fast_download daemon` downloads on a schedule, as
+    /// `[[manifest]]` tables.
+    #[serde(rename = "manifest")]
+    pub manifests: Vec<ManifestConfig>,
+    pub progress: ProgressConfig,
+    /// Settings for the downloads from particular hosts, as
+    /// `[hosts."cdn.example.com"]` tables.
+    pub hosts: HashMap<String, HostConfig>,
+    /// Named queues of `fast_download daemon`, each with its own settings,
+    /// as `[queues.images]` tables.
+    pub queues: HashMap<String, QueueConfig>,
+}
+
+/// A queue the daemon routes entries to: those with a `queue=<name>` field,
+/// or else those matching its `files` or `tags`.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct QueueConfig {
+    /// Downloads of the queue at once.
+    pub max_concurrent: Option<usize>,
+    /// Bytes per second for all its downloads together, e.g. `"20MBps"`.
+    pub rate: Option<String>,
+    /// The directory its paths are under.
+    pub dir: Option<PathBuf>,
+    /// Globs of file names, e.g. `["*.mp4", "*.mkv"]`.
+    pub files: Vec<String>,
+    /// Tags of entries.
+    pub tags: Vec<String>,
+}
+
+/// Limits and headers of the downloads from a host, or with a `*.example.com`
+/// name from a domain and its subdomains.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HostConfig {
+    /// Downloads from the host at once.
+    pub max_concurrent: Option<usize>,
+    /// Bytes per second for all its downloads together, e.g. `"1MBps"`.
+    pub rate: Option<String>,
+    /// Time between the starts of its downloads, e.g. `"200ms"`.
+    pub delay: Option<String>,
+    /// Request headers, e.g. `["Authorization: Bearer ..."]`.
+    pub headers: Vec<String>,
+}
+
+/// The look of the progress bar, overridden by `--progress-template` and
+/// `--progress-chars`.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ProgressConfig {
+    /// An indicatif template, e.g. `"{wide_bar:.cyan/blue} {pos}/{len} {eta}"`.
+    pub template: Option<String>,
+    /// The characters of the bar, from full to empty, e.g. `"=> "`.
+    pub chars: Option<String>,
+}
+
+/// Url rewriting applied to every item before it's downloaded, see
+/// [`crate::rewrite::Rewriter`].
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RewriteConfig {
+    /// Upgrade `http://` urls to `https://`.
+    pub https: bool,
+    /// Host names to replace, e.g. `"cdn1.example.com" = "cdn2.example.com"`.
+    pub hosts: HashMap<String, String>,
+    pub rules: Vec<RewriteRule>,
+}
+
+/// A regex replacement on the whole url; `replace` can refer to capture
+/// groups as `$1` or `$name`.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RewriteRule {
+    pub pattern: String,
+    pub replace: String,
+}
+
+/// A url file downloaded again whenever the cron expression `schedule`, e.g.
+/// `"0 3 * * *"`, matches.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ManifestConfig {
+    pub url_file: PathBuf,
+    pub schedule: String,
+}
+
+pub fn default_path() -> Option<PathBuf> {
+    let dir = env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("APPDATA").map(PathBuf::from))
+        .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".config")))?;
+    Some(dir.join("fast_download").join("config.toml"))
+}
+
+/// Loads `path`, or the default config file if it exists.
+pub fn load(path: Option<&Path>) -> Result<Config, String> {
+    let path = match path {
+        Some(path) => path.to_path_buf(),
+        None => match default_path() {
+            Some(path) if path.is_file() => path,
+            _ => return Ok(Config::default()),
+        },
+    };
+    let text = std::fs::read_to_string(&path)
+        .map_err(|err| format!("failed to read {}: {}", path.display(), err))?;
+    toml::from_str(&text).map_err(|err| format!("invalid config {}: {}", path.display(), err))
+}
@@ -0,0 +1,409 @@
+//! `fast_download coordinator <url_file>`: hands the entries of a url file to
+//! `fast_download worker`s on other machines.
+//!
+//! Workers lease an item, download it and acknowledge it. A lease that isn't
+//! renewed in time expires and the item goes back to the queue, so the items of
+//! a worker that crashed are retried elsewhere. The protocol is JSON over HTTP:
+//!
+//! - `POST /lease` answers `{"lease": ..., "item": {...}}`, `204` when every
+//!   remaining item is leased, or `410` once all of them are done.
+//! - `POST /renew/<lease>` extends a lease, `404` if it already expired.
+//! - `POST /ack/<lease>` with `{"ok": true}` or `{"ok": false, "error": ...}`.
+//! - `POST /boost/<id>` moves the queued item with that [`Image::id`] to the
+//!   front of the queue, `409` if it's leased or finished already.
+//! - `GET /status` answers the counts of items in every state and the next
+//!   items to be leased, in order.
+//!
+//! With a token every request needs it as `Authorization: Bearer <token>`, and
+//! is answered `401` without it. Only a coordinator listening on loopback can
+//! go without one.
+
+use fast_download::Image;
+use hyper::{
+    header,
+    service::{make_service_fn, service_fn},
+    Body, Method, Request, Response, Server, StatusCode,
+};
+use serde::Deserialize;
+use serde_json::json;
+use std::{
+    collections::VecDeque,
+    convert::Infallible,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::sync::Notify;
+
+pub const DEFAULT_ADDR: &str = "127.0.0.1:7878";
+pub const DEFAULT_LEASE: Duration = Duration::from_secs(60);
+/// How many of the next items `/status` lists.
+const STATUS_NEXT: usize = 20;
+/// How long the coordinator stays up after the last item, so that idle workers
+/// polling for more learn the queue is done.
+const WORKER_GRACE: Duration = Duration::from_secs(3);
+
+enum State {
+    Queued,
+    /// `generation` tells the current lease apart from expired ones.
+    Leased {
+        generation: u64,
+        until: Instant,
+    },
+    Done,
+    Failed(String),
+}
+
+struct Slot {
+    image: Image,
+    state: State,
+    failures: u32,
+    /// Moved to the front of the queue by `/boost`.
+    boosted: bool,
+}
+
+struct Queue {
+    slots: Vec<Slot>,
+    queued: VecDeque<usize>,
+    /// Lease deadlines in the order they were handed out; entries of renewed
+    /// or finished leases are skipped when they come up.
+    expiries: VecDeque<(Instant, usize, u64)>,
+    next_generation: u64,
+    remaining: usize,
+}
+
+struct Shared {
+    queue: Mutex<Queue>,
+    lease: Duration,
+    retries: u32,
+    verbose: bool,
+    finished: Notify,
+    /// The `Authorization` header requests need, from the token.
+    authorization: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Ack {
+    ok: bool,
+    #[serde(default)]
+    error: Option<String>,
+}
+
+/// Serves `images` to workers until every one is downloaded or has failed
+/// `retries + 1` times, and returns the failures. Workers have to send `token`,
+/// which `addr` can only go without on loopback.
+pub async fn run(
+    images: Vec<Image>,
+    addr: SocketAddr,
+    token: Option<&str>,
+    lease: Duration,
+    retries: u32,
+    verbose: bool,
+) -> Result<Vec<(Image, String)>, String> {
+    if token.is_none() && !addr.ip().is_loopback() {
+        return Err(format!(
+            "listening on {} lets anyone on the network take items, pass --token <secret>",
+            addr
+        ));
+    }
+    let remaining = images.len();
+    let shared = Arc::new(Shared {
+        queue: Mutex::new(Queue {
+            queued: (0..images.len()).collect(),
+            slots: images
+                .into_iter()
+                .map(|image| Slot {
+                    image,
+                    state: State::Queued,
+                    failures: 0,
+                    boosted: false,
+                })
+                .collect(),
+            expiries: VecDeque::new(),
+            next_generation: 0,
+            remaining,
+        }),
+        lease,
+        retries,
+        verbose,
+        finished: Notify::new(),
+        authorization: token.map(|token| format!("Bearer {}", token)),
+    });
+    let service_shared = shared.clone();
+    let make_service = make_service_fn(move |_| {
+        let shared = service_shared.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request| {
+                let shared = shared.clone();
+                async move { Ok::<_, Infallible>(respond(&shared, request).await) }
+            }))
+        }
+    });
+    let server = Server::try_bind(&addr)
+        .map_err(|err| format!("failed to listen on {}: {}", addr, err))?
+        .serve(make_service);
+    eprintln!(
+        "coordinating {} items on http://{}/",
+        remaining,
+        server.local_addr()
+    );
+    if remaining > 0 {
+        let finished = shared.finished.notified();
+        // workers asking for more after the last ack are told to stop
+        let done = async {
+            finished.await;
+            tokio::time::sleep(WORKER_GRACE).await;
+        };
+        tokio::select! {
+            result = server.with_graceful_shutdown(done) => result.map_err(|err| err.to_string())?,
+            _ = tokio::signal::ctrl_c() => return Err("interrupted".to_string()),
+        }
+    }
+    let queue = shared.queue.lock().unwrap();
+    Ok(queue
+        .slots
+        .iter()
+        .filter_map(|slot| match &slot.state {
+            State::Failed(error) => Some((slot.image.clone(), error.clone())),
+            _ => None,
+        })
+        .collect())
+}
+
+async fn respond(shared: &Shared, request: Request<Body>) -> Response<Body> {
+    if let Some(expected) = &shared.authorization {
+        let sent = request.headers().get(header::AUTHORIZATION);
+        if !sent.is_some_and(|value| crate::serve::same(value.as_bytes(), expected.as_bytes())) {
+            return status(StatusCode::UNAUTHORIZED);
+        }
+    }
+    let path = request.uri().path().to_string();
+    if path == "/status" && request.method() == Method::GET {
+        return queue_status(shared);
+    }
+    if request.method() != Method::POST {
+        return status(StatusCode::METHOD_NOT_ALLOWED);
+    }
+    let lease = |prefix: &str| {
+        let token = path.strip_prefix(prefix)?;
+        let (index, generation) = token.split_once('-')?;
+        Some((
+            index.parse::<usize>().ok()?,
+            generation.parse::<u64>().ok()?,
+        ))
+    };
+    if path == "/lease" {
+        return lease_next(shared);
+    }
+    if let Some((index, generation)) = lease("/renew/") {
+        let mut queue = shared.queue.lock().unwrap();
+        let until = Instant::now() + shared.lease;
+        return match queue.slots.get_mut(index).map(|slot| &mut slot.state) {
+            Some(State::Leased {
+                generation: current,
+                until: deadline,
+            }) if *current == generation => {
+                *deadline = until;
+                queue.expiries.push_back((until, index, generation));
+                status(StatusCode::OK)
+            }
+            _ => status(StatusCode::NOT_FOUND),
+        };
+    }
+    if let Some((index, generation)) = lease("/ack/") {
+        let Ok(body) = hyper::body::to_bytes(request.into_body()).await else {
+            return status(StatusCode::BAD_REQUEST);
+        };
+        let Ok(ack) = serde_json::from_slice::<Ack>(&body) else {
+            return status(StatusCode::BAD_REQUEST);
+        };
+        return acknowledge(shared, index, generation, ack);
+    }
+    if let Some(id) = path.strip_prefix("/boost/") {
+        return boost(shared, id);
+    }
+    status(StatusCode::NOT_FOUND)
+}
+
+/// Moves the queued item `id` ahead of the rest; items already leased keep
+/// downloading where they are.
+fn boost(shared: &Shared, id: &str) -> Response<Body> {
+    let mut queue = shared.queue.lock().unwrap();
+    let mut found = false;
+    let mut queued = None;
+    for (index, slot) in queue.slots.iter().enumerate() {
+        if slot.image.id() == id {
+            found = true;
+            if matches!(slot.state, State::Queued) {
+                queued = Some(index);
+                break;
+            }
+        }
+    }
+    let Some(index) = queued else {
+        return status(match found {
+            true => StatusCode::CONFLICT,
+            false => StatusCode::NOT_FOUND,
+        });
+    };
+    queue.queued.retain(|&queued| queued != index);
+    queue.queued.push_front(index);
+    let slot = &mut queue.slots[index];
+    slot.boosted = true;
+    if shared.verbose {
+        eprintln!("boosted: {}", slot.image.file_name);
+    }
+    status(StatusCode::OK)
+}
+
+fn queue_status(shared: &Shared) -> Response<Body> {
+    let queue = shared.queue.lock().unwrap();
+    let (mut leased, mut done, mut failed) = (0, 0, 0);
+    for slot in &queue.slots {
+        match slot.state {
+            State::Queued => {}
+            State::Leased { .. } => leased += 1,
+            State::Done => done += 1,
+            State::Failed(_) => failed += 1,
+        }
+    }
+    let next = queue
+        .queued
+        .iter()
+        .take(STATUS_NEXT)
+        .map(|&index| {
+            let slot = &queue.slots[index];
+            json!({
+                "id": slot.image.id(),
+                "path": slot.image.file_name,
+                "boosted": slot.boosted,
+            })
+        })
+        .collect::<Vec<_>>();
+    let body = json!({
+        "queued": queue.queued.len(),
+        "leased": leased,
+        "done": done,
+        "failed": failed,
+        "next": next,
+    });
+    Response::builder()
+        .header("content-type", "application/json")
+        .body(Body::from(body.to_string()))
+        .expect("valid response")
+}
+
+fn lease_next(shared: &Shared) -> Response<Body> {
+    let mut queue = shared.queue.lock().unwrap();
+    let now = Instant::now();
+    while let Some(&(until, index, generation)) = queue.expiries.front() {
+        if until > now {
+            break;
+        }
+        queue.expiries.pop_front();
+        let slot = &mut queue.slots[index];
+        // only the latest deadline of the current lease counts
+        if let State::Leased {
+            generation: current,
+            until: deadline,
+        } = slot.state
+        {
+            if current == generation && deadline <= now {
+                if shared.verbose {
+                    eprintln!("lease expired: {}", slot.image.file_name);
+                }
+                slot.state = State::Queued;
+                queue.queued.push_back(index);
+            }
+        }
+    }
+    if queue.remaining == 0 {
+        return status(StatusCode::GONE);
+    }
+    let Some(index) = queue.queued.pop_front() else {
+        return status(StatusCode::NO_CONTENT);
+    };
+    let generation = queue.next_generation;
+    queue.next_generation += 1;
+    let until = now + shared.lease;
+    queue.expiries.push_back((until, index, generation));
+    let slot = &mut queue.slots[index];
+    slot.state = State::Leased { generation, until };
+    let image = &slot.image;
+    let body = json!({
+        "lease": format!("{}-{}", index, generation),
+        "lease_secs": shared.lease.as_secs_f64(),
+        "item": {
+            "url": image.url,
+            "path": image.file_name,
+            "headers": image.headers,
+            "sha256": image.sha256,
+            "sha256tree": image.sha256_tree,
+            "size": image.size,
+            "mirrors": image.mirrors,
+            "copies": image.copies,
+            "meta": image.meta,
+            "tags": image.tags,
+            "source": image.source,
+        },
+    });
+    Response::builder()
+        .header("content-type", "application/json")
+        .body(Body::from(body.to_string()))
+        .expect("valid response")
+}
+
+fn acknowledge(shared: &Shared, index: usize, generation: u64, ack: Ack) -> Response<Body> {
+    let mut queue = shared.queue.lock().unwrap();
+    let Some(slot) = queue.slots.get_mut(index) else {
+        return status(StatusCode::NOT_FOUND);
+    };
+    match &slot.state {
+        State::Leased {
+            generation: current,
+            ..
+        } if *current == generation => {}
+        // a worker that outlived its lease may still have finished the item
+        State::Queued if ack.ok => {}
+        _ => return status(StatusCode::NOT_FOUND),
+    }
+    let was_queued = matches!(slot.state, State::Queued);
+    if ack.ok {
+        if shared.verbose {
+            eprintln!("downloaded: {}", slot.image.file_name);
+        }
+        slot.state = State::Done;
+    } else {
+        let error = ack.error.unwrap_or_else(|| "unknown error".to_string());
+        slot.failures += 1;
+        eprintln!(
+            "{}error : {} url: {} file_name: {} id: {}",
+            crate::located(&slot.image),
+            error,
+            slot.image.url,
+            slot.image.file_name,
+            slot.image.id()
+        );
+        if slot.failures <= shared.retries {
+            slot.state = State::Queued;
+            queue.queued.push_back(index);
+            return status(StatusCode::OK);
+        }
+        slot.state = State::Failed(error);
+    }
+    if was_queued {
+        queue.queued.retain(|&queued| queued != index);
+    }
+    queue.remaining -= 1;
+    if queue.remaining == 0 {
+        shared.finished.notify_one();
+    }
+    status(StatusCode::OK)
+}
+
+fn status(status: StatusCode) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .body(Body::empty())
+        .expect("valid response")
+}
@@ -0,0 +1,284 @@
+//! `fast_download crawl <url>`: walks the auto-index pages Apache and Nginx
+//! serve for directories and writes the url file lines of the files in them,
+//! at the same paths under a local directory.
+//!
+//! With `--webdav` the directories are WebDAV collections instead, like the
+//! shares of Nextcloud and ownCloud, listed with `PROPFIND`; their files get
+//! the `size=` the server reports.
+//!
+//! Links to a subdirectory, ending in `/`, are followed down to `--depth`
+//! levels. Links that leave the starting directory, like the parent directory
+//! entry, and the `?C=M;O=A` links that only sort the listing are skipped.
+
+use crate::{check, urls};
+use fast_download::glob;
+use futures::{stream, StreamExt};
+use regex::Regex;
+use reqwest::{header, Method, Url};
+use std::{
+    collections::HashSet,
+    fs::OpenOptions,
+    io::{self, Write},
+    path::Path,
+    sync::OnceLock,
+};
+
+/// What is read of an index page at most.
+const MAX_PAGE_SIZE: usize = 16 << 20;
+
+/// Which files of the tree to list.
+#[derive(Debug)]
+pub struct Filter {
+    /// Globs of file names, like `*.iso`; all files when empty.
+    pub accept: Vec<String>,
+    /// Globs of file names left out.
+    pub reject: Vec<String>,
+    /// Levels of subdirectories walked; all of them when `None`.
+    pub depth: Option<usize>,
+}
+
+/// Prints a url file line for every file under `url` that `filter` keeps, or
+/// appends them to `enqueue`, with paths under `dir`. With `webdav` the
+/// directories are WebDAV collections, listed with `PROPFIND`.
+pub async fn run(
+    url: &str,
+    client: &check::Client,
+    dir: Option<&str>,
+    filter: &Filter,
+    webdav: bool,
+    max_concurrent: usize,
+    enqueue: Option<&Path>,
+) -> Result<(), String> {
+    let mut root = Url::parse(url).map_err(|err| format!("invalid url {}: {}", url, err))?;
+    // a sorted listing is the same directory
+    root.set_query(None);
+    root.set_fragment(None);
+    if !root.path().ends_with('/') {
+        let path = format!("{}/", root.path());
+        root.set_path(&path);
+    }
+    let (accept, reject) = (&filter.accept, &filter.reject);
+    let keeps = |name: &str| {
+        (accept.is_empty() || accept.iter().any(|pattern| glob::matches(pattern, name)))
+            && !reject.iter().any(|pattern| glob::matches(pattern, name))
+    };
+
+    let mut visited = HashSet::from([root.clone()]);
+    let mut level = vec![root.clone()];
+    let mut files = Vec::new();
+    let mut seen_files = HashSet::new();
+    let mut depth = 0;
+    while !level.is_empty() {
+        let pages = stream::iter(level)
+            .map(|page| async move {
+                let entries = match webdav {
+                    true => propfind(client, &page).await,
+                    false => links(client, &page).await,
+                };
+                (page, entries)
+            })
+            .buffered(max_concurrent.max(1))
+            .collect::<Vec<_>>()
+            .await;
+        let mut next = Vec::new();
+        for (page, entries) in pages {
+            let entries = entries.map_err(|err| format!("failed to list {}: {}", page, err))?;
+            for Entry { mut url, size } in entries {
+                if url.query().is_some() {
+                    continue;
+                }
+                url.set_fragment(None);
+                let Some(relative) = url.as_str().strip_prefix(root.as_str()) else {
+                    continue;
+                };
+                if relative.is_empty() || url == page {
+                    continue;
+                }
+                if relative.ends_with('/') {
+                    if filter.depth.is_none_or(|max| depth < max) && visited.insert(url.clone()) {
+                        next.push(url);
+                    }
+                } else if let Some(path) = local_path(relative) {
+                    let name = path.rsplit('/').next().unwrap_or_default();
+                    if keeps(name) && seen_files.insert(url.clone()) {
+                        files.push((url, path, size));
+                    }
+                }
+            }
+        }
+        level = next;
+        depth += 1;
+    }
+
+    let mut out: Box<dyn Write> = match enqueue {
+        Some(url_file) => Box::new(io::BufWriter::new(
+            OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(url_file)
+                .map_err(|err| format!("failed to open {}: {}", url_file.display(), err))?,
+        )),
+        None => Box::new(io::BufWriter::new(io::stdout().lock())),
+    };
+    let write_error = |err: io::Error| match enqueue {
+        Some(url_file) => format!("failed to write {}: {}", url_file.display(), err),
+        None => err.to_string(),
+    };
+    let mut lines = 0;
+    for (url, path, size) in &files {
+        // url file fields are split on whitespace
+        if path.contains(char::is_whitespace) {
+            eprintln!("skipped {}: its name has whitespace", url);
+            continue;
+        }
+        let url =
+            urls::normalize(url.as_str()).map_err(|err| format!("invalid url {}: {}", url, err))?;
+        let path = match dir {
+            Some(dir) => format!("{}/{}", dir.trim_end_matches('/'), path),
+            None => path.clone(),
+        };
+        match size {
+            Some(size) => writeln!(out, "{} {} size={}", url, path, size),
+            None => writeln!(out, "{} {}", url, path),
+        }
+        .map_err(write_error)?;
+        lines += 1;
+    }
+    out.flush().map_err(write_error)?;
+    if let Some(url_file) = enqueue {
+        eprintln!(
+            "enqueued {} files from {} directories in {}",
+            lines,
+            visited.len(),
+            url_file.display()
+        );
+    }
+    Ok(())
+}
+
+/// A file or directory in a listing; directories end in `/`.
+struct Entry {
+    url: Url,
+    size: Option<u64>,
+}
+
+/// The links of the index page at `page`.
+async fn links(client: &check::Client, page: &Url) -> Result<Vec<Entry>, String> {
+    static HREF: OnceLock<Regex> = OnceLock::new();
+    let html = read(client.request(Method::GET, page.as_str())?).await?;
+    let href = HREF.get_or_init(|| {
+        Regex::new(r#"(?i)href\s*=\s*(?:"([^"]*)"|'([^']*)')"#).expect("valid regex")
+    });
+    Ok(href
+        .captures_iter(&html)
+        .filter_map(|link| {
+            let link = link.get(1).or_else(|| link.get(2))?.as_str();
+            let url = page.join(&html_unescape(link)).ok()?;
+            Some(Entry { url, size: None })
+        })
+        .collect())
+}
+
+/// The patterns a `PROPFIND` answer is read with.
+struct Multistatus {
+    response: Regex,
+    href: Regex,
+    collection: Regex,
+    length: Regex,
+}
+
+/// What a `PROPFIND` of the WebDAV collection at `page` lists.
+async fn propfind(client: &check::Client, page: &Url) -> Result<Vec<Entry>, String> {
+    static MULTISTATUS: OnceLock<Multistatus> = OnceLock::new();
+    let method = Method::from_bytes(b"PROPFIND").expect("valid method");
+    let request = client
+        .request(method, page.as_str())?
+        .header("Depth", "1")
+        .header(header::CONTENT_TYPE, "application/xml")
+        .body(PROPFIND);
+    let xml = read(request).await?;
+    let Multistatus {
+        response,
+        href,
+        collection,
+        length,
+    } = MULTISTATUS.get_or_init(|| Multistatus {
+        response: element_regex("response"),
+        href: element_regex("href"),
+        collection: Regex::new(r"<(?:[\w-]+:)?collection\b").expect("valid regex"),
+        length: element_regex("getcontentlength"),
+    });
+    Ok(response
+        .captures_iter(&xml)
+        .filter_map(|response| {
+            let response = &response[1];
+            let href = html_unescape(href.captures(response)?[1].trim());
+            let mut url = page.join(&href).ok()?;
+            let size = length
+                .captures(response)
+                .and_then(|length| length[1].trim().parse().ok());
+            if collection.is_match(response) && !url.path().ends_with('/') {
+                let path = format!("{}/", url.path());
+                url.set_path(&path);
+            }
+            Some(Entry { url, size })
+        })
+        .collect())
+}
+
+/// The properties a `PROPFIND` asks for.
+const PROPFIND: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+<d:propfind xmlns:d="DAV:"><d:prop><d:resourcetype/><d:getcontentlength/></d:prop></d:propfind>"#;
+
+/// Matches the element `name` in any namespace prefix, capturing its content.
+fn element_regex(name: &str) -> Regex {
+    Regex::new(&format!(
+        r"(?s)<(?:[\w-]+:)?{0}\b[^>]*>(.*?)</(?:[\w-]+:)?{0}>",
+        name
+    ))
+    .expect("valid regex")
+}
+
+/// The body of a listing.
+async fn read(request: reqwest::RequestBuilder) -> Result<String, String> {
+    let mut response = request.send().await.map_err(|err| err.to_string())?;
+    if !response.status().is_success() {
+        return Err(format!("status {}", response.status()));
+    }
+    let mut body = Vec::new();
+    while let Some(chunk) = response.chunk().await.map_err(|err| err.to_string())? {
+        body.extend_from_slice(&chunk);
+        if body.len() > MAX_PAGE_SIZE {
+            return Err("page too large for an index".to_string());
+        }
+    }
+    Ok(String::from_utf8_lossy(&body).into_owned())
+}
+
+/// The path of a file a link points to, relative to the starting directory:
+/// its decoded segments, unless one of them can't be a file name here.
+fn local_path(relative: &str) -> Option<String> {
+    let segments = relative
+        .split('/')
+        .map(|segment| {
+            let segment = percent_encoding::percent_decode_str(segment)
+                .decode_utf8()
+                .ok()?;
+            let invalid = segment.is_empty()
+                || segment == "."
+                || segment == ".."
+                || segment.contains(['/', '\\']);
+            (!invalid).then(|| segment.into_owned())
+        })
+        .collect::<Option<Vec<_>>>()?;
+    Some(segments.join("/"))
+}
+
+/// Decodes the entities index pages put in links.
+fn html_unescape(link: &str) -> String {
+    link.replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&amp;", "&")
+}
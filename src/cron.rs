@@ -0,0 +1,222 @@
+//! Cron expressions for the schedules of `fast_download daemon`.
+//!
+//! The five classic fields are supported, `minute hour day-of-month month
+//! day-of-week`, each `*`, a number, a range `a-b`, a step `*/n` or `a-b/n`, or
+//! a comma separated list of those. Sunday is `0` or `7`. As in cron, a day
+//! matches when either day field does if both are restricted. Times are local.
+
+use chrono::{DateTime, Datelike, Duration, Local, NaiveDate, TimeZone, Timelike};
+
+#[derive(Debug, Clone)]
+pub struct Schedule {
+    minutes: Vec<bool>,
+    hours: Vec<bool>,
+    days: Vec<bool>,
+    months: Vec<bool>,
+    weekdays: Vec<bool>,
+    /// Whether day-of-month and day-of-week were both restricted.
+    either_day: bool,
+}
+
+impl Schedule {
+    pub fn parse(expression: &str) -> Result<Schedule, String> {
+        let fields = expression.split_whitespace().collect::<Vec<_>>();
+        let [minute, hour, day, month, weekday] = fields[..] else {
+            return Err(format!(
+                "expected 5 fields in cron expression {:?}",
+                expression
+            ));
+        };
+        let field = |text: &str, min: u32, max: u32| {
+            parse_field(text, min, max)
+                .ok_or_else(|| format!("invalid field {:?} in {:?}", text, expression))
+        };
+        let mut weekdays = field(weekday, 0, 7)?;
+        weekdays[0] |= weekdays[7];
+        weekdays.truncate(7);
+        Ok(Schedule {
+            minutes: field(minute, 0, 59)?,
+            hours: field(hour, 0, 23)?,
+            days: field(day, 1, 31)?,
+            months: field(month, 1, 12)?,
+            weekdays,
+            either_day: !day.starts_with('*') && !weekday.starts_with('*'),
+        })
+    }
+
+    /// The first matching minute after `after`, within the next few years.
+    pub fn next_after(&self, after: DateTime<Local>) -> Option<DateTime<Local>> {
+        let start = after.naive_local() + Duration::minutes(1);
+        let mut date = start.date();
+        let mut first_day = true;
+        for _ in 0..366 * 5 {
+            if self.months[date.month() as usize] && self.day_matches(date) {
+                let (from_hour, from_minute) = match first_day {
+                    true => (start.hour(), start.minute()),
+                    false => (0, 0),
+                };
+                for hour in from_hour..24 {
+                    if !self.hours[hour as usize] {
+                        continue;
+                    }
+                    let from = if hour == from_hour { from_minute } else { 0 };
+                    for minute in from..60 {
+                        if !self.minutes[minute as usize] {
+                            continue;
+                        }
+                        let time = date.and_hms_opt(hour, minute, 0)?;
+                        // minutes skipped by a DST change never happen
+                        if let Some(time) = Local.from_local_datetime(&time).earliest() {
+                            return Some(time);
+                        }
+                    }
+                }
+            }
+            date = date.succ_opt()?;
+            first_day = false;
+        }
+        None
+    }
+
+    fn day_matches(&self, date: NaiveDate) -> bool {
+        let day = self.days[date.day() as usize];
+        let weekday = self.weekdays[date.weekday().num_days_from_sunday() as usize];
+        match self.either_day {
+            true => day || weekday,
+            false => day && weekday,
+        }
+    }
+}
+
+/// Which values in `0..=max` the field allows; values below `min` never do.
+fn parse_field(text: &str, min: u32, max: u32) -> Option<Vec<bool>> {
+    let mut allowed = vec![false; max as usize + 1];
+    for part in text.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (range, step.parse::<u32>().ok().filter(|&step| step > 0)?),
+            None => (part, 1),
+        };
+        let (first, last) = match range {
+            "*" => (min, max),
+            range => match range.split_once('-') {
+                Some((first, last)) => (first.parse().ok()?, last.parse().ok()?),
+                // `5/15` means from 5 to the end in steps of 15
+                None if step > 1 => (range.parse().ok()?, max),
+                None => {
+                    let value = range.parse().ok()?;
+                    (value, value)
+                }
+            },
+        };
+        if first < min || last > max || first > last {
+            return None;
+        }
+        for value in (first..=last).step_by(step as usize) {
+            allowed[value as usize] = true;
+        }
+    }
+    Some(allowed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(year: i32, month: u32, day: u32, hour: u32, minute: u32) -> DateTime<Local> {
+        Local
+            .with_ymd_and_hms(year, month, day, hour, minute, 0)
+            .unwrap()
+    }
+
+    fn next(expression: &str, after: DateTime<Local>) -> Option<DateTime<Local>> {
+        Schedule::parse(expression).unwrap().next_after(after)
+    }
+
+    #[test]
+    fn follows_ranges_steps_and_lists() {
+        let every_quarter = "*/15 9-17/4 * * *";
+        assert_eq!(
+            next(every_quarter, at(2026, 1, 5, 10, 7)),
+            Some(at(2026, 1, 5, 13, 0))
+        );
+        assert_eq!(
+            next(every_quarter, at(2026, 1, 5, 13, 0)),
+            Some(at(2026, 1, 5, 13, 15))
+        );
+        assert_eq!(
+            next(every_quarter, at(2026, 1, 5, 17, 45)),
+            Some(at(2026, 1, 6, 9, 0))
+        );
+        // from 5 to the end of the hour in steps of 20
+        assert_eq!(
+            next("5/20 10 * * *", at(2026, 1, 5, 10, 30)),
+            Some(at(2026, 1, 5, 10, 45))
+        );
+        assert_eq!(
+            next("1,20-22 10 * * *", at(2026, 1, 5, 10, 1)),
+            Some(at(2026, 1, 5, 10, 20))
+        );
+    }
+
+    #[test]
+    fn either_day_field_matches_when_both_are_restricted() {
+        // the 13th, or any Friday; 2026-01-01 is a Thursday
+        let friday_or_13th = "0 12 13 * 5";
+        assert_eq!(
+            next(friday_or_13th, at(2026, 1, 1, 13, 0)),
+            Some(at(2026, 1, 2, 12, 0))
+        );
+        assert_eq!(
+            next(friday_or_13th, at(2026, 1, 9, 13, 0)),
+            Some(at(2026, 1, 13, 12, 0))
+        );
+        // with one of them `*`, only the other one counts
+        assert_eq!(
+            next("0 12 * * 5", at(2026, 1, 9, 13, 0)),
+            Some(at(2026, 1, 16, 12, 0))
+        );
+        assert_eq!(
+            next("0 12 13 * *", at(2026, 1, 1, 13, 0)),
+            Some(at(2026, 1, 13, 12, 0))
+        );
+        // Sunday is 0 and 7
+        assert_eq!(
+            next("0 12 * * 7", at(2026, 1, 1, 13, 0)),
+            next("0 12 * * 0", at(2026, 1, 1, 13, 0))
+        );
+    }
+
+    #[test]
+    fn rolls_over_months_and_years() {
+        assert_eq!(
+            next("* * * * *", at(2026, 12, 31, 23, 59)),
+            Some(at(2027, 1, 1, 0, 0))
+        );
+        // February has no 31st
+        assert_eq!(
+            next("30 21 31 * *", at(2026, 1, 31, 21, 30)),
+            Some(at(2026, 3, 31, 21, 30))
+        );
+        assert_eq!(
+            next("0 12 1 1 *", at(2026, 6, 1, 12, 0)),
+            Some(at(2027, 1, 1, 12, 0))
+        );
+        assert_eq!(next("0 12 30 2 *", at(2026, 1, 1, 0, 0)), None);
+    }
+
+    #[test]
+    fn rejects_invalid_expressions() {
+        for expression in [
+            "* * * *",
+            "60 * * * *",
+            "* 24 * * *",
+            "* * 0 * *",
+            "5-1 * * * *",
+            "*/0 * * * *",
+            "* * * 13 *",
+            "* * * * 8",
+        ] {
+            assert!(Schedule::parse(expression).is_err(), "{}", expression);
+        }
+    }
+}
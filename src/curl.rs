@@ -0,0 +1,326 @@
+//! `--from-curl <file>`: reads `curl ...` commands, as the "Copy as cURL" of
+//! browser devtools writes them, instead of url file lines, so captured
+//! requests are replayed in bulk with their headers and cookies.
+//!
+//! Commands are split like a POSIX shell would: single, double and `$'...'`
+//! quotes, backslash escapes, and backslash-newline continuing a command on
+//! the next line. Only GET requests are taken; the rest are invalid lines.
+
+use crate::browser;
+use base64::Engine;
+use fast_download::Image;
+
+/// Request headers of the capture that are left to the download: it handles
+/// compression, conditions and ranges itself.
+const DROPPED_HEADERS: &[&str] = &[
+    "accept-encoding",
+    "connection",
+    "content-length",
+    "host",
+    "if-match",
+    "if-modified-since",
+    "if-none-match",
+    "if-range",
+    "range",
+];
+
+/// Options of curl that take a value, but don't matter for the download.
+const IGNORED_WITH_VALUE: &[&str] = &[
+    "--connect-timeout",
+    "--max-time",
+    "-m",
+    "--retry",
+    "--proxy",
+    "-x",
+    "--cacert",
+    "--cert",
+    "-E",
+    "--key",
+    "--limit-rate",
+    "-w",
+    "--write-out",
+];
+
+/// The items of the commands in `text`, each with the line it starts on, or
+/// why the command can't be one.
+pub fn parse(text: &str) -> Vec<(usize, Result<Image, String>)> {
+    commands(text)
+        .into_iter()
+        .filter(|(_, words)| !words.is_empty())
+        .map(|(line, words)| (line, item(&words)))
+        .collect()
+}
+
+fn item(words: &[String]) -> Result<Image, String> {
+    let mut words = words.iter();
+    if words.next().map(String::as_str) != Some("curl") {
+        return Err("not a curl command".to_string());
+    }
+    let (mut url, mut output, mut method) = (None, None, None);
+    let (mut headers, mut cookies) = (Vec::new(), Vec::new());
+    while let Some(word) = words.next() {
+        let mut value = || {
+            words
+                .next()
+                .cloned()
+                .ok_or_else(|| format!("missing value for {}", word))
+        };
+        match word.as_str() {
+            "-H" | "--header" => {
+                let header = value()?;
+                let (name, value) = header
+                    .split_once(':')
+                    .ok_or_else(|| format!("invalid header {}", header))?;
+                headers.push((name.trim().to_string(), value.trim().to_string()));
+            }
+            "-b" | "--cookie" => {
+                let cookie = value()?;
+                // without a `=` it's a cookie file
+                if !cookie.contains('=') {
+                    return Err(format!("cookie files aren't supported: {}", cookie));
+                }
+                cookies.push(cookie);
+            }
+            "-A" | "--user-agent" => headers.push(("User-Agent".to_string(), value()?)),
+            "-e" | "--referer" => headers.push(("Referer".to_string(), value()?)),
+            "-u" | "--user" => {
+                let credentials = base64::engine::general_purpose::STANDARD.encode(value()?);
+                headers.push((
+                    "Authorization".to_string(),
+                    format!("Basic {}", credentials),
+                ));
+            }
+            "-o" | "--output" => output = Some(value()?),
+            "-X" | "--request" => method = Some(value()?),
+            "--url" => url = Some(value()?),
+            "-d" | "--data" | "--data-raw" | "--data-binary" | "--data-urlencode" | "-F"
+            | "--form" | "--json" => return Err("not a GET request".to_string()),
+            "-I" | "--head" => return Err("not a GET request".to_string()),
+            word if IGNORED_WITH_VALUE.contains(&word) => {
+                value()?;
+            }
+            word if word.starts_with('-') => {}
+            word => url = Some(word.to_string()),
+        }
+    }
+    if method.is_some_and(|method| !method.eq_ignore_ascii_case("GET")) {
+        return Err("not a GET request".to_string());
+    }
+    let url = url.ok_or("no url")?;
+    let path = match output {
+        Some(output) => output,
+        None => browser::path_of(&url).ok_or_else(|| format!("no path for {}", url))?,
+    };
+    let mut image = Image::new(url, path);
+    headers.retain(|(name, _)| !DROPPED_HEADERS.contains(&name.to_ascii_lowercase().as_str()));
+    let has_cookie = headers
+        .iter()
+        .any(|(name, _)| name.eq_ignore_ascii_case("cookie"));
+    if !has_cookie && !cookies.is_empty() {
+        headers.push(("Cookie".to_string(), cookies.join("; ")));
+    }
+    image.headers = headers;
+    Ok(image)
+}
+
+/// The words of every command in `text` and the line it starts on.
+fn commands(text: &str) -> Vec<(usize, Vec<String>)> {
+    let mut commands = Vec::new();
+    let (mut words, mut word) = (Vec::new(), None::<String>);
+    let (mut line, mut start) = (1, 1);
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some('\n') => line += 1,
+                Some(c) => word.get_or_insert_with(String::new).push(c),
+                None => {}
+            },
+            '\'' => {
+                let word = word.get_or_insert_with(String::new);
+                for c in chars.by_ref() {
+                    if c == '\'' {
+                        break;
+                    }
+                    line += (c == '\n') as usize;
+                    word.push(c);
+                }
+            }
+            '"' => {
+                let word = word.get_or_insert_with(String::new);
+                while let Some(c) = chars.next() {
+                    match c {
+                        '"' => break,
+                        '\\' if chars.peek().is_some_and(|c| "\"\\$`\n".contains(*c)) => {
+                            let escaped = chars.next().expect("peeked");
+                            if escaped != '\n' {
+                                word.push(escaped);
+                            }
+                            line += (escaped == '\n') as usize;
+                        }
+                        c => {
+                            line += (c == '\n') as usize;
+                            word.push(c);
+                        }
+                    }
+                }
+            }
+            '$' if chars.peek() == Some(&'\'') => {
+                chars.next();
+                // escapes can spell out the bytes of UTF-8
+                let mut bytes = Vec::new();
+                let push = |bytes: &mut Vec<u8>, c: char| {
+                    bytes.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes())
+                };
+                while let Some(c) = chars.next() {
+                    match c {
+                        '\'' => break,
+                        '\\' => match chars.next() {
+                            Some('n') => bytes.push(b'\n'),
+                            Some('t') => bytes.push(b'\t'),
+                            Some('r') => bytes.push(b'\r'),
+                            Some('x') => {
+                                let hex = chars.by_ref().take(2).collect::<String>();
+                                bytes.extend(u8::from_str_radix(&hex, 16).ok());
+                            }
+                            Some('u') => {
+                                let hex = chars.by_ref().take(4).collect::<String>();
+                                let c = u32::from_str_radix(&hex, 16).ok().and_then(char::from_u32);
+                                push(&mut bytes, c.unwrap_or(char::REPLACEMENT_CHARACTER));
+                            }
+                            Some(c) => push(&mut bytes, c),
+                            None => {}
+                        },
+                        c => {
+                            line += (c == '\n') as usize;
+                            push(&mut bytes, c);
+                        }
+                    }
+                }
+                word.get_or_insert_with(String::new)
+                    .push_str(&String::from_utf8_lossy(&bytes));
+            }
+            '#' if word.is_none() => {
+                // a comment, to the end of the line
+                while chars.next_if(|&c| c != '\n').is_some() {}
+            }
+            '\n' | ';' => {
+                words.extend(word.take());
+                if !words.is_empty() {
+                    commands.push((start, std::mem::take(&mut words)));
+                }
+                line += (c == '\n') as usize;
+                start = line;
+            }
+            c if c.is_whitespace() => words.extend(word.take()),
+            c => word.get_or_insert_with(String::new).push(c),
+        }
+    }
+    words.extend(word.take());
+    if !words.is_empty() {
+        commands.push((start, words));
+    }
+    commands
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn words(text: &str) -> Vec<String> {
+        let mut commands = commands(text);
+        assert_eq!(commands.len(), 1, "{:?}", commands);
+        commands.remove(0).1
+    }
+
+    #[test]
+    fn splits_ansi_c_quotes() {
+        assert_eq!(
+            words(r"curl $'https://example.com/a\x20bé' -o $'it\'s\tdone'"),
+            ["curl", "https://example.com/a bé", "-o", "it's\tdone"]
+        );
+        // bytes spelled out one by one are decoded as UTF-8
+        assert_eq!(words(r"echo $'\xc3\xa9'x"), ["echo", "éx"]);
+    }
+
+    #[test]
+    fn continues_commands_after_a_backslash_newline() {
+        let text = concat!(
+            "# devtools\n",
+            "curl 'https://example.com/a' \\\n",
+            "  -H 'Accept: */*' \\\n",
+            "  --compressed\n",
+            "\n",
+            "curl \"https://example.com/b\"; curl c\n",
+        );
+        let commands = commands(text);
+        let starts = commands.iter().map(|(line, _)| *line).collect::<Vec<_>>();
+        assert_eq!(starts, [2, 6, 6]);
+        assert_eq!(
+            commands[0].1,
+            [
+                "curl",
+                "https://example.com/a",
+                "-H",
+                "Accept: */*",
+                "--compressed"
+            ]
+        );
+        assert_eq!(words("curl ex\\\nample"), ["curl", "example"]);
+        assert_eq!(
+            words(r#"curl "a \"b\" \$c \d""#),
+            ["curl", r#"a "b" $c \d"#]
+        );
+    }
+
+    #[test]
+    fn takes_headers_and_cookies() {
+        let image = item(&words(
+            "curl https://example.com/a.iso -H 'Referer: https://example.com/x' \
+             --header 'Accept-Encoding: gzip' -H 'Range: bytes=0-' -b 'a=1' --cookie 'b=2' \
+             -A agent",
+        ))
+        .unwrap();
+        assert_eq!(
+            image.headers,
+            [
+                ("Referer".to_string(), "https://example.com/x".to_string()),
+                ("User-Agent".to_string(), "agent".to_string()),
+                ("Cookie".to_string(), "a=1; b=2".to_string()),
+            ]
+        );
+        assert!(item(&words("curl https://example.com/a -H nocolon")).is_err());
+        assert!(item(&words("curl https://example.com/a -b cookies.txt")).is_err());
+    }
+
+    #[test]
+    fn saves_to_the_output_or_the_url_path() {
+        let image = item(&words("curl -o out/a.iso https://example.com/x/a.iso")).unwrap();
+        assert_eq!(
+            (image.url.as_str(), image.file_name.as_str()),
+            ("https://example.com/x/a.iso", "out/a.iso")
+        );
+        let image = item(&words(
+            "curl --url https://example.com/x/a.iso --max-time 5",
+        ))
+        .unwrap();
+        assert_eq!(image.file_name, "example.com/x/a.iso");
+        assert!(item(&words("curl https://example.com/a -o")).is_err());
+    }
+
+    #[test]
+    fn takes_only_get_requests() {
+        let parsed = parse(
+            "curl https://example.com/a\ncurl -X POST https://example.com/b\n\
+             curl -d x=1 https://example.com/c\ncurl -X get https://example.com/d\nwget x\n",
+        );
+        let ok = parsed
+            .iter()
+            .map(|(line, item)| (*line, item.is_ok()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            ok,
+            [(1, true), (2, false), (3, false), (4, true), (5, false)]
+        );
+    }
+}
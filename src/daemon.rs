@@ -0,0 +1,667 @@
+//! `fast_download daemon`: downloads the jobs posted to a message queue, and
+//! the url files scheduled in the config, until interrupted.
+//!
+//! Every message holds one or more url file lines. A message is acknowledged
+//! only once all of its entries are downloaded and match their `size=` and
+//! `sha256=` fields; otherwise it reappears on the queue when its visibility
+//! timeout runs out, so failed jobs are retried, or moved to a dead-letter
+//! queue by the queue's redrive policy. The queue is held while downloads run.
+//!
+//! A scheduled url file is downloaded again whenever its cron expression
+//! matches, with timestamping, so files the server reports unchanged are
+//! skipped. A run that is still going when the next one is due skips that one.
+//! On unix, SIGHUP reloads the schedules and SIGUSR1 prints what is running.
+//!
+//! Entries are downloaded in the named queues of the config where they are
+//! routed to one, each with its own downloader, concurrency, rate and
+//! directory, see [`Route`].
+//!
+//! [`QueueEvents`] tell when the queue drains, once a receive comes back empty
+//! with no job left running, and when it gets jobs again, so what processes
+//! the downloads can start on its own.
+
+use crate::{
+    config::QueueConfig,
+    cron,
+    hooks::Hooks,
+    signals::{Signal, Signals},
+    sqs, units,
+    webhook::{Lifecycle, Webhook},
+    Stats,
+};
+use chrono::{DateTime, Local};
+use fast_download::{glob, DownloadCompleted, Downloader, Image, Options, Order};
+use futures::{stream::FuturesUnordered, StreamExt};
+use std::{
+    collections::{HashMap, HashSet},
+    future::Future,
+    path::{Component, Path, PathBuf},
+    pin::Pin,
+    time::{Duration, Instant},
+};
+use tokio::sync::Semaphore;
+
+/// How long to wait before receiving again after the queue failed.
+const ERROR_BACKOFF: Duration = Duration::from_secs(5);
+
+type Outcome = (Image, Result<DownloadCompleted, String>);
+
+/// An entry of a message and the queue its `queue=` field names.
+pub type Entry = (Image, Option<String>);
+
+/// A named queue of the config: its entries are downloaded with a downloader
+/// of their own, `max_concurrent` at a time and at its `rate`, under its
+/// `dir`.
+pub struct Route {
+    name: String,
+    downloader: Downloader,
+    slots: Option<Semaphore>,
+    dir: Option<PathBuf>,
+    /// The `files` globs.
+    files: Vec<String>,
+    tags: Vec<String>,
+}
+
+impl Route {
+    /// The routes of the `[queues]` tables, in the order of their names,
+    /// downloading with `options` otherwise.
+    pub fn all(
+        queues: &HashMap<String, QueueConfig>,
+        options: &Options,
+    ) -> Result<Vec<Route>, String> {
+        let mut queues = queues.iter().collect::<Vec<_>>();
+        queues.sort_by_key(|(name, _)| *name);
+        queues
+            .into_iter()
+            .map(|(name, queue)| {
+                let rate = match &queue.rate {
+                    Some(rate) => Some(
+                        units::parse_rate(rate)
+                            .ok_or_else(|| format!("invalid rate for queues.{}: {}", name, rate))?,
+                    ),
+                    None => options.max_rate,
+                };
+                let downloader = Downloader::new(Options {
+                    max_rate: rate,
+                    ..options.clone()
+                })
+                .map_err(|err| err.to_string())?;
+                Ok(Route {
+                    name: name.clone(),
+                    downloader,
+                    slots: queue.max_concurrent.map(|max| Semaphore::new(max.max(1))),
+                    dir: queue.dir.clone(),
+                    files: queue.files.clone(),
+                    tags: queue.tags.clone(),
+                })
+            })
+            .collect()
+    }
+
+    fn matches(&self, image: &Image) -> bool {
+        let name = Path::new(&image.file_name)
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy();
+        self.files
+            .iter()
+            .any(|pattern| glob::matches(pattern, &name))
+            || image.tags.iter().any(|tag| self.tags.contains(tag))
+    }
+}
+
+/// `line` without its `queue=<name>` field, and the name.
+pub fn take_queue_field(line: &str) -> (String, Option<String>) {
+    let mut queue = None;
+    let words = line
+        .split_whitespace()
+        .enumerate()
+        // fields come after the url and path
+        .filter(|(index, word)| match word.strip_prefix("queue=") {
+            Some(name) if *index >= 2 => {
+                queue = Some(name.to_string());
+                false
+            }
+            _ => true,
+        })
+        .map(|(_, word)| word)
+        .collect::<Vec<_>>();
+    (words.join(" "), queue)
+}
+
+/// Whether `file_name`, sent by whoever can post to the queue, stays under the
+/// directory it is joined to: it isn't absolute and doesn't climb out with `..`.
+fn stays_inside(file_name: &str) -> bool {
+    Path::new(file_name)
+        .components()
+        .all(|component| matches!(component, Component::Normal(_) | Component::CurDir))
+}
+
+/// The route an entry goes to: the one `queue` names, or else the first that
+/// matches it, or none for the daemon's own downloader.
+fn pick<'a>(
+    routes: &'a [Route],
+    image: &Image,
+    queue: Option<&str>,
+) -> Result<Option<&'a Route>, String> {
+    match queue {
+        Some(name) => routes
+            .iter()
+            .find(|route| route.name == name)
+            .map(Some)
+            .ok_or_else(|| format!("no queue named {}", name)),
+        None => Ok(routes.iter().find(|route| route.matches(image))),
+    }
+}
+
+/// A url file to download whenever `cron` matches.
+pub struct Scheduled {
+    pub url_file: PathBuf,
+    pub cron: cron::Schedule,
+}
+
+/// The commands and webhook told when the queue drains, `{downloaded}`,
+/// `{skipped}` and `{failed}` being the outcomes since it got jobs, and when
+/// it gets jobs.
+pub struct QueueEvents {
+    pub on_empty: Option<String>,
+    pub on_busy: Option<String>,
+    pub hooks: Hooks,
+    pub webhook: Option<Webhook>,
+}
+
+impl QueueEvents {
+    fn busy(&mut self) {
+        if let Some(template) = &self.on_busy {
+            self.hooks.event(template, Vec::new());
+        }
+        if let Some(webhook) = &self.webhook {
+            webhook.send(Lifecycle::QueueBusy, &Stats::default(), 0, Duration::ZERO);
+        }
+    }
+
+    fn empty(&mut self, stats: &Stats, total: usize, elapsed: Duration) {
+        if let Some(template) = &self.on_empty {
+            let vars = vec![
+                ("downloaded".to_string(), stats.downloaded.to_string()),
+                ("skipped".to_string(), stats.skipped.to_string()),
+                ("failed".to_string(), stats.failed.to_string()),
+            ];
+            self.hooks.event(template, vars);
+        }
+        if let Some(webhook) = &self.webhook {
+            webhook.send(Lifecycle::QueueEmpty, stats, total as u64, elapsed);
+        }
+    }
+
+    /// Waits for the hooks that are still running and the webhook events.
+    pub async fn finish(self) {
+        self.hooks.finish().await;
+        if let Some(webhook) = self.webhook {
+            webhook.finish().await;
+        }
+    }
+}
+
+/// Works off `queue` and `schedules` until ctrl-c, running up to
+/// `max_concurrent` messages at once, each hidden from other consumers for
+/// `lease` at a time, with their entries routed to `routes`. `parse` turns a
+/// message body or url file into its entries, given a name for it to say where
+/// they came from, and `reload` reads the schedules again on SIGHUP.
+#[allow(clippy::too_many_arguments)]
+pub async fn run(
+    queue: Option<&str>,
+    mut schedules: Vec<Scheduled>,
+    reload: impl Fn() -> Result<Vec<Scheduled>, String>,
+    downloader: &Downloader,
+    routes: &[Route],
+    parse: impl Fn(&str, &str) -> Vec<Entry>,
+    max_concurrent: usize,
+    lease: Duration,
+    events: &mut QueueEvents,
+    verbose: bool,
+) -> Result<Stats, String> {
+    if queue.is_some_and(|queue| queue.starts_with("amqp://") || queue.starts_with("amqps://")) {
+        return Err("AMQP queues aren't supported, only SQS queue urls".to_string());
+    }
+    if queue.is_none() && schedules.is_empty() {
+        return Err(
+            "nothing to do, pass --queue or add [[manifest]] schedules to the config".to_string(),
+        );
+    }
+    let queue = queue.map(sqs::Queue::new).transpose()?;
+    let queue = queue.as_ref();
+    let timestamping = Downloader::new(Options {
+        timestamping: true,
+        ..downloader.options().clone()
+    })
+    .map_err(|err| err.to_string())?;
+    let (timestamping, parse) = (&timestamping, &parse);
+    let mut signals =
+        Signals::new().map_err(|err| format!("failed to listen for signals: {}", err))?;
+    let mut due = next_runs(&schedules);
+    // by url file, so that a reload doesn't start one that is still going
+    let mut scheduled_running = HashSet::new();
+    let mut scheduled_runs = FuturesUnordered::new();
+    let max_concurrent = max_concurrent.max(1);
+    let mut stats = Stats::default();
+    let mut running = FuturesUnordered::new();
+    let mut receiving: Option<Pin<Box<dyn Future<Output = _>>>> = None;
+    let mut next_id = 0;
+    // since the queue got jobs: when, the outcomes and how many entries
+    let mut batch: Option<(Instant, Stats, usize)> = None;
+    for route in routes {
+        let mut settings = Vec::new();
+        if let Some(slots) = &route.slots {
+            settings.push(format!("{} at once", slots.available_permits()));
+        }
+        if let Some(rate) = route.downloader.options().max_rate {
+            settings.push(format!("{}/s", indicatif::HumanBytes(rate)));
+        }
+        if let Some(dir) = &route.dir {
+            settings.push(format!("into {}", dir.display()));
+        }
+        eprintln!("queue {}: {}", route.name, settings.join(", "));
+    }
+    if queue.is_some() {
+        eprintln!("waiting for jobs, ctrl-c to stop");
+    }
+    loop {
+        let next_due = due.iter().flatten().min().copied();
+        if let Some(queue) = queue.filter(|_| receiving.is_none() && running.len() < max_concurrent)
+        {
+            receiving = Some(Box::pin(
+                queue.receive(max_concurrent - running.len(), lease),
+            ));
+        }
+        tokio::select! {
+            received = async { receiving.as_mut().expect("checked").await }, if receiving.is_some() => {
+                receiving = None;
+                let messages = match received {
+                    Ok(messages) => messages,
+                    Err(err) => {
+                        eprintln!("failed to receive jobs: {}", err);
+                        tokio::time::sleep(ERROR_BACKOFF).await;
+                        continue;
+                    }
+                };
+                let queue = queue.expect("only a queue is received from");
+                if messages.is_empty() && running.is_empty() {
+                    if let Some((started, stats, total)) = batch.take() {
+                        events.empty(&stats, total, started.elapsed());
+                    }
+                }
+                for message in messages {
+                    let entries = parse(&message.body, &format!("message {}", message.id));
+                    if entries.is_empty() {
+                        eprintln!("no entries in message {}", message.id);
+                        continue;
+                    }
+                    if batch.is_none() {
+                        batch = Some((Instant::now(), Stats::default(), 0));
+                        events.busy();
+                    }
+                    if let Some((_, _, total)) = &mut batch {
+                        *total += entries.len();
+                    }
+                    let first_id = next_id;
+                    next_id += entries.len();
+                    running.push(job(queue, downloader, routes, first_id, message, entries, lease));
+                }
+            }
+            Some(outcomes) = running.next(), if !running.is_empty() => {
+                for (image, result) in outcomes {
+                    if let Some((_, batch, _)) = &mut batch {
+                        match &result {
+                            Ok(DownloadCompleted::Success) => batch.downloaded += 1,
+                            Ok(DownloadCompleted::Skipped) => batch.skipped += 1,
+                            Err(_) => batch.failed += 1,
+                        }
+                    }
+                    match result {
+                        Ok(DownloadCompleted::Success) => {
+                            stats.downloaded += 1;
+                            if verbose {
+                                println!("downloaded: {}", image.file_name);
+                            }
+                        }
+                        Ok(DownloadCompleted::Skipped) => {
+                            stats.skipped += 1;
+                            if verbose {
+                                println!("skipped: {}", image.file_name);
+                            }
+                        }
+                        Err(err) => {
+                            stats.failed += 1;
+                            println!(
+                                "{}error : {} url: {} file_name: {} id: {}",
+                                crate::located(&image),
+                                err,
+                                image.url,
+                                image.file_name,
+                                image.id()
+                            );
+                        }
+                    }
+                }
+            }
+            _ = sleep_until(next_due), if next_due.is_some() => {
+                let now = Local::now();
+                for (index, scheduled) in schedules.iter().enumerate() {
+                    if due[index].is_some_and(|due| due <= now) {
+                        due[index] = scheduled.cron.next_after(now);
+                        if !scheduled_running.insert(scheduled.url_file.clone()) {
+                            eprintln!(
+                                "{} is still downloading, skipping this run",
+                                scheduled.url_file.display()
+                            );
+                            continue;
+                        }
+                        let url_file = scheduled.url_file.clone();
+                        scheduled_runs.push(async move {
+                            let result =
+                                download_url_file(&url_file, timestamping, parse, verbose).await;
+                            (url_file, result)
+                        });
+                    }
+                }
+            }
+            Some((url_file, result)) = scheduled_runs.next(), if !scheduled_runs.is_empty() => {
+                scheduled_running.remove(&url_file);
+                let url_file = url_file.display();
+                match result {
+                    Ok(run) => {
+                        eprintln!("{}: {}", url_file, run);
+                        stats.downloaded += run.downloaded;
+                        stats.skipped += run.skipped;
+                        stats.failed += run.failed;
+                    }
+                    Err(err) => eprintln!("{}: {}", url_file, err),
+                }
+            }
+            signal = signals.recv() => match signal {
+                Signal::Reload => match reload() {
+                    Ok(reloaded) => {
+                        schedules = reloaded;
+                        due = next_runs(&schedules);
+                        eprintln!("reloaded config: {} schedules", schedules.len());
+                    }
+                    Err(err) => eprintln!("failed to reload: {}", err),
+                },
+                Signal::Status => {
+                    eprintln!(
+                        "status: {} jobs running, {} url files downloading, {}",
+                        running.len(),
+                        scheduled_running.len(),
+                        stats
+                    );
+                    for url_file in &scheduled_running {
+                        eprintln!("  downloading: {}", url_file.display());
+                    }
+                }
+            },
+            _ = tokio::signal::ctrl_c() => {
+                // unacknowledged messages go back to the queue on their own
+                return Ok(stats);
+            }
+        }
+    }
+}
+
+/// When each of `schedules` is next due, printing it.
+fn next_runs(schedules: &[Scheduled]) -> Vec<Option<DateTime<Local>>> {
+    let now = Local::now();
+    schedules
+        .iter()
+        .map(|scheduled| {
+            let due = scheduled.cron.next_after(now);
+            if let Some(due) = due {
+                eprintln!(
+                    "{} is next downloaded at {}",
+                    scheduled.url_file.display(),
+                    due.format("%Y-%m-%d %H:%M")
+                );
+            }
+            due
+        })
+        .collect()
+}
+
+/// Waits until local time `time`, right away if it passed.
+async fn sleep_until(time: Option<DateTime<Local>>) {
+    let wait = time
+        .and_then(|time| (time - Local::now()).to_std().ok())
+        .unwrap_or_default();
+    tokio::time::sleep(wait).await;
+}
+
+/// One scheduled download of `url_file`.
+async fn download_url_file(
+    url_file: &Path,
+    downloader: &Downloader,
+    parse: impl Fn(&str, &str) -> Vec<Entry>,
+    verbose: bool,
+) -> Result<Stats, String> {
+    let text = tokio::fs::read_to_string(url_file)
+        .await
+        .map_err(|err| format!("failed to read url file: {}", err))?;
+    let mut stats = Stats::default();
+    let images = parse(&text, &url_file.display().to_string())
+        .into_iter()
+        .map(|(image, _)| image)
+        .collect::<Vec<_>>();
+    let mut outcomes = downloader.stream(images, Order::Completion);
+    while let Some(outcome) = outcomes.next().await {
+        let image = outcome.image;
+        match outcome.result {
+            Ok(DownloadCompleted::Success) => {
+                stats.downloaded += 1;
+                if verbose {
+                    println!("downloaded: {}", image.file_name);
+                }
+            }
+            // unchanged on the server
+            Ok(DownloadCompleted::Skipped) => stats.skipped += 1,
+            Err(err) => {
+                stats.failed += 1;
+                println!(
+                    "{}error : {} url: {} file_name: {} id: {}",
+                    crate::located(&image),
+                    err,
+                    image.url,
+                    image.file_name,
+                    image.id()
+                );
+            }
+        }
+    }
+    Ok(stats)
+}
+
+/// Downloads the entries of `message` one after another, each with its route
+/// or else `downloader`, keeping it hidden until they are done, and
+/// acknowledges it if they all succeeded. Downloads get ids from `first_id`
+/// on.
+async fn job(
+    queue: &sqs::Queue,
+    downloader: &Downloader,
+    routes: &[Route],
+    first_id: usize,
+    message: sqs::Message,
+    entries: Vec<Entry>,
+    lease: Duration,
+) -> Vec<Outcome> {
+    let mut extend = tokio::time::interval((lease / 3).max(Duration::from_secs(1)));
+    // the first tick completes right away, the message was just received
+    extend.tick().await;
+    let mut outcomes = Vec::new();
+    for (offset, (mut image, name)) in entries.into_iter().enumerate() {
+        let route = match pick(routes, &image, name.as_deref()) {
+            Ok(route) if stays_inside(&image.file_name) => route,
+            Ok(_) => {
+                let err = format!("path {} leaves the download directory", image.file_name);
+                outcomes.push((image, Err(err)));
+                continue;
+            }
+            Err(err) => {
+                outcomes.push((image, Err(err)));
+                continue;
+            }
+        };
+        if let Some(dir) = route.and_then(|route| route.dir.as_ref()) {
+            image.file_name = dir.join(&image.file_name).to_string_lossy().into_owned();
+        }
+        let result = {
+            let download = async {
+                // a slot of the queue, held while the download runs
+                let _slot = match route.and_then(|route| route.slots.as_ref()) {
+                    Some(slots) => slots.acquire().await.ok(),
+                    None => None,
+                };
+                route
+                    .map_or(downloader, |route| &route.downloader)
+                    .download(first_id + offset, &image)
+                    .await
+            };
+            tokio::pin!(download);
+            loop {
+                tokio::select! {
+                    result = &mut download => break result.map_err(|err| err.to_string()),
+                    _ = extend.tick() => {
+                        // a message that reappears is at worst downloaded twice
+                        if let Err(err) = queue.extend(&message, lease).await {
+                            eprintln!("failed to hold message {}: {}", message.id, err);
+                        }
+                    }
+                }
+            }
+        };
+        outcomes.push((image, result));
+    }
+    // error statuses are failures too, only downloaded or matching files count
+    let failed = outcomes
+        .iter()
+        .filter(|(_, result)| result.is_err())
+        .count();
+    match failed {
+        0 => {
+            if let Err(err) = queue.delete(&message).await {
+                eprintln!("failed to acknowledge message {}: {}", message.id, err);
+            }
+        }
+        // it reappears once the lease runs out, or goes to the dead-letter
+        // queue
+        failed => eprintln!(
+            "message {}: {} of {} entries failed, leaving it on the queue",
+            message.id,
+            failed,
+            outcomes.len()
+        ),
+    }
+    outcomes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn routes() -> Vec<Route> {
+        let queue = |files: &[&str], tags: &[&str]| QueueConfig {
+            files: files.iter().map(|file| file.to_string()).collect(),
+            tags: tags.iter().map(|tag| tag.to_string()).collect(),
+            ..QueueConfig::default()
+        };
+        let queues = HashMap::from([
+            ("video".to_string(), queue(&["*.mp4", "*.mkv"], &[])),
+            ("bulk".to_string(), queue(&["*"], &["nightly"])),
+            ("archive".to_string(), queue(&["*.tar.?z"], &["backup"])),
+        ]);
+        Route::all(&queues, &Options::default()).unwrap()
+    }
+
+    fn picked(routes: &[Route], image: &Image, queue: Option<&str>) -> Option<String> {
+        pick(routes, image, queue)
+            .unwrap()
+            .map(|route| route.name.clone())
+    }
+
+    #[test]
+    fn routes_are_in_the_order_of_their_names() {
+        let names = routes()
+            .into_iter()
+            .map(|route| route.name)
+            .collect::<Vec<_>>();
+        assert_eq!(names, ["archive", "bulk", "video"]);
+    }
+
+    #[test]
+    fn entries_go_to_the_first_queue_matching_their_file_name_or_tags() {
+        let routes = routes();
+        let image = Image::new("https://example.com/a", "films/a.mp4");
+        // `bulk` matches every name, but `archive` comes first by tag
+        let mut tagged = Image::new("https://example.com/b", "b.bin");
+        tagged.tags = vec!["backup".to_string()];
+        assert_eq!(picked(&routes, &image, None).as_deref(), Some("bulk"));
+        assert_eq!(picked(&routes, &tagged, None).as_deref(), Some("archive"));
+        assert_eq!(
+            picked(
+                &routes,
+                &Image::new("https://example.com/c", "c.tar.xz"),
+                None
+            )
+            .as_deref(),
+            Some("archive")
+        );
+    }
+
+    #[test]
+    fn entries_match_no_queue_when_nothing_matches() {
+        let routes = Route::all(
+            &HashMap::from([("video".to_string(), QueueConfig::default())]),
+            &Options::default(),
+        )
+        .unwrap();
+        assert_eq!(
+            picked(&routes, &Image::new("https://example.com/a", "a.mp4"), None),
+            None
+        );
+    }
+
+    #[test]
+    fn a_queue_field_names_the_route() {
+        let routes = routes();
+        let image = Image::new("https://example.com/a", "a.mp4");
+        assert_eq!(
+            picked(&routes, &image, Some("video")).as_deref(),
+            Some("video")
+        );
+        assert_eq!(
+            pick(&routes, &image, Some("music")).err().as_deref(),
+            Some("no queue named music")
+        );
+    }
+
+    #[test]
+    fn paths_must_stay_inside_the_download_directory() {
+        assert!(stays_inside("films/a.mp4"));
+        assert!(stays_inside("./a.mp4"));
+        assert!(!stays_inside("/etc/cron.d/a"));
+        assert!(!stays_inside("films/../../a.mp4"));
+        assert!(!stays_inside(".."));
+    }
+
+    #[test]
+    fn take_queue_field_leaves_the_url_and_path_alone() {
+        assert_eq!(
+            take_queue_field("https://example.com/a a.mp4 queue=video size=3"),
+            (
+                "https://example.com/a a.mp4 size=3".to_string(),
+                Some("video".to_string())
+            )
+        );
+        assert_eq!(
+            take_queue_field("https://example.com/a queue=video"),
+            ("https://example.com/a queue=video".to_string(), None)
+        );
+    }
+}
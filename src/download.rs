@@ -0,0 +1,1972 @@
+//! Downloading a single item to disk.
+
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+use crate::uring;
+use crate::{
+    compress::{self, Compressor},
+    fixtures::{self, Recording},
+    http_cache::Cached,
+    perms, s3, share,
+    sigv4::hex,
+    sniff, trace,
+    tree_hash::{self, TreeHasher},
+    validators::{self, Validators},
+    warc, writers, xattr, zsync, Control, Image, Options, Owner, RequestRecord,
+};
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use futures::{stream::FuturesUnordered, StreamExt};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
+    fmt,
+    hash::{Hash, Hasher},
+    io,
+    path::{Path, PathBuf},
+    time::{Duration, Instant, SystemTime},
+};
+use tokio::sync::{watch, OnceCell, OwnedSemaphorePermit, Semaphore};
+use tokio::{fs, io::AsyncWriteExt};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DownloadCompleted {
+    Success,
+    Skipped,
+}
+
+#[derive(Debug)]
+#[allow(clippy::enum_variant_names)]
+pub enum DownloadError {
+    FailedToCreateParentDirectory(io::Error),
+    FailedToCreateFile(io::Error),
+    FailedToDownloadToFile(io::Error),
+    FailedToPreallocateFile(io::Error),
+    FailedToSyncFile(io::Error),
+    FailedToMoveFile(io::Error),
+    FailedToSetPermissions(io::Error),
+    FailedToConvertResponseToBytes(reqwest::Error),
+    FailedToGetUrl(reqwest::Error),
+    /// The server answered with something else than the file: a status
+    /// outside of 2xx, or a 304 to a request that wasn't conditional.
+    HttpStatus(reqwest::StatusCode),
+    FailedToUpload(io::Error),
+    FailedToArchive(io::Error),
+    FailedToRecord(io::Error),
+    FailedToReplay(io::Error),
+    PathConflict {
+        path: PathBuf,
+        reason: &'static str,
+    },
+    /// The server answered with what looks like an HTML error page, see
+    /// [`Options::reject_error_pages`](crate::Options::reject_error_pages).
+    ErrorPage {
+        content_type: Option<String>,
+    },
+    /// [`Options::url_policy`](crate::Options::url_policy) doesn't allow
+    /// downloading `url`.
+    Blocked {
+        url: String,
+        reason: String,
+    },
+    /// The server answered with a "not found" page, see
+    /// [`Options::soft_404`](crate::Options::soft_404).
+    SoftNotFound,
+    /// Google Drive sent a page without a way on to the file, as it does for
+    /// files over their download quota, see
+    /// [`Options::share_links`](crate::Options::share_links).
+    SharePage,
+    ChecksumMismatch {
+        expected: String,
+        actual: String,
+    },
+    SizeMismatch {
+        expected: u64,
+        actual: u64,
+    },
+    Cancelled,
+}
+
+pub type DownloadResult = Result<DownloadCompleted, DownloadError>;
+
+/// What to do when an item's path is a symlink or a directory, see
+/// [`Options::on_conflict`](crate::Options::on_conflict).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Conflict {
+    /// Write through symlinks to the file they point to. Directories fail.
+    #[default]
+    Follow,
+    /// Remove the symlink or empty directory before downloading. Directories
+    /// with files in them still fail.
+    Replace,
+    /// Fail the item.
+    Error,
+}
+
+impl Conflict {
+    pub fn parse(name: &str) -> Option<Conflict> {
+        match name {
+            "follow" => Some(Conflict::Follow),
+            "replace" => Some(Conflict::Replace),
+            "error" => Some(Conflict::Error),
+            _ => None,
+        }
+    }
+}
+
+/// Files smaller than this aren't worth an extra syscall to preallocate.
+const PREALLOCATE_MIN_SIZE: u64 = 1 << 20;
+
+/// How files are written to disk, see [`Options::io_backend`](crate::Options::io_backend).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum WriteBackend {
+    /// tokio's files, whose writes run on its blocking thread pool.
+    #[default]
+    Tokio,
+    /// Plain std files, written by one blocking task per chunk.
+    Std,
+    /// Files of known size are memory mapped and chunks copied into the map.
+    Mmap,
+    /// io_uring on a dedicated thread, needs the `io-uring` feature on Linux.
+    Uring,
+    /// A pool of [`Options::writer_threads`](crate::Options::writer_threads)
+    /// disk writer threads that downloads queue their chunks to.
+    Threads,
+}
+
+impl WriteBackend {
+    pub const ALL: [WriteBackend; 5] = [
+        WriteBackend::Tokio,
+        WriteBackend::Std,
+        WriteBackend::Mmap,
+        WriteBackend::Uring,
+        WriteBackend::Threads,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            WriteBackend::Tokio => "tokio",
+            WriteBackend::Std => "std",
+            WriteBackend::Mmap => "mmap",
+            WriteBackend::Uring => "uring",
+            WriteBackend::Threads => "threads",
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<WriteBackend> {
+        WriteBackend::ALL
+            .into_iter()
+            .find(|backend| backend.name() == name)
+    }
+
+    /// Whether this build can write through the backend.
+    pub fn is_available(self) -> bool {
+        self != WriteBackend::Uring || cfg!(all(target_os = "linux", feature = "io-uring"))
+    }
+}
+
+/// The bytes downloads may hold between reading a chunk and writing it out,
+/// see [`Options::max_memory`](crate::Options::max_memory).
+#[derive(Clone)]
+pub(crate) struct MemoryBudget {
+    bytes: Option<std::sync::Arc<Semaphore>>,
+    /// The size in bytes, at most what one reservation can take.
+    max: u32,
+}
+
+impl MemoryBudget {
+    pub(crate) fn new(max: Option<u64>) -> MemoryBudget {
+        let size = max.map_or(0, |max| max.clamp(1, u32::MAX as u64) as u32);
+        MemoryBudget {
+            bytes: max.map(|_| std::sync::Arc::new(Semaphore::new(size as usize))),
+            max: size,
+        }
+    }
+
+    /// Waits until `bytes` fit in the budget. A chunk larger than the whole
+    /// budget waits for all of it.
+    async fn reserve(&self, bytes: usize) -> Option<OwnedSemaphorePermit> {
+        let bytes = u32::try_from(bytes).unwrap_or(u32::MAX).min(self.max);
+        let budget = self.bytes.clone()?;
+        // the semaphore is never closed
+        budget.acquire_many_owned(bytes).await.ok()
+    }
+}
+
+/// The directories a downloader created, so the thousands of files that share
+/// a parent don't each ask the file system for it, see
+/// [`Options::dir_creators`](crate::Options::dir_creators).
+#[derive(Clone)]
+pub(crate) struct DirCache {
+    dirs: std::sync::Arc<std::sync::Mutex<HashMap<PathBuf, std::sync::Arc<OnceCell<()>>>>>,
+    creators: std::sync::Arc<Semaphore>,
+}
+
+impl DirCache {
+    pub(crate) fn new(creators: usize) -> DirCache {
+        DirCache {
+            dirs: Default::default(),
+            creators: std::sync::Arc::new(Semaphore::new(creators.max(1))),
+        }
+    }
+
+    /// Creates `dir` and its missing parents like [`perms::create_dirs`],
+    /// unless it was created before. Files of a directory that is being
+    /// created wait for it instead of creating it too.
+    async fn create(&self, dir: &Path, mode: Option<u32>, owner: Option<Owner>) -> io::Result<()> {
+        let once = self
+            .dirs
+            .lock()
+            .unwrap()
+            .entry(dir.to_path_buf())
+            .or_default()
+            .clone();
+        once.get_or_try_init(|| async {
+            // the semaphore is never closed
+            let _creator = self.creators.acquire().await.ok();
+            perms::create_dirs(dir, mode, owner).await
+        })
+        .await?;
+        Ok(())
+    }
+}
+
+/// Where the download of a url ended up, once it's over: `None` if it failed.
+type Landed = Option<Option<PathBuf>>;
+
+/// The urls a downloader is reading bodies from, after redirects, so items
+/// that end up at the same url download it once, see
+/// [`Options::dedup`](crate::Options::dedup).
+#[derive(Clone, Default)]
+pub(crate) struct InFlight {
+    urls: std::sync::Arc<std::sync::Mutex<HashMap<String, watch::Receiver<Landed>>>>,
+}
+
+enum Claim {
+    /// The first download of the url, which the others wait for.
+    Reading(Reading),
+    /// Another download is reading the url.
+    Waiting(watch::Receiver<Landed>),
+}
+
+/// Tells the downloads waiting for a url where it landed when dropped.
+struct Reading {
+    urls: std::sync::Arc<std::sync::Mutex<HashMap<String, watch::Receiver<Landed>>>>,
+    url: String,
+    landed: Option<PathBuf>,
+    done: watch::Sender<Landed>,
+}
+
+impl InFlight {
+    fn claim(&self, url: &str) -> Claim {
+        let mut urls = self.urls.lock().unwrap();
+        if let Some(reading) = urls.get(url) {
+            return Claim::Waiting(reading.clone());
+        }
+        let (done, reading) = watch::channel(None);
+        urls.insert(url.to_string(), reading);
+        Claim::Reading(Reading {
+            urls: self.urls.clone(),
+            url: url.to_string(),
+            landed: None,
+            done,
+        })
+    }
+}
+
+impl Drop for Reading {
+    fn drop(&mut self) {
+        self.urls.lock().unwrap().remove(&self.url);
+        self.done.send_replace(Some(self.landed.take()));
+    }
+}
+
+/// Where the download `waiting` is for landed, `None` if it failed.
+async fn landed(mut waiting: watch::Receiver<Landed>) -> Option<PathBuf> {
+    match waiting.wait_for(Option::is_some).await {
+        Ok(landed) => landed.clone().flatten(),
+        Err(_) => None,
+    }
+}
+
+/// The running [`WriteBackend`] of a downloader.
+pub(crate) enum IoBackend {
+    Tokio,
+    Std,
+    Mmap,
+    #[cfg(all(target_os = "linux", feature = "io-uring"))]
+    Uring(uring::Writer),
+    Threads(writers::Pool),
+}
+
+/// An open output file of an [`IoBackend`].
+enum FileSink {
+    Tokio(fs::File),
+    /// `None` only while a blocking task has the file.
+    Std(Option<std::fs::File>),
+    /// `None` only while a blocking task maps or copies into it.
+    Mmap(Option<MmapFile>),
+    #[cfg(all(target_os = "linux", feature = "io-uring"))]
+    Uring(uring::FileWriter),
+    Threads(writers::FileWriter),
+    /// Not a file but an object uploaded for [`Options::s3`](crate::Options::s3).
+    Upload(s3::Upload),
+}
+
+struct MmapFile {
+    file: std::fs::File,
+    /// The preallocated file, unmapped while its size is unknown.
+    map: Option<memmap2::MmapMut>,
+    written: u64,
+}
+
+impl fmt::Display for DownloadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DownloadError::FailedToCreateParentDirectory(err) => {
+                write!(f, "failed to create parent directory: {}", err)
+            }
+            DownloadError::FailedToCreateFile(err) => write!(f, "failed to create file: {}", err),
+            DownloadError::FailedToDownloadToFile(err) => {
+                write!(f, "failed to write to file: {}", err)
+            }
+            DownloadError::FailedToPreallocateFile(err) => {
+                write!(f, "failed to preallocate file: {}", err)
+            }
+            DownloadError::FailedToSyncFile(err) => write!(f, "failed to sync file: {}", err),
+            DownloadError::FailedToMoveFile(err) => {
+                write!(f, "failed to move file into place: {}", err)
+            }
+            DownloadError::Blocked { url, reason } => {
+                write!(f, "refusing to download {}: {}", url, reason)
+            }
+            DownloadError::SoftNotFound => {
+                write!(f, "got a page saying the file wasn't found (soft 404)")
+            }
+            DownloadError::SharePage => {
+                write!(
+                    f,
+                    "Google Drive sent a page instead of the file, e.g. over its download quota"
+                )
+            }
+            DownloadError::ErrorPage { content_type } => {
+                write!(f, "got what looks like an error page instead of the file")?;
+                match content_type {
+                    Some(content_type) => write!(f, ", sent as {}", content_type),
+                    None => Ok(()),
+                }
+            }
+            DownloadError::PathConflict { path, reason } => {
+                write!(f, "{} {}", path.display(), reason)
+            }
+            DownloadError::FailedToSetPermissions(err) => {
+                write!(f, "failed to set permissions: {}", err)
+            }
+            DownloadError::FailedToConvertResponseToBytes(err) => {
+                write!(f, "failed to read response body: {}", err)
+            }
+            DownloadError::FailedToGetUrl(err) => write!(f, "failed to get url: {}", err),
+            DownloadError::HttpStatus(status) => write!(f, "server answered {}", status),
+            DownloadError::FailedToUpload(err) => write!(f, "failed to upload: {}", err),
+            DownloadError::FailedToArchive(err) => {
+                write!(f, "failed to write to WARC: {}", err)
+            }
+            DownloadError::FailedToRecord(err) => write!(f, "failed to record: {}", err),
+            DownloadError::FailedToReplay(err) => write!(f, "failed to replay: {}", err),
+            DownloadError::ChecksumMismatch { expected, actual } => {
+                write!(
+                    f,
+                    "checksum mismatch: expected {}, got {}",
+                    expected, actual
+                )
+            }
+            DownloadError::SizeMismatch { expected, actual } => {
+                write!(
+                    f,
+                    "size mismatch: expected {} bytes, got {}",
+                    expected, actual
+                )
+            }
+            DownloadError::Cancelled => write!(f, "cancelled"),
+        }
+    }
+}
+
+impl std::error::Error for DownloadError {}
+
+impl DownloadError {
+    /// Whether the failure came from the network or an overloaded server, so
+    /// trying again may help.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            DownloadError::HttpStatus(status) => {
+                status.is_server_error()
+                    || *status == reqwest::StatusCode::REQUEST_TIMEOUT
+                    || *status == reqwest::StatusCode::TOO_MANY_REQUESTS
+            }
+            _ => matches!(
+                self,
+                DownloadError::FailedToGetUrl(_)
+                    | DownloadError::FailedToConvertResponseToBytes(_)
+                    | DownloadError::FailedToUpload(_)
+            ),
+        }
+    }
+}
+
+impl IoBackend {
+    /// `threads` is the size of the [`WriteBackend::Threads`] pool.
+    pub(crate) fn new(backend: WriteBackend, threads: usize) -> io::Result<IoBackend> {
+        match backend {
+            WriteBackend::Threads => Ok(IoBackend::Threads(writers::Pool::spawn(threads)?)),
+            WriteBackend::Tokio => Ok(IoBackend::Tokio),
+            WriteBackend::Std => Ok(IoBackend::Std),
+            WriteBackend::Mmap => Ok(IoBackend::Mmap),
+            #[cfg(all(target_os = "linux", feature = "io-uring"))]
+            WriteBackend::Uring => Ok(IoBackend::Uring(uring::Writer::spawn()?)),
+            #[cfg(not(all(target_os = "linux", feature = "io-uring")))]
+            WriteBackend::Uring => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "io_uring requires a Linux build with the io-uring feature",
+            )),
+        }
+    }
+
+    async fn create(&self, path: &Path) -> io::Result<FileSink> {
+        match self {
+            IoBackend::Tokio => Ok(FileSink::Tokio(fs::File::create(path).await?)),
+            IoBackend::Std => {
+                let path = path.to_path_buf();
+                let file =
+                    tokio::task::spawn_blocking(move || std::fs::File::create(path)).await??;
+                Ok(FileSink::Std(Some(file)))
+            }
+            IoBackend::Mmap => {
+                // the map needs a file opened for reading too
+                let path = path.to_path_buf();
+                let file = tokio::task::spawn_blocking(move || {
+                    std::fs::OpenOptions::new()
+                        .read(true)
+                        .write(true)
+                        .create(true)
+                        .truncate(true)
+                        .open(path)
+                })
+                .await??;
+                Ok(FileSink::Mmap(Some(MmapFile {
+                    file,
+                    map: None,
+                    written: 0,
+                })))
+            }
+            #[cfg(all(target_os = "linux", feature = "io-uring"))]
+            IoBackend::Uring(writer) => Ok(FileSink::Uring(writer.create(path.to_path_buf()))),
+            IoBackend::Threads(pool) => Ok(FileSink::Threads(pool.create(path.to_path_buf()))),
+        }
+    }
+
+    /// Opens the existing file at `path` to write on from `offset`,
+    /// dropping anything past it.
+    async fn open_at(&self, path: &Path, offset: u64) -> io::Result<FileSink> {
+        let file = {
+            let path = path.to_path_buf();
+            tokio::task::spawn_blocking(move || {
+                use std::io::{Seek, SeekFrom};
+                let mut file = std::fs::OpenOptions::new()
+                    .read(true)
+                    .write(true)
+                    .open(path)?;
+                file.set_len(offset)?;
+                file.seek(SeekFrom::Start(offset))?;
+                Ok::<_, io::Error>(file)
+            })
+            .await??
+        };
+        match self {
+            IoBackend::Tokio => Ok(FileSink::Tokio(fs::File::from_std(file))),
+            IoBackend::Std => Ok(FileSink::Std(Some(file))),
+            IoBackend::Mmap => Ok(FileSink::Mmap(Some(MmapFile {
+                file,
+                map: None,
+                written: offset,
+            }))),
+            #[cfg(all(target_os = "linux", feature = "io-uring"))]
+            IoBackend::Uring(writer) => {
+                drop(file);
+                Ok(FileSink::Uring(writer.open_at(path.to_path_buf(), offset)))
+            }
+            IoBackend::Threads(pool) => Ok(FileSink::Threads(pool.adopt(file))),
+        }
+    }
+
+    async fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        match self {
+            IoBackend::Tokio | IoBackend::Std | IoBackend::Mmap => fs::rename(from, to).await,
+            #[cfg(all(target_os = "linux", feature = "io-uring"))]
+            IoBackend::Uring(writer) => writer.rename(from.to_path_buf(), to.to_path_buf()).await,
+            IoBackend::Threads(pool) => pool.rename(from.to_path_buf(), to.to_path_buf()).await,
+        }
+    }
+}
+
+impl FileSink {
+    /// Writes `chunk`, releasing `reserved` once it is out of memory.
+    async fn write(
+        &mut self,
+        chunk: Bytes,
+        reserved: Option<OwnedSemaphorePermit>,
+    ) -> io::Result<()> {
+        match self {
+            FileSink::Tokio(file) => file.write_all(&chunk).await,
+            FileSink::Std(file) => {
+                blocking(file, move |file| std::io::Write::write_all(file, &chunk)).await
+            }
+            FileSink::Mmap(file) => blocking(file, move |file| file.write(&chunk)).await,
+            #[cfg(all(target_os = "linux", feature = "io-uring"))]
+            FileSink::Uring(file) => file.write(chunk, reserved).await,
+            FileSink::Threads(file) => file.write(chunk, reserved).await,
+            FileSink::Upload(upload) => upload.write(&chunk).await,
+        }
+    }
+
+    /// Reserves `len` bytes on disk so a full disk fails the download up front.
+    async fn preallocate(&mut self, len: u64) -> io::Result<()> {
+        match self {
+            FileSink::Tokio(_) | FileSink::Std(_) | FileSink::Threads(_)
+                if len < PREALLOCATE_MIN_SIZE =>
+            {
+                Ok(())
+            }
+            FileSink::Threads(file) => file.preallocate(len).await,
+            FileSink::Tokio(file) => {
+                let file = file.try_clone().await?.into_std().await;
+                tokio::task::spawn_blocking(move || preallocate_file(&file, len)).await?
+            }
+            FileSink::Std(file) => blocking(file, move |file| preallocate_file(file, len)).await,
+            // mapped no matter the size, which is the point of the backend
+            FileSink::Mmap(file) if len > 0 => blocking(file, move |file| file.map(len)).await,
+            FileSink::Mmap(_) | FileSink::Upload(_) => Ok(()),
+            #[cfg(all(target_os = "linux", feature = "io-uring"))]
+            FileSink::Uring(_) if len < PREALLOCATE_MIN_SIZE => Ok(()),
+            #[cfg(all(target_os = "linux", feature = "io-uring"))]
+            FileSink::Uring(file) => file.preallocate(len).await,
+        }
+    }
+
+    /// Flushes the file contents and metadata to disk.
+    async fn sync(&mut self) -> io::Result<()> {
+        match self {
+            FileSink::Tokio(file) => {
+                file.flush().await?;
+                file.sync_all().await
+            }
+            FileSink::Std(file) => blocking(file, |file| file.sync_all()).await,
+            FileSink::Mmap(file) => blocking(file, MmapFile::sync).await,
+            #[cfg(all(target_os = "linux", feature = "io-uring"))]
+            FileSink::Uring(file) => file.sync().await,
+            FileSink::Threads(file) => file.sync().await,
+            FileSink::Upload(_) => Ok(()),
+        }
+    }
+
+    async fn finish(self) -> io::Result<()> {
+        match self {
+            FileSink::Tokio(mut file) => file.flush().await,
+            FileSink::Std(_) => Ok(()),
+            FileSink::Mmap(mut file) => blocking(&mut file, |file| file.finish()).await,
+            #[cfg(all(target_os = "linux", feature = "io-uring"))]
+            FileSink::Uring(file) => file.finish().await,
+            FileSink::Threads(file) => file.finish().await,
+            FileSink::Upload(upload) => upload.finish().await,
+        }
+    }
+}
+
+impl MmapFile {
+    /// Preallocates the file to `len` bytes and maps it.
+    fn map(&mut self, len: u64) -> io::Result<()> {
+        preallocate_file(&self.file, len)?;
+        self.file.set_len(len)?;
+        // SAFETY: the part file is only written through this map until it is finished
+        self.map = Some(unsafe { memmap2::MmapMut::map_mut(&self.file)? });
+        Ok(())
+    }
+
+    /// Copies `chunk` into the map, or writes it to the file past its end.
+    fn write(&mut self, chunk: &[u8]) -> io::Result<()> {
+        let start = self.written as usize;
+        let end = start + chunk.len();
+        match &mut self.map {
+            Some(map) if end <= map.len() => map[start..end].copy_from_slice(chunk),
+            _ => {
+                use std::io::{Seek, SeekFrom, Write};
+                self.file.seek(SeekFrom::Start(self.written))?;
+                self.file.write_all(chunk)?;
+            }
+        }
+        self.written += chunk.len() as u64;
+        Ok(())
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        if let Some(map) = &self.map {
+            map.flush()?;
+        }
+        self.file.sync_all()
+    }
+
+    /// Unmaps the file and cuts off what a shorter body left preallocated.
+    fn finish(&mut self) -> io::Result<()> {
+        if let Some(map) = self.map.take() {
+            let mapped = map.len() as u64;
+            drop(map);
+            if self.written < mapped {
+                self.file.set_len(self.written)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Runs `op` on a std or mapped file on the blocking thread pool, handing it
+/// back after.
+async fn blocking<F: Send + 'static, T: Send + 'static>(
+    file: &mut Option<F>,
+    op: impl FnOnce(&mut F) -> io::Result<T> + Send + 'static,
+) -> io::Result<T> {
+    let mut taken = file
+        .take()
+        .ok_or_else(|| io::Error::other("file lost by an earlier failed write"))?;
+    let (taken, result) = tokio::task::spawn_blocking(move || {
+        let result = op(&mut taken);
+        (taken, result)
+    })
+    .await?;
+    *file = Some(taken);
+    result
+}
+
+#[cfg(target_os = "linux")]
+pub(crate) fn preallocate_file(file: &std::fs::File, len: u64) -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+    // SAFETY: the descriptor stays open for the duration of the call
+    let res = unsafe { libc::fallocate(file.as_raw_fd(), 0, 0, len as libc::off_t) };
+    if res == 0 {
+        return Ok(());
+    }
+    let err = io::Error::last_os_error();
+    // not every filesystem supports fallocate, the download can go ahead without it
+    if err.raw_os_error() == Some(libc::EOPNOTSUPP) {
+        Ok(())
+    } else {
+        Err(err)
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn preallocate_file(file: &std::fs::File, len: u64) -> io::Result<()> {
+    file.set_len(len)
+}
+
+/// Makes the directory entry of a newly created file durable.
+#[cfg(unix)]
+async fn sync_dir(dir: &Path) -> io::Result<()> {
+    let dir = if dir.as_os_str().is_empty() {
+        Path::new(".")
+    } else {
+        dir
+    };
+    fs::File::open(dir).await?.sync_all().await
+}
+
+/// Directories can't be opened for syncing on other platforms.
+#[cfg(not(unix))]
+async fn sync_dir(_dir: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+/// Removes a partially written file when dropped, unless it was marked as complete.
+///
+/// This covers both download errors and downloads that are cancelled because
+/// another download failed. `Drop` can't await, so the removal is a blocking
+/// call, but it only runs on these failure paths.
+struct PartialFile<'a> {
+    path: &'a Path,
+    complete: bool,
+    /// Left in place for [`Options::resume`](crate::Options::resume).
+    keep: bool,
+}
+
+impl Drop for PartialFile<'_> {
+    fn drop(&mut self) {
+        if !self.complete && !self.keep {
+            let _ = std::fs::remove_file(self.path);
+            let _ = std::fs::remove_file(resume_path(self.path));
+        }
+    }
+}
+
+/// Where the validators a part file was started with are kept.
+fn resume_path(part: &Path) -> PathBuf {
+    with_extension_suffix(part, ".resume")
+}
+
+/// The size of a part file left by an interrupted download, and the
+/// validators it was started with.
+async fn resume_point(part: &Path) -> Option<(u64, Validators)> {
+    let json = fs::read(resume_path(part)).await.ok()?;
+    let saved = serde_json::from_slice::<Validators>(&json).ok()?;
+    let len = fs::metadata(part).await.ok()?.len();
+    (len > 0 && saved.if_range().is_some()).then_some((len, saved))
+}
+
+/// Records what the server said about a download starting from zero, if that
+/// could tell a later resume whether the file is still the same.
+async fn save_resume_point(
+    part: &Path,
+    url: &str,
+    response: &reqwest::Response,
+) -> Option<Validators> {
+    let saved = Validators::of(url, response, response.content_length().unwrap_or(0))
+        .filter(|saved| saved.if_range().is_some());
+    let Some(saved) = saved else {
+        let _ = fs::remove_file(resume_path(part)).await;
+        return None;
+    };
+    let json = serde_json::to_vec_pretty(&saved).expect("validators serialize");
+    fs::write(resume_path(part), json).await.ok()?;
+    Some(saved)
+}
+
+/// Whether `response` is the rest of the file a part file of `offset` bytes
+/// was started with.
+fn continues(response: &reqwest::Response, offset: u64, saved: &Validators) -> bool {
+    let header = |name| {
+        response
+            .headers()
+            .get(name)
+            .and_then(|value: &reqwest::header::HeaderValue| value.to_str().ok())
+    };
+    let starts_at_offset = header(reqwest::header::CONTENT_RANGE)
+        .and_then(|range| range.strip_prefix("bytes "))
+        .and_then(|range| range.split_once('-'))
+        .and_then(|(start, _)| start.parse::<u64>().ok())
+        == Some(offset);
+    // If-Range already asks the server for this, in case it gets it wrong
+    let same_file = match (&saved.etag, &saved.last_modified) {
+        (Some(etag), _) if !etag.starts_with("W/") => header(reqwest::header::ETAG) == Some(etag),
+        (_, Some(last_modified)) => {
+            header(reqwest::header::LAST_MODIFIED).is_none_or(|value| value == last_modified)
+        }
+        _ => false,
+    };
+    response.status() == reqwest::StatusCode::PARTIAL_CONTENT && starts_at_offset && same_file
+}
+
+/// Flushes a part file cut off after `written` bytes and drops what
+/// preallocation added past them, so it can be resumed.
+async fn keep_part(file: FileSink, part: &Path, written: u64) -> io::Result<()> {
+    file.finish().await?;
+    let part = part.to_path_buf();
+    tokio::task::spawn_blocking(move || {
+        std::fs::OpenOptions::new()
+            .write(true)
+            .open(part)?
+            .set_len(written)
+    })
+    .await?
+}
+
+/// SHA-256 state after the first `len` bytes of the file at `path`.
+async fn hash_prefix(path: &Path, len: u64) -> io::Result<Sha256> {
+    let path = path.to_path_buf();
+    tokio::task::spawn_blocking(move || {
+        let mut hasher = Sha256::new();
+        io::copy(
+            &mut io::Read::take(std::fs::File::open(path)?, len),
+            &mut hasher,
+        )?;
+        Ok(hasher)
+    })
+    .await?
+}
+
+/// Where a download is written until it's complete.
+///
+/// The temp dir is flat, so names there carry a hash of the destination to keep
+/// files with the same name in different directories apart.
+fn part_path(path: &Path, temp_dir: Option<&Path>) -> PathBuf {
+    match temp_dir {
+        None => with_extension_suffix(path, ".part"),
+        Some(dir) => {
+            let mut hasher = DefaultHasher::new();
+            path.hash(&mut hasher);
+            let name = path.file_name().unwrap_or_default().to_string_lossy();
+            dir.join(format!("{}.{:016x}.part", name, hasher.finish()))
+        }
+    }
+}
+
+/// `path` in the `\\?\` form that lifts the 260 character limit of Windows
+/// paths, if it's that long.
+#[cfg(windows)]
+fn long_path(path: PathBuf) -> PathBuf {
+    // the limit of directories is a little below MAX_PATH
+    let text = path.to_string_lossy();
+    if text.len() < 248 || text.starts_with(r"\\?\") {
+        return path;
+    }
+    // the prefix turns off resolving `..` and `/`, so that's done here
+    let Ok(absolute) = std::path::absolute(&path) else {
+        return path;
+    };
+    let absolute = absolute.to_string_lossy();
+    match absolute.strip_prefix(r"\\") {
+        Some(unc) => PathBuf::from(format!(r"\\?\UNC\{}", unc)),
+        None => PathBuf::from(format!(r"\\?\{}", absolute)),
+    }
+}
+
+/// Other platforms don't limit paths this way.
+#[cfg(not(windows))]
+fn long_path(path: PathBuf) -> PathBuf {
+    path
+}
+
+/// Where to write `path` given what is there now, by `policy`: `path` itself
+/// if it's a file or nothing, the file a symlink points to for
+/// [`Conflict::Follow`]. [`Conflict::Replace`] removes what is in the way.
+async fn resolve_conflict(path: PathBuf, policy: Conflict) -> Result<PathBuf, DownloadError> {
+    let conflict = |path: &Path, reason| DownloadError::PathConflict {
+        path: path.to_path_buf(),
+        reason,
+    };
+    let meta = match fs::symlink_metadata(&path).await {
+        Ok(meta) if meta.is_file() => return Ok(path),
+        Ok(meta) => meta,
+        Err(_) => return Ok(path),
+    };
+    match (meta.is_symlink(), policy) {
+        (true, Conflict::Follow) => {
+            let target = fs::read_link(&path)
+                .await
+                .map_err(DownloadError::FailedToCreateFile)?;
+            // relative targets are relative to the link's directory
+            let target = path.parent().unwrap_or(Path::new("")).join(target);
+            match fs::metadata(&target).await {
+                Ok(meta) if meta.is_dir() => Err(conflict(&path, "is a symlink to a directory")),
+                // a dangling link gets its file
+                _ => Ok(target),
+            }
+        }
+        (true, Conflict::Replace) => {
+            fs::remove_file(&path)
+                .await
+                .map_err(DownloadError::FailedToCreateFile)?;
+            Ok(path)
+        }
+        (true, Conflict::Error) => Err(conflict(&path, "is a symlink")),
+        (false, Conflict::Replace) if meta.is_dir() => match fs::remove_dir(&path).await {
+            Ok(()) => Ok(path),
+            Err(_) => Err(conflict(&path, "is a directory that isn't empty")),
+        },
+        (false, _) if meta.is_dir() => Err(conflict(&path, "is a directory")),
+        // sockets, fifos and devices aren't written over
+        (false, _) => Err(conflict(&path, "isn't a regular file")),
+    }
+}
+
+/// Appends `suffix` to the file name, keeping any existing extension.
+fn with_extension_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+/// Moves a finished part file to its destination.
+///
+/// When the temp dir is on another filesystem the file is first copied next to
+/// the destination, so the destination only ever sees a complete file appear.
+async fn move_into_place(io: &IoBackend, part: &Path, dest: &Path, fsync: bool) -> io::Result<()> {
+    match io.rename(part, dest).await {
+        Err(err) if err.kind() == io::ErrorKind::CrossesDevices => {
+            let staged = with_extension_suffix(dest, ".part");
+            let mut copied = PartialFile {
+                path: &staged,
+                complete: false,
+                keep: false,
+            };
+            fs::copy(part, &staged).await?;
+            if fsync {
+                fs::File::open(&staged).await?.sync_all().await?;
+            }
+            fs::rename(&staged, dest).await?;
+            copied.complete = true;
+            fs::remove_file(part).await
+        }
+        result => result,
+    }
+}
+
+/// Writes `chunk` to the part file and the part files of its copies.
+async fn write_out(
+    file: &mut FileSink,
+    copies: &mut [FileSink],
+    chunk: Bytes,
+    reserved: Option<OwnedSemaphorePermit>,
+) -> Result<(), DownloadError> {
+    if chunk.is_empty() {
+        return Ok(());
+    }
+    for copy in copies {
+        copy.write(chunk.clone(), None)
+            .await
+            .map_err(DownloadError::FailedToDownloadToFile)?;
+    }
+    let failed = match file {
+        FileSink::Upload(_) => DownloadError::FailedToUpload,
+        _ => DownloadError::FailedToDownloadToFile,
+    };
+    file.write(chunk, reserved).await.map_err(failed)
+}
+
+/// Syncs, closes and moves a completely written part file to `dest`.
+async fn finish_into_place(
+    io: &IoBackend,
+    mut file: FileSink,
+    part: &Path,
+    dest: &Path,
+    fsync: bool,
+) -> Result<(), DownloadError> {
+    if fsync {
+        file.sync().await.map_err(DownloadError::FailedToSyncFile)?;
+    }
+    file.finish()
+        .await
+        .map_err(DownloadError::FailedToDownloadToFile)?;
+    move_into_place(io, part, dest, fsync)
+        .await
+        .map_err(DownloadError::FailedToMoveFile)
+}
+
+/// Moves `file`, the download of `image` that failed validation for `reason`,
+/// to its path under `dir` and writes a `.reason` file next to it saying so,
+/// with the url and the `content_type` the server sent. Returns where the
+/// file went.
+pub async fn quarantine(
+    dir: &Path,
+    image: &Image,
+    file: &Path,
+    reason: &str,
+    content_type: Option<&str>,
+) -> io::Result<PathBuf> {
+    // absolute paths and .. stay inside the quarantine
+    let relative = Path::new(&image.file_name)
+        .components()
+        .filter(|component| matches!(component, std::path::Component::Normal(_)))
+        .collect::<PathBuf>();
+    let dest = dir.join(relative);
+    if let Some(parent) = dest.parent() {
+        fs::create_dir_all(parent).await?;
+    }
+    match fs::rename(file, &dest).await {
+        Err(err) if err.kind() == io::ErrorKind::CrossesDevices => {
+            fs::copy(file, &dest).await?;
+            fs::remove_file(file).await?;
+        }
+        result => result?,
+    }
+    let mut text = format!(
+        "url: {}\npath: {}\nreason: {}\n",
+        image.url, image.file_name, reason
+    );
+    if let Some(content_type) = content_type {
+        text.push_str(&format!("content-type: {}\n", content_type));
+    }
+    text.push_str(&format!("time: {}\n", Utc::now().to_rfc3339()));
+    fs::write(with_extension_suffix(&dest, ".reason"), text).await?;
+    Ok(dest)
+}
+
+/// SHA-256 of the file at `path` in lowercase hex, as [`Image::sha256`]
+/// expects it.
+pub async fn file_sha256(path: &Path) -> io::Result<String> {
+    let path = path.to_path_buf();
+    tokio::task::spawn_blocking(move || {
+        let mut hasher = Sha256::new();
+        io::copy(&mut std::fs::File::open(path)?, &mut hasher)?;
+        Ok(hex(&hasher.finalize()))
+    })
+    .await?
+}
+
+/// Writes `files` files of `file_size` bytes into `dir` through `backend`,
+/// up to `concurrency` at once, the way downloads are written: to a part
+/// file, preallocated, in 64 KiB chunks and renamed into place. Returns how
+/// long that took; the files are removed again.
+pub async fn benchmark_writes(
+    backend: WriteBackend,
+    dir: &Path,
+    files: usize,
+    file_size: usize,
+    concurrency: usize,
+) -> io::Result<std::time::Duration> {
+    let io = IoBackend::new(backend, crate::Options::default().writer_threads)?;
+    fs::create_dir_all(dir).await?;
+    let chunk = Bytes::from(vec![0xa5u8; file_size.min(64 * 1024)]);
+    let write_one = |index: usize| {
+        let (io, chunk) = (&io, chunk.clone());
+        let part = dir.join(format!("{}.part", index));
+        let dest = dir.join(index.to_string());
+        async move {
+            let mut file = io.create(&part).await?;
+            file.preallocate(file_size as u64).await?;
+            let mut left = file_size;
+            while left > 0 {
+                let len = left.min(chunk.len());
+                file.write(chunk.slice(..len), None).await?;
+                left -= len;
+            }
+            file.finish().await?;
+            io.rename(&part, &dest).await
+        }
+    };
+    let start = Instant::now();
+    let results = futures::stream::iter(0..files)
+        .map(write_one)
+        .buffer_unordered(concurrency.max(1))
+        .collect::<Vec<_>>()
+        .await;
+    let elapsed = start.elapsed();
+    for index in 0..files {
+        let _ = fs::remove_file(dir.join(index.to_string())).await;
+        let _ = fs::remove_file(dir.join(format!("{}.part", index))).await;
+    }
+    results.into_iter().collect::<io::Result<()>>()?;
+    Ok(elapsed)
+}
+
+/// Sends `requests` at once and returns the first response to send body data,
+/// with that data, cancelling the others. Responses with an error status only
+/// win if nothing else succeeds.
+async fn race(
+    client: &reqwest::Client,
+    requests: Vec<reqwest::RequestBuilder>,
+    record_requests: bool,
+    trace: &mut Option<RequestRecord>,
+) -> reqwest::Result<(reqwest::Response, Option<Bytes>)> {
+    let mut racing = requests
+        .into_iter()
+        .map(|request| async move {
+            let mut record = None;
+            let sent = if record_requests {
+                trace::send(client, request, &mut record).await
+            } else {
+                request.send().await
+            };
+            let result = match sent {
+                Ok(mut response) => match response.chunk().await {
+                    Ok(chunk) => Ok((response, chunk)),
+                    Err(err) => Err(err),
+                },
+                Err(err) => Err(err),
+            };
+            (result, record)
+        })
+        .collect::<FuturesUnordered<_>>();
+    let mut fallback = None;
+    let mut last_error = None;
+    while let Some((result, record)) = racing.next().await {
+        match result {
+            Ok((response, chunk))
+                if response.status().is_success()
+                    || response.status() == reqwest::StatusCode::NOT_MODIFIED =>
+            {
+                *trace = record;
+                return Ok((response, chunk));
+            }
+            Ok(lost) => {
+                if fallback.is_none() {
+                    fallback = Some((lost, record));
+                }
+            }
+            Err(err) => {
+                if fallback.is_none() {
+                    *trace = record;
+                }
+                last_error = Some(err);
+            }
+        }
+    }
+    match fallback {
+        Some((response, record)) => {
+            *trace = record;
+            Ok(response)
+        }
+        None => Err(last_error.expect("a request was sent")),
+    }
+}
+
+/// `time` as an HTTP date, e.g. `Tue, 15 Nov 1994 08:12:31 GMT`.
+fn http_date(time: SystemTime) -> String {
+    DateTime::<Utc>::from(time)
+        .format("%a, %d %b %Y %H:%M:%S GMT")
+        .to_string()
+}
+
+/// Whether a HEAD request reports the size `local` the existing file has.
+/// Servers that don't answer with a Content-Length don't confirm it.
+async fn remote_size_matches(
+    client: &reqwest::Client,
+    image: &Image,
+    local: u64,
+) -> Result<bool, DownloadError> {
+    let mut request = client.head(&image.url);
+    for (name, value) in &image.headers {
+        request = request.header(name, value);
+    }
+    let response = request
+        .send()
+        .await
+        .map_err(DownloadError::FailedToGetUrl)?;
+    Ok(response.status().is_success()
+        && response
+            .headers()
+            .get(reqwest::header::CONTENT_LENGTH)
+            .and_then(|len| len.to_str().ok()?.parse::<u64>().ok())
+            == Some(local))
+}
+
+/// Checks a part file that was put together without streaming a response
+/// through [`download_image`], from a delta or the cache, and moves it to
+/// `path` like a download.
+#[allow(clippy::too_many_arguments)]
+async fn install(
+    image: &Image,
+    options: &Options,
+    io: &IoBackend,
+    part: &Path,
+    path: &Path,
+    length: u64,
+    mtime: Option<SystemTime>,
+    etag: Option<&str>,
+) -> Result<(), DownloadError> {
+    let mut mismatch =
+        image
+            .size
+            .filter(|&size| size != length)
+            .map(|expected| DownloadError::SizeMismatch {
+                expected,
+                actual: length,
+            });
+    if let Some(expected) = image.sha256.as_ref().filter(|_| !options.defers_sha256()) {
+        let actual = file_sha256(part)
+            .await
+            .map_err(DownloadError::FailedToDownloadToFile)?;
+        if &actual != expected && mismatch.is_none() {
+            mismatch = Some(DownloadError::ChecksumMismatch {
+                expected: expected.clone(),
+                actual,
+            });
+        }
+    }
+    if let Some(expected) = image.sha256_tree.as_ref().filter(|_| mismatch.is_none()) {
+        let actual = tree_hash::file_sha256_tree(part)
+            .await
+            .map_err(DownloadError::FailedToDownloadToFile)?;
+        if &actual != expected {
+            mismatch = Some(DownloadError::ChecksumMismatch {
+                expected: expected.clone(),
+                actual,
+            });
+        }
+    }
+    if let Some(err) = mismatch {
+        if let Some(dir) = &options.quarantine {
+            let _ = quarantine(dir, image, part, &err.to_string(), None).await;
+        }
+        return Err(err);
+    }
+    if options.fsync {
+        let file = fs::File::open(part)
+            .await
+            .map_err(DownloadError::FailedToSyncFile)?;
+        file.sync_all()
+            .await
+            .map_err(DownloadError::FailedToSyncFile)?;
+    }
+    move_into_place(io, part, path, options.fsync)
+        .await
+        .map_err(DownloadError::FailedToMoveFile)?;
+    perms::apply(path, options.chmod, options.chown)
+        .await
+        .map_err(DownloadError::FailedToSetPermissions)?;
+    if let Some(mtime) = mtime.filter(|_| options.timestamping) {
+        let _ = std::fs::File::options()
+            .write(true)
+            .open(path)
+            .and_then(|file| file.set_modified(mtime));
+    }
+    if options.xattr {
+        for (name, value) in xattr::origin(&image.url, etag, None) {
+            let _ = xattr::set(path, name, &value);
+        }
+    }
+    if options.fsync {
+        if let Some(parent) = path.parent() {
+            sync_dir(parent)
+                .await
+                .map_err(DownloadError::FailedToSyncFile)?;
+        }
+    }
+    Ok(())
+}
+
+/// Installs a copy of `source`, the file of the same url kept in the cache or
+/// downloaded by another item, at `path` instead of downloading it. Returns
+/// its size.
+#[allow(clippy::too_many_arguments)]
+async fn install_copy(
+    image: &Image,
+    options: &Options,
+    io: &IoBackend,
+    control: &Control,
+    part: &Path,
+    path: &Path,
+    source: &Path,
+    mtime: Option<SystemTime>,
+    etag: Option<&str>,
+) -> Result<u64, DownloadError> {
+    for dir in [part, path].into_iter().filter_map(Path::parent) {
+        control
+            .dirs
+            .create(dir, options.dir_chmod, options.chown)
+            .await
+            .map_err(DownloadError::FailedToCreateParentDirectory)?;
+    }
+    let mut partial = PartialFile {
+        path: part,
+        complete: false,
+        keep: false,
+    };
+    let length = fs::copy(source, part)
+        .await
+        .map_err(DownloadError::FailedToDownloadToFile)?;
+    install(image, options, io, part, path, length, mtime, etag).await?;
+    partial.complete = true;
+    Ok(length)
+}
+
+/// Downloads a single image, calling `on_chunk` with the size of every chunk
+/// received, the expected total size when the server sent one and whether
+/// the bytes were copied from a local file, like the cache, instead of read
+/// from a response. While `control` is paused no more of the body is read.
+pub(crate) async fn download_image(
+    image: &Image,
+    options: &Options,
+    client: &reqwest::Client,
+    io: &IoBackend,
+    control: &Control,
+    trace: &mut Option<RequestRecord>,
+    mut on_chunk: impl FnMut(usize, Option<u64>, bool),
+) -> DownloadResult {
+    let path = options.output_path(&image.file_name);
+    // an upload only goes to the one object
+    let copies = match &options.s3 {
+        Some(_) => Vec::new(),
+        None => image
+            .copies
+            .iter()
+            .map(|copy| long_path(options.output_path(copy)))
+            .collect::<Vec<_>>(),
+    };
+    let upload = options.s3.as_ref().map(|dest| (dest, dest.key(&path)));
+    let path = long_path(path);
+    let (path, copies) = match upload {
+        Some(_) => (path, copies),
+        None => {
+            let mut resolved = Vec::with_capacity(copies.len());
+            for copy in copies {
+                resolved.push(resolve_conflict(copy, options.on_conflict).await?);
+            }
+            (resolve_conflict(path, options.on_conflict).await?, resolved)
+        }
+    };
+    if let (Some((dest, key)), false) = (&upload, options.force_redownload) {
+        let size = dest
+            .size(client, key)
+            .await
+            .map_err(DownloadError::FailedToUpload)?;
+        // the object's checksum isn't known, and its size only without
+        // compression
+        if size.is_some_and(|size| {
+            options.compress.is_some() || image.size.is_none_or(|expected| expected == size)
+        }) {
+            return Ok(DownloadCompleted::Skipped);
+        }
+    }
+    // the request headers that revalidate an existing file
+    let mut conditional = Vec::new();
+    let validators = options.validators.as_deref().filter(|_| upload.is_none());
+    let refresh = validators.is_some() && options.force_refresh;
+    let mut exists = upload.is_none();
+    for dest in std::iter::once(&path).chain(&copies) {
+        exists &= fs::try_exists(dest).await.unwrap_or(false);
+    }
+    if !options.force_redownload && !refresh && exists {
+        let unchecked =
+            image.size.is_none() && image.sha256.is_none() && image.sha256_tree.is_none();
+        // what a compressed file holds, only worked out when it's compared
+        let decompressed = match options.compress {
+            Some(compression) if !unchecked || options.skip_if_size_matches => {
+                compress::decompressed_digest(&path, compression, image.sha256_tree.is_some())
+                    .await
+                    .ok()
+            }
+            _ => None,
+        };
+        // a file that can't be read is replaced like one that doesn't match
+        let size_matches = match (image.size, &decompressed, options.compress) {
+            (None, _, _) => true,
+            (Some(size), Some((actual, _, _)), _) => *actual == size,
+            (Some(_), None, Some(_)) => false,
+            (Some(size), None, None) => fs::metadata(&path)
+                .await
+                .is_ok_and(|meta| meta.len() == size),
+        };
+        let sha256_matches = match (&image.sha256, &decompressed, options.compress) {
+            (None, _, _) => true,
+            (Some(expected), Some((_, actual, _)), _) => actual == expected,
+            (Some(_), None, Some(_)) => false,
+            (Some(expected), None, None) => {
+                size_matches && file_sha256(&path).await.ok().as_ref() == Some(expected)
+            }
+        };
+        let sha256_matches = sha256_matches
+            && match (&image.sha256_tree, &decompressed, options.compress) {
+                (None, _, _) => true,
+                (Some(expected), Some((_, _, actual)), _) => actual.as_ref() == Some(expected),
+                (Some(_), None, Some(_)) => false,
+                (Some(expected), None, None) => {
+                    size_matches
+                        && tree_hash::file_sha256_tree(&path).await.ok().as_ref() == Some(expected)
+                }
+            };
+        // without size= or sha256= the server says how big the file should be
+        let size_matches = match options.skip_if_size_matches && unchecked {
+            true => {
+                let local = match (&decompressed, options.compress) {
+                    (Some((size, _, _)), _) => Some(*size),
+                    (None, Some(_)) => None,
+                    (None, None) => fs::metadata(&path).await.ok().map(|meta| meta.len()),
+                };
+                match (local, &options.replay) {
+                    (Some(local), Some(dir)) => {
+                        fixtures::recorded_size(dir, &image.url)
+                            .await
+                            .map_err(DownloadError::FailedToReplay)?
+                            == Some(local)
+                    }
+                    (Some(local), None) => remote_size_matches(client, image, local).await?,
+                    (None, _) => false,
+                }
+            }
+            false => size_matches,
+        };
+        if size_matches && sha256_matches {
+            if !(options.timestamping || validators.is_some())
+                || image.sha256.is_some()
+                || image.sha256_tree.is_some()
+            {
+                return Ok(DownloadCompleted::Skipped);
+            }
+            let meta = fs::metadata(&path).await.ok();
+            let saved = match validators {
+                Some(dir) => validators::load(dir, &image.url, &path).await,
+                None => None,
+            };
+            match saved {
+                // a file changed since its download is downloaded again
+                Some(saved) if Some(saved.size) == meta.as_ref().map(|meta| meta.len()) => {
+                    conditional = saved.headers();
+                }
+                Some(_) => {}
+                None => {
+                    if let Some(modified) = meta.and_then(|meta| meta.modified().ok()) {
+                        conditional.push((reqwest::header::IF_MODIFIED_SINCE, http_date(modified)));
+                    }
+                }
+            }
+        }
+    }
+    let part = long_path(part_path(&path, options.temp_dir.as_deref()));
+    let copy_parts = copies
+        .iter()
+        .map(|copy| long_path(part_path(copy, options.temp_dir.as_deref())))
+        .collect::<Vec<_>>();
+    // the copies would miss the part written before, and a compressed part
+    // ends mid-frame
+    let resumes =
+        options.resume && copies.is_empty() && options.compress.is_none() && upload.is_none();
+    // where an interrupted download stopped, and what the server said then
+    let mut resume = match resumes {
+        true => resume_point(&part).await,
+        false => None,
+    };
+    let delta = options.zsync
+        && resume.is_none()
+        && conditional.is_empty()
+        && upload.is_none()
+        && copies.is_empty()
+        && options.compress.is_none()
+        && options.replay.is_none();
+    if delta && fs::metadata(&path).await.is_ok_and(|meta| meta.is_file()) {
+        if let Some(parent) = part.parent() {
+            control
+                .dirs
+                .create(parent, options.dir_chmod, options.chown)
+                .await
+                .map_err(DownloadError::FailedToCreateParentDirectory)?;
+        }
+        let mut partial = PartialFile {
+            path: &part,
+            complete: false,
+            keep: false,
+        };
+        // anything that goes wrong costs the whole file, not the download
+        let on_range = |bytes, total| on_chunk(bytes, total, false);
+        if let Ok(Some(delta)) = zsync::fetch(client, image, &path, &part, on_range).await {
+            let mtime = delta
+                .mtime
+                .map(|mtime| SystemTime::UNIX_EPOCH + Duration::from_secs(mtime as u64));
+            install(image, options, io, &part, &path, delta.length, mtime, None).await?;
+            partial.complete = true;
+            return Ok(DownloadCompleted::Success);
+        }
+    }
+    // a url fetched before, maybe for another url file
+    let cache = control.http_cache.as_deref().filter(|_| {
+        resume.is_none()
+            && conditional.is_empty()
+            && upload.is_none()
+            && copies.is_empty()
+            && options.compress.is_none()
+            && options.replay.is_none()
+            && options.record.is_none()
+            && control.warc.is_none()
+    });
+    let mut cached = match cache {
+        Some(cache) => cache.get(&image.url).await,
+        None => None,
+    };
+    // a kept body of the wrong size is outdated, whatever it says
+    cached = cached.filter(|(entry, _)| image.size.is_none_or(|size| size == entry.size));
+    if let (Some(cache), Some((entry, body))) = (cache, &cached) {
+        if entry.is_fresh() {
+            let (mtime, etag) = (entry.modified(), entry.etag.as_deref());
+            install_copy(image, options, io, control, &part, &path, body, mtime, etag).await?;
+            on_chunk(entry.size as usize, Some(entry.size), true);
+            cache.touch(&image.url);
+            return Ok(DownloadCompleted::Success);
+        }
+    }
+    let mut dedup = options.dedup
+        && upload.is_none()
+        && copies.is_empty()
+        && options.compress.is_none()
+        && options.replay.is_none();
+    let mut reading = None;
+    // the url after Google Drive's confirm form, which can't be asked again
+    let mut url = image.url.clone();
+    let mut confirmed = false;
+    let (mut response, mut first_chunk) = loop {
+        let request = |url: &str| {
+            let mut request = client.get(url);
+            for (name, value) in &image.headers {
+                request = request.header(name, value);
+            }
+            match &resume {
+                // only continues the part file if it is still the same file
+                Some((offset, saved)) => {
+                    request = request.header(reqwest::header::RANGE, format!("bytes={}-", offset));
+                    if let Some(validator) = saved.if_range() {
+                        request = request.header(reqwest::header::IF_RANGE, validator);
+                    }
+                }
+                None => {
+                    for (name, value) in &conditional {
+                        request = request.header(name, value);
+                    }
+                    for (name, value) in cached.iter().flat_map(|(entry, _)| entry.headers()) {
+                        request = request.header(name, value);
+                    }
+                }
+            }
+            request
+        };
+        let urls = std::iter::once(&url)
+            .chain(&image.mirrors)
+            .take(options.race_mirrors.max(1))
+            .map(|url| request(url))
+            .collect::<Vec<_>>();
+        // the body data that won the race, before the rest of the response
+        let (response, first_chunk) = if let Some(dir) = &options.replay {
+            let response = fixtures::replay(dir, &image.url)
+                .await
+                .map_err(DownloadError::FailedToReplay)?;
+            (response, None)
+        } else if urls.len() > 1 {
+            race(client, urls, options.record_requests, trace)
+                .await
+                .map_err(DownloadError::FailedToGetUrl)?
+        } else {
+            let request = urls.into_iter().next().expect("one url");
+            let sent = if options.record_requests {
+                trace::send(client, request, trace).await
+            } else {
+                request.send().await
+            };
+            (sent.map_err(DownloadError::FailedToGetUrl)?, None)
+        };
+        if options.share_links && share::is_warning(&response) {
+            if confirmed {
+                return Err(DownloadError::SharePage);
+            }
+            url = share::confirm_url(response)
+                .await
+                .ok_or(DownloadError::SharePage)?;
+            if let Some(Err(reason)) = options.url_policy.as_ref().map(|p| p.check(&url)) {
+                return Err(DownloadError::Blocked { url, reason });
+            }
+            confirmed = true;
+            continue;
+        }
+        // another item reading the same url, maybe after other redirects, is
+        // waited for instead of read along
+        if dedup && response.status() == reqwest::StatusCode::OK {
+            match control.in_flight.claim(response.url().as_str()) {
+                Claim::Reading(claim) => reading = Some(claim),
+                Claim::Waiting(waiting) => {
+                    let header = |name| {
+                        response
+                            .headers()
+                            .get(name)
+                            .and_then(|value: &reqwest::header::HeaderValue| value.to_str().ok())
+                    };
+                    let mtime = header(reqwest::header::LAST_MODIFIED)
+                        .and_then(|value| DateTime::parse_from_rfc2822(value).ok())
+                        .map(SystemTime::from);
+                    let etag = header(reqwest::header::ETAG).map(str::to_string);
+                    drop(response);
+                    if let Some(source) = landed(waiting).await {
+                        let (part, path) = (&part, &path);
+                        let etag = etag.as_deref();
+                        let length = install_copy(
+                            image, options, io, control, part, path, &source, mtime, etag,
+                        )
+                        .await?;
+                        on_chunk(length as usize, Some(length), true);
+                        return Ok(DownloadCompleted::Success);
+                    }
+                    // it failed, this one may still get the file
+                    dedup = false;
+                    continue;
+                }
+            }
+        }
+        match &resume {
+            // a range of something else than the part file has, start over
+            Some((offset, saved))
+                if response.status() == reqwest::StatusCode::PARTIAL_CONTENT
+                    && !continues(&response, *offset, saved) =>
+            {
+                resume = None;
+            }
+            _ => break (response, first_chunk),
+        }
+    };
+    // a full response means the file changed, or the server ignored the range
+    let resume = resume.filter(|(offset, saved)| continues(&response, *offset, saved));
+    let resumed_from = resume.as_ref().map(|(offset, _)| *offset);
+    let conditional = match resumed_from {
+        Some(_) => Vec::new(),
+        None => conditional,
+    };
+    let headers_received = Instant::now();
+    if !conditional.is_empty() && response.status() == reqwest::StatusCode::NOT_MODIFIED {
+        return Ok(DownloadCompleted::Skipped);
+    }
+    if let (Some(cache), Some((mut entry, body))) = (cache, cached) {
+        if response.status() == reqwest::StatusCode::NOT_MODIFIED {
+            let (mtime, etag) = (entry.modified(), entry.etag.as_deref());
+            install_copy(
+                image, options, io, control, &part, &path, &body, mtime, etag,
+            )
+            .await?;
+            on_chunk(entry.size as usize, Some(entry.size), true);
+            entry.revalidated(&response);
+            // without it the body is only revalidated again next time
+            let _ = cache.update(&entry).await;
+            cache.touch(&image.url);
+            return Ok(DownloadCompleted::Success);
+        }
+    }
+    // a 206 is 2xx too, and a 304 to a conditional request was handled above
+    if !response.status().is_success() {
+        return Err(DownloadError::HttpStatus(response.status()));
+    }
+    let header = |name| {
+        response
+            .headers()
+            .get(name)
+            .and_then(|value: &reqwest::header::HeaderValue| value.to_str().ok())
+    };
+    let content_type = header(reqwest::header::CONTENT_TYPE).map(str::to_string);
+    let origin = (options.xattr && upload.is_none()).then(|| {
+        xattr::origin(
+            &image.url,
+            header(reqwest::header::ETAG),
+            header(reqwest::header::CONTENT_TYPE),
+        )
+    });
+    let last_modified = response
+        .headers()
+        .get(reqwest::header::LAST_MODIFIED)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| DateTime::parse_from_rfc2822(value).ok())
+        .filter(|_| options.timestamping && upload.is_none());
+    let mut revalidate_with = validators.and_then(|_| Validators::of(&image.url, &response, 0));
+    let cacheable = cache.and_then(|_| Cached::of(&image.url, &response));
+    let dests = std::iter::once(&path).chain(&copies);
+    for dir in dests
+        .chain(&copy_parts)
+        .filter_map(|dest| dest.parent())
+        .filter(|_| upload.is_none())
+    {
+        control
+            .dirs
+            .create(dir, options.dir_chmod, options.chown)
+            .await
+            .map_err(DownloadError::FailedToCreateParentDirectory)?;
+    }
+    let mut file = match (&upload, resumed_from) {
+        (Some((dest, key)), _) => Ok(FileSink::Upload(dest.upload(client, key.clone()))),
+        (None, Some(offset)) => io.open_at(&part, offset).await,
+        (None, None) => io.create(&part).await,
+    }
+    .map_err(DownloadError::FailedToCreateFile)?;
+    // nothing is on disk while uploading
+    let mut partial = upload.is_none().then_some(PartialFile {
+        path: &part,
+        complete: false,
+        keep: false,
+    });
+    let mut copy_files = Vec::new();
+    let mut copy_partials = Vec::new();
+    for copy_part in &copy_parts {
+        copy_files.push(
+            io.create(copy_part)
+                .await
+                .map_err(DownloadError::FailedToCreateFile)?,
+        );
+        copy_partials.push(PartialFile {
+            path: copy_part,
+            complete: false,
+            keep: false,
+        });
+    }
+    // what the part file can be resumed with if the download is cut off
+    let resumable = match (resumes, resumed_from) {
+        (false, _) => None,
+        (true, Some(_)) => resume.map(|(_, saved)| saved),
+        (true, None) => save_resume_point(&part, &image.url, &response).await,
+    };
+    // the request that was sent, as far as it was recorded
+    let sent_headers = trace
+        .as_ref()
+        .map_or(&image.headers, |record| &record.request_headers);
+    let mut capture = match &control.warc {
+        Some(archive) => Some(
+            warc::Capture::start(archive, sent_headers, &response)
+                .await
+                .map_err(DownloadError::FailedToArchive)?,
+        ),
+        None => None,
+    };
+    let mut recording = match &options.record {
+        Some(dir) => Some(
+            Recording::start(dir, &image.url, &response)
+                .await
+                .map_err(DownloadError::FailedToRecord)?,
+        ),
+        None => None,
+    };
+    let offset = resumed_from.unwrap_or(0);
+    let content_length = response.content_length().map(|len| len + offset);
+    // a compressed file is smaller than the body, and the length of a part
+    // that may be resumed is how far it got
+    let preallocate = options.compress.is_none() && resumable.is_none();
+    if let Some(len) = content_length.filter(|_| preallocate) {
+        for file in std::iter::once(&mut file).chain(&mut copy_files) {
+            file.preallocate(len)
+                .await
+                .map_err(DownloadError::FailedToPreallocateFile)?;
+        }
+    }
+    let expected_sha256 = image.sha256.as_ref().filter(|_| !options.defers_sha256());
+    let mut hasher = match (expected_sha256, resumed_from) {
+        (None, _) => None,
+        (Some(_), None) => Some(Sha256::new()),
+        // the checksum covers the part written before too
+        (Some(_), Some(offset)) => Some(
+            hash_prefix(&part, offset)
+                .await
+                .map_err(DownloadError::FailedToDownloadToFile)?,
+        ),
+    };
+    let mut tree = match (&image.sha256_tree, resumed_from) {
+        (None, _) => None,
+        (Some(_), None) => Some(TreeHasher::new()),
+        (Some(_), Some(offset)) => Some(
+            TreeHasher::of_prefix(&part, offset)
+                .await
+                .map_err(DownloadError::FailedToDownloadToFile)?,
+        ),
+    };
+    let mut compressor = options
+        .compress
+        .map(Compressor::new)
+        .transpose()
+        .map_err(DownloadError::FailedToDownloadToFile)?;
+    let mut written = offset;
+    // what went into the file, less than `written` when compressing
+    let mut stored = offset;
+    // the start of the body, to tell an error page from the file
+    let mut head = Vec::new();
+    let sniff = options.reject_error_pages && offset == 0;
+    let head_len = match &options.soft_404 {
+        Some(_) if offset == 0 => sniff::SIGNATURE_LEN,
+        _ if sniff => sniff::HEAD_LEN,
+        _ => 0,
+    };
+    // only error pages, which are small, are hashed to compare with known ones
+    let mut page_hasher = (!options.soft_404_sha256.is_empty() && offset == 0).then(Sha256::new);
+    loop {
+        control.wait_while_paused().await;
+        let chunk = match first_chunk.take() {
+            Some(chunk) => Ok(Some(chunk)),
+            None => response.chunk().await,
+        };
+        let chunk = match chunk {
+            Ok(Some(chunk)) => chunk,
+            Ok(None) => break,
+            Err(err) => {
+                if let Some(trace) = trace {
+                    trace.error = Some(err.to_string());
+                    trace.timings.transfer = Some(headers_received.elapsed());
+                }
+                if let (Some(partial), Some(_)) = (&mut partial, &resumable) {
+                    partial.keep = keep_part(file, &part, written).await.is_ok();
+                }
+                return Err(DownloadError::FailedToConvertResponseToBytes(err));
+            }
+        };
+        if let Some(trace) = trace {
+            trace.bytes += chunk.len() as u64;
+        }
+        on_chunk(chunk.len(), content_length, false);
+        if let Some(host) = &control.host {
+            host.pace(chunk.len()).await;
+        }
+        if let Some(rate) = &control.rate {
+            rate.pace(chunk.len()).await;
+        }
+        if head.len() < head_len {
+            let take = chunk.len().min(head_len - head.len());
+            head.extend_from_slice(&chunk[..take]);
+        }
+        if let Some(page_hasher) = &mut page_hasher {
+            page_hasher.update(&chunk);
+        }
+        if written + chunk.len() as u64 > sniff::PAGE_MAX_SIZE {
+            page_hasher = None;
+        }
+        if let Some(tree) = &mut tree {
+            tree.update(&chunk)
+                .await
+                .map_err(DownloadError::FailedToDownloadToFile)?;
+        }
+        if let Some(hasher) = &mut hasher {
+            hasher.update(&chunk);
+        }
+        if let Some(capture) = &mut capture {
+            capture
+                .write(&chunk)
+                .await
+                .map_err(DownloadError::FailedToArchive)?;
+        }
+        if let Some(recording) = &mut recording {
+            recording
+                .write(&chunk)
+                .await
+                .map_err(DownloadError::FailedToRecord)?;
+        }
+        written += chunk.len() as u64;
+        let chunk = match &mut compressor {
+            Some(compressor) => compressor
+                .compress(&chunk)
+                .map_err(DownloadError::FailedToDownloadToFile)?,
+            None => chunk,
+        };
+        stored += chunk.len() as u64;
+        // held until the chunk is written out, which stalls reading when the
+        // disk falls behind
+        let reserved = control.memory.reserve(chunk.len()).await;
+        write_out(&mut file, &mut copy_files, chunk, reserved).await?;
+    }
+    if let Some(compressor) = compressor {
+        let rest = compressor
+            .finish()
+            .map_err(DownloadError::FailedToDownloadToFile)?;
+        stored += rest.len() as u64;
+        write_out(&mut file, &mut copy_files, rest, None).await?;
+    }
+    if let Some(trace) = trace {
+        trace.timings.transfer = Some(headers_received.elapsed());
+    }
+    // what the server sent is archived even if it's not what was expected
+    if let Some(capture) = capture {
+        capture
+            .finish()
+            .await
+            .map_err(DownloadError::FailedToArchive)?;
+    }
+    if let Some(recording) = recording {
+        recording
+            .finish()
+            .await
+            .map_err(DownloadError::FailedToRecord)?;
+    }
+    let error_page = sniff
+        && sniff::error_page(
+            &image.file_name,
+            content_type.as_deref(),
+            &head[..head.len().min(sniff::HEAD_LEN)],
+            written,
+        );
+    let soft_404 = options
+        .soft_404
+        .as_ref()
+        .is_some_and(|signature| offset == 0 && signature.is_match(&head))
+        || page_hasher.is_some_and(|page_hasher| {
+            let page = hex(&page_hasher.finalize());
+            options.soft_404_sha256.iter().any(|known| known == &page)
+        });
+    let tree = match tree {
+        Some(tree) => Some(
+            tree.finish()
+                .await
+                .map_err(DownloadError::FailedToDownloadToFile)?,
+        ),
+        None => None,
+    };
+    let mismatch = match (image.size, hasher, &image.sha256) {
+        _ if soft_404 => Some(DownloadError::SoftNotFound),
+        _ if error_page => Some(DownloadError::ErrorPage {
+            content_type: content_type.clone(),
+        }),
+        (Some(expected), _, _) if expected != written => Some(DownloadError::SizeMismatch {
+            expected,
+            actual: written,
+        }),
+        (_, Some(hasher), Some(expected)) => {
+            let actual = hex(&hasher.finalize());
+            (&actual != expected).then(|| DownloadError::ChecksumMismatch {
+                expected: expected.clone(),
+                actual,
+            })
+        }
+        _ => None,
+    }
+    .or_else(|| match (tree, &image.sha256_tree) {
+        (Some(actual), Some(expected)) if &actual != expected => {
+            Some(DownloadError::ChecksumMismatch {
+                expected: expected.clone(),
+                actual,
+            })
+        }
+        _ => None,
+    });
+    if let Some(err) = mismatch {
+        if let (Some(dir), false) = (&options.quarantine, matches!(file, FileSink::Upload(_))) {
+            // what was written so far may still be buffered
+            if file.finish().await.is_ok() {
+                let reason = err.to_string();
+                let _ = quarantine(dir, image, &part, &reason, content_type.as_deref()).await;
+            }
+        }
+        return Err(err);
+    }
+    for (copy, (copy_part, dest)) in copy_files.into_iter().zip(copy_parts.iter().zip(&copies)) {
+        finish_into_place(io, copy, copy_part, dest, options.fsync).await?;
+    }
+    match file {
+        FileSink::Upload(upload) => upload
+            .finish()
+            .await
+            .map_err(DownloadError::FailedToUpload)?,
+        file => finish_into_place(io, file, &part, &path, options.fsync).await?,
+    }
+    for partial in partial.iter_mut().chain(&mut copy_partials) {
+        partial.complete = true;
+    }
+    if resumable.is_some() {
+        let _ = fs::remove_file(resume_path(&part)).await;
+    }
+    if (options.chmod.is_some() || options.chown.is_some()) && upload.is_none() {
+        for dest in std::iter::once(&path).chain(&copies) {
+            perms::apply(dest, options.chmod, options.chown)
+                .await
+                .map_err(DownloadError::FailedToSetPermissions)?;
+        }
+    }
+    if let Some(last_modified) = last_modified {
+        // a wrong mtime only costs a needless download next time
+        for dest in std::iter::once(&path).chain(&copies) {
+            let _ = std::fs::File::options()
+                .write(true)
+                .open(dest)
+                .and_then(|file| file.set_modified(last_modified.into()));
+        }
+    }
+    if let Some(origin) = &origin {
+        // like the mtime, provenance isn't worth failing a complete file over
+        for dest in std::iter::once(&path).chain(&copies) {
+            for (name, value) in origin {
+                let _ = xattr::set(dest, name, value);
+            }
+        }
+    }
+    if let (Some(dir), Some(saved)) = (validators, &mut revalidate_with) {
+        saved.size = stored;
+        // without them the file is only downloaded again next time
+        let _ = validators::store(dir, &image.url, &path, saved).await;
+    }
+    if let (Some(cache), Some(cacheable)) = (cache, cacheable) {
+        // like the validators, the cache only saves a later download
+        let _ = cache.store(cacheable, &path).await;
+    }
+    if options.fsync && upload.is_none() {
+        for parent in std::iter::once(&path)
+            .chain(&copies)
+            .filter_map(|dest| dest.parent())
+        {
+            sync_dir(parent)
+                .await
+                .map_err(DownloadError::FailedToSyncFile)?;
+        }
+    }
+    if let Some(reading) = &mut reading {
+        reading.landed = Some(path.clone());
+    }
+    Ok(DownloadCompleted::Success)
+}
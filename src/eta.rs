@@ -0,0 +1,142 @@
+//! The time a run has left, weighted by bytes rather than files, for the
+//! `{eta}` of the progress bar.
+//!
+//! Items count with their `size=` or the Content-Length their server sent, and
+//! items of unknown size with the average size of the files downloaded so far.
+//! What is left is divided by the throughput of the last half minute.
+
+use fast_download::Image;
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// How far back the throughput is measured.
+const WINDOW: Duration = Duration::from_secs(30);
+
+/// Shared between the run, which reports the items, and the progress bar.
+#[derive(Clone, Default)]
+pub struct Eta(Arc<Mutex<Model>>);
+
+#[derive(Default)]
+struct Model {
+    /// The queued items of known size and their bytes.
+    queued_known: usize,
+    queued_bytes: u64,
+    queued_unknown: usize,
+    /// The sizes of the running items count once known.
+    active: HashMap<usize, Item>,
+    downloaded: usize,
+    downloaded_bytes: u64,
+    /// Bytes received and when, over the last [`WINDOW`].
+    samples: VecDeque<(Instant, u64)>,
+}
+
+struct Item {
+    expected: Option<u64>,
+    received: u64,
+}
+
+impl Eta {
+    pub fn queued(&self, image: &Image) {
+        let mut model = self.0.lock().unwrap();
+        match image.size {
+            Some(size) => {
+                model.queued_known += 1;
+                model.queued_bytes += size;
+            }
+            None => model.queued_unknown += 1,
+        }
+    }
+
+    pub fn started(&self, id: usize, image: &Image) {
+        let mut model = self.0.lock().unwrap();
+        match image.size {
+            Some(size) => {
+                model.queued_known = model.queued_known.saturating_sub(1);
+                model.queued_bytes = model.queued_bytes.saturating_sub(size);
+            }
+            None => model.queued_unknown = model.queued_unknown.saturating_sub(1),
+        }
+        model.active.insert(
+            id,
+            Item {
+                expected: image.size,
+                received: 0,
+            },
+        );
+    }
+
+    /// `total` is the size of the whole file, when the server sent it.
+    pub fn received(&self, id: usize, bytes: usize, total: Option<u64>) {
+        let mut model = self.0.lock().unwrap();
+        if let Some(item) = model.active.get_mut(&id) {
+            item.received += bytes as u64;
+            item.expected = item.expected.or(total);
+        }
+        let now = Instant::now();
+        model.samples.push_back((now, bytes as u64));
+        while model
+            .samples
+            .front()
+            .is_some_and(|(at, _)| now.duration_since(*at) > WINDOW)
+        {
+            model.samples.pop_front();
+        }
+    }
+
+    /// Only downloaded items tell what the unknown ones weigh, skipped and
+    /// failed ones cost next to nothing.
+    pub fn finished(&self, id: usize, downloaded: bool) {
+        let mut model = self.0.lock().unwrap();
+        if let Some(item) = model.active.remove(&id).filter(|_| downloaded) {
+            model.downloaded += 1;
+            model.downloaded_bytes += item.received;
+        }
+    }
+
+    /// `None` without a throughput over the last [`WINDOW`], or anything to
+    /// guess the size of unknown items from.
+    pub fn left(&self) -> Option<Duration> {
+        self.0.lock().unwrap().left()
+    }
+}
+
+impl Model {
+    fn left(&self) -> Option<Duration> {
+        // a transfer that stalled for the whole window has no throughput
+        let now = Instant::now();
+        let recent = self
+            .samples
+            .iter()
+            .filter(|(at, _)| now.duration_since(*at) <= WINDOW);
+        let (first, _) = recent.clone().next()?;
+        let bytes = recent.map(|(_, bytes)| bytes).sum::<u64>();
+        let rate = bytes as f64
+            / now
+                .duration_since(*first)
+                .max(Duration::from_secs(1))
+                .as_secs_f64();
+        let average = match self.downloaded {
+            // the known sizes are the best guess until a file completes
+            0 => {
+                let known = self.active.values().filter_map(|item| item.expected);
+                let (count, total) = known.fold(
+                    (self.queued_known as u64, self.queued_bytes),
+                    |(count, total), size| (count + 1, total + size),
+                );
+                (count > 0).then(|| total / count)
+            }
+            downloaded => Some(self.downloaded_bytes / downloaded as u64),
+        };
+        let mut left = self.queued_bytes;
+        for item in self.active.values() {
+            left += item.expected.or(average)?.saturating_sub(item.received);
+        }
+        if self.queued_unknown > 0 {
+            left += self.queued_unknown as u64 * average?;
+        }
+        (rate > 0.0).then(|| Duration::from_secs_f64(left as f64 / rate))
+    }
+}
@@ -0,0 +1,268 @@
+//! `fast_download expand <url_template>`: writes the url file lines of every
+//! combination of variables, instead of generating them with a shell script.
+//!
+//! `{name}` in the url and path templates is replaced by the variable `name`.
+//! Variables come in tables: a CSV file with a header row, a JSON array of
+//! objects, or an inline `--var name=a,b,c`. The lines are the cross product
+//! of the rows of all tables, in order, the first table varying slowest.
+
+use crate::urls;
+use percent_encoding::{utf8_percent_encode, AsciiSet, CONTROLS};
+use serde_json::Value;
+use std::{
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    path::Path,
+};
+
+/// What a value can't hold unescaped in the url column of a url file line.
+const URL_UNSAFE: &AsciiSet = &CONTROLS.add(b' ');
+
+/// Columns and rows of variables.
+#[derive(Debug)]
+pub struct Table {
+    columns: Vec<String>,
+    rows: Vec<Vec<String>>,
+}
+
+impl Table {
+    /// Reads a `.json` file as an array of objects and anything else as CSV.
+    pub fn load(path: &Path) -> Result<Table, String> {
+        let text = fs::read_to_string(path)
+            .map_err(|err| format!("failed to read {}: {}", path.display(), err))?;
+        let table = match path.extension().is_some_and(|ext| ext == "json") {
+            true => from_json(&text),
+            false => from_csv(&text),
+        };
+        table.map_err(|err| format!("{}: {}", path.display(), err))
+    }
+
+    /// Parses `name=a,b,c`, one column with a row per value.
+    pub fn inline(spec: &str) -> Option<Table> {
+        let (name, values) = spec.split_once('=')?;
+        (!name.is_empty()).then(|| Table {
+            columns: vec![name.to_string()],
+            rows: values
+                .split(',')
+                .map(|value| vec![value.to_string()])
+                .collect(),
+        })
+    }
+}
+
+fn from_csv(text: &str) -> Result<Table, String> {
+    let mut records = csv_records(text)?.into_iter();
+    let columns = records.next().ok_or("no header row")?;
+    let rows = records
+        .enumerate()
+        .map(|(number, row)| match row.len() == columns.len() {
+            true => Ok(row),
+            false => Err(format!(
+                "row {} has {} fields, the header {}",
+                number + 2,
+                row.len(),
+                columns.len()
+            )),
+        })
+        .collect::<Result<_, _>>()?;
+    Ok(Table { columns, rows })
+}
+
+/// The records of RFC 4180 CSV: fields in double quotes may hold commas,
+/// newlines and `""` for a quote. Empty lines are left out.
+fn csv_records(text: &str) -> Result<Vec<Vec<String>>, String> {
+    let mut records = Vec::new();
+    let (mut record, mut field) = (Vec::new(), String::new());
+    let (mut quoted, mut was_quoted) = (false, false);
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                chars.next();
+                field.push('"');
+            }
+            '"' if quoted => quoted = false,
+            '"' if field.is_empty() => (quoted, was_quoted) = (true, true),
+            ',' if !quoted => {
+                record.push(std::mem::take(&mut field));
+                was_quoted = false;
+            }
+            '\r' if !quoted && chars.peek() == Some(&'\n') => {}
+            '\n' if !quoted => {
+                if !field.is_empty() || was_quoted || !record.is_empty() {
+                    record.push(std::mem::take(&mut field));
+                    records.push(std::mem::take(&mut record));
+                }
+                was_quoted = false;
+            }
+            c => field.push(c),
+        }
+    }
+    if quoted {
+        return Err("unterminated quoted field".to_string());
+    }
+    if !field.is_empty() || was_quoted || !record.is_empty() {
+        record.push(field);
+        records.push(record);
+    }
+    Ok(records)
+}
+
+fn from_json(text: &str) -> Result<Table, String> {
+    let items = match serde_json::from_str::<Value>(text).map_err(|err| err.to_string())? {
+        Value::Array(items) => items,
+        _ => return Err("expected an array of objects".to_string()),
+    };
+    let mut columns = Vec::<String>::new();
+    let mut objects = Vec::with_capacity(items.len());
+    for item in items {
+        let Value::Object(object) = item else {
+            return Err("expected an array of objects".to_string());
+        };
+        for key in object.keys() {
+            if !columns.contains(key) {
+                columns.push(key.clone());
+            }
+        }
+        objects.push(object);
+    }
+    let rows = objects
+        .iter()
+        .map(|object| {
+            columns
+                .iter()
+                .map(|column| match object.get(column) {
+                    Some(Value::String(value)) => Ok(value.clone()),
+                    Some(value @ (Value::Number(_) | Value::Bool(_))) => Ok(value.to_string()),
+                    None | Some(Value::Null) => Ok(String::new()),
+                    Some(_) => Err(format!("{} must be a string, number or boolean", column)),
+                })
+                .collect::<Result<Vec<_>, _>>()
+        })
+        .collect::<Result<_, _>>()?;
+    Ok(Table { columns, rows })
+}
+
+/// Writes a url file line for every combination of the rows of `tables`, to
+/// stdout or appended to `enqueue`. Without `path` files are named after the
+/// last segment of their url.
+pub fn run(
+    url: &str,
+    path: Option<&str>,
+    tables: &[Table],
+    enqueue: Option<&Path>,
+) -> Result<(), String> {
+    let columns = tables
+        .iter()
+        .flat_map(|table| &table.columns)
+        .collect::<Vec<_>>();
+    for template in std::iter::once(url).chain(path) {
+        if let Some(unknown) =
+            placeholders(template).find(|name| !columns.iter().any(|column| column == name))
+        {
+            return Err(format!("no variable {{{}}} in {}", unknown, template));
+        }
+    }
+    let mut out: Box<dyn Write> = match enqueue {
+        Some(url_file) => Box::new(io::BufWriter::new(
+            OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(url_file)
+                .map_err(|err| format!("failed to open {}: {}", url_file.display(), err))?,
+        )),
+        None => Box::new(io::BufWriter::new(io::stdout().lock())),
+    };
+    let write_error = |err: io::Error| match enqueue {
+        Some(url_file) => format!("failed to write {}: {}", url_file.display(), err),
+        None => err.to_string(),
+    };
+    // the row of every table in the current combination
+    let mut at = vec![0; tables.len()];
+    let mut lines = 0;
+    'lines: while tables.iter().all(|table| !table.rows.is_empty()) {
+        let vars = tables
+            .iter()
+            .zip(&at)
+            .flat_map(|(table, &row)| table.columns.iter().zip(&table.rows[row]))
+            .collect::<Vec<_>>();
+        if let Some((name, _)) = vars.iter().find(|(_, value)| value.contains('\n')) {
+            return Err(format!("{{{}}} has a line break", name));
+        }
+        let expanded = normalize_url(&render(url, &vars, true))?;
+        let file_name = match path {
+            Some(path) => render(path, &vars, false),
+            None => file_name(&expanded)
+                .ok_or_else(|| format!("no file name in {}, pass --path", expanded))?,
+        };
+        writeln!(out, "{} {}", expanded, file_name).map_err(write_error)?;
+        lines += 1;
+        // the next combination, counting with the last table as the lowest
+        // digit
+        let mut table = tables.len();
+        loop {
+            let Some(previous) = table.checked_sub(1) else {
+                break 'lines;
+            };
+            table = previous;
+            at[table] += 1;
+            if at[table] < tables[table].rows.len() {
+                break;
+            }
+            at[table] = 0;
+        }
+    }
+    out.flush().map_err(write_error)?;
+    if let Some(url_file) = enqueue {
+        eprintln!("enqueued {} entries in {}", lines, url_file.display());
+    }
+    Ok(())
+}
+
+fn normalize_url(url: &str) -> Result<String, String> {
+    urls::normalize(url).map_err(|err| format!("invalid url {}: {}", url, err))
+}
+
+/// The names of the `{name}` placeholders of `template`.
+fn placeholders(template: &str) -> impl Iterator<Item = &str> {
+    template.split('{').skip(1).filter_map(|rest| {
+        let (name, _) = rest.split_once('}')?;
+        Some(name)
+    })
+}
+
+/// `template` with its placeholders replaced, percent-encoding what a url
+/// can't hold when `in_url`.
+fn render(template: &str, vars: &[(&String, &String)], in_url: bool) -> String {
+    let mut rendered = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        rendered.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let var = vars.iter().find(|(name, _)| {
+            rest[1..].starts_with(name.as_str()) && rest[1 + name.len()..].starts_with('}')
+        });
+        match var {
+            Some((name, value)) => {
+                match in_url {
+                    true => rendered.extend(utf8_percent_encode(value, URL_UNSAFE)),
+                    false => rendered.push_str(value),
+                }
+                rest = &rest[name.len() + 2..];
+            }
+            None => {
+                rendered.push('{');
+                rest = &rest[1..];
+            }
+        }
+    }
+    rendered.push_str(rest);
+    rendered
+}
+
+/// The last segment of the path of `url`, if it's not empty.
+fn file_name(url: &str) -> Option<String> {
+    let url = reqwest::Url::parse(url).ok()?;
+    let name = url.path_segments()?.next_back()?;
+    (!name.is_empty()).then(|| name.to_string())
+}
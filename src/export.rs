@@ -0,0 +1,135 @@
+//! `fast_download export <url_file_name> --format <format>`: writes the parsed
+//! entries of a url file, with their paths and headers, in the input format of
+//! another downloader.
+//!
+//! - `aria2` is an aria2 input file, for `aria2c -i`, and keeps mirrors and
+//!   `sha256=` too.
+//! - `curl-config` is a config file for `curl -K`, with the entries in
+//!   sections split by `next`, so headers stay with their url.
+//! - `wget` is a shell script with a `wget` command per entry, as wget's own
+//!   input files only hold urls.
+
+use fast_download::Image;
+use std::{
+    io::{self, Write},
+    path::Path,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Aria2,
+    CurlConfig,
+    Wget,
+}
+
+impl Format {
+    pub fn parse(name: &str) -> Option<Format> {
+        match name {
+            "aria2" => Some(Format::Aria2),
+            "curl-config" => Some(Format::CurlConfig),
+            "wget" => Some(Format::Wget),
+            _ => None,
+        }
+    }
+}
+
+/// Prints `images` in `format`.
+pub fn run(images: &[Image], format: Format) -> io::Result<()> {
+    let mut out = io::BufWriter::new(io::stdout().lock());
+    if format == Format::Wget {
+        writeln!(out, "#!/bin/sh")?;
+        writeln!(out, "set -e")?;
+    }
+    for (index, image) in images.iter().enumerate() {
+        if format == Format::CurlConfig && index > 0 {
+            writeln!(out, "next")?;
+        }
+        match format {
+            Format::Aria2 => aria2(&mut out, image)?,
+            Format::CurlConfig => curl_config(&mut out, image)?,
+            Format::Wget => wget(&mut out, image)?,
+        }
+    }
+    out.flush()
+}
+
+fn aria2(out: &mut impl Write, image: &Image) -> io::Result<()> {
+    let urls = std::iter::once(&image.url)
+        .chain(&image.mirrors)
+        .map(String::as_str)
+        .collect::<Vec<_>>();
+    writeln!(out, "{}", urls.join("\t"))?;
+    let path = Path::new(&image.file_name);
+    match (
+        path.parent().filter(|dir| !dir.as_os_str().is_empty()),
+        path.file_name(),
+    ) {
+        (Some(dir), Some(name)) => {
+            writeln!(out, "  dir={}", dir.display())?;
+            writeln!(out, "  out={}", name.to_string_lossy())?;
+        }
+        _ => writeln!(out, "  out={}", image.file_name)?,
+    }
+    if let Some(sha256) = &image.sha256 {
+        writeln!(out, "  checksum=sha-256={}", sha256)?;
+    }
+    for (name, value) in &image.headers {
+        writeln!(out, "  header={}: {}", name, value)?;
+    }
+    Ok(())
+}
+
+fn curl_config(out: &mut impl Write, image: &Image) -> io::Result<()> {
+    writeln!(out, "url = {}", curl_quote(&image.url))?;
+    writeln!(out, "output = {}", curl_quote(&image.file_name))?;
+    writeln!(out, "create-dirs")?;
+    writeln!(out, "location")?;
+    for (name, value) in &image.headers {
+        writeln!(
+            out,
+            "header = {}",
+            curl_quote(&format!("{}: {}", name, value))
+        )?;
+    }
+    Ok(())
+}
+
+/// A double quoted string of a curl config file.
+fn curl_quote(value: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in value.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\t' => quoted.push_str("\\t"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+fn wget(out: &mut impl Write, image: &Image) -> io::Result<()> {
+    if let Some(dir) = Path::new(&image.file_name)
+        .parent()
+        .filter(|dir| !dir.as_os_str().is_empty())
+    {
+        writeln!(out, "mkdir -p {}", sh_quote(&dir.to_string_lossy()))?;
+    }
+    write!(out, "wget -O {}", sh_quote(&image.file_name))?;
+    for (name, value) in &image.headers {
+        write!(
+            out,
+            " --header={}",
+            sh_quote(&format!("{}: {}", name, value))
+        )?;
+    }
+    writeln!(out, " {}", sh_quote(&image.url))
+}
+
+/// `value` in single quotes for a POSIX shell.
+fn sh_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', r"'\''"))
+}
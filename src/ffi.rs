@@ -0,0 +1,216 @@
+//! C API for embedding the downloader, enabled with the `ffi` feature. The
+//! declarations are in `include/fast_download.h`.
+//!
+//! A manager owns its own runtime. Downloads are enqueued from any thread and
+//! their progress is read back by polling events, so the host never has to
+//! hand callbacks to Rust.
+
+use crate::{DownloadCompleted, DownloadHandle, Downloader, Event, Image, Options};
+use std::{
+    collections::HashMap,
+    ffi::{c_char, c_int, CStr, CString},
+    ptr,
+    sync::Arc,
+};
+use tokio::{runtime::Runtime, sync::mpsc};
+
+pub struct FastDownloadManager {
+    runtime: Runtime,
+    downloader: Arc<Downloader>,
+    events: mpsc::UnboundedReceiver<Event>,
+    handles: HashMap<usize, DownloadHandle>,
+    next_id: usize,
+    /// Backs `message` of the last polled event.
+    message: Option<CString>,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FastDownloadEventKind {
+    Started = 0,
+    Chunk = 1,
+    Finished = 2,
+    Failed = 3,
+}
+
+#[repr(C)]
+pub struct FastDownloadEvent {
+    pub kind: FastDownloadEventKind,
+    pub id: u64,
+    /// Chunk size for `Chunk` events.
+    pub bytes: u64,
+    /// Expected file size for `Chunk` events, -1 when unknown.
+    pub total: i64,
+    /// Set on `Finished` events when the file already existed.
+    pub skipped: bool,
+    pub attempt: u32,
+    pub will_retry: bool,
+    /// The url for `Started` events and the error for `Failed` events, null
+    /// otherwise. Valid until the next poll.
+    pub message: *const c_char,
+}
+
+/// Creates a manager, or returns null if its runtime can't be started.
+#[no_mangle]
+pub extern "C" fn fast_download_new(
+    max_concurrent_downloads: usize,
+    retries: u32,
+) -> *mut FastDownloadManager {
+    let options = Options {
+        max_concurrent_downloads,
+        retries,
+        ..Options::default()
+    };
+    let (Ok(runtime), Ok(downloader)) = (Runtime::new(), Downloader::new(options)) else {
+        return ptr::null_mut();
+    };
+    let events = downloader.subscribe();
+    Box::into_raw(Box::new(FastDownloadManager {
+        runtime,
+        downloader: Arc::new(downloader),
+        events,
+        handles: HashMap::new(),
+        next_id: 0,
+        message: None,
+    }))
+}
+
+/// Starts downloading `url` to `path` and returns its id, or -1 if either
+/// string isn't valid UTF-8.
+///
+/// # Safety
+///
+/// `manager` must come from `fast_download_new`, and `url` and `path` must be
+/// nul terminated strings.
+#[no_mangle]
+pub unsafe extern "C" fn fast_download_enqueue(
+    manager: *mut FastDownloadManager,
+    url: *const c_char,
+    path: *const c_char,
+) -> i64 {
+    let manager = &mut *manager;
+    let (Ok(url), Ok(path)) = (CStr::from_ptr(url).to_str(), CStr::from_ptr(path).to_str()) else {
+        return -1;
+    };
+    let id = manager.next_id;
+    manager.next_id += 1;
+    let _runtime = manager.runtime.enter();
+    let handle = manager.downloader.submit(id, Image::new(url, path));
+    manager.handles.insert(id, handle);
+    id as i64
+}
+
+/// Cancels a download. Returns 0 if `id` isn't running.
+///
+/// # Safety
+///
+/// `manager` must come from `fast_download_new`.
+#[no_mangle]
+pub unsafe extern "C" fn fast_download_cancel(manager: *mut FastDownloadManager, id: u64) -> c_int {
+    let manager = &mut *manager;
+    match manager.handles.get(&(id as usize)) {
+        Some(handle) => {
+            handle.cancel();
+            1
+        }
+        None => 0,
+    }
+}
+
+/// Fills `event` with the next event and returns 1, or returns 0 without
+/// blocking when there is none.
+///
+/// # Safety
+///
+/// `manager` must come from `fast_download_new` and `event` must point to
+/// writable memory for one event.
+#[no_mangle]
+pub unsafe extern "C" fn fast_download_poll(
+    manager: *mut FastDownloadManager,
+    event: *mut FastDownloadEvent,
+) -> c_int {
+    let manager = &mut *manager;
+    // the manager doesn't record requests, but skip their events regardless
+    let next = loop {
+        match manager.events.try_recv() {
+            Ok(Event::Request { .. }) => continue,
+            Ok(next) => break next,
+            Err(_) => return 0,
+        }
+    };
+    let mut out = FastDownloadEvent {
+        kind: FastDownloadEventKind::Started,
+        id: 0,
+        bytes: 0,
+        total: -1,
+        skipped: false,
+        attempt: 0,
+        will_retry: false,
+        message: ptr::null(),
+    };
+    let mut message = None;
+    let mut finished = None;
+    match next {
+        Event::ItemStarted { id, url, .. } => {
+            out.id = id as u64;
+            message = Some(url);
+        }
+        Event::ChunkReceived {
+            id, bytes, total, ..
+        } => {
+            out.kind = FastDownloadEventKind::Chunk;
+            out.id = id as u64;
+            out.bytes = bytes as u64;
+            out.total = total.map_or(-1, |total| total as i64);
+        }
+        Event::ItemFinished { id, outcome } => {
+            out.kind = FastDownloadEventKind::Finished;
+            out.id = id as u64;
+            out.skipped = outcome == DownloadCompleted::Skipped;
+            finished = Some(id);
+        }
+        Event::ItemFailed {
+            id,
+            error,
+            attempt,
+            will_retry,
+        } => {
+            out.kind = FastDownloadEventKind::Failed;
+            out.id = id as u64;
+            out.attempt = attempt;
+            out.will_retry = will_retry;
+            message = Some(error);
+            if !will_retry {
+                finished = Some(id);
+            }
+        }
+        Event::Request { .. } => unreachable!("skipped above"),
+    }
+    if let Some(id) = finished {
+        manager.handles.remove(&id);
+    }
+    // urls and errors can't contain nul bytes in practice, drop them if they do
+    manager.message = message
+        .map(|message| CString::new(message.replace('\0', "")).expect("nul bytes were removed"));
+    out.message = manager
+        .message
+        .as_ref()
+        .map_or(ptr::null(), |message| message.as_ptr());
+    event.write(out);
+    1
+}
+
+/// Cancels every running download and frees the manager.
+///
+/// # Safety
+///
+/// `manager` must come from `fast_download_new` or be null, and must not be
+/// used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn fast_download_destroy(manager: *mut FastDownloadManager) {
+    if manager.is_null() {
+        return;
+    }
+    let manager = Box::from_raw(manager);
+    manager.downloader.shutdown();
+}
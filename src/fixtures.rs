@@ -0,0 +1,124 @@
+//! [`Options::record`](crate::Options::record) and
+//! [`Options::replay`](crate::Options::replay): saving the response of every
+//! download to a directory, and answering downloads from it later without the
+//! network.
+//!
+//! The response to an item's url is kept as `<sha256 of the url>.json`, with
+//! its status and headers, and its body as `<sha256 of the url>.body`.
+
+use crate::sigv4::hex;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    io,
+    path::{Path, PathBuf},
+};
+use tokio::{fs, io::AsyncWriteExt};
+
+#[derive(Serialize, Deserialize)]
+struct Fixture {
+    url: String,
+    status: u16,
+    headers: Vec<(String, String)>,
+}
+
+/// The response of one download being saved. Dropping it before
+/// [`finish`](Recording::finish) leaves the last recording of the url in place.
+pub(crate) struct Recording {
+    /// The entry path without an extension.
+    entry: PathBuf,
+    body: fs::File,
+    fixture: Fixture,
+}
+
+/// The recorded response to `url`, as if the server had sent it. Fails with
+/// [`io::ErrorKind::NotFound`] if it was never recorded.
+pub(crate) async fn replay(dir: &Path, url: &str) -> io::Result<reqwest::Response> {
+    let entry = entry_path(dir, url);
+    let not_recorded = |err: io::Error| match err.kind() {
+        io::ErrorKind::NotFound => io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("no recording of {} in {}", url, dir.display()),
+        ),
+        _ => err,
+    };
+    let json = fs::read(entry.with_extension("json"))
+        .await
+        .map_err(not_recorded)?;
+    let fixture = serde_json::from_slice::<Fixture>(&json)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+    let body = fs::read(entry.with_extension("body"))
+        .await
+        .map_err(not_recorded)?;
+    let mut response = hyper::Response::builder().status(fixture.status);
+    for (name, value) in &fixture.headers {
+        response = response.header(name, value);
+    }
+    let response = response
+        .body(body)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+    Ok(reqwest::Response::from(response))
+}
+
+/// The `Content-Length` of the recorded response to `url`, for a replayed HEAD
+/// request.
+pub(crate) async fn recorded_size(dir: &Path, url: &str) -> io::Result<Option<u64>> {
+    let response = replay(dir, url).await?;
+    Ok(response
+        .headers()
+        .get(reqwest::header::CONTENT_LENGTH)
+        .and_then(|len| len.to_str().ok()?.parse().ok()))
+}
+
+impl Recording {
+    /// Starts saving `response`, the answer to `url`.
+    pub async fn start(dir: &Path, url: &str, response: &reqwest::Response) -> io::Result<Self> {
+        fs::create_dir_all(dir).await?;
+        let entry = entry_path(dir, url);
+        let headers = response
+            .headers()
+            .iter()
+            .map(|(name, value)| {
+                (
+                    name.to_string(),
+                    String::from_utf8_lossy(value.as_bytes()).into_owned(),
+                )
+            })
+            .collect();
+        Ok(Recording {
+            body: fs::File::create(entry.with_extension("body.part")).await?,
+            entry,
+            fixture: Fixture {
+                url: url.to_string(),
+                status: response.status().as_u16(),
+                headers,
+            },
+        })
+    }
+
+    pub async fn write(&mut self, chunk: &[u8]) -> io::Result<()> {
+        self.body.write_all(chunk).await
+    }
+
+    /// Replaces the recording of the url with this complete response.
+    pub async fn finish(mut self) -> io::Result<()> {
+        self.body.flush().await?;
+        let json = serde_json::to_vec_pretty(&self.fixture).expect("fixtures serialize");
+        fs::write(self.entry.with_extension("json.part"), json).await?;
+        fs::rename(
+            self.entry.with_extension("body.part"),
+            self.entry.with_extension("body"),
+        )
+        .await?;
+        fs::rename(
+            self.entry.with_extension("json.part"),
+            self.entry.with_extension("json"),
+        )
+        .await
+    }
+}
+
+fn entry_path(dir: &Path, url: &str) -> PathBuf {
+    let name = hex(&Sha256::digest(url));
+    dir.join(name)
+}
@@ -0,0 +1,63 @@
+//! Globs like `*.iso` or `*.example.com`, where `*` stands for any characters
+//! and `?` for one: the host patterns of
+//! [`Options::url_policy`](crate::Options::url_policy), and the file name
+//! filters of `crawl` and the queues of `fast_download daemon`.
+
+/// Whether all of `text` matches `pattern`.
+pub fn matches(pattern: &str, text: &str) -> bool {
+    let pattern = pattern.chars().collect::<Vec<_>>();
+    let text = text.chars().collect::<Vec<_>>();
+    let (mut p, mut t) = (0, 0);
+    // where the last `*` was and the text position it stands in for up to
+    let mut star = None;
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, t));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match star {
+                // let the `*` take one more character
+                Some((at, taken)) => {
+                    star = Some((at, taken + 1));
+                    p = at + 1;
+                    t = taken + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::matches;
+
+    #[test]
+    fn star_stands_for_any_characters() {
+        assert!(matches("*.iso", "debian.iso"));
+        assert!(matches("*.iso", ".iso"));
+        assert!(!matches("*.iso", "debian.iso.sig"));
+        assert!(matches("a*b*c", "abbbc"));
+        assert!(matches("*", ""));
+    }
+
+    #[test]
+    fn question_mark_stands_for_one_character() {
+        assert!(matches("disk?.img", "disk1.img"));
+        assert!(!matches("disk?.img", "disk.img"));
+        assert!(!matches("disk?.img", "disk12.img"));
+    }
+
+    #[test]
+    fn matches_all_of_the_text_case_sensitively() {
+        assert!(!matches("*.example.com", "example.com"));
+        assert!(!matches("movie.mp4", "a-movie.mp4"));
+        assert!(!matches("*.MP4", "movie.mp4"));
+    }
+}
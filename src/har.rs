@@ -0,0 +1,111 @@
+//! `--har`: every request of a run in HTTP Archive 1.2 format. Bodies aren't
+//! recorded, only their sizes.
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use fast_download::RequestRecord;
+use reqwest::{StatusCode, Url};
+use serde_json::{json, Value};
+use std::{fs, io, path::Path, time::Duration};
+
+#[derive(Default)]
+pub struct Har {
+    entries: Vec<Value>,
+}
+
+impl Har {
+    pub fn add(&mut self, record: &RequestRecord) {
+        let millis = |duration: Option<Duration>| {
+            duration.map_or(-1.0, |duration| duration.as_secs_f64() * 1000.0)
+        };
+        let header = |headers: &[(String, String)]| {
+            headers
+                .iter()
+                .map(|(name, value)| json!({ "name": name, "value": value }))
+                .collect::<Vec<_>>()
+        };
+        let query = Url::parse(&record.url)
+            .map(|url| {
+                url.query_pairs()
+                    .map(|(name, value)| json!({ "name": name, "value": value }))
+                    .collect::<Vec<_>>()
+            })
+            .unwrap_or_default();
+        let content_type = record
+            .response_headers
+            .iter()
+            .find(|(name, _)| name == "content-type")
+            .map_or("", |(_, value)| value.as_str());
+        let http_version = record.http_version.as_deref().unwrap_or("");
+        let timings = &record.timings;
+        let dns = millis(timings.dns);
+        // HAR splits the time to first byte into connect, ssl and wait, of
+        // which only the total is known
+        let wait = timings.ttfb.map_or(-1.0, |ttfb| {
+            (ttfb.as_secs_f64() * 1000.0 - dns.max(0.0)).max(0.0)
+        });
+        let receive = millis(timings.transfer).max(0.0);
+        let mut entry = json!({
+            "startedDateTime": DateTime::<Utc>::from(record.started)
+                .to_rfc3339_opts(SecondsFormat::Millis, true),
+            "time": dns.max(0.0) + wait.max(0.0) + receive,
+            "request": {
+                "method": record.method,
+                "url": record.url,
+                "httpVersion": http_version,
+                "cookies": [],
+                "headers": header(&record.request_headers),
+                "queryString": query,
+                "headersSize": -1,
+                "bodySize": 0,
+            },
+            "response": {
+                "status": record.status.unwrap_or(0),
+                "statusText": record
+                    .status
+                    .and_then(|status| StatusCode::from_u16(status).ok()?.canonical_reason())
+                    .unwrap_or(""),
+                "httpVersion": http_version,
+                "cookies": [],
+                "headers": header(&record.response_headers),
+                "content": { "size": record.bytes, "mimeType": content_type },
+                "redirectURL": "",
+                "headersSize": -1,
+                "bodySize": record.bytes,
+            },
+            "cache": {},
+            "timings": {
+                "blocked": -1,
+                "dns": dns,
+                "connect": -1,
+                "ssl": -1,
+                "send": 0,
+                "wait": wait,
+                "receive": receive,
+            },
+            "_redirects": record
+                .redirects
+                .iter()
+                .map(|redirect| json!({ "status": redirect.status, "url": redirect.location }))
+                .collect::<Vec<_>>(),
+            "comment": record.error.as_deref().unwrap_or(""),
+        });
+        if let Some(addr) = record.remote_addr {
+            entry["serverIPAddress"] = addr.ip().to_string().into();
+        }
+        self.entries.push(entry);
+    }
+
+    pub fn write(&self, path: &Path) -> io::Result<()> {
+        let har = json!({
+            "log": {
+                "version": "1.2",
+                "creator": {
+                    "name": env!("CARGO_PKG_NAME"),
+                    "version": env!("CARGO_PKG_VERSION"),
+                },
+                "entries": self.entries,
+            }
+        });
+        fs::write(path, serde_json::to_string_pretty(&har)? + "\n")
+    }
+}
@@ -0,0 +1,619 @@
+//! The run history: every download run is saved to a local sqlite database,
+//! which `fast_download history` lists and re-enqueues failures from.
+//!
+//! The database also holds the bytes received per day, as the runs receive
+//! them, which `--monthly-cap` holds a month to and `history --usage` lists.
+
+use crate::Stats;
+use chrono::{DateTime, Local, NaiveDate, TimeZone};
+use fast_download::{sigv4::hex, DownloadCompleted, DownloadResult, Image};
+use indicatif::{HumanBytes, HumanDuration};
+use rusqlite::{params, Connection};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashSet,
+    env,
+    fs::{self, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS runs (
+    id INTEGER PRIMARY KEY,
+    started INTEGER NOT NULL,
+    directory TEXT NOT NULL,
+    url_file TEXT NOT NULL,
+    arguments TEXT NOT NULL,
+    downloaded INTEGER NOT NULL,
+    skipped INTEGER NOT NULL,
+    failed INTEGER NOT NULL,
+    bytes INTEGER NOT NULL,
+    duration_ms INTEGER NOT NULL,
+    error TEXT,
+    manifest_sha256 TEXT
+);
+CREATE TABLE IF NOT EXISTS items (
+    run INTEGER NOT NULL REFERENCES runs(id),
+    url TEXT NOT NULL,
+    path TEXT NOT NULL,
+    outcome TEXT NOT NULL,
+    error TEXT,
+    bytes INTEGER,
+    duration_ms INTEGER NOT NULL,
+    sha256 TEXT,
+    item_id TEXT
+);
+CREATE INDEX IF NOT EXISTS items_run ON items(run);
+CREATE TABLE IF NOT EXISTS usage (
+    day TEXT PRIMARY KEY,
+    bytes INTEGER NOT NULL
+);
+";
+
+struct Item {
+    id: String,
+    url: String,
+    path: String,
+    sha256: Option<String>,
+    outcome: &'static str,
+    error: Option<String>,
+    bytes: Option<u64>,
+    duration: Duration,
+}
+
+/// A url file entry as `--incremental` compares it with earlier runs.
+#[derive(Debug, PartialEq, Eq, Hash)]
+pub struct Entry {
+    url: String,
+    /// Absolute, as runs may start in different directories.
+    path: PathBuf,
+    sha256: Option<String>,
+}
+
+impl Entry {
+    pub fn new(image: &Image) -> Entry {
+        Entry {
+            url: image.url.clone(),
+            path: env::current_dir()
+                .unwrap_or_default()
+                .join(&image.file_name),
+            sha256: image.sha256.clone(),
+        }
+    }
+}
+
+/// The items of the current run, saved once it ends.
+pub struct History {
+    items: Vec<Item>,
+    /// The sha256 of the url file as the run read it.
+    manifest: Option<String>,
+}
+
+impl History {
+    pub fn new(manifest: Option<String>) -> History {
+        History {
+            items: Vec::new(),
+            manifest,
+        }
+    }
+
+    pub fn record(
+        &mut self,
+        image: &Image,
+        result: &DownloadResult,
+        bytes: u64,
+        duration: Duration,
+    ) {
+        let (outcome, error) = match result {
+            Ok(DownloadCompleted::Success) => ("downloaded", None),
+            Ok(DownloadCompleted::Skipped) => ("skipped", None),
+            Err(err) => ("failed", Some(err.to_string())),
+        };
+        // what a skipped file would have taken isn't known
+        let bytes = matches!(result, Ok(DownloadCompleted::Success)).then_some(bytes);
+        self.items.push(Item {
+            id: image.id(),
+            url: image.url.clone(),
+            path: image.file_name.clone(),
+            sha256: image.sha256.clone(),
+            outcome,
+            error,
+            bytes,
+            duration,
+        });
+    }
+
+    /// Saves the run to the database at `path`. Only downloaded files count
+    /// towards its bytes.
+    pub fn save(
+        &self,
+        path: &Path,
+        url_file: &Path,
+        stats: &Stats,
+        elapsed: Duration,
+        error: Option<&str>,
+    ) -> Result<(), String> {
+        let mut conn = open(path)?;
+        let started = SystemTime::now() - elapsed;
+        let directory = env::current_dir().unwrap_or_default();
+        let url_file = fs::canonicalize(url_file).unwrap_or_else(|_| url_file.to_path_buf());
+        let arguments = serde_json::to_string(&env::args().skip(1).collect::<Vec<_>>())
+            .expect("strings serialize");
+        let bytes = self
+            .items
+            .iter()
+            .filter(|item| item.outcome == "downloaded")
+            .filter_map(|item| item.bytes)
+            .sum::<u64>();
+        let mut save = || -> rusqlite::Result<()> {
+            let tx = conn.transaction()?;
+            tx.execute(
+                "INSERT INTO runs (started, directory, url_file, arguments, downloaded, skipped,
+                     failed, bytes, duration_ms, error, manifest_sha256)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)",
+                params![
+                    unix_seconds(started),
+                    directory.to_string_lossy(),
+                    url_file.to_string_lossy(),
+                    arguments,
+                    stats.downloaded,
+                    stats.skipped,
+                    stats.failed,
+                    bytes,
+                    elapsed.as_millis() as u64,
+                    error,
+                    self.manifest,
+                ],
+            )?;
+            let run = tx.last_insert_rowid();
+            {
+                let mut insert = tx.prepare(
+                    "INSERT INTO items (run, url, path, outcome, error, bytes, duration_ms, sha256,
+                         item_id)
+                     VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+                )?;
+                for item in &self.items {
+                    insert.execute(params![
+                        run,
+                        item.url,
+                        item.path,
+                        item.outcome,
+                        item.error,
+                        item.bytes,
+                        item.duration.as_millis() as u64,
+                        item.sha256,
+                        item.id,
+                    ])?;
+                }
+            }
+            tx.commit()
+        };
+        save().map_err(|err| err.to_string())
+    }
+}
+
+/// The bytes a run received, added to the usage of the day whenever it is
+/// saved, so runs at the same time count towards the same month. The database
+/// is opened once, and written on the blocking pool, so a run waiting for
+/// another one's lock doesn't hold up the runtime.
+pub struct Usage {
+    conn: Arc<Mutex<Connection>>,
+    unsaved: u64,
+    /// The usage of this month when it was last saved, of every run.
+    month: u64,
+}
+
+impl Usage {
+    pub async fn open(path: &Path) -> Result<Usage, String> {
+        let path = path.to_path_buf();
+        let conn = tokio::task::spawn_blocking(move || open(&path))
+            .await
+            .map_err(|err| err.to_string())??;
+        let mut usage = Usage {
+            conn: Arc::new(Mutex::new(conn)),
+            unsaved: 0,
+            month: 0,
+        };
+        usage.save().await?;
+        Ok(usage)
+    }
+
+    pub fn received(&mut self, bytes: u64) {
+        self.unsaved += bytes;
+    }
+
+    /// The bytes received this month, counting those not saved yet.
+    pub fn month(&self) -> u64 {
+        self.month + self.unsaved
+    }
+
+    /// Adds what was received since the last save to today, and reads the
+    /// month again.
+    pub async fn save(&mut self) -> Result<(), String> {
+        let (conn, unsaved) = (self.conn.clone(), self.unsaved);
+        let today = Local::now().date_naive();
+        let save = move || -> rusqlite::Result<u64> {
+            let mut conn = conn.lock().expect("usage saves don't panic");
+            let tx = conn.transaction()?;
+            if unsaved > 0 {
+                tx.execute(
+                    "INSERT INTO usage (day, bytes) VALUES (?1, ?2)
+                     ON CONFLICT (day) DO UPDATE SET bytes = bytes + ?2",
+                    params![today.format("%Y-%m-%d").to_string(), unsaved],
+                )?;
+            }
+            let month = tx.query_row(
+                "SELECT COALESCE(SUM(bytes), 0) FROM usage WHERE substr(day, 1, 7) = ?1",
+                [today.format("%Y-%m").to_string()],
+                |row| row.get(0),
+            )?;
+            tx.commit()?;
+            Ok(month)
+        };
+        self.month = tokio::task::spawn_blocking(save)
+            .await
+            .map_err(|err| err.to_string())?
+            .map_err(|err| err.to_string())?;
+        self.unsaved = 0;
+        Ok(())
+    }
+}
+
+pub fn default_path() -> Option<PathBuf> {
+    let dir = env::var_os("XDG_DATA_HOME")
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("LOCALAPPDATA").map(PathBuf::from))
+        .or_else(|| {
+            env::var_os("HOME").map(|home| Path::new(&home).join(".local").join("share"))
+        })?;
+    Some(dir.join("fast_download").join("history.sqlite"))
+}
+
+/// Parses `--since` as a duration before now like `2h` or `7d`, a date like
+/// `2024-05-01` (local midnight) or an RFC 3339 timestamp, into unix seconds.
+pub fn parse_since(s: &str) -> Option<i64> {
+    if let Some(ago) = crate::units::parse_duration(s) {
+        return Some(unix_seconds(SystemTime::now().checked_sub(ago)?));
+    }
+    if let Ok(date) = NaiveDate::parse_from_str(s, "%Y-%m-%d") {
+        let midnight = date.and_hms_opt(0, 0, 0)?;
+        return Some(Local.from_local_datetime(&midnight).earliest()?.timestamp());
+    }
+    DateTime::parse_from_rfc3339(s)
+        .ok()
+        .map(|time| time.timestamp())
+}
+
+/// The entries that the latest run of `url_file` to get to them downloaded or
+/// skipped, with the checksum they had then. Nothing is unchanged without a
+/// history.
+pub fn unchanged(path: &Path, url_file: &Path) -> Result<HashSet<Entry>, String> {
+    if !path.is_file() {
+        return Ok(HashSet::new());
+    }
+    let conn = open(path)?;
+    let url_file = fs::canonicalize(url_file).unwrap_or_else(|_| url_file.to_path_buf());
+    let select = || -> rusqlite::Result<HashSet<Entry>> {
+        let mut select = conn.prepare(
+            "SELECT items.url, items.path, runs.directory, items.outcome, items.sha256,
+                 MAX(runs.id)
+             FROM items JOIN runs ON items.run = runs.id
+             WHERE runs.url_file = ?1
+             GROUP BY items.url, runs.directory, items.path",
+        )?;
+        let rows = select.query_map([url_file.to_string_lossy()], |row| {
+            let directory = row.get::<_, String>(2)?;
+            let entry = Entry {
+                url: row.get(0)?,
+                path: Path::new(&directory).join(row.get::<_, String>(1)?),
+                sha256: row.get(4)?,
+            };
+            Ok((entry, row.get::<_, String>(3)? != "failed"))
+        })?;
+        let mut unchanged = HashSet::new();
+        for row in rows {
+            if let (entry, true) = row? {
+                unchanged.insert(entry);
+            }
+        }
+        Ok(unchanged)
+    };
+    select().map_err(|err| err.to_string())
+}
+
+/// The sha256 of the url file at `path`, which `--idempotent` recognizes a
+/// manifest by.
+pub fn manifest_sha256(path: &Path) -> Result<String, String> {
+    let text = fs::read(path).map_err(|err| format!("{}: {}", path.display(), err))?;
+    Ok(hex(&Sha256::digest(text)))
+}
+
+/// The entries that runs of the exact manifest with sha256 `manifest`
+/// downloaded, or found already in place and matching their `size=` and
+/// `sha256=`.
+pub fn completed(path: &Path, manifest: &str) -> Result<HashSet<Entry>, String> {
+    if !path.is_file() {
+        return Ok(HashSet::new());
+    }
+    let conn = open(path)?;
+    let select = || -> rusqlite::Result<HashSet<Entry>> {
+        let mut select = conn.prepare(
+            "SELECT DISTINCT items.url, items.path, runs.directory, items.sha256
+             FROM items JOIN runs ON items.run = runs.id
+             WHERE runs.manifest_sha256 = ?1 AND items.outcome != 'failed'",
+        )?;
+        let rows = select.query_map([manifest], |row| {
+            let directory = row.get::<_, String>(2)?;
+            Ok(Entry {
+                url: row.get(0)?,
+                path: Path::new(&directory).join(row.get::<_, String>(1)?),
+                sha256: row.get(3)?,
+            })
+        })?;
+        rows.collect()
+    };
+    select().map_err(|err| err.to_string())
+}
+
+/// The url files of past runs that no longer exist, with how many runs each
+/// had. Unless `dry_run`, those runs and their items are deleted.
+pub fn forget_missing(path: &Path, dry_run: bool) -> Result<Vec<(PathBuf, usize)>, String> {
+    if !path.is_file() {
+        return Ok(Vec::new());
+    }
+    let mut conn = open(path)?;
+    let mut forget = || -> rusqlite::Result<Vec<(PathBuf, usize)>> {
+        let tx = conn.transaction()?;
+        let runs = {
+            let mut select = tx.prepare("SELECT id, directory, url_file FROM runs")?;
+            let rows = select.query_map([], |row| {
+                let directory = row.get::<_, String>(1)?;
+                let url_file = row.get::<_, String>(2)?;
+                Ok((row.get::<_, i64>(0)?, directory, url_file))
+            })?;
+            rows.collect::<rusqlite::Result<Vec<_>>>()?
+        };
+        let mut missing = Vec::<(PathBuf, usize)>::new();
+        for (run, directory, url_file) in runs {
+            // runs of workers and daemons have no url file of their own
+            let url_file = Path::new(&directory).join(url_file);
+            if url_file.exists() || url_file == Path::new(&directory) {
+                continue;
+            }
+            match missing.iter_mut().find(|(path, _)| *path == url_file) {
+                Some((_, count)) => *count += 1,
+                None => missing.push((url_file, 1)),
+            }
+            if !dry_run {
+                tx.execute("DELETE FROM items WHERE run = ?1", [run])?;
+                tx.execute("DELETE FROM runs WHERE id = ?1", [run])?;
+            }
+        }
+        tx.commit()?;
+        Ok(missing)
+    };
+    forget().map_err(|err| err.to_string())
+}
+
+/// Lists the runs started since `since`. With `failed` it prints the items that
+/// failed instead, as url file lines, leaving out those downloaded by a later
+/// run, and appends them to `enqueue` when given. With `usage` it prints the
+/// bytes received per day and month.
+pub fn show(
+    path: &Path,
+    failed: bool,
+    usage: bool,
+    since: Option<i64>,
+    enqueue: Option<&Path>,
+    verbose: bool,
+) -> Result<(), String> {
+    if !path.is_file() {
+        return Err(format!("no history at {}", path.display()));
+    }
+    let conn = open(path)?;
+    let since = since.unwrap_or(0);
+    if usage {
+        return show_usage(&conn, since).map_err(|err| err.to_string());
+    }
+    if failed {
+        let failures = failures(&conn, since).map_err(|err| err.to_string())?;
+        let lines = failures
+            .iter()
+            .map(|(url, path, _)| format!("{} {}", url, path))
+            .collect::<Vec<_>>();
+        match enqueue {
+            Some(url_file) => {
+                let mut file = OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(url_file)
+                    .map_err(|err| format!("failed to open {}: {}", url_file.display(), err))?;
+                let text = lines
+                    .iter()
+                    .map(|line| format!("{}\n", line))
+                    .collect::<String>();
+                file.write_all(text.as_bytes())
+                    .map_err(|err| format!("failed to write {}: {}", url_file.display(), err))?;
+                eprintln!(
+                    "enqueued {} failures in {}",
+                    lines.len(),
+                    url_file.display()
+                );
+            }
+            None => {
+                for ((_, _, error), line) in failures.iter().zip(&lines) {
+                    println!("{}", line);
+                    if verbose {
+                        eprintln!("  {}", error);
+                    }
+                }
+            }
+        }
+        return Ok(());
+    }
+    let mut select = conn
+        .prepare(
+            "SELECT id, started, url_file, downloaded, skipped, failed, bytes, duration_ms, error
+             FROM runs WHERE started >= ?1 ORDER BY started",
+        )
+        .map_err(|err| err.to_string())?;
+    let runs = select
+        .query_map([since], |row| {
+            Ok((
+                row.get::<_, i64>(0)?,
+                row.get::<_, i64>(1)?,
+                row.get::<_, String>(2)?,
+                row.get::<_, u64>(3)?,
+                row.get::<_, u64>(4)?,
+                row.get::<_, u64>(5)?,
+                row.get::<_, u64>(6)?,
+                row.get::<_, u64>(7)?,
+                row.get::<_, Option<String>>(8)?,
+            ))
+        })
+        .and_then(|rows| rows.collect::<rusqlite::Result<Vec<_>>>())
+        .map_err(|err| err.to_string())?;
+    println!(
+        "{:>5}  {:<19}  {:>10}  {:>6} {:>6} {:>6}  {:>10}  URL FILE",
+        "RUN", "STARTED", "TOOK", "DONE", "SKIP", "FAIL", "SIZE"
+    );
+    for (id, started, url_file, downloaded, skipped, failed, bytes, millis, error) in runs {
+        let started = Local
+            .timestamp_opt(started, 0)
+            .single()
+            .map_or("-".to_string(), |time| {
+                time.format("%Y-%m-%d %H:%M:%S").to_string()
+            });
+        let mut line = format!(
+            "{:>5}  {:<19}  {:>10}  {:>6} {:>6} {:>6}  {:>10}  {}",
+            id,
+            started,
+            HumanDuration(Duration::from_millis(millis)).to_string(),
+            downloaded,
+            skipped,
+            failed,
+            HumanBytes(bytes).to_string(),
+            url_file
+        );
+        if let Some(error) = error {
+            line.push_str(&format!(" ({})", error));
+        }
+        println!("{}", line);
+    }
+    Ok(())
+}
+
+/// Prints the bytes received on every day since `since`, each month followed
+/// by its total.
+fn show_usage(conn: &Connection, since: i64) -> rusqlite::Result<()> {
+    let since = Local
+        .timestamp_opt(since, 0)
+        .single()
+        .map_or("-".to_string(), |time| time.format("%Y-%m-%d").to_string());
+    let mut select = conn.prepare("SELECT day, bytes FROM usage WHERE day >= ?1 ORDER BY day")?;
+    let days = select
+        .query_map([since], |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, u64>(1)?))
+        })?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    println!("{:<13}  {:>10}", "DAY", "RECEIVED");
+    let mut month = None::<(String, u64)>;
+    for (day, bytes) in days {
+        let current = day.get(..7).unwrap_or(&day).to_string();
+        if let Some((name, total)) = month.take_if(|(name, _)| *name != current) {
+            let name = format!("{} total", name);
+            println!("{:<13}  {:>10}", name, HumanBytes(total).to_string());
+        }
+        month.get_or_insert((current, 0)).1 += bytes;
+        println!("{:<13}  {:>10}", day, HumanBytes(bytes).to_string());
+    }
+    if let Some((name, total)) = month {
+        let name = format!("{} total", name);
+        println!("{:<13}  {:>10}", name, HumanBytes(total).to_string());
+    }
+    Ok(())
+}
+
+/// The latest failure of every url and path since `since` that no later run
+/// downloaded or skipped. Relative paths are resolved against the directory
+/// of their run.
+fn failures(conn: &Connection, since: i64) -> rusqlite::Result<Vec<(String, String, String)>> {
+    let mut select = conn.prepare(
+        "SELECT items.url, items.path, runs.directory, items.error, MAX(runs.started) AS last
+         FROM items JOIN runs ON items.run = runs.id
+         WHERE items.outcome = 'failed' AND runs.started >= ?1
+           AND NOT EXISTS (
+               SELECT 1 FROM items AS later JOIN runs AS later_run ON later.run = later_run.id
+               WHERE later.url = items.url AND later.path = items.path
+                 AND later.outcome != 'failed' AND later_run.id > runs.id
+           )
+         GROUP BY items.url, items.path
+         ORDER BY last",
+    )?;
+    let rows = select.query_map([since], |row| {
+        let (url, path, directory) = (
+            row.get::<_, String>(0)?,
+            row.get::<_, String>(1)?,
+            row.get::<_, String>(2)?,
+        );
+        let path = Path::new(&directory).join(path);
+        Ok((
+            url,
+            path.to_string_lossy().into_owned(),
+            row.get::<_, Option<String>>(3)?.unwrap_or_default(),
+        ))
+    })?;
+    rows.collect()
+}
+
+fn open(path: &Path) -> Result<Connection, String> {
+    let fail = |err: &dyn std::fmt::Display| format!("{}: {}", path.display(), err);
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        fs::create_dir_all(dir).map_err(|err| fail(&err))?;
+    }
+    let conn = Connection::open(path).map_err(|err| fail(&err))?;
+    conn.execute_batch(SCHEMA).map_err(|err| fail(&err))?;
+    migrate(&conn).map_err(|err| fail(&err))?;
+    Ok(conn)
+}
+
+/// Brings databases written by older versions up to `SCHEMA`.
+fn migrate(conn: &Connection) -> rusqlite::Result<()> {
+    let version = conn.query_row("PRAGMA user_version", [], |row| row.get::<_, i64>(0))?;
+    if version < 1 {
+        let has_sha256 = conn
+            .prepare("SELECT 1 FROM pragma_table_info('items') WHERE name = 'sha256'")?
+            .exists([])?;
+        if !has_sha256 {
+            conn.execute_batch("ALTER TABLE items ADD COLUMN sha256 TEXT")?;
+        }
+        conn.execute_batch("PRAGMA user_version = 1")?;
+    }
+    if version < 2 {
+        let has_manifest = conn
+            .prepare("SELECT 1 FROM pragma_table_info('runs') WHERE name = 'manifest_sha256'")?
+            .exists([])?;
+        if !has_manifest {
+            conn.execute_batch("ALTER TABLE runs ADD COLUMN manifest_sha256 TEXT")?;
+        }
+        conn.execute_batch("PRAGMA user_version = 2")?;
+    }
+    if version < 3 {
+        let has_item_id = conn
+            .prepare("SELECT 1 FROM pragma_table_info('items') WHERE name = 'item_id'")?
+            .exists([])?;
+        if !has_item_id {
+            conn.execute_batch("ALTER TABLE items ADD COLUMN item_id TEXT")?;
+        }
+        conn.execute_batch("PRAGMA user_version = 3")?;
+    }
+    Ok(())
+}
+
+fn unix_seconds(time: SystemTime) -> i64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs() as i64)
+}
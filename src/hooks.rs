@@ -0,0 +1,177 @@
+//! Commands run after each download for `--exec` and `--exec-on-error`, and
+//! when the queue of the daemon drains or gets jobs, for `--on-queue-empty`
+//! and `--on-queue-busy`.
+//!
+//! `{path}`, `{url}`, `{id}` (see [`Image::id`]) and, for error hooks, `{error}`
+//! in the command are replaced with shell quoted values, which are also
+//! available to the command as the `FAST_DOWNLOAD_PATH`, `FAST_DOWNLOAD_URL`,
+//! `FAST_DOWNLOAD_ID` and `FAST_DOWNLOAD_ERROR` environment variables. The
+//! `meta.<key>=` fields of the entry are replaced as `{meta.<key>}` and set as
+//! `FAST_DOWNLOAD_META_<KEY>`, with any character but letters and digits
+//! written as `_`. On Windows the placeholders are replaced with those
+//! variables instead, as `"%FAST_DOWNLOAD_PATH%"`, since cmd can't quote a `%`.
+
+use fast_download::Image;
+use std::{sync::Arc, time::Duration};
+use tokio::{process::Command, sync::Semaphore, task::JoinSet};
+
+pub struct Hooks {
+    on_success: Option<String>,
+    on_error: Option<String>,
+    timeout: Option<Duration>,
+    permits: Arc<Semaphore>,
+    running: JoinSet<()>,
+}
+
+impl Hooks {
+    pub fn new(
+        on_success: Option<String>,
+        on_error: Option<String>,
+        max_running: usize,
+        timeout: Option<Duration>,
+    ) -> Hooks {
+        Hooks {
+            on_success,
+            on_error,
+            timeout,
+            permits: Arc::new(Semaphore::new(max_running.max(1))),
+            running: JoinSet::new(),
+        }
+    }
+
+    pub fn downloaded(&mut self, image: &Image) {
+        if let Some(template) = &self.on_success {
+            let vars = vars(image, None);
+            let command = render(template, &vars);
+            self.spawn(command, vars);
+        }
+    }
+
+    pub fn failed(&mut self, image: &Image, error: &str) {
+        if let Some(template) = &self.on_error {
+            let vars = vars(image, Some(error));
+            let command = render(template, &vars);
+            self.spawn(command, vars);
+        }
+    }
+
+    /// Runs `template` for an event that isn't about one item, with `vars`
+    /// replaced and set like the fields of an item.
+    pub fn event(&mut self, template: &str, vars: Vec<(String, String)>) {
+        let command = render(template, &vars);
+        self.spawn(command, vars);
+    }
+
+    /// Waits for every started hook to exit.
+    pub async fn finish(mut self) {
+        while self.running.join_next().await.is_some() {}
+    }
+
+    fn spawn(&mut self, command: String, vars: Vec<(String, String)>) {
+        let permits = self.permits.clone();
+        let timeout = self.timeout;
+        self.running.spawn(async move {
+            let Ok(_permit) = permits.acquire_owned().await else {
+                return;
+            };
+            let mut child = shell(&command);
+            for (name, value) in vars {
+                child.env(env_name(&name), value);
+            }
+            let status = child.kill_on_drop(true).status();
+            let status = match timeout {
+                Some(timeout) => match tokio::time::timeout(timeout, status).await {
+                    Ok(status) => status,
+                    Err(_) => {
+                        eprintln!("hook timed out after {:?}: {}", timeout, command);
+                        return;
+                    }
+                },
+                None => status.await,
+            };
+            match status {
+                Ok(status) if status.success() => {}
+                Ok(status) => eprintln!("hook exited with {}: {}", status, command),
+                Err(err) => eprintln!("failed to run hook {}: {}", command, err),
+            }
+        });
+    }
+}
+
+fn vars(image: &Image, error: Option<&str>) -> Vec<(String, String)> {
+    let mut vars = vec![
+        ("path".to_string(), image.file_name.clone()),
+        ("url".to_string(), image.url.clone()),
+        ("id".to_string(), image.id()),
+    ];
+    if let Some(error) = error {
+        vars.push(("error".to_string(), error.to_string()));
+    }
+    for (key, value) in &image.meta {
+        vars.push((format!("meta.{}", key), value.clone()));
+    }
+    vars
+}
+
+fn env_name(var: &str) -> String {
+    let name = var
+        .chars()
+        .map(|c| match c.is_ascii_alphanumeric() {
+            true => c.to_ascii_uppercase(),
+            false => '_',
+        })
+        .collect::<String>();
+    format!("FAST_DOWNLOAD_{}", name)
+}
+
+fn render(template: &str, vars: &[(String, String)]) -> String {
+    let mut command = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        command.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let var = vars.iter().find(|(name, _)| {
+            rest[1..].starts_with(name) && rest[1 + name.len()..].starts_with('}')
+        });
+        match var {
+            Some((name, value)) => {
+                command.push_str(&placeholder(name, value));
+                rest = &rest[name.len() + 2..];
+            }
+            None => {
+                command.push('{');
+                rest = &rest[1..];
+            }
+        }
+    }
+    command.push_str(rest);
+    command
+}
+
+#[cfg(unix)]
+fn shell(command: &str) -> Command {
+    let mut child = Command::new("sh");
+    child.arg("-c").arg(command);
+    child
+}
+
+#[cfg(not(unix))]
+fn shell(command: &str) -> Command {
+    let mut child = Command::new("cmd");
+    child.arg("/C").arg(command);
+    child
+}
+
+/// What `{name}` in a command is replaced with: the value, quoted for sh.
+#[cfg(unix)]
+fn placeholder(_name: &str, value: &str) -> String {
+    format!("'{}'", value.replace('\'', r"'\''"))
+}
+
+/// What `{name}` in a command is replaced with. cmd expands `%VAR%` even
+/// within quotes and has no escape for `%` there, so the value stays in its
+/// environment variable, whose own `%`s cmd doesn't expand again.
+#[cfg(not(unix))]
+fn placeholder(name: &str, _value: &str) -> String {
+    format!("\"%{}%\"", env_name(name))
+}
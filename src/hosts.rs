@@ -0,0 +1,122 @@
+//! `--host-stats`: a live panel under the progress bar with the active and
+//! queued items, throughput and failures of every host, to tell which host
+//! holds a mixed batch up.
+
+use fast_download::Image;
+use indicatif::{HumanBytes, MultiProgress, ProgressBar, ProgressStyle};
+use reqwest::Url;
+use std::{
+    collections::{BTreeMap, HashMap},
+    time::Instant,
+};
+
+pub struct HostPanel {
+    multi: MultiProgress,
+    hosts: BTreeMap<String, Host>,
+    /// The host of every running download by id.
+    ids: HashMap<usize, String>,
+}
+
+struct Host {
+    line: ProgressBar,
+    active: usize,
+    queued: usize,
+    bytes: u64,
+    errors: usize,
+    /// When its first download started, which its speed is averaged from.
+    since: Option<Instant>,
+}
+
+impl HostPanel {
+    /// Draws the host lines below the bars of `multi`.
+    pub fn new(multi: MultiProgress) -> HostPanel {
+        HostPanel {
+            multi,
+            hosts: BTreeMap::new(),
+            ids: HashMap::new(),
+        }
+    }
+
+    pub fn queued(&mut self, image: &Image) {
+        let host = self.host(&image.url);
+        host.queued += 1;
+        host.redraw();
+    }
+
+    pub fn started(&mut self, id: usize, image: &Image) {
+        self.ids.insert(id, host_name(&image.url));
+        let host = self.host(&image.url);
+        host.queued = host.queued.saturating_sub(1);
+        host.active += 1;
+        host.since.get_or_insert_with(Instant::now);
+        host.redraw();
+    }
+
+    pub fn received(&mut self, id: usize, bytes: usize) {
+        let Some(host) = self.ids.get(&id).and_then(|name| self.hosts.get_mut(name)) else {
+            return;
+        };
+        host.bytes += bytes as u64;
+        host.redraw();
+    }
+
+    pub fn finished(&mut self, id: usize, failed: bool) {
+        let Some(host) = self
+            .ids
+            .remove(&id)
+            .and_then(|name| self.hosts.get_mut(&name))
+        else {
+            return;
+        };
+        host.active = host.active.saturating_sub(1);
+        host.errors += failed as usize;
+        host.redraw();
+    }
+
+    pub fn clear(&self) {
+        for host in self.hosts.values() {
+            host.line.finish_and_clear();
+        }
+    }
+
+    fn host(&mut self, url: &str) -> &mut Host {
+        let name = host_name(url);
+        let multi = &self.multi;
+        self.hosts.entry(name.clone()).or_insert_with(|| {
+            let line = multi.add(ProgressBar::new_spinner());
+            line.set_style(ProgressStyle::with_template("  {prefix} {msg}").expect("valid"));
+            line.set_prefix(name);
+            Host {
+                line,
+                active: 0,
+                queued: 0,
+                bytes: 0,
+                errors: 0,
+                since: None,
+            }
+        })
+    }
+}
+
+impl Host {
+    fn redraw(&self) {
+        let speed = match self.since {
+            Some(since) => self.bytes as f64 / since.elapsed().as_secs_f64().max(0.001),
+            None => 0.0,
+        };
+        self.line.set_message(format!(
+            "{} active, {} queued, {}/s, {} failed",
+            self.active,
+            self.queued,
+            HumanBytes(speed as u64),
+            self.errors
+        ));
+    }
+}
+
+fn host_name(url: &str) -> String {
+    Url::parse(url)
+        .ok()
+        .and_then(|url| url.host_str().map(str::to_string))
+        .unwrap_or_else(|| "?".to_string())
+}
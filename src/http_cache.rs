@@ -0,0 +1,247 @@
+//! [`Options::http_cache`](crate::Options::http_cache): responses kept across
+//! runs, so a url that many url files share, like a common thumbnail, is only
+//! transferred once.
+//!
+//! Every url has `<sha256 of the url>.json`, with the validators and freshness
+//! of its response, next to its body in `<sha256 of the url>.body`. A body
+//! within its `max-age` is used as is, an older one once the server answers
+//! its validators with `304 Not Modified`. When the bodies outgrow the size of
+//! the cache, those used longest ago are evicted.
+
+use crate::sigv4::hex;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    io,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::{fs, sync::Mutex};
+
+/// Distinguishes the temp files of bodies stored at the same time.
+static NEXT_TEMP: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct Cached {
+    pub url: String,
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+    /// Unix seconds until which the body is used without asking the server.
+    pub fresh_until: Option<u64>,
+    pub size: u64,
+}
+
+impl Cached {
+    /// What to keep of a `200` response to `url`, unless it can't be cached:
+    /// it says `no-store`, or has neither validators nor a `max-age`.
+    pub fn of(url: &str, response: &reqwest::Response) -> Option<Cached> {
+        if response.status() != reqwest::StatusCode::OK {
+            return None;
+        }
+        let header = |name| {
+            response
+                .headers()
+                .get(name)
+                .and_then(|value: &reqwest::header::HeaderValue| value.to_str().ok())
+                .map(str::to_string)
+        };
+        let cache_control = header(reqwest::header::CACHE_CONTROL).unwrap_or_default();
+        if directives(&cache_control).any(|directive| directive == "no-store") {
+            return None;
+        }
+        let cached = Cached {
+            url: url.to_string(),
+            etag: header(reqwest::header::ETAG),
+            last_modified: header(reqwest::header::LAST_MODIFIED),
+            fresh_until: fresh_until(&cache_control),
+            size: 0,
+        };
+        (cached.etag.is_some() || cached.last_modified.is_some() || cached.fresh_until.is_some())
+            .then_some(cached)
+    }
+
+    /// Whether the body may be used without revalidating it.
+    pub fn is_fresh(&self) -> bool {
+        self.fresh_until.is_some_and(|until| until > unix_now())
+    }
+
+    /// The Last-Modified time of the body, for
+    /// [`Options::timestamping`](crate::Options::timestamping).
+    pub fn modified(&self) -> Option<SystemTime> {
+        let last_modified = self.last_modified.as_deref()?;
+        chrono::DateTime::parse_from_rfc2822(last_modified)
+            .ok()
+            .map(SystemTime::from)
+    }
+
+    /// The conditional request headers that revalidate the body.
+    pub fn headers(&self) -> Vec<(reqwest::header::HeaderName, String)> {
+        let mut headers = Vec::new();
+        if let Some(etag) = &self.etag {
+            headers.push((reqwest::header::IF_NONE_MATCH, etag.clone()));
+        }
+        if let Some(last_modified) = &self.last_modified {
+            headers.push((reqwest::header::IF_MODIFIED_SINCE, last_modified.clone()));
+        }
+        headers
+    }
+
+    /// Takes the freshness and validators a `304` sent along.
+    pub fn revalidated(&mut self, response: &reqwest::Response) {
+        let header = |name| {
+            response
+                .headers()
+                .get(name)
+                .and_then(|value: &reqwest::header::HeaderValue| value.to_str().ok())
+        };
+        if let Some(etag) = header(reqwest::header::ETAG) {
+            self.etag = Some(etag.to_string());
+        }
+        self.fresh_until = fresh_until(header(reqwest::header::CACHE_CONTROL).unwrap_or_default());
+    }
+}
+
+pub(crate) struct HttpCache {
+    dir: PathBuf,
+    max_size: u64,
+    /// The bytes of all bodies, `None` until they are first counted, which
+    /// happens when evicting.
+    stored: Mutex<Option<u64>>,
+}
+
+impl HttpCache {
+    pub fn open(dir: &Path, max_size: u64) -> io::Result<HttpCache> {
+        std::fs::create_dir_all(dir)?;
+        Ok(HttpCache {
+            dir: dir.to_path_buf(),
+            max_size,
+            stored: Mutex::new(None),
+        })
+    }
+
+    /// The entry for `url` and its body, if the body is still there.
+    pub async fn get(&self, url: &str) -> Option<(Cached, PathBuf)> {
+        let (entry, body) = self.paths(url);
+        let json = fs::read(entry).await.ok()?;
+        let cached = serde_json::from_slice::<Cached>(&json).ok()?;
+        let meta = fs::metadata(&body).await.ok()?;
+        (cached.url == url && meta.len() == cached.size).then_some((cached, body))
+    }
+
+    /// Marks the body of `url` as just used, so it's evicted last.
+    pub fn touch(&self, url: &str) {
+        let (_, body) = self.paths(url);
+        let _ = std::fs::File::options()
+            .write(true)
+            .open(body)
+            .and_then(|file| file.set_modified(SystemTime::now()));
+    }
+
+    /// Keeps a copy of `file` as the body of `cached`, then evicts bodies if
+    /// the cache is over its size.
+    pub async fn store(&self, mut cached: Cached, file: &Path) -> io::Result<()> {
+        let (_, body) = self.paths(&cached.url);
+        let temp = self.dir.join(format!(
+            "{}.{}.part",
+            std::process::id(),
+            NEXT_TEMP.fetch_add(1, Ordering::Relaxed)
+        ));
+        cached.size = match fs::copy(file, &temp).await {
+            Ok(size) => size,
+            Err(err) => {
+                let _ = fs::remove_file(&temp).await;
+                return Err(err);
+            }
+        };
+        fs::rename(&temp, &body).await?;
+        self.update(&cached).await?;
+        // held while evicting, so stores at the same time don't count apart
+        let mut stored = self.stored.lock().await;
+        *stored = match *stored {
+            Some(total) if total + cached.size <= self.max_size => Some(total + cached.size),
+            _ => {
+                let (dir, max_size) = (self.dir.clone(), self.max_size);
+                let left = tokio::task::spawn_blocking(move || evict(&dir, max_size))
+                    .await
+                    .map_err(io::Error::other)??;
+                Some(left)
+            }
+        };
+        Ok(())
+    }
+
+    /// Writes the entry of `cached`, whose body is already stored.
+    pub async fn update(&self, cached: &Cached) -> io::Result<()> {
+        let (entry, _) = self.paths(&cached.url);
+        let staged = entry.with_extension("json.part");
+        let json = serde_json::to_vec_pretty(cached).expect("entries serialize");
+        fs::write(&staged, json).await?;
+        fs::rename(&staged, &entry).await
+    }
+
+    fn paths(&self, url: &str) -> (PathBuf, PathBuf) {
+        let name = hex(&Sha256::digest(url));
+        (
+            self.dir.join(format!("{}.json", name)),
+            self.dir.join(format!("{}.body", name)),
+        )
+    }
+}
+
+/// Removes the bodies used longest ago, and their entries, until the rest fit
+/// in `max_size`. Returns the bytes left.
+fn evict(dir: &Path, max_size: u64) -> io::Result<u64> {
+    let mut bodies = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path
+            .extension()
+            .is_some_and(|extension| extension == "body")
+        {
+            if let Ok(meta) = std::fs::metadata(&path) {
+                let used = meta.modified().unwrap_or(UNIX_EPOCH);
+                bodies.push((used, meta.len(), path));
+            }
+        }
+    }
+    let mut stored = bodies.iter().map(|(_, size, _)| size).sum::<u64>();
+    bodies.sort();
+    for (_, size, body) in bodies {
+        if stored <= max_size {
+            break;
+        }
+        let _ = std::fs::remove_file(body.with_extension("json"));
+        if std::fs::remove_file(&body).is_ok() {
+            stored -= size;
+        }
+    }
+    Ok(stored)
+}
+
+fn directives(cache_control: &str) -> impl Iterator<Item = String> + '_ {
+    cache_control
+        .split(',')
+        .map(|directive| directive.trim().to_ascii_lowercase())
+}
+
+/// When a response with `cache_control` goes stale, if it may be used without
+/// revalidating it at all.
+fn fresh_until(cache_control: &str) -> Option<u64> {
+    let mut max_age = None;
+    for directive in directives(cache_control) {
+        match directive.split_once('=') {
+            _ if directive == "no-cache" => return None,
+            Some(("max-age", seconds)) => max_age = seconds.trim_matches('"').parse::<u64>().ok(),
+            _ => {}
+        }
+    }
+    max_age.map(|max_age| unix_now() + max_age)
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or(Duration::ZERO)
+        .as_secs()
+}
@@ -0,0 +1,103 @@
+//! `fast_download bench-io`: times the write backends on the disk downloads
+//! go to, and remembers the fastest for `--io-backend auto`.
+
+use crate::cache;
+use fast_download::{benchmark_writes, WriteBackend};
+use std::{
+    path::{Path, PathBuf},
+    process,
+    time::Duration,
+};
+use tokio::fs;
+
+/// The batch `bench-io` writes: many small files, where the backends differ
+/// the most, and a few large ones.
+const RUNS: [(usize, usize); 2] = [(2000, 16 * 1024), (8, 32 << 20)];
+
+/// The smaller batch `--io-backend auto` writes when nothing is saved yet.
+const QUICK_RUNS: [(usize, usize); 2] = [(200, 16 * 1024), (2, 8 << 20)];
+
+/// Benchmarks every backend of this build in `dir`, prints the results and
+/// saves the fastest.
+pub async fn run(dir: &Path, concurrency: usize) -> Result<(), String> {
+    println!(
+        "{:>7}  {:>12}  {:>12}  {:>10}",
+        "BACKEND", "SMALL FILES", "LARGE FILES", "TOTAL"
+    );
+    let best = fastest(dir, concurrency, &RUNS, |backend, times| {
+        let total = times.iter().sum::<Duration>();
+        println!(
+            "{:>7}  {:>12.1?}  {:>12.1?}  {:>10.1?}",
+            backend.name(),
+            times[0],
+            times[1],
+            total
+        );
+    })
+    .await?;
+    save(best)?;
+    println!("fastest: {}, used by --io-backend auto", best.name());
+    Ok(())
+}
+
+/// The saved fastest backend, benchmarked quickly in `dir` and saved first if
+/// there is none.
+pub async fn auto(dir: &Path, concurrency: usize) -> Result<WriteBackend, String> {
+    if let Some(saved) = saved_path()
+        .and_then(|path| std::fs::read_to_string(path).ok())
+        .and_then(|name| WriteBackend::parse(name.trim()))
+        .filter(|backend| backend.is_available())
+    {
+        return Ok(saved);
+    }
+    eprintln!("benchmarking write backends, see `fast_download bench-io`");
+    let best = fastest(dir, concurrency, &QUICK_RUNS, |_, _| {}).await?;
+    save(best)?;
+    Ok(best)
+}
+
+async fn fastest(
+    dir: &Path,
+    concurrency: usize,
+    runs: &[(usize, usize)],
+    mut report: impl FnMut(WriteBackend, &[Duration]),
+) -> Result<WriteBackend, String> {
+    let scratch = dir.join(format!(".fast_download-bench-io-{}", process::id()));
+    let mut best: Option<(WriteBackend, Duration)> = None;
+    for backend in WriteBackend::ALL
+        .into_iter()
+        .filter(|backend| backend.is_available())
+    {
+        let mut times = Vec::new();
+        for &(files, file_size) in runs {
+            let time = benchmark_writes(backend, &scratch, files, file_size, concurrency).await;
+            match time {
+                Ok(time) => times.push(time),
+                Err(err) => {
+                    let _ = fs::remove_dir_all(&scratch).await;
+                    return Err(format!("{} backend failed: {}", backend.name(), err));
+                }
+            }
+        }
+        report(backend, &times);
+        let total = times.iter().sum::<Duration>();
+        if best.is_none_or(|(_, best)| total < best) {
+            best = Some((backend, total));
+        }
+    }
+    let _ = fs::remove_dir_all(&scratch).await;
+    Ok(best.expect("the tokio backend is always available").0)
+}
+
+fn saved_path() -> Option<PathBuf> {
+    cache::default_dir().map(|dir| dir.join("io-backend"))
+}
+
+fn save(backend: WriteBackend) -> Result<(), String> {
+    let path = saved_path().ok_or("no cache directory to save the result in")?;
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent).map_err(|err| err.to_string())?;
+    }
+    std::fs::write(&path, backend.name())
+        .map_err(|err| format!("failed to save {}: {}", path.display(), err))
+}
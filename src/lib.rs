@@ -0,0 +1,998 @@
+//! Concurrent bulk downloading of urls to files.
+//!
+//! A [`Downloader`] downloads [`Image`]s, each a url and the path to save it
+//! to. Applications that want to show their own progress can
+//! [`subscribe`](Downloader::subscribe) to the [`Event`]s of every download
+//! instead of parsing the command line tool's output.
+//!
+//! Downloads started with [`Downloader::submit`] run in the background and
+//! return a [`DownloadHandle`] that can pause, resume or cancel that one
+//! transfer, while cancelling the downloader's
+//! [`cancellation_token`](Downloader::cancellation_token) stops all of them.
+//!
+//! [`Downloader::stream`] downloads a list and yields an [`Outcome`] per item,
+//! either in the order the items were given or as soon as each one finishes.
+//!
+//! ```no_run
+//! # async fn run() -> std::io::Result<()> {
+//! use fast_download::{Downloader, Event, Image, Options, Order};
+//! use futures::StreamExt;
+//!
+//! let downloader = Downloader::new(Options::default())?;
+//! let mut events = downloader.subscribe();
+//! tokio::spawn(async move {
+//!     while let Some(event) = events.recv().await {
+//!         if let Event::ItemFailed { id, error, .. } = event {
+//!             eprintln!("item {} failed: {}", id, error);
+//!         }
+//!     }
+//! });
+//! let images = vec![Image::new("https://example.com/a.jpg", "images/a.jpg")];
+//! let mut outcomes = downloader.stream(images, Order::Completion);
+//! while let Some(outcome) = outcomes.next().await {
+//!     println!("{}: {:?}", outcome.image.file_name, outcome.result);
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use futures::{
+    stream::{self, BoxStream},
+    StreamExt,
+};
+use sha2::{Digest, Sha256};
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+use tokio::{
+    sync::{mpsc, watch, Semaphore},
+    task::JoinHandle,
+};
+use tokio_util::sync::CancellationToken;
+
+mod compress;
+mod download;
+#[cfg(feature = "ffi")]
+mod ffi;
+mod fixtures;
+pub mod glob;
+mod http_cache;
+mod names;
+mod perms;
+mod policy;
+mod profiles;
+#[cfg(feature = "python")]
+mod python;
+mod s3;
+mod share;
+pub mod sigv4;
+mod sniff;
+mod trace;
+mod tree_hash;
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+mod uring;
+mod validators;
+mod warc;
+mod writers;
+mod xattr;
+mod zsync;
+
+pub use compress::{decompressed_digest, Compression};
+pub use download::{
+    benchmark_writes, file_sha256, quarantine, Conflict, DownloadCompleted, DownloadError,
+    DownloadResult, WriteBackend,
+};
+use download::{DirCache, InFlight, IoBackend, MemoryBudget};
+pub use names::Normalization;
+pub use perms::Owner;
+pub use policy::{IpRange, UrlPolicy};
+pub use profiles::HostProfile;
+use profiles::{HostLimit, HostLimits};
+pub use s3::S3Dest;
+pub use trace::{Redirect, RequestRecord, Timings, TlsDetails};
+pub use tree_hash::file_sha256_tree;
+
+/// A url to download and the path to save it to.
+#[derive(Debug, Clone)]
+pub struct Image {
+    pub url: String,
+    pub file_name: String,
+    /// Extra request headers sent with the download.
+    pub headers: Vec<(String, String)>,
+    /// Expected SHA-256 of the file in lowercase hex. A download that doesn't
+    /// match fails, and an existing file is only skipped if it matches.
+    pub sha256: Option<String>,
+    /// Expected SHA-256 tree hash of the file in lowercase hex, checked like
+    /// `sha256` but hashed on every core, see [`file_sha256_tree`].
+    pub sha256_tree: Option<String>,
+    /// Expected size of the file in bytes, checked like `sha256`.
+    pub size: Option<u64>,
+    /// Other urls serving the same file, raced against `url` when
+    /// [`Options::race_mirrors`] is set.
+    pub mirrors: Vec<String>,
+    /// More paths the file is written to, in the same pass as `file_name`.
+    pub copies: Vec<String>,
+    /// The caller's own fields, like a database id, passed along untouched in
+    /// [`Event::ItemStarted`] for it to tell which of its records this is.
+    pub meta: Vec<(String, String)>,
+    /// Labels like `dataset1`, for the caller to pick items or group results
+    /// by; downloads don't look at them.
+    pub tags: Vec<String>,
+    /// Where the item was read from, like `list.txt:4132`, for errors and
+    /// reports to point at.
+    pub source: Option<String>,
+}
+
+impl Image {
+    pub fn new(url: impl Into<String>, file_name: impl Into<String>) -> Image {
+        Image {
+            url: url.into(),
+            file_name: file_name.into(),
+            headers: Vec::new(),
+            sha256: None,
+            sha256_tree: None,
+            size: None,
+            mirrors: Vec::new(),
+            copies: Vec::new(),
+            meta: Vec::new(),
+            tags: Vec::new(),
+            source: None,
+        }
+    }
+
+    /// An id for the item that is the same in every run: the first 16 hex
+    /// digits of the SHA-256 of its url and path, to find the url file line
+    /// behind a log line, an event or a report entry.
+    pub fn id(&self) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(self.url.as_bytes());
+        hasher.update([0]);
+        hasher.update(self.file_name.as_bytes());
+        sigv4::hex(&hasher.finalize()[..8])
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Options {
+    /// How many downloads [`Downloader::stream`] and [`Downloader::submit`]
+    /// run at once.
+    pub max_concurrent_downloads: usize,
+    /// Download files even if they already exist.
+    pub force_redownload: bool,
+    /// fsync completed files and their directories.
+    pub fsync: bool,
+    /// Where in-progress `.part` files are kept, instead of next to the
+    /// destination.
+    pub temp_dir: Option<PathBuf>,
+    /// How files are written to disk.
+    pub io_backend: WriteBackend,
+    /// The number of disk writer threads of [`WriteBackend::Threads`].
+    pub writer_threads: usize,
+    /// How many directories are created at once. Every directory is only
+    /// created once per downloader, by the first of its files, so one removed
+    /// while the downloader runs isn't made again.
+    pub dir_creators: usize,
+    /// How many more times a download that failed on the network is tried.
+    pub retries: u32,
+    /// Emit an [`Event::Request`] with the details of every request.
+    pub record_requests: bool,
+    /// Ask the server whether an existing file changed since it was
+    /// downloaded, via `If-Modified-Since`, instead of skipping it. Downloaded
+    /// files get the server's `Last-Modified` time. Files with a matching
+    /// [`Image::sha256`] are still skipped without asking.
+    pub timestamping: bool,
+    /// Request up to this many of an item's url and [`Image::mirrors`] at
+    /// once, keep the first to send data and cancel the rest. `0` and `1`
+    /// only request `url`.
+    pub race_mirrors: usize,
+    /// When a download gets a response from a url another download is already
+    /// reading, after following redirects, wait for that one and copy its
+    /// file instead of reading the url again, for lists full of short links
+    /// to the same files. If it fails the url is read after all. Uploads,
+    /// compressed and replayed downloads and items with copies aren't
+    /// deduplicated.
+    pub dedup: bool,
+    /// Download the files Google Drive and Dropbox share links are for, rather
+    /// than their preview pages: the links are rewritten to direct download
+    /// urls, and Drive's warning page for files too large to scan for viruses
+    /// is confirmed.
+    pub share_links: bool,
+    /// Which address family connections use. With [`IpFamily::Any`] dual-stack
+    /// hosts are connected to Happy Eyeballs style: the family of the first
+    /// resolved address goes first and the other is tried in parallel after
+    /// 300ms, so a broken IPv6 route costs that much rather than a full
+    /// connect timeout.
+    pub ip_family: IpFamily,
+    /// Gives up connecting after this long. It is shared between the
+    /// addresses of a host, so one unreachable address doesn't use it all up.
+    pub connect_timeout: Option<Duration>,
+    /// How many idle connections to keep per host, unlimited by default.
+    pub pool_max_idle_per_host: Option<usize>,
+    /// How long idle connections are kept open, 90 seconds by default.
+    pub pool_idle_timeout: Option<Duration>,
+    /// Send TCP keepalive probes on idle connections at this interval.
+    pub tcp_keepalive: Option<Duration>,
+    /// Disable Nagle's algorithm, on by default.
+    pub tcp_nodelay: bool,
+    /// The HTTP/2 flow control window of streams and connections in bytes,
+    /// or `None` to let it adapt to the measured bandwidth-delay product.
+    pub http2_window: Option<u32>,
+    /// Speak HTTP/2 without negotiating it and spread downloads over this
+    /// many connections per host, each multiplexing its share of them, for
+    /// batches of small files from one host. Without it every download in
+    /// flight to an HTTP/1.1 host, or to any host before its first connection
+    /// is up, opens a connection of its own. Hosts without HTTP/2 fail.
+    pub http2_connections: Option<usize>,
+    /// Bounds the bytes downloads hold in memory between the network and the
+    /// disk. When writing falls behind, downloads stop reading and the
+    /// servers are slowed down by flow control.
+    pub max_memory: Option<u64>,
+    /// Bytes per second for all downloads together, paced like the `rate` of
+    /// a [`HostProfile`], which still applies on top.
+    pub max_rate: Option<u64>,
+    /// Skip an existing file without `size=` or `sha256=` only if a HEAD
+    /// request reports the size it has, instead of whenever it exists.
+    pub skip_if_size_matches: bool,
+    /// Where the ETag and Last-Modified of downloaded files are kept. With it,
+    /// existing files without `sha256` are revalidated with the server instead
+    /// of skipped, and skipped if it answers 304 Not Modified.
+    pub validators: Option<PathBuf>,
+    /// Where responses are kept across runs, so urls that several url files
+    /// share are transferred once. A kept response is used as is while its
+    /// `Cache-Control: max-age` lasts, and after that if the server answers
+    /// its ETag or Last-Modified with 304 Not Modified. Responses marked
+    /// `no-store` or without either aren't kept, nor are those of resumed,
+    /// compressed, uploaded, archived, recorded or replayed downloads and of
+    /// items with copies.
+    pub http_cache: Option<PathBuf>,
+    /// The bytes [`Options::http_cache`] holds at most; the responses used
+    /// longest ago are evicted first.
+    pub http_cache_size: u64,
+    /// Ignore [`Options::validators`] and download existing files again,
+    /// saving their new validators.
+    pub force_refresh: bool,
+    /// Keep the `.part` file of a download cut off by the network, and
+    /// continue it with a range request on the next attempt or run. The part
+    /// is only continued if the server's strong ETag, or else Last-Modified,
+    /// still matches what it sent when the download started; the download
+    /// starts over otherwise. Such part files aren't preallocated, their
+    /// length is where they are continued from.
+    pub resume: bool,
+    /// Compress files as they are written, to `file_name` with the
+    /// compression's extension added. `size` and `sha256` are still those of
+    /// the uncompressed file, and skipping an existing file that has them
+    /// means decompressing it. Downloads that are compressed aren't resumed.
+    pub compress: Option<Compression>,
+    /// Upload downloads to this bucket and prefix, at the key of their path,
+    /// instead of writing them to disk. Existing objects are skipped unless
+    /// their size differs from `size`; copies, resuming, timestamps and
+    /// validators only apply to files on disk.
+    pub s3: Option<S3Dest>,
+    /// Append every response body read, with its request and headers, to this
+    /// WARC file, gzipped if it ends in `.gz`. Files are written as usual.
+    pub warc: Option<PathBuf>,
+    /// Save the response to every item's url, with its body, in this
+    /// directory for [`Options::replay`].
+    pub record: Option<PathBuf>,
+    /// Answer downloads with the responses saved by [`Options::record`] in
+    /// this directory instead of sending requests. Items without one fail.
+    pub replay: Option<PathBuf>,
+    /// Write the characters Windows doesn't allow in names, like `:` and `?`,
+    /// as this one, so that url files written elsewhere can be downloaded
+    /// as is. Trailing dots and spaces are replaced too, and reserved device
+    /// names like `CON` get it appended. Defaults to `_` on Windows only.
+    pub replace_invalid_chars: Option<char>,
+    /// Write file names in this Unicode form, so a name typed on macOS and
+    /// the same name typed on Linux end up as one file, or in plain ASCII.
+    pub normalize_filenames: Option<Normalization>,
+    /// Give downloaded files this mode, like `0o644`, regardless of the
+    /// umask. Unix only, like [`Options::dir_chmod`] and [`Options::chown`].
+    pub chmod: Option<u32>,
+    /// Give the directories created for downloads this mode. Directories that
+    /// already existed keep theirs.
+    pub dir_chmod: Option<u32>,
+    /// Give downloaded files and the directories created for them to this
+    /// user and group, which usually needs root.
+    pub chown: Option<Owner>,
+    /// Save the url, ETag and content type of every download and when it
+    /// finished in extended attributes of the file, `user.xdg.origin.url` and
+    /// the like, where the file system supports them.
+    pub xattr: bool,
+    /// What to do when an item's path, or one of its copies, is a symlink or
+    /// a directory rather than a file.
+    pub on_conflict: Conflict,
+    /// When an existing file is downloaded again, fetch `<url>.zsync` and
+    /// only download the blocks of the new file that aren't in the old one.
+    /// Without a control file, or if the server ignores ranges, the whole
+    /// file is downloaded. Only applies to files on disk without copies or
+    /// compression.
+    pub zsync: bool,
+    /// Don't hash downloads against their `sha256=` as they arrive, so that
+    /// hashing doesn't hold up the network: a download succeeds once all its
+    /// bytes are written, and the caller checks the file afterwards. Files
+    /// that are compressed or uploaded are still checked while downloading,
+    /// see [`Options::defers_sha256`].
+    pub defer_sha256: bool,
+    /// Move downloads that don't match their `size=` or `sha256=` to their
+    /// path under this directory, with a `.reason` file saying why, instead
+    /// of removing them.
+    pub quarantine: Option<PathBuf>,
+    /// Fail downloads whose body looks like an HTML error page rather than
+    /// the file: sent as `text/html` for a name with an extension, starting
+    /// with `<html` while small, or starting with markup where the extension,
+    /// like `.png` or `.zip`, calls for a binary format. Files named `.html`,
+    /// `.xml` and the like are never rejected. Resumed downloads aren't checked.
+    pub reject_error_pages: bool,
+    /// Fail downloads with a 200 whose first 64 KiB match this, like the
+    /// "page not found" text of a site that doesn't send 404s.
+    pub soft_404: Option<regex::bytes::Regex>,
+    /// Fail downloads whose body has one of these SHA-256s, in lowercase
+    /// hex, like a site's "not found" page. Bodies over 1 MiB aren't hashed.
+    pub soft_404_sha256: Vec<String>,
+    /// Only download urls, mirrors and redirects this allows, for urls that
+    /// come from someone else. Names that resolve to a blocked address are
+    /// never connected to.
+    pub url_policy: Option<UrlPolicy>,
+    /// Limits and headers for the downloads from particular hosts. A download
+    /// waiting for a slot of its host holds its place in
+    /// [`Options::max_concurrent_downloads`].
+    pub host_profiles: Vec<HostProfile>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum IpFamily {
+    #[default]
+    Any,
+    V4,
+    V6,
+}
+
+impl Default for Options {
+    fn default() -> Options {
+        Options {
+            max_concurrent_downloads: 20,
+            force_redownload: false,
+            fsync: false,
+            temp_dir: None,
+            io_backend: WriteBackend::Tokio,
+            writer_threads: 4,
+            dir_creators: 8,
+            retries: 0,
+            record_requests: false,
+            timestamping: false,
+            race_mirrors: 0,
+            dedup: true,
+            share_links: true,
+            ip_family: IpFamily::Any,
+            connect_timeout: None,
+            pool_max_idle_per_host: None,
+            pool_idle_timeout: None,
+            tcp_keepalive: None,
+            tcp_nodelay: true,
+            http2_window: None,
+            http2_connections: None,
+            max_memory: None,
+            max_rate: None,
+            skip_if_size_matches: false,
+            validators: None,
+            http_cache: None,
+            http_cache_size: 1 << 30,
+            force_refresh: false,
+            resume: false,
+            compress: None,
+            s3: None,
+            warc: None,
+            record: None,
+            replay: None,
+            replace_invalid_chars: cfg!(windows).then_some('_'),
+            normalize_filenames: None,
+            chmod: None,
+            dir_chmod: None,
+            chown: None,
+            xattr: false,
+            on_conflict: Conflict::Follow,
+            zsync: false,
+            defer_sha256: false,
+            quarantine: None,
+            reject_error_pages: false,
+            soft_404: None,
+            soft_404_sha256: Vec::new(),
+            url_policy: None,
+            host_profiles: Vec::new(),
+        }
+    }
+}
+
+impl Options {
+    /// Where an item with `file_name` ends up, different with
+    /// [`Options::compress`], [`Options::replace_invalid_chars`] and
+    /// [`Options::normalize_filenames`].
+    pub fn output_path(&self, file_name: &str) -> PathBuf {
+        let normalized = self
+            .normalize_filenames
+            .map(|normalization| normalization.apply(file_name));
+        let file_name = normalized.as_deref().unwrap_or(file_name);
+        let mut path = match self.replace_invalid_chars {
+            Some(replacement) => names::sanitize(file_name, replacement).into(),
+            None => PathBuf::from(file_name).into_os_string(),
+        };
+        if let Some(compression) = self.compress {
+            path.push(compression.extension());
+        }
+        PathBuf::from(path)
+    }
+
+    /// A client for the requests made besides downloads, like the HEADs of
+    /// `check`. It connects like the downloads, and its resolver and redirects
+    /// keep to [`Options::url_policy`]; urls are checked with
+    /// [`UrlPolicy::check`] before they are requested.
+    pub fn client(&self) -> io::Result<reqwest::Client> {
+        client(self)
+    }
+
+    /// Whether downloads with `sha256=` are left for the caller to check, see
+    /// [`Options::defer_sha256`].
+    pub fn defers_sha256(&self) -> bool {
+        self.defer_sha256 && self.compress.is_none() && self.s3.is_none()
+    }
+
+    /// Whether the item with `file_name` was downloaded already: there is a
+    /// file at its [output path](Options::output_path), or at the end of a
+    /// symlink there with [`Conflict::Follow`].
+    pub fn output_exists(&self, file_name: &str) -> bool {
+        let path = self.output_path(file_name);
+        match self.on_conflict {
+            Conflict::Follow => path.is_file(),
+            Conflict::Replace | Conflict::Error => {
+                path.symlink_metadata().is_ok_and(|meta| meta.is_file())
+            }
+        }
+    }
+}
+
+/// Progress of the downloads of a [`Downloader`]. `id` is the id the item was
+/// submitted with, or its index for [`Downloader::stream`].
+#[derive(Debug, Clone)]
+pub enum Event {
+    ItemStarted {
+        id: usize,
+        url: String,
+        file_name: String,
+        /// [`Image::id`] of the item.
+        item_id: String,
+        meta: Vec<(String, String)>,
+    },
+    ChunkReceived {
+        id: usize,
+        bytes: usize,
+        /// The size of the whole file, when the server sent it.
+        total: Option<u64>,
+        /// The bytes were copied from a local file, like the
+        /// [`Options::http_cache`] or the download of another item, instead of
+        /// received over the network.
+        copied: bool,
+    },
+    ItemFinished {
+        id: usize,
+        outcome: DownloadCompleted,
+    },
+    ItemFailed {
+        id: usize,
+        error: String,
+        /// Starts at 1 and counts up with every retry.
+        attempt: u32,
+        will_retry: bool,
+    },
+    /// Sent after every attempt that made a request, when
+    /// [`Options::record_requests`] is set.
+    Request {
+        id: usize,
+        record: Box<RequestRecord>,
+    },
+}
+
+pub struct Downloader {
+    options: Options,
+    /// One client, or one per [`Options::http2_connections`], which
+    /// downloads take turns on.
+    clients: Vec<reqwest::Client>,
+    next_client: AtomicUsize,
+    io: IoBackend,
+    subscribers: Mutex<Vec<mpsc::UnboundedSender<Event>>>,
+    shutdown: CancellationToken,
+    /// Limits submitted downloads to [`Options::max_concurrent_downloads`].
+    permits: Arc<Semaphore>,
+    /// Set by [`Downloader::pause`], holds every download.
+    paused: watch::Sender<bool>,
+    memory: MemoryBudget,
+    warc: Option<Arc<warc::Archive>>,
+    dirs: DirCache,
+    http_cache: Option<Arc<http_cache::HttpCache>>,
+    in_flight: InFlight,
+    hosts: HostLimits,
+    /// Paces all downloads, see [`Options::max_rate`].
+    rate: Option<Arc<HostLimit>>,
+}
+
+/// The order [`Downloader::stream`] yields results in.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Order {
+    /// The order the items were given, holding back results that finish early.
+    Submission,
+    /// As soon as each download finishes.
+    Completion,
+}
+
+/// The result of one item of [`Downloader::stream`].
+#[derive(Debug)]
+pub struct Outcome {
+    /// The index of the item in the input.
+    pub id: usize,
+    pub image: Image,
+    pub result: DownloadResult,
+}
+
+/// Pause and cancel state of one download.
+pub(crate) struct Control {
+    cancel: CancellationToken,
+    paused: watch::Sender<bool>,
+    /// Whether the whole downloader is paused.
+    all_paused: watch::Receiver<bool>,
+    /// Shared with the other downloads of the downloader.
+    pub(crate) memory: MemoryBudget,
+    /// Where responses are archived, see [`Options::warc`].
+    pub(crate) warc: Option<Arc<warc::Archive>>,
+    /// Shared with the other downloads of the downloader.
+    pub(crate) dirs: DirCache,
+    /// See [`Options::http_cache`].
+    pub(crate) http_cache: Option<Arc<http_cache::HttpCache>>,
+    /// Shared with the other downloads of the downloader.
+    pub(crate) in_flight: InFlight,
+    /// The limits of the host of the item, see [`Options::host_profiles`].
+    pub(crate) host: Option<Arc<HostLimit>>,
+    /// Shared with the other downloads of the downloader.
+    pub(crate) rate: Option<Arc<HostLimit>>,
+}
+
+/// A download started with [`Downloader::submit`]. Dropping the handle leaves
+/// the download running in the background.
+pub struct DownloadHandle {
+    id: usize,
+    control: Arc<Control>,
+    task: JoinHandle<DownloadResult>,
+}
+
+impl Control {
+    pub(crate) async fn wait_while_paused(&self) {
+        // either may be paused again while waiting for the other
+        loop {
+            if *self.paused.borrow() {
+                let _ = self.paused.subscribe().wait_for(|paused| !paused).await;
+            } else if *self.all_paused.borrow() {
+                let _ = self.all_paused.clone().wait_for(|paused| !paused).await;
+            } else {
+                return;
+            }
+        }
+    }
+}
+
+impl DownloadHandle {
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    /// Stops the download and removes its partial file. It finishes with
+    /// [`DownloadError::Cancelled`].
+    pub fn cancel(&self) {
+        self.control.cancel.cancel();
+    }
+
+    /// Stops reading the response body until [`resume`](Self::resume). The
+    /// connection stays open, so servers that time out idle connections may
+    /// fail a download paused for long.
+    pub fn pause(&self) {
+        self.control.paused.send_replace(true);
+    }
+
+    pub fn resume(&self) {
+        self.control.paused.send_replace(false);
+    }
+
+    pub fn is_paused(&self) -> bool {
+        *self.control.paused.borrow()
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.task.is_finished()
+    }
+
+    /// Waits for the download to finish.
+    pub async fn join(self) -> DownloadResult {
+        match self.task.await {
+            Ok(result) => result,
+            Err(err) if err.is_panic() => std::panic::resume_unwind(err.into_panic()),
+            Err(_) => Err(DownloadError::Cancelled),
+        }
+    }
+}
+
+impl Downloader {
+    /// Fails if the io backend selected in `options` isn't available, or the
+    /// [`Options::warc`] file or [`Options::http_cache`] can't be opened.
+    pub fn new(options: Options) -> io::Result<Downloader> {
+        Ok(Downloader {
+            io: IoBackend::new(options.io_backend, options.writer_threads)?,
+            clients: (0..options.http2_connections.unwrap_or(1).max(1))
+                .map(|_| client(&options))
+                .collect::<io::Result<_>>()?,
+            next_client: AtomicUsize::new(0),
+            permits: Arc::new(Semaphore::new(options.max_concurrent_downloads.max(1))),
+            memory: MemoryBudget::new(options.max_memory),
+            dirs: DirCache::new(options.dir_creators),
+            in_flight: InFlight::default(),
+            hosts: HostLimits::new(&options.host_profiles),
+            rate: options.max_rate.map(|rate| {
+                Arc::new(HostLimit::new(HostProfile {
+                    rate: Some(rate),
+                    ..HostProfile::new("*")
+                }))
+            }),
+            warc: options
+                .warc
+                .as_deref()
+                .map(warc::Archive::open)
+                .transpose()?,
+            http_cache: options
+                .http_cache
+                .as_deref()
+                .map(|dir| http_cache::HttpCache::open(dir, options.http_cache_size).map(Arc::new))
+                .transpose()?,
+            options,
+            subscribers: Mutex::new(Vec::new()),
+            shutdown: CancellationToken::new(),
+            paused: watch::channel(false).0,
+        })
+    }
+
+    pub fn options(&self) -> &Options {
+        &self.options
+    }
+
+    /// Cancelling this token cancels every running download and makes new ones
+    /// fail with [`DownloadError::Cancelled`].
+    pub fn cancellation_token(&self) -> &CancellationToken {
+        &self.shutdown
+    }
+
+    /// Cancels all downloads, see [`cancellation_token`](Self::cancellation_token).
+    pub fn shutdown(&self) {
+        self.shutdown.cancel();
+    }
+
+    /// Stops reading the response bodies of all downloads, running and new
+    /// ones, until [`resume`](Self::resume), like [`DownloadHandle::pause`].
+    pub fn pause(&self) {
+        self.paused.send_replace(true);
+    }
+
+    pub fn resume(&self) {
+        self.paused.send_replace(false);
+    }
+
+    pub fn is_paused(&self) -> bool {
+        *self.paused.borrow()
+    }
+
+    /// Returns a receiver for the events of all downloads started from now on.
+    pub fn subscribe(&self) -> mpsc::UnboundedReceiver<Event> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        self.subscribers.lock().unwrap().push(tx);
+        rx
+    }
+
+    /// Downloads one image, retrying network failures up to
+    /// [`Options::retries`] times.
+    pub async fn download(&self, id: usize, image: &Image) -> DownloadResult {
+        self.download_counted(id, image).await.0
+    }
+
+    /// Like [`Downloader::download`], also returning the bytes the download
+    /// received, or copied from the cache or another item; 0 unless it
+    /// succeeded.
+    pub async fn download_counted(&self, id: usize, image: &Image) -> (DownloadResult, u64) {
+        let control = self.control(image);
+        let mut received = 0;
+        let result = self.download_with(id, image, &control, &mut received).await;
+        (result, received)
+    }
+
+    /// Starts downloading `image` in the background. At most
+    /// [`Options::max_concurrent_downloads`] submitted downloads run at once,
+    /// the rest wait for a free slot.
+    pub fn submit(self: &Arc<Self>, id: usize, image: Image) -> DownloadHandle {
+        let control = Arc::new(self.control(&image));
+        let downloader = self.clone();
+        let task = tokio::spawn({
+            let control = control.clone();
+            async move {
+                let _permit = tokio::select! {
+                    permit = downloader.permits.clone().acquire_owned() => permit,
+                    _ = control.cancel.cancelled() => return Err(DownloadError::Cancelled),
+                };
+                downloader.download_with(id, &image, &control, &mut 0).await
+            }
+        });
+        DownloadHandle { id, control, task }
+    }
+
+    /// The state of a new download of `image`, shared with the others where
+    /// it's shared.
+    fn control(&self, image: &Image) -> Control {
+        Control {
+            cancel: self.shutdown.child_token(),
+            paused: watch::channel(false).0,
+            all_paused: self.paused.subscribe(),
+            memory: self.memory.clone(),
+            warc: self.warc.clone(),
+            dirs: self.dirs.clone(),
+            http_cache: self.http_cache.clone(),
+            in_flight: self.in_flight.clone(),
+            host: self.hosts.get(&image.url),
+            rate: self.rate.clone(),
+        }
+    }
+
+    /// Downloads `image`, setting `received` to the bytes of the attempt that
+    /// succeeded.
+    async fn download_with(
+        &self,
+        id: usize,
+        image: &Image,
+        control: &Control,
+        received: &mut u64,
+    ) -> DownloadResult {
+        tokio::select! {
+            // dropping the download removes its partial file
+            _ = control.cancel.cancelled() => {}
+            result = self.try_download(id, image, control, received) => return result,
+        }
+        self.emit(|| Event::ItemFailed {
+            id,
+            error: DownloadError::Cancelled.to_string(),
+            attempt: 1,
+            will_retry: false,
+        });
+        Err(DownloadError::Cancelled)
+    }
+
+    async fn try_download(
+        &self,
+        id: usize,
+        image: &Image,
+        control: &Control,
+        received: &mut u64,
+    ) -> DownloadResult {
+        self.emit(|| Event::ItemStarted {
+            id,
+            url: image.url.clone(),
+            file_name: image.file_name.clone(),
+            item_id: image.id(),
+            meta: image.meta.clone(),
+        });
+        let shared;
+        let image = match share::direct(&image.url).filter(|_| self.options.share_links) {
+            Some(url) => {
+                shared = Image {
+                    url,
+                    ..image.clone()
+                };
+                &shared
+            }
+            None => image,
+        };
+        if let Some(policy) = &self.options.url_policy {
+            let urls = std::iter::once(&image.url).chain(&image.mirrors);
+            for url in urls {
+                if let Err(reason) = policy.check(url) {
+                    let err = DownloadError::Blocked {
+                        url: url.clone(),
+                        reason,
+                    };
+                    self.emit(|| Event::ItemFailed {
+                        id,
+                        error: err.to_string(),
+                        attempt: 1,
+                        will_retry: false,
+                    });
+                    return Err(err);
+                }
+            }
+        }
+        let profiled;
+        let image = match &control.host {
+            Some(host) if !host.profile.headers.is_empty() => {
+                profiled = host.profile.apply(image);
+                &profiled
+            }
+            _ => image,
+        };
+        let mut attempt = 1;
+        loop {
+            *received = 0;
+            let on_chunk = |bytes, total, copied| {
+                *received += bytes as u64;
+                self.emit(|| Event::ChunkReceived {
+                    id,
+                    bytes,
+                    total,
+                    copied,
+                })
+            };
+            let mut record = None;
+            let client = self.next_client.fetch_add(1, Ordering::Relaxed) % self.clients.len();
+            let _slot = match &control.host {
+                Some(host) => host.start().await,
+                None => None,
+            };
+            let result = download::download_image(
+                image,
+                &self.options,
+                &self.clients[client],
+                &self.io,
+                control,
+                &mut record,
+                on_chunk,
+            )
+            .await;
+            if let Some(record) = record {
+                self.emit(|| Event::Request {
+                    id,
+                    record: Box::new(record),
+                });
+            }
+            match result {
+                Ok(outcome) => {
+                    self.emit(|| Event::ItemFinished { id, outcome });
+                    return Ok(outcome);
+                }
+                Err(err) => {
+                    let will_retry = err.is_retryable() && attempt <= self.options.retries;
+                    self.emit(|| Event::ItemFailed {
+                        id,
+                        error: err.to_string(),
+                        attempt,
+                        will_retry,
+                    });
+                    if !will_retry {
+                        return Err(err);
+                    }
+                    // back off a little more on every attempt, up to half a minute
+                    tokio::time::sleep(Duration::from_secs(1 << (attempt - 1).min(5))).await;
+                    attempt += 1;
+                }
+            }
+        }
+    }
+
+    /// Downloads `images`, at most [`Options::max_concurrent_downloads`] at
+    /// once, yielding their outcomes in `order`. Images are only taken from the
+    /// iterator as slots free up.
+    pub fn stream<'a, I>(&'a self, images: I, order: Order) -> BoxStream<'a, Outcome>
+    where
+        I: IntoIterator<Item = Image>,
+        I::IntoIter: Send + 'a,
+    {
+        let limit = self.options.max_concurrent_downloads.max(1);
+        let downloads =
+            stream::iter(images.into_iter().enumerate()).map(move |(id, image)| async move {
+                let result = self.download(id, &image).await;
+                Outcome { id, image, result }
+            });
+        match order {
+            Order::Submission => downloads.buffered(limit).boxed(),
+            Order::Completion => downloads.buffer_unordered(limit).boxed(),
+        }
+    }
+
+    /// Downloads all images, returning their results in the same order.
+    pub async fn download_all(&self, images: &[Image]) -> Vec<DownloadResult> {
+        self.stream(images.to_vec(), Order::Submission)
+            .map(|outcome| outcome.result)
+            .collect()
+            .await
+    }
+
+    /// Builds the event only when someone is listening, which keeps
+    /// `ChunkReceived` cheap for callers that don't subscribe.
+    fn emit(&self, event: impl FnOnce() -> Event) {
+        let mut subscribers = self.subscribers.lock().unwrap();
+        if subscribers.is_empty() {
+            return;
+        }
+        let event = event();
+        subscribers.retain(|subscriber| subscriber.send(event.clone()).is_ok());
+    }
+}
+
+fn client(options: &Options) -> io::Result<reqwest::Client> {
+    use reqwest::redirect;
+
+    let mut builder = reqwest::Client::builder();
+    // binding to an unspecified address of one family only connects to
+    // addresses of that family
+    match options.ip_family {
+        IpFamily::Any => {}
+        IpFamily::V4 => builder = builder.local_address(IpAddr::from(Ipv4Addr::UNSPECIFIED)),
+        IpFamily::V6 => builder = builder.local_address(IpAddr::from(Ipv6Addr::UNSPECIFIED)),
+    }
+    if let Some(timeout) = options.connect_timeout {
+        builder = builder.connect_timeout(timeout);
+    }
+    if let Some(max) = options.pool_max_idle_per_host {
+        builder = builder.pool_max_idle_per_host(max);
+    }
+    if let Some(timeout) = options.pool_idle_timeout {
+        builder = builder.pool_idle_timeout(timeout);
+    }
+    if let Some(interval) = options.tcp_keepalive {
+        builder = builder.tcp_keepalive(interval);
+    }
+    builder = builder.tcp_nodelay(options.tcp_nodelay);
+    // an adaptive window could grow past the memory cap
+    let window = options.http2_window.or_else(|| {
+        options.max_memory.map(|max| {
+            (max / options.max_concurrent_downloads.max(1) as u64).clamp(1 << 16, 1 << 24) as u32
+        })
+    });
+    builder = match window {
+        Some(window) => builder
+            .http2_initial_stream_window_size(window)
+            .http2_initial_connection_window_size(window),
+        None => builder.http2_adaptive_window(true),
+    };
+    if options.http2_connections.is_some() {
+        builder = builder.http2_prior_knowledge();
+    }
+    if options.record_requests {
+        builder = trace::instrument(builder);
+    }
+    let timed = options.record_requests.then_some(trace::TimedResolver);
+    builder = match (&options.url_policy, timed) {
+        (Some(policy), timed) => {
+            builder.dns_resolver(Arc::new(policy::GuardedResolver::new(policy, timed)))
+        }
+        (None, Some(timed)) => builder.dns_resolver(Arc::new(timed)),
+        (None, None) => builder,
+    };
+    if options.record_requests || options.url_policy.is_some() {
+        let record = options.record_requests;
+        let policy = options.url_policy.clone();
+        builder = builder.redirect(redirect::Policy::custom(move |attempt| {
+            if record {
+                trace::record_redirect(&attempt);
+            }
+            if let Some(Err(reason)) = policy.as_ref().map(|p| p.check(attempt.url().as_str())) {
+                let url = attempt.url().to_string();
+                return attempt.error(format!(
+                    "refusing to follow a redirect to {}: {}",
+                    url, reason
+                ));
+            }
+            // the same limit as reqwest's default policy
+            if attempt.previous().len() > 10 {
+                attempt.error("too many redirects")
+            } else {
+                attempt.follow()
+            }
+        }));
+    }
+    builder.build().map_err(io::Error::other)
+}
@@ -1,184 +1,2911 @@
-use futures::{stream::FuturesUnordered, StreamExt};
-use indicatif::ProgressBar;
-use reqwest;
+use fast_download::{
+    Compression, Conflict, DownloadCompleted, DownloadError, Downloader, Event, HostProfile, Image,
+    IpFamily, IpRange, Normalization, Options, Owner, S3Dest, UrlPolicy, WriteBackend,
+};
+use futures::{future::BoxFuture, stream::FuturesUnordered, FutureExt, StreamExt};
+use indicatif::{HumanBytes, HumanDuration, MultiProgress, ProgressBar};
 use std::{
-    env,
-    fs::File,
-    io::{BufRead, BufReader},
-    path::PathBuf,
+    collections::{BTreeMap, HashSet, VecDeque},
+    env, fmt,
+    fs::{File, TryLockError},
+    io::{self, BufRead, BufReader, IsTerminal, Read, Write},
+    net::SocketAddr,
+    path::{Component, Path, PathBuf},
+    process::ExitCode,
+    sync::Arc,
+    time::{Duration, Instant},
 };
+use tokio::sync::{mpsc, Semaphore};
+
+mod aria2;
+mod audit;
+mod bench;
+mod browser;
+mod cache;
+mod check;
+mod clean;
+mod completions;
+mod config;
+mod coordinator;
+mod crawl;
+mod cron;
+mod curl;
+mod daemon;
+mod eta;
+mod expand;
+mod export;
+mod har;
+mod history;
+mod hooks;
+mod hosts;
+mod io_bench;
+mod notify;
+mod progress;
+mod proxy;
+mod report;
+mod rewrite;
+mod script;
+mod select;
+mod serve;
+mod signals;
+mod sqs;
+mod sums;
+mod sync;
+mod trace_http;
+mod units;
+mod update;
+mod urls;
+mod verify;
+mod webhook;
+mod window;
+mod worker;
+
+use eta::Eta;
+use har::Har;
+use history::History;
+use hooks::Hooks;
+use hosts::HostPanel;
+use report::Report;
+use rewrite::Rewriter;
+use script::Script;
+use signals::{Signal, Signals};
+use webhook::{Lifecycle, Webhook};
+
+#[derive(Debug, Clone, PartialEq)]
+enum Command {
+    Download,
+    /// HEAD every url and report what a download would fetch.
+    Check,
+    /// Check the downloaded files against the sizes and checksums of the url
+    /// file.
+    Verify,
+    /// Download the url repeatedly to find the fastest concurrency.
+    Bench(String),
+    /// Print the completion script for a shell.
+    Completions(String),
+    /// Serve a directory over HTTP.
+    ServeFiles(PathBuf),
+    /// Run a caching HTTP proxy.
+    Proxy,
+    /// Lease the entries of the url file out to workers.
+    Coordinator,
+    /// Download what a coordinator leases out.
+    Worker,
+    /// Download the jobs posted to a message queue until interrupted.
+    Daemon,
+    /// Replace the binary with the latest release, or with `true` only report
+    /// whether there is one.
+    SelfUpdate(bool),
+    /// List past runs, or their failures.
+    History,
+    /// Time the write backends on the disk of a directory.
+    BenchIo(PathBuf),
+    /// Write the url file lines of a url template and its variables.
+    Expand(String),
+    /// Write the url file lines of the files under a directory index.
+    Crawl(String),
+    /// Write the entries of the url file in another downloader's format.
+    Export,
+    /// Report on the url file without downloading it.
+    Audit,
+    /// Remove stale part files, quarantine entries and history runs under a
+    /// directory.
+    Clean(PathBuf),
+}
+
+/// What a url file holds.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Input {
+    /// `url path` lines.
+    Lines,
+    /// `curl` commands.
+    Curl,
+    /// An aria2 input file.
+    Aria2,
+}
 
 #[derive(Debug)]
 struct Args {
+    command: Command,
     url_file_name: PathBuf,
+    /// What the url file holds, see `--from-curl` and `--aria2`.
+    input: Input,
     ignore_download_errors: bool,
+    /// Fail on invalid url file lines instead of skipping them.
+    strict: bool,
     verbose: bool,
-    force_redownload: bool,
-    max_concurrent_downloads: Option<usize>,
+    options: Options,
+    notify: bool,
+    notify_url: Option<String>,
+    notify_format: Option<webhook::Format>,
+    notify_every: u64,
+    exec: Option<String>,
+    exec_on_error: Option<String>,
+    /// Run when the queue of the daemon drains.
+    on_queue_empty: Option<String>,
+    /// Run when the queue of the daemon gets jobs after being empty.
+    on_queue_busy: Option<String>,
+    exec_jobs: usize,
+    exec_timeout: Option<Duration>,
+    script: Option<PathBuf>,
+    config: Option<PathBuf>,
+    json: bool,
+    /// The `--format` of `export`.
+    export_format: Option<export::Format>,
+    confirm_over: Option<u64>,
+    yes: bool,
+    interactive: bool,
+    quiet: bool,
+    no_progress: bool,
+    report: Option<PathBuf>,
+    trace_http: bool,
+    har: Option<PathBuf>,
+    history: Option<PathBuf>,
+    no_history: bool,
+    incremental: bool,
+    idempotent: bool,
+    repair: bool,
+    discover_sums: bool,
+    listen: Option<SocketAddr>,
+    auth: Option<String>,
+    /// The shared secret of coordinator and workers.
+    token: Option<String>,
+    cache_dir: Option<PathBuf>,
+    queue: Option<String>,
+    lease: Duration,
+    active_hours: Option<window::ActiveHours>,
+    /// Bytes a month may receive, going by the usage in the history.
+    monthly_cap: Option<u64>,
+    deadline: Option<Duration>,
+    /// `--io-backend auto`, resolved once the command is known.
+    auto_io_backend: bool,
+    /// The `--umask` of the process, set once the arguments are all valid.
+    umask: Option<u32>,
+    revalidate: bool,
+    http_cache: bool,
+    start_at: usize,
+    sync: Option<PathBuf>,
+    also_write_to: Vec<PathBuf>,
+    /// The `--vars` and `--var` tables of `expand`, in order.
+    vars: Vec<expand::Table>,
+    /// The path template of `expand`, or the directory of `crawl`.
+    path_template: Option<String>,
+    /// The files `crawl` lists.
+    crawl: crawl::Filter,
+    /// Whether `crawl` lists WebDAV collections.
+    webdav: bool,
+    /// `--tag`s added to every entry.
+    tags: Vec<String>,
+    only_tags: Vec<String>,
+    skip_tags: Vec<String>,
+    dry_run: bool,
+    failed: bool,
+    /// `history --usage`.
+    usage: bool,
+    since: Option<i64>,
+    /// What `clean` counts as stale.
+    older_than: Option<Duration>,
+    enqueue: Option<PathBuf>,
+    progress_template: Option<String>,
+    progress_chars: Option<String>,
+    host_stats: bool,
+    /// Check `sha256=` in a pool of this many workers once files land.
+    verify_workers: Option<usize>,
 }
 
-#[derive(Debug)]
-struct Image {
-    url: String,
-    file_name: String,
+impl Default for Args {
+    fn default() -> Args {
+        Args {
+            command: Command::Download,
+            url_file_name: PathBuf::new(),
+            input: Input::Lines,
+            ignore_download_errors: false,
+            strict: false,
+            verbose: false,
+            options: Options::default(),
+            notify: false,
+            notify_url: None,
+            notify_format: None,
+            notify_every: 10,
+            exec: None,
+            exec_on_error: None,
+            on_queue_empty: None,
+            on_queue_busy: None,
+            exec_jobs: 4,
+            exec_timeout: None,
+            script: None,
+            config: None,
+            json: false,
+            export_format: None,
+            confirm_over: None,
+            yes: false,
+            interactive: false,
+            quiet: false,
+            no_progress: false,
+            report: None,
+            trace_http: false,
+            har: None,
+            history: None,
+            no_history: false,
+            incremental: false,
+            idempotent: false,
+            repair: false,
+            discover_sums: false,
+            listen: None,
+            auth: None,
+            token: None,
+            cache_dir: None,
+            queue: None,
+            lease: coordinator::DEFAULT_LEASE,
+            active_hours: None,
+            monthly_cap: None,
+            deadline: None,
+            auto_io_backend: false,
+            umask: None,
+            revalidate: false,
+            http_cache: false,
+            start_at: 0,
+            sync: None,
+            also_write_to: Vec::new(),
+            vars: Vec::new(),
+            path_template: None,
+            crawl: crawl::Filter {
+                accept: Vec::new(),
+                reject: Vec::new(),
+                depth: None,
+            },
+            webdav: false,
+            tags: Vec::new(),
+            only_tags: Vec::new(),
+            skip_tags: Vec::new(),
+            dry_run: false,
+            failed: false,
+            usage: false,
+            since: None,
+            older_than: None,
+            enqueue: None,
+            progress_template: None,
+            progress_chars: None,
+            host_stats: false,
+            verify_workers: None,
+        }
+    }
 }
 
-#[derive(Debug)]
-enum DownloadCompleted {
-    Success,
-    Skipped,
+/// Outcome counts of a run so far.
+#[derive(Debug, Default)]
+struct Stats {
+    downloaded: usize,
+    /// Of the downloaded files, those whose `sha256=` was checked after they
+    /// landed, with `--verify-workers` only.
+    verified: Option<usize>,
+    skipped: usize,
+    failed: usize,
 }
 
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} downloaded, ", self.downloaded)?;
+        if let Some(verified) = self.verified {
+            write!(f, "{} verified, ", verified)?;
+        }
+        write!(f, "{} skipped, {} failed", self.skipped, self.failed)
+    }
+}
+
+/// How many of the invalid lines `--strict` prints.
+const STRICT_SHOWN: usize = 10;
+
+/// How often a run saves the bytes it received to the usage in the history,
+/// and sees those of other runs.
+const USAGE_SAVE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Errors that abort the whole run.
 #[derive(Debug)]
-enum DownloadError {
-    FailedToCreateParentDirectory,
-    FailedToCreateFile,
-    FailedToDownloadToFile,
-    FailedToConvertResponseToBytes,
-    FailedToGetUrl,
+enum Error {
+    InvalidArgs(String),
+    FailedToReadUrlFile(PathBuf, io::Error),
+    FailedToStartIoBackend(io::Error),
+    FailedToBuildClient(String),
+    FailedToLock(PathBuf, io::Error),
+    /// The lock file, and who holds it when it says.
+    DirectoryInUse(PathBuf, Option<String>),
+    FailedToListenForSignals(io::Error),
+    FailedToLoadScript(PathBuf, String),
+    InvalidConfig(String),
+    Download(Box<Image>, DownloadError),
+    CheckFailed(usize),
+    Audit(String),
+    AuditFailed(usize),
+    Clean(String),
+    VerifyFailed(usize),
+    Verify(String),
+    Serve(String),
+    Proxy(String),
+    Coordinator(String),
+    Worker(String),
+    Daemon(String),
+    Bench(String),
+    IoBench(String),
+    NotConfirmed(u64),
+    Interactive(String),
+    FailedToWriteReport(PathBuf, io::Error),
+    FailedToWriteHar(PathBuf, io::Error),
+    SelfUpdate(String),
+    History(String),
+    Expand(String),
+    Crawl(String),
+    Export(io::Error),
+    FailedToSync(PathBuf, io::Error),
+    DeadlineReached(usize, PathBuf),
+    FailedToWriteRemaining(PathBuf, io::Error),
+    /// `--strict` found these invalid lines.
+    InvalidUrlFile(PathBuf, Vec<String>),
 }
 
-type DownloadResult = Result<DownloadCompleted, DownloadError>;
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::InvalidArgs(msg) => write!(f, "{}", msg),
+            Error::FailedToReadUrlFile(path, err) => {
+                write!(f, "failed to read url file {}: {}", path.display(), err)
+            }
+            Error::FailedToStartIoBackend(err) => write!(f, "failed to start io backend: {}", err),
+            Error::FailedToBuildClient(err) => write!(f, "failed to build http client: {}", err),
+            Error::FailedToLock(path, err) => {
+                write!(f, "failed to lock {}: {}", path.display(), err)
+            }
+            Error::FailedToListenForSignals(err) => {
+                write!(f, "failed to listen for signals: {}", err)
+            }
+            Error::FailedToLoadScript(path, err) => {
+                write!(f, "failed to load script {}: {}", path.display(), err)
+            }
+            Error::InvalidConfig(err) => write!(f, "{}", err),
+            Error::FailedToWriteReport(path, err) => {
+                write!(f, "failed to write report {}: {}", path.display(), err)
+            }
+            Error::FailedToWriteHar(path, err) => {
+                write!(f, "failed to write HAR {}: {}", path.display(), err)
+            }
+            Error::Interactive(err) => write!(f, "failed to pick files: {}", err),
+            Error::Bench(err) => write!(f, "benchmark failed: {}", err),
+            Error::IoBench(err) => write!(f, "write benchmark failed: {}", err),
+            Error::SelfUpdate(err) => write!(f, "self-update failed: {}", err),
+            Error::History(err) => write!(f, "failed to read history: {}", err),
+            Error::Expand(err) => write!(f, "failed to expand: {}", err),
+            Error::Crawl(err) => write!(f, "failed to crawl: {}", err),
+            Error::Export(err) => write!(f, "failed to export: {}", err),
+            Error::FailedToSync(path, err) => {
+                write!(f, "failed to sync {}: {}", path.display(), err)
+            }
+            Error::DeadlineReached(left, path) => write!(
+                f,
+                "deadline reached with {} entries left, written to {}",
+                left,
+                path.display()
+            ),
+            Error::FailedToWriteRemaining(path, err) => {
+                write!(f, "failed to write {}: {}", path.display(), err)
+            }
+            Error::InvalidUrlFile(path, invalid) => {
+                write!(f, "{} invalid lines in {}", invalid.len(), path.display())?;
+                for line in invalid.iter().take(STRICT_SHOWN) {
+                    write!(f, "\n  {}", line)?;
+                }
+                if invalid.len() > STRICT_SHOWN {
+                    write!(f, "\n  and {} more", invalid.len() - STRICT_SHOWN)?;
+                }
+                Ok(())
+            }
+            Error::CheckFailed(failed) => write!(f, "{} urls failed the check", failed),
+            Error::Audit(err) => write!(f, "failed to audit: {}", err),
+            Error::AuditFailed(problems) => write!(f, "the audit found {} problems", problems),
+            Error::Clean(err) => write!(f, "failed to clean: {}", err),
+            Error::VerifyFailed(bad) => write!(f, "{} files are missing or corrupt", bad),
+            Error::Verify(err) => write!(f, "verify failed: {}", err),
+            Error::Serve(err) => write!(f, "failed to serve files: {}", err),
+            Error::Proxy(err) => write!(f, "proxy failed: {}", err),
+            Error::Coordinator(err) => write!(f, "coordinator failed: {}", err),
+            Error::Worker(err) => write!(f, "worker failed: {}", err),
+            Error::Daemon(err) => write!(f, "daemon failed: {}", err),
+            Error::NotConfirmed(total) => write!(
+                f,
+                "not downloading {} without confirmation, pass --yes to skip the prompt",
+                HumanBytes(*total)
+            ),
+            Error::DirectoryInUse(path, holder) => write!(
+                f,
+                "another fast_download run{} is downloading here (lock file {} is held)",
+                holder
+                    .as_ref()
+                    .map_or(String::new(), |holder| format!(" ({})", holder)),
+                path.display()
+            ),
+            Error::Download(image, err) => write!(
+                f,
+                "{}{} url: {} file_name: {} id: {}",
+                located(image),
+                err,
+                image.url,
+                image.file_name,
+                image.id()
+            ),
+        }
+    }
+}
 
 #[tokio::main]
-async fn main() {
-    let args = parse_args().expect("failed to parse args");
-    let images = parse_url_file(&args);
+async fn main() -> ExitCode {
+    match run().await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("error: {}", err);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+async fn run() -> Result<(), Error> {
+    let mut args = match parse_args()? {
+        Some(args) => args,
+        None => return Ok(()),
+    };
+    #[cfg(unix)]
+    if let Some(mask) = args.umask {
+        // SAFETY: umask can't fail, it only swaps the process' mask
+        unsafe {
+            libc::umask(mask as libc::mode_t);
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = args.umask;
+    if let Command::BenchIo(dir) = &args.command {
+        return io_bench::run(dir, args.options.max_concurrent_downloads)
+            .await
+            .map_err(Error::IoBench);
+    }
+    let cache_dir = |flag: &str| {
+        args.cache_dir
+            .clone()
+            .or_else(cache::default_dir)
+            .ok_or_else(|| {
+                Error::InvalidArgs(format!("no cache directory for {}, pass --cache-dir", flag))
+            })
+    };
+    if args.revalidate {
+        args.options.validators = Some(cache_dir("--revalidate")?.join("validators"));
+    }
+    if args.http_cache {
+        args.options.http_cache = Some(cache_dir("--http-cache")?.join("http"));
+    }
+    if matches!(
+        args.command,
+        Command::Download | Command::Worker | Command::Daemon
+    ) {
+        let config = config::load(args.config.as_deref()).map_err(Error::InvalidConfig)?;
+        args.options.host_profiles = host_profiles(&config).map_err(Error::InvalidConfig)?;
+    }
+    if args.auto_io_backend {
+        // measured where part files are written
+        let dir = args.options.temp_dir.clone().unwrap_or_else(|| ".".into());
+        args.options.io_backend = io_bench::auto(&dir, args.options.max_concurrent_downloads)
+            .await
+            .map_err(Error::IoBench)?;
+    }
+    if let Command::Completions(shell) = &args.command {
+        let script = completions::script(shell, &usage("fast_download")).ok_or_else(|| {
+            Error::InvalidArgs(format!(
+                "unknown shell: {}, expected one of {}",
+                shell,
+                completions::SHELLS.join(", ")
+            ))
+        })?;
+        print!("{}", script);
+        return Ok(());
+    }
+    if let Command::Expand(url) = &args.command {
+        return expand::run(
+            url,
+            args.path_template.as_deref(),
+            &args.vars,
+            args.enqueue.as_deref(),
+        )
+        .map_err(Error::Expand);
+    }
+    if let Command::Crawl(url) = &args.command {
+        let client = check::Client::new(&args.options).map_err(Error::FailedToBuildClient)?;
+        return crawl::run(
+            url,
+            &client,
+            args.path_template.as_deref(),
+            &args.crawl,
+            args.webdav,
+            args.options.max_concurrent_downloads,
+            args.enqueue.as_deref(),
+        )
+        .await
+        .map_err(Error::Crawl);
+    }
+    if let Command::SelfUpdate(check_only) = args.command {
+        return update::run(check_only).await.map_err(Error::SelfUpdate);
+    }
+    if let Command::Clean(dir) = &args.command {
+        let history = match args.no_history {
+            true => None,
+            false => Some(history_path(&args)?),
+        };
+        return clean::run(
+            dir,
+            args.options.temp_dir.as_deref(),
+            args.options.quarantine.as_deref(),
+            history.as_deref(),
+            args.older_than.unwrap_or(clean::DEFAULT_AGE),
+            args.dry_run,
+            args.verbose,
+        )
+        .map_err(Error::Clean);
+    }
+    if args.command == Command::History {
+        let path = history_path(&args)?;
+        return history::show(
+            &path,
+            args.failed,
+            args.usage,
+            args.since,
+            args.enqueue.as_deref(),
+            args.verbose,
+        )
+        .map_err(Error::History);
+    }
+    if let Command::ServeFiles(dir) = &args.command {
+        let addr = args
+            .listen
+            .unwrap_or_else(|| serve::DEFAULT_ADDR.parse().expect("valid address"));
+        return serve::run(dir, addr, args.auth.as_deref())
+            .await
+            .map_err(Error::Serve);
+    }
+    if args.command == Command::Proxy {
+        let addr = args
+            .listen
+            .unwrap_or_else(|| proxy::DEFAULT_ADDR.parse().expect("valid address"));
+        let dir = args
+            .cache_dir
+            .clone()
+            .or_else(cache::default_dir)
+            .ok_or_else(|| Error::Proxy("no cache directory, pass --cache-dir".to_string()))?;
+        return proxy::run(&dir, addr).await.map_err(Error::Proxy);
+    }
+    if args.command == Command::Worker {
+        let queue = args
+            .queue
+            .as_ref()
+            .ok_or_else(|| Error::InvalidArgs("worker needs --queue <url>".to_string()))?;
+        let downloader =
+            Downloader::new(args.options.clone()).map_err(Error::FailedToStartIoBackend)?;
+        let stats = worker::run(
+            queue,
+            args.token.as_deref(),
+            &downloader,
+            args.options.max_concurrent_downloads,
+            args.verbose,
+        )
+        .await
+        .map_err(Error::Worker)?;
+        eprintln!("queue done: {}", stats);
+        return Ok(());
+    }
+    if args.command == Command::Daemon {
+        let config = config::load(args.config.as_deref()).map_err(Error::InvalidConfig)?;
+        let schedules = manifest_schedules(config.manifests)?;
+        // the [queues] and [hosts] tables are only read now
+        let reload = || {
+            config::load(args.config.as_deref())
+                .map_err(Error::InvalidConfig)
+                .and_then(|config| manifest_schedules(config.manifests))
+                .map_err(|err| err.to_string())
+        };
+        let script = load_script(&args)?;
+        let rewriter = load_rewriter(&args)?;
+        let downloader =
+            Downloader::new(args.options.clone()).map_err(Error::FailedToStartIoBackend)?;
+        let routes =
+            daemon::Route::all(&config.queues, &args.options).map_err(Error::InvalidConfig)?;
+        let parse = |body: &str, name: &str| {
+            body.lines()
+                .enumerate()
+                .filter_map(|(number, line)| {
+                    let source = format!("{}:{}", name, number + 1);
+                    let (line, queue) = daemon::take_queue_field(line);
+                    parse_line(&line, source, script.as_ref(), &rewriter)
+                        .unwrap_or_else(|err| {
+                            println!("{}", err);
+                            None
+                        })
+                        .map(|image| (image, queue))
+                })
+                .filter_map(|(mut image, queue)| {
+                    tagged(&args, &mut image).then_some((image, queue))
+                })
+                .collect()
+        };
+        let mut events = daemon::QueueEvents {
+            on_empty: args.on_queue_empty.clone(),
+            on_busy: args.on_queue_busy.clone(),
+            hooks: Hooks::new(None, None, args.exec_jobs, args.exec_timeout),
+            webhook: webhook(&args),
+        };
+        let stats = daemon::run(
+            args.queue.as_deref(),
+            schedules,
+            reload,
+            &downloader,
+            &routes,
+            parse,
+            args.options.max_concurrent_downloads,
+            args.lease,
+            &mut events,
+            args.verbose,
+        )
+        .await;
+        events.finish().await;
+        let stats = stats.map_err(Error::Daemon)?;
+        eprintln!("stopped: {}", stats);
+        return Ok(());
+    }
+    if args.command == Command::Coordinator {
+        let images = parse_url_file(
+            &args,
+            load_script(&args)?.as_ref(),
+            &load_rewriter(&args)?,
+            &ProgressBar::hidden(),
+        )?;
+        let addr = args
+            .listen
+            .unwrap_or_else(|| coordinator::DEFAULT_ADDR.parse().expect("valid address"));
+        let n_images = images.len();
+        let failed = coordinator::run(
+            images,
+            addr,
+            args.token.as_deref(),
+            args.lease,
+            args.options.retries,
+            args.verbose,
+        )
+        .await
+        .map_err(Error::Coordinator)?;
+        eprintln!(
+            "{} downloaded by workers, {} failed",
+            n_images - failed.len(),
+            failed.len()
+        );
+        return match failed.len() {
+            0 => Ok(()),
+            failed => Err(Error::Coordinator(format!("{} items failed", failed))),
+        };
+    }
+    if let Command::Bench(url) = &args.command {
+        return bench::run(url, &args.options).await.map_err(Error::Bench);
+    }
+    if args.command == Command::Check {
+        let images = parse_url_file(
+            &args,
+            load_script(&args)?.as_ref(),
+            &load_rewriter(&args)?,
+            &ProgressBar::hidden(),
+        )?;
+        let client = check::Client::new(&args.options).map_err(Error::FailedToBuildClient)?;
+        let failed = check::run(
+            &images,
+            &client,
+            args.options.max_concurrent_downloads,
+            args.json,
+        )
+        .await;
+        return match failed {
+            0 => Ok(()),
+            failed => Err(Error::CheckFailed(failed)),
+        };
+    }
+    if args.command == Command::Audit {
+        let images = parse_url_file(
+            &args,
+            load_script(&args)?.as_ref(),
+            &load_rewriter(&args)?,
+            &ProgressBar::hidden(),
+        )?;
+        let problems = audit::run(&images, &args.options, args.json)
+            .await
+            .map_err(Error::Audit)?;
+        return match problems {
+            0 => Ok(()),
+            problems => Err(Error::AuditFailed(problems)),
+        };
+    }
+    if args.command == Command::Export {
+        let format = args.export_format.ok_or_else(|| {
+            Error::InvalidArgs("export needs --format aria2|curl-config|wget".to_string())
+        })?;
+        let images = parse_url_file(
+            &args,
+            load_script(&args)?.as_ref(),
+            &load_rewriter(&args)?,
+            &ProgressBar::hidden(),
+        )?;
+        return export::run(&images, format).map_err(Error::Export);
+    }
+    if args.command == Command::Verify {
+        let images = parse_url_file(
+            &args,
+            load_script(&args)?.as_ref(),
+            &load_rewriter(&args)?,
+            &ProgressBar::hidden(),
+        )?;
+        let bad = verify::run(
+            &images,
+            &args.options,
+            args.options.max_concurrent_downloads,
+            args.json,
+            args.verbose,
+            args.enqueue.as_deref(),
+        )
+        .await
+        .map_err(Error::Verify)?;
+        return match bad.len() {
+            0 => Ok(()),
+            bad => Err(Error::VerifyFailed(bad)),
+        };
+    }
+    let start = Instant::now();
+    let _lock = lock_downloads(&args)?;
+    let script = load_script(&args)?;
+    let mut rewriter = load_rewriter(&args)?;
+    let hide_stages = args.quiet || args.no_progress;
+    let parsing = progress::stage(hide_stages, "parsing url file", true);
+    let mut images = parse_entries(&args, script.as_ref(), &rewriter, &parsing)?;
+    parsing.finish_and_clear();
+    // --start-at counts positions in the url file, whatever the tags pick
+    let mut skipped = images
+        .drain(..args.start_at.min(images.len()))
+        .collect::<Vec<_>>();
+    skipped.retain_mut(|image| tagged(&args, image));
+    images.retain_mut(|image| tagged(&args, image));
+    // items left out of an interactive pick stay seen, so a reload doesn't add
+    // them back
+    let mut seen = skipped
+        .iter()
+        .chain(&images)
+        .map(|image| (image.url.clone(), image.file_name.clone()))
+        .collect::<HashSet<_>>();
+    // what --sync keeps, copy= and --also-write-to paths too
+    let mut written = skipped
+        .iter()
+        .chain(&images)
+        .flat_map(paths)
+        .collect::<HashSet<_>>();
+    // a url file that can no longer be read just isn't recognized
+    let manifest = history::manifest_sha256(&args.url_file_name).ok();
+    if let (true, Some(manifest)) = (args.idempotent, &manifest) {
+        let reading = progress::stage(hide_stages, "reading history", false);
+        let completed = history_path(&args)
+            .map_err(|err| err.to_string())
+            .and_then(|path| history::completed(&path, manifest))
+            .map_err(Error::History)?;
+        reading.finish_and_clear();
+        let before = images.len();
+        images.retain(|image| !completed.contains(&history::Entry::new(image)));
+        if !args.quiet {
+            eprintln!(
+                "idempotent: {} entries completed by earlier runs of this manifest, {} left",
+                before - images.len(),
+                images.len()
+            );
+        }
+    }
+    if args.incremental {
+        let reading = progress::stage(hide_stages, "reading history", false);
+        let unchanged = history_path(&args)
+            .map_err(|err| err.to_string())
+            .and_then(|path| history::unchanged(&path, &args.url_file_name))
+            .map_err(Error::History)?;
+        reading.finish_and_clear();
+        let before = images.len();
+        images.retain(|image| !unchanged.contains(&history::Entry::new(image)));
+        if !args.quiet {
+            eprintln!(
+                "incremental: {} new or changed entries, {} unchanged",
+                images.len(),
+                before - images.len()
+            );
+        }
+    }
+    if args.discover_sums {
+        let discovering = progress::stage(hide_stages, "discovering checksums", false);
+        let client = check::Client::new(&args.options).map_err(Error::FailedToBuildClient)?;
+        let found = sums::discover(
+            &mut images,
+            &client,
+            args.options.max_concurrent_downloads,
+            &discovering,
+        )
+        .await;
+        discovering.finish_and_clear();
+        if !args.quiet {
+            eprintln!("discover-sums: found checksums for {} entries", found);
+        }
+    }
+    if args.repair {
+        let checking = progress::stage(hide_stages, "checking files", false);
+        let client = check::Client::new(&args.options).map_err(Error::FailedToBuildClient)?;
+        let (repair, ok) = verify::repair(
+            images,
+            &args.options,
+            &client,
+            args.options.max_concurrent_downloads,
+            args.verbose && !args.quiet,
+            &checking,
+        )
+        .await;
+        checking.finish_and_clear();
+        images = repair;
+        if !args.quiet {
+            eprintln!(
+                "repair: {} files are fine, {} to download",
+                ok,
+                images.len()
+            );
+        }
+    }
+    if args.interactive {
+        images = select::pick(images).map_err(Error::Interactive)?;
+    }
+    if args.dry_run {
+        for image in &images {
+            if args.options.force_redownload || !args.options.output_exists(&image.file_name) {
+                println!("download: {}", image.file_name);
+            }
+        }
+        if let Some(root) = &args.sync {
+            for path in sync_extraneous(&args, root, &written)? {
+                println!("delete: {}", path.display());
+            }
+        }
+        return Ok(());
+    }
+    if let Some(threshold) = args.confirm_over {
+        confirm_size(&args, &images, threshold).await?;
+    }
+    let mut usage = match (args.no_history, args.monthly_cap) {
+        (true, Some(_)) => {
+            return Err(Error::InvalidArgs(
+                "--monthly-cap counts in the history, it can't be used with --no-history"
+                    .to_string(),
+            ))
+        }
+        (true, None) => None,
+        (false, cap) => {
+            let opened = match history_path(&args) {
+                Ok(path) => history::Usage::open(&path).await,
+                Err(err) => Err(err.to_string()),
+            };
+            match opened {
+                Ok(usage) => Some(usage),
+                Err(err) if cap.is_some() => return Err(Error::History(err)),
+                // saving the history reports it at the end
+                Err(_) => None,
+            }
+        }
+    };
+    let downloader = Downloader::new(Options {
+        defer_sha256: args.verify_workers.is_some(),
+        ..args.options.clone()
+    })
+    .map_err(Error::FailedToStartIoBackend)?;
+    let mut signals = Signals::new().map_err(Error::FailedToListenForSignals)?;
     let n_images = images.len();
     let mut futures = FuturesUnordered::new();
 
-    let pb = ProgressBar::new(n_images.try_into().unwrap());
-    let max_concurrent_downloads = args.max_concurrent_downloads.unwrap_or(20);
-    for image in images {
-        let fut = async move {
-            match download_image(&image, args.force_redownload).await {
-                Err(err) => {
-                    println!(
-                        "error : {:?} url: {} file_name: {}",
-                        err, image.url, image.file_name
-                    );
-                    if !args.ignore_download_errors {
-                        panic!("exiting due to error");
+    let config = config::load(args.config.as_deref()).map_err(Error::InvalidConfig)?;
+    let eta = Eta::default();
+    let pb = progress::bar(
+        n_images as u64,
+        args.quiet || args.no_progress,
+        args.progress_template.as_deref(),
+        args.progress_chars.as_deref(),
+        &config.progress,
+        &eta,
+    )
+    .map_err(Error::InvalidArgs)?;
+    pb.set_length(n_images as u64);
+    // the host lines go below the bar, and only where it is drawn
+    let (pb, mut hosts) = match args.host_stats && !pb.is_hidden() {
+        true => {
+            let multi = MultiProgress::new();
+            (multi.add(pb), Some(HostPanel::new(multi)))
+        }
+        false => (pb, None),
+    };
+    let mut events = (args.options.record_requests || !pb.is_hidden() || usage.is_some())
+        .then(|| downloader.subscribe());
+    let verbose = args.verbose && !args.quiet;
+    let mut report = Report::default();
+    let mut har = Har::default();
+    let mut history = History::new(manifest);
+    let max_concurrent_downloads = args.options.max_concurrent_downloads;
+    let mut pending = VecDeque::from(images);
+    pending.iter().for_each(|image| eta.queued(image));
+    if let Some(hosts) = &mut hosts {
+        pending.iter().for_each(|image| hosts.queued(image));
+    }
+    // the in-flight items by file name
+    let mut active = BTreeMap::new();
+    let mut stats = Stats::default();
+    // downloads whose sha256= is checked off their download slot
+    let verify_slots = args
+        .verify_workers
+        .filter(|_| downloader.options().defers_sha256())
+        .map(|workers| Arc::new(Semaphore::new(workers.max(1))));
+    if verify_slots.is_some() {
+        stats.verified = Some(0);
+    }
+    let mut verifying = FuturesUnordered::<BoxFuture<Verification>>::new();
+    let (args, downloader) = (&args, &downloader);
+    let webhook = webhook(args);
+    if let Some(webhook) = &webhook {
+        webhook.send(Lifecycle::Started, &stats, n_images as u64, start.elapsed());
+    }
+    let mut hooks = Hooks::new(
+        args.exec.clone(),
+        args.exec_on_error.clone(),
+        args.exec_jobs,
+        args.exec_timeout,
+    );
+    let mut next_progress = args.notify_every;
+    let mut next_id = 0;
+    let deadline = args.deadline.map(|deadline| start + deadline);
+    // how long downloads took so far, to tell if another still fits before the
+    // deadline
+    let (mut finished, mut finished_took) = (0, Duration::ZERO);
+    let mut outside_hours = false;
+    if let Some(hours) = args.active_hours {
+        if !hours.contains(chrono::Local::now()) {
+            outside_hours = true;
+            if !args.quiet {
+                eprintln!(
+                    "outside active hours, waiting until {}",
+                    hours.start().format("%H:%M")
+                );
+            }
+        }
+    }
+    let mut capped = false;
+    if let (Some(cap), Some(usage)) = (args.monthly_cap, &usage) {
+        if usage.month() >= cap {
+            capped = true;
+            if !args.quiet {
+                eprintln!(
+                    "monthly cap of {} reached, waiting until next month",
+                    HumanBytes(cap)
+                );
+            }
+        }
+    }
+    let mut next_usage_save = usage.as_ref().map(|_| Instant::now() + USAGE_SAVE_INTERVAL);
+    let mut result = loop {
+        let out_of_time = deadline.is_some_and(|deadline| {
+            let expected = match finished {
+                0 => Duration::ZERO,
+                finished => finished_took / finished,
+            };
+            Instant::now() + expected >= deadline
+        });
+        if out_of_time && futures.is_empty() && !pending.is_empty() {
+            break Err(write_remaining(args, pending.drain(..)));
+        }
+        while futures.len() < max_concurrent_downloads && !outside_hours && !capped && !out_of_time
+        {
+            let Some(image) = pending.pop_front() else {
+                break;
+            };
+            active.insert(image.file_name.clone(), image.clone());
+            let id = next_id;
+            next_id += 1;
+            eta.started(id, &image);
+            if let Some(hosts) = &mut hosts {
+                hosts.started(id, &image);
+            }
+            futures.push(async move {
+                let started = Instant::now();
+                let (result, bytes) = downloader.download_counted(id, &image).await;
+                (id, image, result, bytes, started.elapsed())
+            });
+        }
+        if futures.is_empty()
+            && verifying.is_empty()
+            && (pending.is_empty() || !(outside_hours || capped))
+        {
+            break Ok(());
+        }
+        let (id, image, result, bytes, took) = tokio::select! {
+            Some(done) = futures.next() => done,
+            _ = sleep_until(deadline) => {
+                // dropping the running downloads below removes their partial
+                // files
+                let left = active.values().cloned().chain(pending.drain(..));
+                break Err(write_remaining(args, left.collect::<Vec<_>>()));
+            }
+            _ = window::next_change(args.active_hours) => {
+                let hours = args.active_hours.expect("only waited for with active hours");
+                let inside = hours.contains(chrono::Local::now());
+                if inside == outside_hours {
+                    outside_hours = !inside;
+                    // paused transfers keep their connections and partial files
+                    if outside_hours {
+                        downloader.pause();
+                    } else if !capped {
+                        downloader.resume();
+                    }
+                    if !args.quiet {
+                        pb.suspend(|| match (outside_hours, capped) {
+                            (true, _) => eprintln!(
+                                "outside active hours, pausing until {}",
+                                hours.start().format("%H:%M")
+                            ),
+                            (false, false) => eprintln!("active hours started, resuming"),
+                            (false, true) => {}
+                        });
                     }
                 }
-                Ok(DownloadCompleted::Skipped) => {
-                    if args.verbose {
-                        println!("skipped: {}", image.file_name);
+                continue;
+            }
+            _ = sleep_until(next_usage_save) => {
+                next_usage_save = Some(Instant::now() + USAGE_SAVE_INTERVAL);
+                let usage = usage.as_mut().expect("only saved with usage");
+                // also reads what other runs received, and when a new month started
+                if let Err(err) = usage.save().await {
+                    pb.suspend(|| eprintln!("failed to save usage: {}", err));
+                }
+                capped = apply_monthly_cap(args, usage, capped, outside_hours, downloader, &pb);
+                continue;
+            }
+            Some((image, bytes, took, verified)) = verifying.next() => {
+                let result = verified.map(|()| DownloadCompleted::Success);
+                history.record(&image, &result, bytes, took);
+                match result {
+                    Ok(_) => {
+                        *stats.verified.get_or_insert(0) += 1;
+                        report.tally(&image, |stats| *stats.verified.get_or_insert(0) += 1);
+                        hooks.downloaded(&image);
+                        if verbose {
+                            pb.suspend(|| println!("verified: {}", image.file_name));
+                        }
+                    }
+                    Err(err) => {
+                        stats.failed += 1;
+                        hooks.failed(&image, &err.to_string());
+                        report.failed(&image, &err.to_string());
+                        report.tally(&image, |stats| stats.failed += 1);
+                        if !args.ignore_download_errors {
+                            break Err(Error::Download(Box::new(image), err));
+                        }
+                        pb.suspend(|| {
+                            println!(
+                                "{}error : {} url: {} file_name: {} id: {}",
+                                located(&image),
+                                err,
+                                image.url,
+                                image.file_name,
+                                image.id()
+                            )
+                        });
                     }
                 }
-                Ok(DownloadCompleted::Success) => {
-                    if args.verbose {
-                        println!("downloaded: {}", image.file_name);
+                continue;
+            }
+            Some(event) = next_event(&mut events) => {
+                match event {
+                    Event::Request { record, .. } => {
+                        if args.trace_http {
+                            pb.suspend(|| eprint!("{}", trace_http::format(&record)));
+                        }
+                        if args.har.is_some() {
+                            har.add(&record);
+                        }
+                        report.request(&record);
+                    }
+                    Event::ChunkReceived {
+                        id,
+                        bytes,
+                        total,
+                        copied,
+                    } => {
+                        eta.received(id, bytes, total);
+                        if let Some(hosts) = &mut hosts {
+                            hosts.received(id, bytes);
+                        }
+                        // only what came over the network counts towards the cap
+                        if let Some(usage) = usage.as_mut().filter(|_| !copied) {
+                            usage.received(bytes as u64);
+                            capped = apply_monthly_cap(
+                                args,
+                                usage,
+                                capped,
+                                outside_hours,
+                                downloader,
+                                &pb,
+                            );
+                        }
                     }
+                    _ => {}
                 }
+                continue;
+            }
+            signal = signals.recv() => {
+                match signal {
+                    Signal::Reload => match load_rewriter(args).and_then(|reloaded| {
+                        rewriter = reloaded;
+                        parse_url_file(args, script.as_ref(), &rewriter, &ProgressBar::hidden())
+                    }) {
+                        Ok(images) => {
+                            let new_images = images
+                                .into_iter()
+                                .filter(|image| {
+                                    seen.insert((image.url.clone(), image.file_name.clone()))
+                                })
+                                .collect::<Vec<_>>();
+                            written.extend(new_images.iter().flat_map(paths));
+                            pb.inc_length(new_images.len() as u64);
+                            if !args.quiet {
+                                pb.suspend(|| {
+                                    eprintln!(
+                                        "reloaded url file: {} new entries",
+                                        new_images.len()
+                                    )
+                                });
+                            }
+                            new_images.iter().for_each(|image| eta.queued(image));
+                            if let Some(hosts) = &mut hosts {
+                                new_images.iter().for_each(|image| hosts.queued(image));
+                            }
+                            pending.extend(new_images);
+                        }
+                        Err(err) => pb.suspend(|| eprintln!("failed to reload: {}", err)),
+                    },
+                    Signal::Status => pb.suspend(|| {
+                        eprintln!(
+                            "status: {} active, {} queued, {}",
+                            active.len(),
+                            pending.len(),
+                            stats
+                        );
+                        for file_name in active.keys() {
+                            eprintln!("  active: {}", file_name);
+                        }
+                    }),
+                }
+                continue;
             }
         };
-        futures.push(fut);
-        if futures.len() > max_concurrent_downloads {
-            futures.next().await.unwrap();
-            pb.inc(1);
+        active.remove(&image.file_name);
+        finished += 1;
+        finished_took += took;
+        pb.inc(1);
+        // checked in the background, recorded once that's done
+        let verify = verify_slots
+            .as_ref()
+            .filter(|_| image.sha256.is_some() && matches!(result, Ok(DownloadCompleted::Success)));
+        if verify.is_none() {
+            history.record(&image, &result, bytes, took);
+        }
+        eta.finished(id, matches!(result, Ok(DownloadCompleted::Success)));
+        if let Some(hosts) = &mut hosts {
+            hosts.finished(id, result.is_err());
+        }
+        match result {
+            Err(err) => {
+                stats.failed += 1;
+                hooks.failed(&image, &err.to_string());
+                report.failed(&image, &err.to_string());
+                report.tally(&image, |stats| stats.failed += 1);
+                if !args.ignore_download_errors {
+                    break Err(Error::Download(Box::new(image), err));
+                }
+                pb.suspend(|| {
+                    println!(
+                        "{}error : {} url: {} file_name: {} id: {}",
+                        located(&image),
+                        err,
+                        image.url,
+                        image.file_name,
+                        image.id()
+                    )
+                });
+            }
+            Ok(DownloadCompleted::Skipped) => {
+                stats.skipped += 1;
+                report.tally(&image, |stats| stats.skipped += 1);
+                if verbose {
+                    pb.suspend(|| println!("skipped: {}", image.file_name));
+                }
+            }
+            Ok(DownloadCompleted::Success) => {
+                stats.downloaded += 1;
+                report.tally(&image, |stats| stats.downloaded += 1);
+                if verbose {
+                    pb.suspend(|| println!("downloaded: {}", image.file_name));
+                }
+                match verify {
+                    Some(slots) => verifying.push(
+                        verify_sha256(slots.clone(), &args.options, image, bytes, took).boxed(),
+                    ),
+                    None => hooks.downloaded(&image),
+                }
+            }
+        }
+        if let Some(webhook) = &webhook {
+            let total = pb.length().unwrap_or(0).max(1);
+            let percent = pb.position() * 100 / total;
+            if args.notify_every > 0 && percent >= next_progress && percent < 100 {
+                webhook.send(Lifecycle::Progress(percent), &stats, total, start.elapsed());
+                next_progress = (percent / args.notify_every + 1) * args.notify_every;
+            }
+        }
+    };
+    // dropping the in-flight downloads removes their partial files
+    drop(futures);
+    if let Some(events) = &mut events {
+        while let Ok(event) = events.try_recv() {
+            match event {
+                Event::Request { record, .. } => {
+                    if args.trace_http {
+                        pb.suspend(|| eprint!("{}", trace_http::format(&record)));
+                    }
+                    if args.har.is_some() {
+                        har.add(&record);
+                    }
+                    report.request(&record);
+                }
+                Event::ChunkReceived {
+                    bytes,
+                    copied: false,
+                    ..
+                } => {
+                    if let Some(usage) = &mut usage {
+                        usage.received(bytes as u64);
+                    }
+                }
+                _ => {}
+            }
         }
     }
-    while futures.len() > 0 {
-        futures.next().await.unwrap();
-        pb.inc(1);
+    if let Some(usage) = &mut usage {
+        if let Err(err) = usage.save().await {
+            pb.suspend(|| eprintln!("failed to save usage: {}", err));
+        }
+    }
+    // a run that stopped early doesn't get to delete anything
+    if let (Some(root), Ok(())) = (&args.sync, &result) {
+        match sync_extraneous(args, root, &written).and_then(|files| {
+            sync::remove(root, &files)
+                .map(|()| files)
+                .map_err(|err| Error::FailedToSync(root.clone(), err))
+        }) {
+            Ok(files) => {
+                if verbose {
+                    for file in &files {
+                        pb.suspend(|| println!("deleted: {}", file.display()));
+                    }
+                }
+                if !args.quiet {
+                    pb.suspend(|| eprintln!("sync: deleted {} files", files.len()));
+                }
+            }
+            Err(err) => result = Err(err),
+        }
+    }
+    let total = pb.length().unwrap_or(0);
+    let elapsed = start.elapsed();
+    if let Some(hosts) = &hosts {
+        hosts.clear();
+    }
+    match &result {
+        Ok(()) => pb.finish_and_clear(),
+        Err(_) => pb.abandon(),
     }
-    pb.finish_and_clear();
+    hooks.finish().await;
+    if let Some(path) = &args.report {
+        let error = result.as_ref().err().map(|err| err.to_string());
+        if let Err(err) = report.write(path, &stats, total, elapsed, error.as_deref()) {
+            fail_or_warn(&mut result, Error::FailedToWriteReport(path.clone(), err));
+        }
+    }
+    if let Some(path) = &args.har {
+        if let Err(err) = har.write(path) {
+            fail_or_warn(&mut result, Error::FailedToWriteHar(path.clone(), err));
+        }
+    }
+    if !args.no_history {
+        let error = result.as_ref().err().map(|err| err.to_string());
+        // the history is a convenience, failing to save it doesn't fail the run
+        if let Err(err) = history_path(args)
+            .map_err(|err| err.to_string())
+            .and_then(|path| {
+                history.save(
+                    &path,
+                    &args.url_file_name,
+                    &stats,
+                    elapsed,
+                    error.as_deref(),
+                )
+            })
+        {
+            pb.suspend(|| eprintln!("failed to save history: {}", err));
+        }
+    }
+    if args.notify {
+        let (summary, body) = match &result {
+            Ok(()) => (
+                "fast_download finished",
+                format!("{} in {}", stats, HumanDuration(elapsed)),
+            ),
+            Err(err) => (
+                "fast_download stopped after a failure",
+                format!("{} in {}\n{}", stats, HumanDuration(elapsed), err),
+            ),
+        };
+        notify::desktop(summary.to_string(), body).await;
+    }
+    if let Some(webhook) = webhook {
+        let event = match &result {
+            Ok(()) => Lifecycle::Finished,
+            Err(err) => Lifecycle::Failed(err.to_string()),
+        };
+        webhook.send(event, &stats, total, elapsed);
+        webhook.finish().await;
+    }
+    result
 }
 
-fn parse_args() -> Option<Args> {
+fn usage(program: &str) -> String {
+    format!(
+        "usage: {0} <url_file_name> [options]
+       {0} --from-curl <file> [options]
+       {0} --aria2 <file> [options]
+       {0} check <url_file_name> [--json] [options]
+       {0} audit <url_file_name> [--json] [options]
+       {0} verify <url_file_name> [--json] [--enqueue <url_file_name>] [options]
+       {0} bench <url> [-c<number>]
+       {0} bench-io [<dir>] [-c<number>]
+       {0} completions <bash|zsh|fish|powershell>
+       {0} serve-files <dir> [--listen <addr>] [--auth <user:password>]
+       {0} proxy [--listen <addr>] [--cache-dir <dir>]
+       {0} coordinator <url_file_name> [--listen <addr>] [--token <secret>]
+                       [--lease <duration>]
+       {0} worker --queue <url> [--token <secret>] [options]
+       {0} daemon [--queue <sqs_queue_url>] [options]
+       {0} self-update [--check]
+       {0} clean [<dir>] [--older-than <duration>] [--temp-dir <dir>] [--quarantine <dir>]
+                 [--dry-run]
+       {0} history [--failed [--enqueue <url_file_name>] | --usage]
+                   [--since <when>]
+       {0} expand <url_template> [--vars <file>] [--var <name=a,b>] [--path <template>]
+                  [--enqueue <url_file_name>]
+       {0} crawl <index_url> [--webdav] [--accept <glob>] [--reject <glob>]
+                 [--depth <n>] [--path <dir>] [--enqueue <url_file_name>]
+       {0} export <url_file_name> --format <aria2|curl-config|wget>
+
+<url_file_name> holds `url path` lines, or is a HAR file or a JSON
+chrome.downloads export. --from-curl <file> in its place reads the curl
+commands of \"Copy as cURL\" instead, one per line, and --aria2 <file> an
+aria2 input file, with its out=, dir= and checksum= options. On unix, SIGHUP
+adds the lines appended to the url file and rereads the [rewrite] rules of the
+config, not its other tables, and SIGUSR1 prints a status snapshot.
+check sends a HEAD request for every url and reports status, size, content
+type and redirects, without downloading anything. audit reports the item count
+and size of the url file per host, urls listed twice, paths written twice and
+hosts that can't be reached, sending a HEAD request only for items without
+size= and one per host, and fails if it finds a problem. verify checks the
+files of the url file exist and match their size= and sha256= fields, offline.
+bench downloads <url> at increasing concurrency, up to -c, and recommends the
+fastest setting.
+bench-io times writing files through each --io-backend in <dir>, the current
+directory by default, and saves the fastest for --io-backend auto.
+serve-files serves the files under <dir> over HTTP, on 0.0.0.0:8000 by default.
+proxy is a caching HTTP proxy on 127.0.0.1:3128, for use through HTTP_PROXY.
+coordinator hands the entries of a url file out to workers on 127.0.0.1:7878,
+which download them; items of workers that stop renewing their lease are
+handed out again, and POST /boost/<id> moves an item to the front of its
+queue. Listening beyond loopback needs --token, which workers send too. daemon downloads the url file lines posted to an SQS queue and deletes
+each message once its files are downloaded and verified, and downloads the
+[[manifest]] url files of the config on their cron schedules; SIGHUP rereads
+only those schedules, and SIGUSR1 prints what is running.
+self-update installs the latest release after verifying its checksum, or with
+--check only reports whether there is one. history lists past runs, or with
+--failed the files that failed and weren't downloaded since, as url file lines,
+or with --usage the bytes downloads received per day.
+clean deletes the .part files under <dir>, the current directory by default,
+and --temp-dir older than --older-than, the .lock files of url files that are
+gone, --quarantine entries older than --older-than, and the history runs of
+url files that no longer exist.
+expand prints the url file lines of <url_template> for every combination of
+the rows of the --vars and --var tables, with {{name}} replaced by the variable
+name. crawl walks the Apache or Nginx directory index at <index_url> and its
+subdirectories and prints the url file lines of the files in them, at the same
+paths; with --webdav <index_url> is a WebDAV collection, listed with PROPFIND.
+export prints the entries of the url file, with their paths and headers, as an
+aria2 input file, a curl config file for curl -K, or a shell script of wget
+commands.
+
+options:
+  -i                ignore download errors
+  --strict          stop before downloading if any url file line is invalid,
+                    instead of skipping it
+  -v                print every downloaded and skipped file
+  -q, --quiet       print only errors, no progress bar
+  --no-progress     don't draw the progress bar
+  --progress-template <template>
+                    draw the progress bar with an indicatif template, e.g.
+                    '{{wide_bar:.cyan/blue}} {{pos}}/{{len}} {{eta}}'
+  --progress-chars <chars>
+                    the characters of the bar from full to empty, e.g. '=> '
+  --no-color        don't color the progress bar, like NO_COLOR=1
+  --host-stats      show the active and queued items, speed and failures of
+                    every host below the progress bar
+  --report <file>   write a JSON summary of the run, with request timings, to
+                    <file>, - for stdout
+  --trace-http      print the headers, redirects, certificate and timings of
+                    every request
+  --har <file>      save every request and response, without bodies, as a HAR
+  --warc <file>     also archive every request and response, with bodies, in
+                    the WARC <file>, gzipped if it ends in .gz
+  --record <dir>    save the response to every url, with its body, in <dir>
+  --replay <dir>    answer downloads with the responses saved by --record in
+                    <dir>, without the network
+  -f                redownload files that already exist
+  -N, --timestamping
+                    redownload existing files only if the server has a newer
+                    version
+  --revalidate      remember the ETag and Last-Modified of files, and download
+                    existing files again only if the server reports a change
+  --force-refresh   with --revalidate, download existing files regardless
+  --http-cache      keep responses across runs and with ETags or max-age reuse
+                    them for the same urls, even in other url files
+  --http-cache-size <size>
+                    the most --http-cache keeps, evicting the responses used
+                    longest ago (default 1GiB)
+  --resume          keep the .part files of downloads cut off by the network and
+                    continue them if the server still has the same file
+  --skip-if-size-matches
+                    skip existing files only if the server reports the same
+                    size, catching truncated files without checksums
+  -c<number>        maximum concurrent downloads (default 20)
+  --verify-workers <n>
+                    count files as downloaded once their bytes land, and check
+                    their sha256= in a pool of <n> workers apart from the
+                    downloads; the summary counts verified files apart
+  --retries <n>     retry downloads that failed on the network or with a 5xx,
+                    408 or 429 status <n> times (default 0), for coordinator
+                    how often failed items are handed out again
+  --io-backend <tokio|std|mmap|uring|threads|auto>
+                    how files are written (default tokio); uring needs Linux and
+                    the io-uring feature, auto uses the fastest found by
+                    bench-io
+  --writer-threads <n>
+                    disk writer threads of --io-backend threads (default 4)
+  --mkdir-jobs <n>  directories created at once, each only once per run
+                    (default 8)
+  --io-uring        same as --io-backend uring
+  --fsync           fsync completed files and their directories
+  --temp-dir <dir>  keep in-progress .part files in <dir>
+  --reject-error-pages
+                    fail downloads that look like an HTML error page, e.g. a
+                    200 text/html response for a .zip
+  --soft-404 <regex>
+                    fail downloads whose first 64KB match <regex>, like the
+                    text of a not found page sent with a 200
+  --soft-404-sha256 <hex>
+                    fail downloads whose body has this sha256, like a known
+                    not found page; repeatable
+  --quarantine <dir>
+                    move downloads that don't match their size= or sha256= to
+                    <dir>, with a .reason file, instead of deleting them
+  --compress zstd   compress files as they are written, to <path>.zst
+  --replace-invalid-chars <char|off>
+                    write characters Windows doesn't allow in names, like : and
+                    ?, as <char> (default _ on Windows, off elsewhere)
+  --normalize-filenames <nfc|nfd|ascii>
+                    write file names in Unicode NFC or NFD form, or as ASCII
+                    without accents
+  --chmod <mode>    give downloaded files the octal <mode>, e.g. 0644
+  --dir-chmod <mode>
+                    give the directories created for downloads <mode>
+  --chown <user:group>
+                    give downloaded files and created directories to
+                    <user:group>, by name or id; usually needs root
+  --umask <mask>    create files and directories with the octal umask <mask>
+  --on-conflict <follow|replace|error>
+                    when a path is a symlink, write to its target (default),
+                    replace it or fail; paths that are directories fail unless
+                    replace finds them empty
+  --zsync           when an existing file is downloaded again, only fetch the
+                    blocks that changed, if the server has <url>.zsync
+  --xattr           save the url, ETag, content type and time of downloads in
+                    extended attributes like user.xdg.origin.url
+  --dest s3://<bucket>/<prefix>/
+                    upload files to S3 at <prefix><path> instead of writing
+                    them to disk, with credentials from AWS_* variables
+  --also-write-to <dir>
+                    write every file to the same path under <dir> too, in the
+                    same pass; repeatable
+  --tag <tag>       add <tag> to every entry, as if it had a tag= field;
+                    repeatable
+  --only-tag <tag>  only download the entries tagged <tag>; repeatable, for
+                    entries with any of the tags
+  --skip-tag <tag>  leave out the entries tagged <tag>; repeatable
+  --notify          show a desktop notification when the run ends
+  --notify-url <url>
+                    POST started/progress/finished/failed events to <url>,
+                    or for daemon queue_busy/queue_empty events
+  --notify-format <json|slack|ntfy>
+                    payload shape for --notify-url (detected from the url)
+  --notify-every <percent>
+                    progress event interval for --notify-url (default 10)
+  --config <file>   read settings such as url rewrite rules from <file>
+  --script <file>   rewrite or drop items with the Rhai fn transform(item) in
+                    <file>
+  --exec <cmd>      run <cmd> after each download, {{path}}, {{url}} and the
+                    {{meta.<key>}} fields of the entry are replaced
+  --exec-on-error <cmd>
+                    run <cmd> after each failure, also replaces {{error}}
+  --on-queue-empty <cmd>
+                    run <cmd> when the queue of daemon drains, {{downloaded}},
+                    {{skipped}} and {{failed}} are the counts since it got jobs
+  --on-queue-busy <cmd>
+                    run <cmd> when the queue of daemon gets jobs after being
+                    empty
+  --exec-jobs <n>   maximum hooks running at once (default 4)
+  --exec-timeout <duration>
+                    kill hooks running longer than this, e.g. 30s or 5m
+  --interactive     pick the files to download from a searchable list
+  --confirm-over <size>
+                    ask before downloading more than <size>, e.g. 10GB
+  -y, --yes         don't ask, for --confirm-over
+  --json            print check and audit results as JSON
+  --history <file>  run history database (default in the user data directory)
+  --no-history      don't save this run to the history
+  --sync <dir>      after downloading, delete the files under <dir> that the url
+                    file doesn't list
+  --dry-run         print what would be downloaded, and deleted by --sync or
+                    clean, without doing either
+  --repair          download only missing files and files that are empty,
+                    truncated or don't match their sha256, asking the server for
+                    the size of files without size= or sha256=
+  --discover-sums   look up the sha256 of entries without sha256= in the
+                    SHA256SUMS of their directory or their <url>.sha256
+  --incremental     download only entries that are new, or whose sha256 changed,
+                    since the last runs of this url file in the history
+  --idempotent      leave out entries an earlier run of the exact same url file
+                    completed, going by the history without checking the files
+  --failed          list failed files, for history
+  --usage           list the bytes received per day and month, for history
+  --since <when>    only runs since a duration ago, date or timestamp, for
+                    history
+  --older-than <duration>
+                    what clean removes, part files and quarantine entries older
+                    than this (default 7d)
+  --listen <addr>   address for serve-files and proxy, e.g. 127.0.0.1:8080
+  --auth <user:password>
+                    require basic auth for serve-files
+  --queue <url>     the coordinator a worker downloads for, e.g.
+                    http://host:7878, or the SQS queue url of daemon
+  --token <secret>  the secret coordinator requires and worker sends, needed
+                    when the coordinator listens beyond loopback
+  --start-at <n>    skip the first <n> entries of the url file
+  -4, -6            only connect over IPv4 or IPv6, instead of racing both
+  --allow-schemes <list>
+                    only download urls with these schemes, e.g. https
+  --allow-host <glob>
+                    only download from hosts matching <glob>, e.g.
+                    '*.example.com', also after redirects; repeatable
+  --deny-host <glob>
+                    never download from hosts matching <glob>; repeatable
+  --block-internal  never connect to loopback, private, link-local or cloud
+                    metadata addresses, for urls that come from a queue
+  --block-ip <cidr> never connect to addresses in <cidr>, e.g. 10.0.0.0/8;
+                    repeatable
+  --connect-timeout <duration>
+                    give up connecting to a host after <duration>
+  --pool-max-idle-per-host <n>
+                    keep at most <n> idle connections per host (default
+                    unlimited)
+  --pool-idle-timeout <duration>
+                    close connections idle for longer (default 90s)
+  --tcp-keepalive <duration>
+                    send TCP keepalives on idle connections at this interval
+  --tcp-nodelay <on|off>
+                    send small writes right away instead of batching them
+                    (default on)
+  --http2-window <size>
+                    fixed HTTP/2 receive window, e.g. 16MB (default adapts to
+                    the link)
+  --max-memory <size>
+                    hold at most <size> of downloaded data in memory, e.g. 512M;
+                    reading slows down when the disk can't keep up
+  --max-rate <rate> download at most <rate> in total, e.g. 10MBps
+  --http2-connections <n>
+                    speak HTTP/2 right away and multiplex the downloads of a
+                    host over <n> connections, for many small files; the host
+                    must support HTTP/2
+  --race-mirrors <n>
+                    request <n> of the url and mirror= urls of an entry at once
+                    and keep the first to send data
+  --no-dedup        read a url again even while another entry that redirects
+                    to it downloads it, instead of copying that entry's file
+  --no-share-links  download Google Drive and Dropbox share links as they are,
+                    instead of the files they share
+  --deadline <duration>
+                    stop starting downloads when they wouldn't finish within
+                    <duration>, abort the rest then, and write the unfinished
+                    entries to <url_file_name>.remaining
+  --active-hours <HH:MM-HH:MM>
+                    only transfer during this daily window, local time, and
+                    pause the running downloads outside it
+  --monthly-cap <size>
+                    pause once this calendar month received <size>, e.g. 1TB,
+                    counting every run in the history, until the next month
+  --lease <duration>
+                    how long a worker may go without renewing an item before
+                    the coordinator hands it out again, for daemon the
+                    visibility timeout of messages (default 60s)
+  --cache-dir <dir> where proxy and --http-cache keep responses and
+                    --revalidate the ETags of files (default in the user cache
+                    directory)
+  --enqueue <file>  append the failed files to the url file <file>, for history
+                    and verify, or the lines of expand and crawl
+  --vars <file>     for expand, a table of variables: a CSV file with a header
+                    row or a .json array of objects; repeatable
+  --var <name=a,b>  for expand, a variable and its values; repeatable
+  --path <template> for expand, the path of every file, with {{name}}
+                    placeholders (default the last segment of its url), for
+                    crawl the directory the tree goes in (default .)
+  --accept <glob>   for crawl, only list files whose name matches <glob>, e.g.
+                    '*.iso'; repeatable
+  --reject <glob>   for crawl, leave out files whose name matches <glob>;
+                    repeatable
+  --webdav          for crawl, list a WebDAV collection, like a Nextcloud share,
+                    instead of an index page
+  --depth <n>       for crawl, walk <n> levels of subdirectories at most
+                    (default all)",
+        program
+    )
+}
+
+/// Returns `None` when only the usage was requested.
+fn parse_args() -> Result<Option<Args>, Error> {
     let args = env::args().collect::<Vec<_>>();
+    let program = args.first().map(String::as_str).unwrap_or("fast_download");
     if args.len() < 2 {
-        return None;
+        return Err(Error::InvalidArgs(usage(program)));
     }
     let first = &args[1];
-    match first.as_str() {
-        "-h" => {
-            println!("usage: {} <url_file_name> [-i] [-v] [-f] [-c<number>]", args[0]);
-            return None;
-        }
-        filename => {
-            let url_file_name = PathBuf::from(filename);
-            if url_file_name.exists() && url_file_name.is_file() {
-                let ignore_download_errors = args.contains(&"-i".to_string());
-                let verbose = args.contains(&"-v".to_string());
-                let force_redownload = args.contains(&"-f".to_string());
-                let max_concurrent_downloads = args
-                    .iter()
-                    .find(|s| s.starts_with("-c"))
-                    .map(|s| s[2..].parse::<usize>().expect("failed to parse -c argument"));
-                return Some(Args {
-                    url_file_name,
-                    ignore_download_errors,
-                    verbose,
-                    force_redownload,
-                    max_concurrent_downloads,
-                });
+    if first == "-h" {
+        println!("{}", usage(program));
+        return Ok(None);
+    }
+    let (command, mut operand) = match first.as_str() {
+        "check" | "verify" | "coordinator" | "bench" | "completions" | "serve-files" | "expand"
+        | "crawl" | "export" | "audit" => {
+            let operand = args
+                .get(2)
+                .ok_or_else(|| Error::InvalidArgs(usage(program)))?;
+            let command = match first.as_str() {
+                "check" => Command::Check,
+                "verify" => Command::Verify,
+                "coordinator" => Command::Coordinator,
+                "bench" => Command::Bench(operand.clone()),
+                "serve-files" => Command::ServeFiles(PathBuf::from(operand)),
+                "expand" => Command::Expand(operand.clone()),
+                "crawl" => Command::Crawl(operand.clone()),
+                "export" => Command::Export,
+                "audit" => Command::Audit,
+                _ => Command::Completions(operand.clone()),
+            };
+            (command, 2)
+        }
+        "history" => (Command::History, 1),
+        "bench-io" => match args.get(2).filter(|arg| !arg.starts_with('-')) {
+            Some(dir) => (Command::BenchIo(PathBuf::from(dir)), 2),
+            None => (Command::BenchIo(PathBuf::from(".")), 1),
+        },
+        "clean" => match args.get(2).filter(|arg| !arg.starts_with('-')) {
+            Some(dir) => (Command::Clean(PathBuf::from(dir)), 2),
+            None => (Command::Clean(PathBuf::from(".")), 1),
+        },
+        "proxy" => (Command::Proxy, 1),
+        "worker" => (Command::Worker, 1),
+        "daemon" => (Command::Daemon, 1),
+        "self-update" => {
+            let check_only = args.get(2).is_some_and(|arg| arg == "--check");
+            (Command::SelfUpdate(check_only), 1 + check_only as usize)
+        }
+        _ => (Command::Download, 1),
+    };
+    let mut url_file_name = PathBuf::new();
+    let mut input = Input::Lines;
+    if matches!(
+        command,
+        Command::Download
+            | Command::Check
+            | Command::Verify
+            | Command::Coordinator
+            | Command::Export
+            | Command::Audit
+    ) {
+        // `--from-curl <file>` and `--aria2 <file>` stand in for the url file
+        let flag = args[operand].clone();
+        input = match flag.as_str() {
+            "--from-curl" => Input::Curl,
+            "--aria2" => Input::Aria2,
+            _ => Input::Lines,
+        };
+        if input != Input::Lines {
+            operand += 1;
+        }
+        let url_file = args
+            .get(operand)
+            .ok_or_else(|| Error::InvalidArgs(format!("missing value for {}", flag)))?;
+        url_file_name = PathBuf::from(url_file);
+        if !(url_file_name.exists() && url_file_name.is_file()) {
+            return Err(Error::InvalidArgs(format!(
+                "invalid url file: {}",
+                args[operand]
+            )));
+        }
+    }
+    let mut parsed = Args {
+        command,
+        url_file_name,
+        input,
+        ..Args::default()
+    };
+    let mut rest = args[operand + 1..].iter();
+    while let Some(arg) = rest.next() {
+        let mut value = || {
+            rest.next()
+                .ok_or_else(|| Error::InvalidArgs(format!("missing value for {}", arg)))
+        };
+        match arg.as_str() {
+            "-i" => parsed.ignore_download_errors = true,
+            "--strict" => parsed.strict = true,
+            "-v" => parsed.verbose = true,
+            "-f" => parsed.options.force_redownload = true,
+            "-N" | "--timestamping" => parsed.options.timestamping = true,
+            "--skip-if-size-matches" => parsed.options.skip_if_size_matches = true,
+            "--revalidate" => parsed.revalidate = true,
+            "--force-refresh" => parsed.options.force_refresh = true,
+            "--http-cache" => parsed.http_cache = true,
+            "--http-cache-size" => {
+                let size = value()?;
+                parsed.options.http_cache_size = units::parse_size(size).ok_or_else(|| {
+                    Error::InvalidArgs(format!("failed to parse --http-cache-size: {}", size))
+                })?;
+            }
+            "--resume" => parsed.options.resume = true,
+            "--dest" => {
+                let dest = value()?;
+                parsed.options.s3 = Some(S3Dest::parse(dest).map_err(Error::InvalidArgs)?);
+            }
+            "--compress" => {
+                let name = value()?;
+                parsed.options.compress = Some(Compression::parse(name).ok_or_else(|| {
+                    Error::InvalidArgs(format!("--compress expects zstd, not {}", name))
+                })?);
+            }
+            "--replace-invalid-chars" => {
+                let replacement = value()?;
+                let mut chars = replacement.chars();
+                parsed.options.replace_invalid_chars = match (chars.next(), chars.next()) {
+                    _ if replacement == "off" => None,
+                    (Some(replacement), None) => Some(replacement),
+                    _ => {
+                        return Err(Error::InvalidArgs(format!(
+                            "--replace-invalid-chars expects a character or off, not {}",
+                            replacement
+                        )))
+                    }
+                };
+            }
+            "--normalize-filenames" => {
+                let name = value()?;
+                parsed.options.normalize_filenames =
+                    Some(Normalization::parse(name).ok_or_else(|| {
+                        Error::InvalidArgs(format!(
+                            "--normalize-filenames expects nfc, nfd or ascii, not {}",
+                            name
+                        ))
+                    })?);
+            }
+            "--on-conflict" => {
+                let policy = value()?;
+                parsed.options.on_conflict = Conflict::parse(policy).ok_or_else(|| {
+                    Error::InvalidArgs(format!(
+                        "--on-conflict expects follow, replace or error, not {}",
+                        policy
+                    ))
+                })?;
+            }
+            "--zsync" => parsed.options.zsync = true,
+            "--xattr" => parsed.options.xattr = true,
+            "--chmod" => parsed.options.chmod = Some(parse_mode("--chmod", value()?)?),
+            "--dir-chmod" => parsed.options.dir_chmod = Some(parse_mode("--dir-chmod", value()?)?),
+            "--chown" => {
+                let owner = value()?;
+                parsed.options.chown = Some(
+                    Owner::parse(owner)
+                        .map_err(|err| Error::InvalidArgs(format!("--chown: {}", err)))?,
+                );
+            }
+            "--umask" => parsed.umask = Some(parse_mode("--umask", value()?)?),
+            "--io-uring" => {
+                parsed.options.io_backend = WriteBackend::Uring;
+                parsed.auto_io_backend = false;
+            }
+            "--mkdir-jobs" => {
+                let jobs = value()?;
+                parsed.options.dir_creators =
+                    jobs.parse().ok().filter(|&jobs| jobs > 0).ok_or_else(|| {
+                        Error::InvalidArgs(format!("failed to parse --mkdir-jobs: {}", jobs))
+                    })?;
+            }
+            "--writer-threads" => {
+                let threads = value()?;
+                parsed.options.writer_threads = threads
+                    .parse()
+                    .ok()
+                    .filter(|&threads| threads > 0)
+                    .ok_or_else(|| {
+                        Error::InvalidArgs(format!("failed to parse --writer-threads: {}", threads))
+                    })?;
+            }
+            "--io-backend" => match value()?.as_str() {
+                "auto" => parsed.auto_io_backend = true,
+                name => {
+                    parsed.options.io_backend = WriteBackend::parse(name).ok_or_else(|| {
+                        Error::InvalidArgs(format!(
+                            "--io-backend expects tokio, std, mmap, uring, threads or auto, not {}",
+                            name
+                        ))
+                    })?;
+                    parsed.auto_io_backend = false;
+                }
+            },
+            "--fsync" => parsed.options.fsync = true,
+            "--retries" => {
+                let retries = value()?;
+                parsed.options.retries = retries.parse().map_err(|_| {
+                    Error::InvalidArgs(format!("failed to parse --retries: {}", retries))
+                })?;
+            }
+            "--notify" => parsed.notify = true,
+            "--json" => parsed.json = true,
+            "--format" => {
+                let format = value()?;
+                parsed.export_format = Some(export::Format::parse(format).ok_or_else(|| {
+                    Error::InvalidArgs(format!(
+                        "--format expects aria2, curl-config or wget, not {}",
+                        format
+                    ))
+                })?);
+            }
+            "--history" => parsed.history = Some(PathBuf::from(value()?)),
+            "--no-history" => parsed.no_history = true,
+            "--no-dedup" => parsed.options.dedup = false,
+            "--no-share-links" => parsed.options.share_links = false,
+            "--incremental" => parsed.incremental = true,
+            "--idempotent" => parsed.idempotent = true,
+            "--repair" => parsed.repair = true,
+            "--discover-sums" => parsed.discover_sums = true,
+            "--listen" => {
+                let addr = value()?;
+                parsed.listen = Some(addr.parse().map_err(|_| {
+                    Error::InvalidArgs(format!("failed to parse --listen: {}", addr))
+                })?);
+            }
+            "--cache-dir" => parsed.cache_dir = Some(PathBuf::from(value()?)),
+            "--queue" => parsed.queue = Some(value()?.clone()),
+            "--start-at" => {
+                let start_at = value()?;
+                parsed.start_at = start_at.parse().map_err(|_| {
+                    Error::InvalidArgs(format!("failed to parse --start-at: {}", start_at))
+                })?;
+            }
+            "--allow-schemes" => {
+                let schemes = value()?;
+                policy(&mut parsed).schemes = schemes
+                    .split(',')
+                    .map(|scheme| scheme.trim().to_ascii_lowercase())
+                    .collect();
+            }
+            "--allow-host" => {
+                let pattern = value()?.to_ascii_lowercase();
+                policy(&mut parsed).allow_hosts.push(pattern);
+            }
+            "--deny-host" => {
+                let pattern = value()?.to_ascii_lowercase();
+                policy(&mut parsed).deny_hosts.push(pattern);
+            }
+            "--block-internal" => policy(&mut parsed).blocked.extend(IpRange::internal()),
+            "--block-ip" => {
+                let cidr = value()?;
+                let range = IpRange::parse(cidr).ok_or_else(|| {
+                    Error::InvalidArgs(format!(
+                        "--block-ip expects an address or a cidr, not {}",
+                        cidr
+                    ))
+                })?;
+                policy(&mut parsed).blocked.push(range);
+            }
+            "-4" => parsed.options.ip_family = IpFamily::V4,
+            "-6" => parsed.options.ip_family = IpFamily::V6,
+            "--connect-timeout" => {
+                let timeout = value()?;
+                parsed.options.connect_timeout = Some(
+                    units::parse_duration(timeout)
+                        .filter(|timeout| !timeout.is_zero())
+                        .ok_or_else(|| {
+                            Error::InvalidArgs(format!(
+                                "failed to parse --connect-timeout: {}",
+                                timeout
+                            ))
+                        })?,
+                );
+            }
+            "--pool-max-idle-per-host" => {
+                let max = value()?;
+                parsed.options.pool_max_idle_per_host = Some(max.parse().map_err(|_| {
+                    Error::InvalidArgs(format!("failed to parse --pool-max-idle-per-host: {}", max))
+                })?);
+            }
+            "--pool-idle-timeout" => {
+                let timeout = value()?;
+                parsed.options.pool_idle_timeout =
+                    Some(units::parse_duration(timeout).ok_or_else(|| {
+                        Error::InvalidArgs(format!(
+                            "failed to parse --pool-idle-timeout: {}",
+                            timeout
+                        ))
+                    })?);
+            }
+            "--tcp-keepalive" => {
+                let interval = value()?;
+                parsed.options.tcp_keepalive = Some(
+                    units::parse_duration(interval)
+                        .filter(|interval| !interval.is_zero())
+                        .ok_or_else(|| {
+                            Error::InvalidArgs(format!(
+                                "failed to parse --tcp-keepalive: {}",
+                                interval
+                            ))
+                        })?,
+                );
+            }
+            "--tcp-nodelay" => {
+                parsed.options.tcp_nodelay = match value()?.as_str() {
+                    "on" => true,
+                    "off" => false,
+                    other => {
+                        return Err(Error::InvalidArgs(format!(
+                            "--tcp-nodelay expects on or off, not {}",
+                            other
+                        )))
+                    }
+                };
+            }
+            "--http2-connections" => {
+                let connections = value()?;
+                parsed.options.http2_connections = Some(
+                    connections
+                        .parse()
+                        .ok()
+                        .filter(|&connections| connections > 0)
+                        .ok_or_else(|| {
+                            Error::InvalidArgs(format!(
+                                "failed to parse --http2-connections: {}",
+                                connections
+                            ))
+                        })?,
+                );
+            }
+            "--http2-window" => {
+                let window = value()?;
+                parsed.options.http2_window = Some(
+                    units::parse_size(window)
+                        .and_then(|window| u32::try_from(window).ok())
+                        .filter(|&window| window > 0)
+                        .ok_or_else(|| {
+                            Error::InvalidArgs(format!(
+                                "failed to parse --http2-window: {}",
+                                window
+                            ))
+                        })?,
+                );
+            }
+            "--max-memory" => {
+                let max = value()?;
+                parsed.options.max_memory = Some(
+                    units::parse_size(max)
+                        .filter(|&max| max > 0)
+                        .ok_or_else(|| {
+                            Error::InvalidArgs(format!("failed to parse --max-memory: {}", max))
+                        })?,
+                );
+            }
+            "--max-rate" => {
+                let rate = value()?;
+                parsed.options.max_rate = Some(
+                    units::parse_rate(rate)
+                        .filter(|&rate| rate > 0)
+                        .ok_or_else(|| {
+                            Error::InvalidArgs(format!("failed to parse --max-rate: {}", rate))
+                        })?,
+                );
+            }
+            "--race-mirrors" => {
+                let race = value()?;
+                parsed.options.race_mirrors = race.parse().map_err(|_| {
+                    Error::InvalidArgs(format!("failed to parse --race-mirrors: {}", race))
+                })?;
+            }
+            "--deadline" => {
+                let deadline = value()?;
+                parsed.deadline = Some(units::parse_duration(deadline).ok_or_else(|| {
+                    Error::InvalidArgs(format!("failed to parse --deadline: {}", deadline))
+                })?);
+            }
+            "--active-hours" => {
+                let hours = value()?;
+                parsed.active_hours = Some(window::ActiveHours::parse(hours).ok_or_else(|| {
+                    Error::InvalidArgs(format!("failed to parse --active-hours: {}", hours))
+                })?);
+            }
+            "--monthly-cap" => {
+                let cap = value()?;
+                parsed.monthly_cap = Some(
+                    units::parse_size(cap)
+                        .filter(|&cap| cap > 0)
+                        .ok_or_else(|| {
+                            Error::InvalidArgs(format!("failed to parse --monthly-cap: {}", cap))
+                        })?,
+                );
+            }
+            "--lease" => {
+                let lease = value()?;
+                parsed.lease = units::parse_duration(lease)
+                    .filter(|lease| !lease.is_zero())
+                    .ok_or_else(|| {
+                        Error::InvalidArgs(format!("failed to parse --lease: {}", lease))
+                    })?;
+            }
+            "--auth" => {
+                let auth = value()?;
+                if !auth.contains(':') {
+                    return Err(Error::InvalidArgs(
+                        "--auth expects <user:password>".to_string(),
+                    ));
+                }
+                parsed.auth = Some(auth.clone());
+            }
+            "--token" => parsed.token = Some(value()?.clone()),
+            "--sync" => parsed.sync = Some(PathBuf::from(value()?)),
+            "--dry-run" => parsed.dry_run = true,
+            "--failed" => parsed.failed = true,
+            "--usage" => parsed.usage = true,
+            "--since" => {
+                let since = value()?;
+                parsed.since = Some(history::parse_since(since).ok_or_else(|| {
+                    Error::InvalidArgs(format!("failed to parse --since: {}", since))
+                })?);
+            }
+            "--older-than" => {
+                let age = value()?;
+                parsed.older_than = Some(units::parse_duration(age).ok_or_else(|| {
+                    Error::InvalidArgs(format!("failed to parse --older-than: {}", age))
+                })?);
+            }
+            "--enqueue" => parsed.enqueue = Some(PathBuf::from(value()?)),
+            "--yes" | "-y" => parsed.yes = true,
+            "--interactive" => parsed.interactive = true,
+            "-q" | "--quiet" => parsed.quiet = true,
+            "--no-progress" => parsed.no_progress = true,
+            "--progress-template" => parsed.progress_template = Some(value()?.clone()),
+            "--progress-chars" => parsed.progress_chars = Some(value()?.clone()),
+            "--no-color" => progress::disable_colors(),
+            "--host-stats" => parsed.host_stats = true,
+            "--verify-workers" => {
+                let workers = value()?;
+                parsed.verify_workers = Some(
+                    workers
+                        .parse()
+                        .ok()
+                        .filter(|&workers| workers > 0)
+                        .ok_or_else(|| {
+                            Error::InvalidArgs(format!(
+                                "failed to parse --verify-workers: {}",
+                                workers
+                            ))
+                        })?,
+                );
+            }
+            "--trace-http" => {
+                parsed.trace_http = true;
+                parsed.options.record_requests = true;
+            }
+            "--har" => {
+                parsed.har = Some(PathBuf::from(value()?));
+                parsed.options.record_requests = true;
+            }
+            "--record" => parsed.options.record = Some(PathBuf::from(value()?)),
+            "--replay" => parsed.options.replay = Some(PathBuf::from(value()?)),
+            "--warc" => {
+                parsed.options.warc = Some(PathBuf::from(value()?));
+                // for the request headers of the archived requests
+                parsed.options.record_requests = true;
+            }
+            "--report" => {
+                parsed.report = Some(PathBuf::from(value()?));
+                parsed.options.record_requests = true;
+            }
+            "--confirm-over" => {
+                let size = value()?;
+                parsed.confirm_over = Some(units::parse_size(size).ok_or_else(|| {
+                    Error::InvalidArgs(format!("failed to parse --confirm-over: {}", size))
+                })?);
+            }
+            "--config" => parsed.config = Some(PathBuf::from(value()?)),
+            "--script" => parsed.script = Some(PathBuf::from(value()?)),
+            "--exec" => parsed.exec = Some(value()?.clone()),
+            "--exec-on-error" => parsed.exec_on_error = Some(value()?.clone()),
+            "--on-queue-empty" => parsed.on_queue_empty = Some(value()?.clone()),
+            "--on-queue-busy" => parsed.on_queue_busy = Some(value()?.clone()),
+            "--exec-jobs" => {
+                let jobs = value()?;
+                parsed.exec_jobs = jobs.parse().map_err(|_| {
+                    Error::InvalidArgs(format!("failed to parse --exec-jobs: {}", jobs))
+                })?;
+            }
+            "--exec-timeout" => {
+                let timeout = value()?;
+                parsed.exec_timeout = Some(units::parse_duration(timeout).ok_or_else(|| {
+                    Error::InvalidArgs(format!("failed to parse --exec-timeout: {}", timeout))
+                })?);
+            }
+            "--notify-url" => parsed.notify_url = Some(value()?.clone()),
+            "--notify-format" => {
+                let format = value()?;
+                parsed.notify_format = Some(webhook::Format::parse(format).ok_or_else(|| {
+                    Error::InvalidArgs(format!("unknown notification format: {}", format))
+                })?);
+            }
+            "--notify-every" => {
+                let every = value()?;
+                parsed.notify_every = every.parse().map_err(|_| {
+                    Error::InvalidArgs(format!("failed to parse --notify-every: {}", every))
+                })?;
+            }
+            "--reject-error-pages" => parsed.options.reject_error_pages = true,
+            "--soft-404" => {
+                let signature = value()?;
+                parsed.options.soft_404 =
+                    Some(regex::bytes::Regex::new(signature).map_err(|err| {
+                        Error::InvalidArgs(format!("invalid --soft-404: {}", err))
+                    })?);
+            }
+            "--soft-404-sha256" => {
+                let sha256 = value()?.to_ascii_lowercase();
+                if sha256.len() != 64 || !sha256.bytes().all(|b| b.is_ascii_hexdigit()) {
+                    return Err(Error::InvalidArgs(format!(
+                        "--soft-404-sha256 expects a sha256 in hex, not {}",
+                        sha256
+                    )));
+                }
+                parsed.options.soft_404_sha256.push(sha256);
+            }
+            "--quarantine" => parsed.options.quarantine = Some(PathBuf::from(value()?)),
+            "--temp-dir" => parsed.options.temp_dir = Some(PathBuf::from(value()?)),
+            "--also-write-to" => parsed.also_write_to.push(PathBuf::from(value()?)),
+            "--vars" => parsed
+                .vars
+                .push(expand::Table::load(Path::new(value()?)).map_err(Error::InvalidArgs)?),
+            "--var" => {
+                let spec = value()?;
+                parsed.vars.push(expand::Table::inline(spec).ok_or_else(|| {
+                    Error::InvalidArgs(format!("--var expects name=a,b,c, not {}", spec))
+                })?);
+            }
+            "--path" => parsed.path_template = Some(value()?.to_string()),
+            "--webdav" => parsed.webdav = true,
+            "--accept" => parsed.crawl.accept.push(value()?.to_string()),
+            "--reject" => parsed.crawl.reject.push(value()?.to_string()),
+            "--depth" => {
+                let depth = value()?;
+                parsed.crawl.depth = Some(depth.parse().map_err(|_| {
+                    Error::InvalidArgs(format!("failed to parse --depth: {}", depth))
+                })?);
+            }
+            "--tag" => parsed.tags.push(value()?.to_string()),
+            "--only-tag" => parsed.only_tags.push(value()?.to_string()),
+            "--skip-tag" => parsed.skip_tags.push(value()?.to_string()),
+            s if s.starts_with("-c") => {
+                let n = s[2..].parse::<usize>().map_err(|_| {
+                    Error::InvalidArgs(format!("failed to parse -c argument: {}", s))
+                })?;
+                parsed.options.max_concurrent_downloads = n;
+            }
+            unknown => {
+                return Err(Error::InvalidArgs(format!(
+                    "unknown argument: {}\n{}",
+                    unknown,
+                    usage(program)
+                )))
             }
-            println!("invalid url file: {}", filename);
-            return None;
         }
     }
+    Ok(Some(parsed))
 }
 
-fn parse_url_file(args: &Args) -> Vec<Image> {
-    let file = File::open(&args.url_file_name).expect("failed to open url file");
-    let reader = BufReader::new(file);
-    let mut images = Vec::new();
-    for line in reader.lines() {
-        let line = line.expect("faild to read line");
-        if line.len() == 0 {
-            continue;
-        }
-        let parts: Vec<&str> = line.split_whitespace().collect();
-        if parts.len() < 2 {
-            println!("invalid line: {}", line);
-            continue;
-        }
-        let url = parts[0];
-        let file_name = parts[1..].join(" ");
-        images.push(Image {
-            url: url.to_string(),
-            file_name: file_name.to_string(),
+/// The url policy the flags so far set up, `http` and `https` to anywhere
+/// until they narrow it.
+fn policy(parsed: &mut Args) -> &mut UrlPolicy {
+    parsed.options.url_policy.get_or_insert_with(|| UrlPolicy {
+        blocked: Vec::new(),
+        ..UrlPolicy::public()
+    })
+}
+
+/// An octal mode or umask like `0644` or `755`.
+fn parse_mode(flag: &str, mode: &str) -> Result<u32, Error> {
+    u32::from_str_radix(mode, 8)
+        .ok()
+        .filter(|&mode| mode <= 0o7777)
+        .ok_or_else(|| Error::InvalidArgs(format!("{} expects an octal mode, not {}", flag, mode)))
+}
+
+/// The lock file of the directory downloads write their part files to, the
+/// `--temp-dir` or else the current one, which `--sync` leaves alone.
+fn lock_path(args: &Args) -> PathBuf {
+    let dir = args.options.temp_dir.as_deref().unwrap_or(Path::new("."));
+    dir.join(".fast_download.lock")
+}
+
+/// Where `--deadline` leaves the entries it didn't get to.
+fn remaining_path(args: &Args) -> PathBuf {
+    let mut path = args.url_file_name.clone().into_os_string();
+    path.push(".remaining");
+    PathBuf::from(path)
+}
+
+/// Writes `left` as a url file to resume from, returning the error that ends
+/// the run.
+fn write_remaining(args: &Args, left: impl IntoIterator<Item = Image>) -> Error {
+    let path = remaining_path(args);
+    let left = left.into_iter().collect::<Vec<_>>();
+    let text = left
+        .iter()
+        .map(|image| verify::line(image) + "\n")
+        .collect::<String>();
+    match std::fs::write(&path, text) {
+        Ok(()) => Error::DeadlineReached(left.len(), path),
+        Err(err) => Error::FailedToWriteRemaining(path, err),
+    }
+}
+
+/// Pauses `downloader` once `usage` reaches `--monthly-cap`, and resumes it
+/// once a new month brings it back under, returning whether it is capped now.
+fn apply_monthly_cap(
+    args: &Args,
+    usage: &history::Usage,
+    capped: bool,
+    outside_hours: bool,
+    downloader: &Downloader,
+    pb: &ProgressBar,
+) -> bool {
+    let Some(cap) = args.monthly_cap else {
+        return false;
+    };
+    let reached = usage.month() >= cap;
+    if reached == capped {
+        return capped;
+    }
+    if reached {
+        downloader.pause();
+    } else if !outside_hours {
+        downloader.resume();
+    }
+    if !args.quiet {
+        pb.suspend(|| match reached {
+            true => eprintln!(
+                "monthly cap of {} reached, pausing until next month",
+                HumanBytes(cap)
+            ),
+            false => eprintln!("a new month started, resuming"),
         });
     }
-    images
+    reached
 }
 
-async fn download_image(image: &Image, force_redownload: bool) -> DownloadResult {
-    let path = PathBuf::from(&image.file_name);
-    if path.exists() {
-        if force_redownload {
-            if let Err(_) = std::fs::remove_file(&path) {
-                return Err(DownloadError::FailedToCreateFile);
+/// Waits until `deadline`, forever without one.
+async fn sleep_until(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => tokio::time::sleep_until(deadline.into()).await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Takes an advisory lock on the directory of the part files, see
+/// [`lock_path`], so two runs, of the same url file or not, don't write each
+/// other's part files. The lock is released when the returned file is dropped;
+/// the lock file itself is left in place.
+fn lock_downloads(args: &Args) -> Result<File, Error> {
+    let path = lock_path(args);
+    let lock_error = |err| Error::FailedToLock(path.clone(), err);
+    if let Some(dir) = &args.options.temp_dir {
+        std::fs::create_dir_all(dir).map_err(lock_error)?;
+    }
+    let mut file = File::options()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(&path)
+        .map_err(lock_error)?;
+    match file.try_lock() {
+        Ok(()) => {}
+        Err(TryLockError::WouldBlock) => {
+            // where the lock keeps other handles from reading, the holder
+            // is just left out
+            let holder = std::fs::read_to_string(&path)
+                .ok()
+                .map(|holder| holder.trim().to_string())
+                .filter(|holder| !holder.is_empty());
+            return Err(Error::DirectoryInUse(path, holder));
+        }
+        Err(TryLockError::Error(err)) => return Err(lock_error(err)),
+    }
+    // the holder is only informational, so failing to record it isn't fatal
+    let _ = file.set_len(0).and_then(|_| {
+        write!(
+            file,
+            "pid {}, url file {}",
+            std::process::id(),
+            args.url_file_name.display()
+        )
+    });
+    Ok(file)
+}
+
+/// A file checked by [`verify_sha256`], the bytes its download received and
+/// how long it took.
+type Verification = (Image, u64, Duration, Result<(), DownloadError>);
+
+/// Checks a downloaded file against its `sha256=` once one of `slots` is free,
+/// and removes it and its copies if it doesn't match, or quarantines it.
+async fn verify_sha256(
+    slots: Arc<Semaphore>,
+    options: &Options,
+    image: Image,
+    bytes: u64,
+    took: Duration,
+) -> Verification {
+    let _slot = slots.acquire_owned().await.expect("never closed");
+    let path = options.output_path(&image.file_name);
+    let expected = image
+        .sha256
+        .clone()
+        .expect("only files with sha256= are verified");
+    let result = match fast_download::file_sha256(&path).await {
+        Ok(actual) if actual == expected => Ok(()),
+        Ok(actual) => {
+            let err = DownloadError::ChecksumMismatch { expected, actual };
+            if let Some(dir) = &options.quarantine {
+                let _ = fast_download::quarantine(dir, &image, &path, &err.to_string(), None).await;
+            }
+            // like a download that doesn't match, which leaves nothing behind
+            for file_name in std::iter::once(&image.file_name).chain(&image.copies) {
+                let _ = tokio::fs::remove_file(options.output_path(file_name)).await;
             }
-        } else {
-            return Ok(DownloadCompleted::Skipped);
+            Err(err)
         }
+        Err(err) => Err(DownloadError::FailedToDownloadToFile(err)),
+    };
+    (image, bytes, took, result)
+}
+
+/// Asks before downloading more than `threshold` bytes. Files that would be
+/// skipped don't count.
+async fn confirm_size(args: &Args, images: &[Image], threshold: u64) -> Result<(), Error> {
+    let missing = images
+        .iter()
+        .filter(|image| {
+            args.options.force_redownload || !args.options.output_exists(&image.file_name)
+        })
+        .cloned()
+        .collect::<Vec<_>>();
+    let asking = progress::stage(
+        args.quiet || args.no_progress,
+        "asking servers for sizes",
+        false,
+    );
+    let client = check::Client::new(&args.options).map_err(Error::FailedToBuildClient)?;
+    let (total, unknown) = check::total_size(
+        &missing,
+        &client,
+        args.options.max_concurrent_downloads,
+        &asking,
+    )
+    .await;
+    asking.finish_and_clear();
+    if total <= threshold {
+        return Ok(());
     }
-    match reqwest::get(&image.url).await {
-        Ok(response) => {
-            let bytes = response.bytes().await;
-            match bytes {
-                Ok(bytes) => {
-                    if let Some(parent) = path.parent() {
-                        if let Err(_) = std::fs::create_dir_all(parent) {
-                            return Err(DownloadError::FailedToCreateParentDirectory);
-                        }
-                    }
-                    match File::create(path) {
-                        Ok(mut file) => {
-                            if let Err(_) = std::io::copy(&mut bytes.as_ref(), &mut file) {
-                                return Err(DownloadError::FailedToDownloadToFile);
-                            }
-                        }
-                        Err(_) => return Err(DownloadError::FailedToCreateFile),
-                    }
-                }
-                Err(_) => return Err(DownloadError::FailedToConvertResponseToBytes),
+    eprint!(
+        "about to download {} in {} files",
+        HumanBytes(total),
+        missing.len()
+    );
+    if unknown > 0 {
+        eprint!(" ({} of unknown size)", unknown);
+    }
+    eprintln!();
+    if args.yes {
+        return Ok(());
+    }
+    if !io::stdin().is_terminal() {
+        return Err(Error::NotConfirmed(total));
+    }
+    eprint!("continue? [y/N] ");
+    let mut answer = String::new();
+    // an unreadable stdin leaves the answer empty, which declines
+    let _ = io::stdin().read_line(&mut answer);
+    match answer.trim() {
+        "y" | "Y" | "yes" => Ok(()),
+        _ => Err(Error::NotConfirmed(total)),
+    }
+}
+
+/// The files under `root` that `--sync` deletes: everything that isn't an entry
+/// of the url file or one of the run's own inputs.
+fn sync_extraneous(
+    args: &Args,
+    root: &Path,
+    written: &HashSet<String>,
+) -> Result<Vec<PathBuf>, Error> {
+    let mut listed = written
+        .iter()
+        .map(|file_name| sync::normalize(&args.options.output_path(file_name)))
+        .collect::<HashSet<_>>();
+    let inputs = [
+        Some(&args.url_file_name),
+        Some(&lock_path(args)),
+        Some(&remaining_path(args)),
+        args.script.as_ref(),
+        args.config.as_ref(),
+        args.history.as_ref(),
+        args.options.warc.as_ref(),
+    ];
+    listed.extend(
+        inputs
+            .into_iter()
+            .flatten()
+            .map(|path| sync::normalize(path)),
+    );
+    sync::extraneous(root, &listed).map_err(|err| Error::FailedToSync(root.to_path_buf(), err))
+}
+
+/// The `[[manifest]]` schedules of the config.
+fn manifest_schedules(
+    manifests: Vec<config::ManifestConfig>,
+) -> Result<Vec<daemon::Scheduled>, Error> {
+    manifests
+        .into_iter()
+        .map(|manifest| {
+            Ok(daemon::Scheduled {
+                cron: cron::Schedule::parse(&manifest.schedule).map_err(|err| {
+                    Error::InvalidConfig(format!(
+                        "invalid schedule for {}: {}",
+                        manifest.url_file.display(),
+                        err
+                    ))
+                })?,
+                url_file: manifest.url_file,
+            })
+        })
+        .collect()
+}
+
+/// The `--notify-url` webhook.
+fn webhook(args: &Args) -> Option<Webhook> {
+    args.notify_url.as_ref().map(|url| {
+        let format = args
+            .notify_format
+            .unwrap_or_else(|| webhook::Format::detect(url));
+        Webhook::new(url.clone(), format)
+    })
+}
+
+fn history_path(args: &Args) -> Result<PathBuf, Error> {
+    args.history
+        .clone()
+        .or_else(history::default_path)
+        .ok_or_else(|| Error::History("no data directory, pass --history".to_string()))
+}
+
+/// Fails a run that succeeded so far with `err`, or prints it if the run has
+/// already failed for another reason.
+fn fail_or_warn(result: &mut Result<(), Error>, err: Error) {
+    match result {
+        Ok(()) => *result = Err(err),
+        Err(_) => eprintln!("error: {}", err),
+    }
+}
+
+/// Waits for the next event of the downloader, forever when there is no
+/// subscription.
+async fn next_event(events: &mut Option<mpsc::UnboundedReceiver<Event>>) -> Option<Event> {
+    match events {
+        Some(events) => events.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+fn load_script(args: &Args) -> Result<Option<Script>, Error> {
+    args.script
+        .as_ref()
+        .map(|path| Script::load(path).map_err(|err| Error::FailedToLoadScript(path.clone(), err)))
+        .transpose()
+}
+
+fn load_rewriter(args: &Args) -> Result<Rewriter, Error> {
+    let config = config::load(args.config.as_deref()).map_err(Error::InvalidConfig)?;
+    Rewriter::new(&config.rewrite).map_err(Error::InvalidConfig)
+}
+
+/// The `[hosts]` tables of the config, in the order of their names.
+fn host_profiles(config: &config::Config) -> Result<Vec<HostProfile>, String> {
+    let mut hosts = config.hosts.iter().collect::<Vec<_>>();
+    hosts.sort_by_key(|(host, _)| *host);
+    hosts
+        .into_iter()
+        .map(|(host, settings)| {
+            let mut profile = HostProfile::new(host.clone());
+            profile.max_concurrent = settings.max_concurrent;
+            if let Some(rate) = &settings.rate {
+                profile.rate = Some(
+                    units::parse_rate(rate)
+                        .ok_or_else(|| format!("invalid rate for hosts.\"{}\": {}", host, rate))?,
+                );
+            }
+            if let Some(delay) = &settings.delay {
+                profile.delay =
+                    Some(units::parse_duration(delay).ok_or_else(|| {
+                        format!("invalid delay for hosts.\"{}\": {}", host, delay)
+                    })?);
+            }
+            for header in &settings.headers {
+                let (name, value) = header
+                    .split_once(':')
+                    .ok_or_else(|| format!("invalid header for hosts.\"{}\": {}", host, header))?;
+                profile
+                    .headers
+                    .push((name.trim().to_string(), value.trim().to_string()));
+            }
+            Ok(profile)
+        })
+        .collect()
+}
+
+/// Parses the url file, counting the bytes read on `progress`, with the
+/// entries `--only-tag` and `--skip-tag` keep.
+fn parse_url_file(
+    args: &Args,
+    script: Option<&Script>,
+    rewriter: &Rewriter,
+    progress: &ProgressBar,
+) -> Result<Vec<Image>, Error> {
+    let mut images = parse_entries(args, script, rewriter, progress)?;
+    images.retain_mut(|image| tagged(args, image));
+    Ok(images)
+}
+
+/// Every entry of the url file, before the tags pick some, see
+/// [`parse_url_file`].
+fn parse_entries(
+    args: &Args,
+    script: Option<&Script>,
+    rewriter: &Rewriter,
+    progress: &ProgressBar,
+) -> Result<Vec<Image>, Error> {
+    let read_error = |err| Error::FailedToReadUrlFile(args.url_file_name.clone(), err);
+    let file = File::open(&args.url_file_name).map_err(read_error)?;
+    if let Ok(meta) = file.metadata() {
+        progress.set_length(meta.len());
+    }
+    let mut reader = BufReader::new(file);
+    let mut images = Vec::new();
+    let mut invalid = Vec::new();
+    // what export and audit print is the output, maybe JSON
+    let skip = |err: String| match args.command {
+        Command::Export | Command::Audit => eprintln!("{}", err),
+        _ => println!("{}", err),
+    };
+    let start = reader.fill_buf().map_err(read_error)?;
+    if args.input != Input::Lines {
+        let mut text = String::new();
+        reader.read_to_string(&mut text).map_err(read_error)?;
+        progress.inc(text.len() as u64);
+        let items = match args.input {
+            Input::Curl => curl::parse(&text),
+            _ => aria2::parse(&text),
+        };
+        for (number, image) in items {
+            let source = format!("{}:{}", args.url_file_name.display(), number);
+            let image = image.map_err(|err| format!("{}: {}", source, err));
+            match image.and_then(|image| {
+                let url = image.url.clone();
+                finish_image(image, source, &url, script, rewriter)
+            }) {
+                Ok(image) => images.extend(image),
+                Err(err) if args.strict => invalid.push(err),
+                Err(err) => skip(err),
+            }
+        }
+    } else if browser::is_export(&String::from_utf8_lossy(start)) {
+        let mut text = String::new();
+        reader.read_to_string(&mut text).map_err(read_error)?;
+        progress.inc(text.len() as u64);
+        let entries = browser::parse(&text).map_err(|err| {
+            Error::InvalidUrlFile(
+                args.url_file_name.clone(),
+                vec![format!("{}: {}", args.url_file_name.display(), err)],
+            )
+        })?;
+        for (index, image) in entries {
+            let source = format!("{}:{}", args.url_file_name.display(), index + 1);
+            let url = image.url.clone();
+            match finish_image(image, source, &url, script, rewriter) {
+                Ok(image) => images.extend(image),
+                Err(err) if args.strict => invalid.push(err),
+                Err(err) => skip(err),
+            }
+        }
+    }
+    for (number, line) in reader.lines().enumerate() {
+        let line = line.map_err(read_error)?;
+        progress.inc(line.len() as u64 + 1);
+        let source = format!("{}:{}", args.url_file_name.display(), number + 1);
+        match parse_line(&line, source, script, rewriter) {
+            Ok(image) => images.extend(image),
+            Err(err) if args.strict => invalid.push(err),
+            Err(err) => skip(err),
+        }
+    }
+    if !invalid.is_empty() {
+        return Err(Error::InvalidUrlFile(args.url_file_name.clone(), invalid));
+    }
+    for image in &mut images {
+        for dir in &args.also_write_to {
+            image.copies.push(also_path(dir, &image.file_name));
+        }
+    }
+    Ok(images)
+}
+
+/// The paths `image` is written to, its file and its copies.
+fn paths(image: &Image) -> Vec<String> {
+    std::iter::once(&image.file_name)
+        .chain(&image.copies)
+        .cloned()
+        .collect()
+}
+
+/// Where `--also-write-to <dir>` puts the file at `file_name`: at the same
+/// path under `dir`, absolute paths included.
+fn also_path(dir: &Path, file_name: &str) -> String {
+    let relative = Path::new(file_name)
+        .components()
+        .filter(|component| !matches!(component, Component::Prefix(_) | Component::RootDir));
+    dir.join(relative.collect::<PathBuf>())
+        .to_string_lossy()
+        .into_owned()
+}
+
+/// Adds the `--tag`s to `image`, and tells whether `--only-tag` and
+/// `--skip-tag` keep it.
+fn tagged(args: &Args, image: &mut Image) -> bool {
+    for tag in &args.tags {
+        if !image.tags.contains(tag) {
+            image.tags.push(tag.clone());
+        }
+    }
+    let any = |tags: &[String]| tags.iter().any(|tag| image.tags.contains(tag));
+    (args.only_tags.is_empty() || any(&args.only_tags)) && !any(&args.skip_tags)
+}
+
+/// `<file>:<line>: ` in front of the errors of an item parsed from a url file.
+fn located(image: &Image) -> String {
+    match &image.source {
+        Some(source) => format!("{}: ", source),
+        None => String::new(),
+    }
+}
+
+/// Parses one url file line, `None` for an empty line or one the script
+/// drops, and why it's invalid otherwise. `source` says where the line is, like
+/// `list.txt:4132`.
+fn parse_line(
+    line: &str,
+    source: String,
+    script: Option<&Script>,
+    rewriter: &Rewriter,
+) -> Result<Option<Image>, String> {
+    if line.is_empty() {
+        return Ok(None);
+    }
+    let mut parts: Vec<&str> = line.split_whitespace().collect();
+    if parts.len() < 2 {
+        return Err(format!("{}: invalid line: {}", source, line));
+    }
+    // trailing `key=value` fields describe the file rather than its path
+    let (mut sha256, mut sha256_tree, mut size) = (None, None, None);
+    let (mut mirrors, mut copies, mut meta) = (Vec::new(), Vec::new(), Vec::new());
+    let mut tags = Vec::new();
+    let mut valid = true;
+    while parts.len() > 2 {
+        match parts[parts.len() - 1].split_once('=') {
+            Some(("sha256", hex)) => {
+                valid &= hex.len() == 64 && hex.chars().all(|c| c.is_ascii_hexdigit());
+                sha256 = Some(hex.to_ascii_lowercase());
+            }
+            Some(("sha256tree", hex)) => {
+                valid &= hex.len() == 64 && hex.chars().all(|c| c.is_ascii_hexdigit());
+                sha256_tree = Some(hex.to_ascii_lowercase());
+            }
+            Some(("size", bytes)) => {
+                size = bytes.parse::<u64>().ok();
+                valid &= size.is_some();
+            }
+            // fields are taken from the end, so mirrors go in front
+            Some(("mirror", url)) => mirrors.insert(0, url.to_string()),
+            Some(("copy", path)) => copies.insert(0, path.to_string()),
+            Some(("tag", tag)) => tags.insert(0, tag.to_string()),
+            Some((key, value)) if key.len() > "meta.".len() && key.starts_with("meta.") => {
+                meta.insert(0, (key["meta.".len()..].to_string(), value.to_string()))
             }
-            Ok(DownloadCompleted::Success)
+            _ => break,
         }
-        Err(_) => Err(DownloadError::FailedToGetUrl),
+        parts.pop();
+    }
+    if !valid {
+        return Err(format!("{}: invalid field: {}", source, line));
+    }
+    let url = parts[0];
+    let file_name = parts[1..].join(" ");
+    let mut image = Image::new(url, file_name);
+    image.sha256 = sha256;
+    image.sha256_tree = sha256_tree;
+    image.size = size;
+    image.mirrors = mirrors;
+    image.copies = copies;
+    image.meta = meta;
+    image.tags = tags;
+    finish_image(image, source, line, script, rewriter)
+}
+
+/// Runs the script on a parsed `image` and rewrites and normalizes its urls.
+/// `line` is what it was parsed from, for errors.
+fn finish_image(
+    image: Image,
+    source: String,
+    line: &str,
+    script: Option<&Script>,
+    rewriter: &Rewriter,
+) -> Result<Option<Image>, String> {
+    let transformed = match script {
+        Some(script) => script.transform(image),
+        None => Ok(Some(image)),
+    };
+    match transformed {
+        Ok(Some(mut image)) => {
+            let normalize = |url: &str| {
+                urls::normalize(&rewriter.rewrite(url))
+                    .map_err(|err| format!("{}: invalid url {}: {}", source, url, err))
+            };
+            image.url = normalize(&image.url)?;
+            for mirror in &mut image.mirrors {
+                *mirror = normalize(mirror)?;
+            }
+            image.source = Some(source);
+            Ok(Some(image))
+        }
+        Ok(None) => Ok(None),
+        Err(err) => Err(format!("{}: script error: {} line: {}", source, err, line)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn image(tags: &[&str]) -> Image {
+        let mut image = Image::new("https://example.com/a.iso", "a.iso");
+        image.tags = tags.iter().map(|tag| tag.to_string()).collect();
+        image
+    }
+
+    fn args(tags: &[&str], only_tags: &[&str], skip_tags: &[&str]) -> Args {
+        let strings = |tags: &[&str]| tags.iter().map(|tag| tag.to_string()).collect();
+        Args {
+            tags: strings(tags),
+            only_tags: strings(only_tags),
+            skip_tags: strings(skip_tags),
+            ..Args::default()
+        }
+    }
+
+    #[test]
+    fn tag_fields_are_kept_in_line_order() {
+        let image = parse_line(
+            "https://example.com/a.iso a.iso tag=iso size=3 tag=nightly",
+            "urls.txt:1".to_string(),
+            None,
+            &Rewriter::default(),
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(image.tags, ["iso", "nightly"]);
+        assert_eq!(image.size, Some(3));
+    }
+
+    #[test]
+    fn only_tag_keeps_entries_with_any_of_the_tags() {
+        let only = args(&[], &["iso", "img"], &[]);
+        assert!(tagged(&only, &mut image(&["nightly", "img"])));
+        assert!(!tagged(&only, &mut image(&["nightly"])));
+        assert!(!tagged(&only, &mut image(&[])));
+        assert!(tagged(&args(&[], &[], &[]), &mut image(&[])));
+    }
+
+    #[test]
+    fn skip_tag_wins_over_only_tag() {
+        let filters = args(&[], &["iso"], &["nightly"]);
+        assert!(tagged(&filters, &mut image(&["iso"])));
+        assert!(!tagged(&filters, &mut image(&["iso", "nightly"])));
+    }
+
+    #[test]
+    fn run_tags_are_added_once_before_filtering() {
+        let run = args(&["iso", "batch"], &["batch"], &[]);
+        let mut entry = image(&["iso"]);
+        assert!(tagged(&run, &mut entry));
+        assert_eq!(entry.tags, ["iso", "batch"]);
     }
 }
@@ -0,0 +1,127 @@
+//! [`Options::replace_invalid_chars`](crate::Options::replace_invalid_chars)
+//! and [`Options::normalize_filenames`](crate::Options::normalize_filenames):
+//! turning the paths of a url file into names every file system takes alike.
+
+use unicode_normalization::{char::is_combining_mark, UnicodeNormalization};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Normalization {
+    /// Composed characters, as Linux and Windows tools mostly write them.
+    Nfc,
+    /// Decomposed characters, as macOS file systems store them.
+    Nfd,
+    /// Only ASCII, for file systems and tools that break on anything else.
+    Ascii,
+}
+
+impl Normalization {
+    pub fn parse(name: &str) -> Option<Normalization> {
+        match name {
+            "nfc" => Some(Normalization::Nfc),
+            "nfd" => Some(Normalization::Nfd),
+            "ascii" => Some(Normalization::Ascii),
+            _ => None,
+        }
+    }
+
+    /// `file_name` in this form. [`Normalization::Ascii`] drops accents,
+    /// spells out letters like `ß` and `æ` and writes what is left as `_`.
+    pub(crate) fn apply(self, file_name: &str) -> String {
+        match self {
+            Normalization::Nfc => file_name.nfc().collect(),
+            Normalization::Nfd => file_name.nfd().collect(),
+            Normalization::Ascii => {
+                let mut ascii = String::with_capacity(file_name.len());
+                for c in file_name.nfkd().filter(|c| !is_combining_mark(*c)) {
+                    match c {
+                        c if c.is_ascii() => ascii.push(c),
+                        'ß' => ascii.push_str("ss"),
+                        'æ' => ascii.push_str("ae"),
+                        'Æ' => ascii.push_str("AE"),
+                        'œ' => ascii.push_str("oe"),
+                        'Œ' => ascii.push_str("OE"),
+                        'ø' => ascii.push('o'),
+                        'Ø' => ascii.push('O'),
+                        'đ' | 'ð' => ascii.push('d'),
+                        'Đ' | 'Ð' => ascii.push('D'),
+                        'ł' => ascii.push('l'),
+                        'Ł' => ascii.push('L'),
+                        'þ' => ascii.push_str("th"),
+                        'Þ' => ascii.push_str("TH"),
+                        _ => ascii.push('_'),
+                    }
+                }
+                ascii
+            }
+        }
+    }
+}
+
+/// Characters Windows doesn't allow in names, besides control characters.
+const INVALID: &[char] = &['<', '>', ':', '"', '|', '?', '*'];
+
+/// Device names Windows reserves, with any extension.
+const RESERVED: &[&str] = &[
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// `file_name` with `replacement` for the characters Windows doesn't allow in
+/// names and for trailing dots and spaces, which it drops. Reserved device
+/// names like `CON.txt` get it appended. `/` and `\` both separate names, and
+/// a leading drive like `C:` or a `\\?\` prefix is kept.
+pub(crate) fn sanitize(file_name: &str, replacement: char) -> String {
+    let (prefix, rest) = split_prefix(file_name);
+    let mut sanitized = String::from(prefix);
+    let mut name = String::new();
+    for c in rest.chars() {
+        if c == '/' || c == '\\' {
+            sanitized.push_str(&sanitize_name(&name, replacement));
+            sanitized.push(c);
+            name.clear();
+        } else {
+            name.push(c);
+        }
+    }
+    sanitized.push_str(&sanitize_name(&name, replacement));
+    sanitized
+}
+
+/// The part of `file_name` that isn't a name: `\\?\`, `\\?\UNC\` or a drive.
+fn split_prefix(file_name: &str) -> (&str, &str) {
+    let verbatim = ["\\\\?\\UNC\\", "\\\\?\\"]
+        .into_iter()
+        .find(|prefix| file_name.starts_with(prefix))
+        .map_or(0, str::len);
+    let rest = &file_name[verbatim..];
+    let drive = match rest.as_bytes() {
+        [letter, b':', ..] if letter.is_ascii_alphabetic() => 2,
+        _ => 0,
+    };
+    file_name.split_at(verbatim + drive)
+}
+
+fn sanitize_name(name: &str, replacement: char) -> String {
+    if name == "." || name == ".." {
+        return name.to_string();
+    }
+    let mut sanitized = name
+        .chars()
+        .map(|c| match c.is_control() || INVALID.contains(&c) {
+            true => replacement,
+            false => c,
+        })
+        .collect::<String>();
+    let kept = sanitized.trim_end_matches(['.', ' ']).len();
+    let trailing = sanitized.len() - kept;
+    sanitized.truncate(kept);
+    sanitized.extend(std::iter::repeat_n(replacement, trailing));
+    let stem = sanitized.split('.').next().unwrap_or_default();
+    if RESERVED
+        .iter()
+        .any(|reserved| stem.eq_ignore_ascii_case(reserved))
+    {
+        sanitized.insert(stem.len(), replacement);
+    }
+    sanitized
+}
@@ -0,0 +1,22 @@
+//! Desktop notifications for `--notify`.
+
+use notify_rust::Notification;
+
+/// Shows a desktop notification without blocking the runtime. Notifications are
+/// best effort: a missing notification daemon only prints a warning.
+pub async fn desktop(summary: String, body: String) {
+    let shown = tokio::task::spawn_blocking(move || {
+        Notification::new()
+            .appname("fast_download")
+            .summary(&summary)
+            .body(&body)
+            .show()
+            .map(|_| ())
+    })
+    .await;
+    match shown {
+        Ok(Ok(())) => {}
+        Ok(Err(err)) => eprintln!("failed to show notification: {}", err),
+        Err(err) => eprintln!("failed to show notification: {}", err),
+    }
+}
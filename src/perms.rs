@@ -0,0 +1,113 @@
+//! [`Options::chmod`](crate::Options::chmod),
+//! [`Options::dir_chmod`](crate::Options::dir_chmod) and
+//! [`Options::chown`](crate::Options::chown): the permissions and owner of
+//! downloaded files and the directories created for them, on unix.
+
+use std::{io, path::Path};
+
+/// The user and group to give files to. Either can be left as it is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Owner {
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+}
+
+impl Owner {
+    /// Parses `user`, `user:group` or `:group`, by name or number.
+    pub fn parse(spec: &str) -> Result<Owner, String> {
+        let (user, group) = spec.split_once(':').unwrap_or((spec, ""));
+        let owner = Owner {
+            uid: (!user.is_empty()).then(|| user_id(user)).transpose()?,
+            gid: (!group.is_empty()).then(|| group_id(group)).transpose()?,
+        };
+        if owner == Owner::default() {
+            return Err(format!("expected user:group, not {:?}", spec));
+        }
+        Ok(owner)
+    }
+}
+
+/// Gives `path` `mode` and `owner`, where set.
+#[cfg(unix)]
+pub(crate) async fn apply(path: &Path, mode: Option<u32>, owner: Option<Owner>) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    if let Some(owner) = owner {
+        std::os::unix::fs::chown(path, owner.uid, owner.gid)?;
+    }
+    // after chown, which may clear the setuid and setgid bits
+    if let Some(mode) = mode {
+        tokio::fs::set_permissions(path, std::fs::Permissions::from_mode(mode)).await?;
+    }
+    Ok(())
+}
+
+/// Other platforms have neither modes nor owners like these.
+#[cfg(not(unix))]
+pub(crate) async fn apply(_: &Path, _: Option<u32>, _: Option<Owner>) -> io::Result<()> {
+    Ok(())
+}
+
+/// Creates `dir` and its missing parents, giving the ones it creates `mode`
+/// and `owner`. Directories that existed are left alone.
+pub(crate) async fn create_dirs(
+    dir: &Path,
+    mode: Option<u32>,
+    owner: Option<Owner>,
+) -> io::Result<()> {
+    if mode.is_none() && owner.is_none() {
+        return tokio::fs::create_dir_all(dir).await;
+    }
+    let mut missing = Vec::new();
+    for ancestor in dir
+        .ancestors()
+        .filter(|ancestor| !ancestor.as_os_str().is_empty())
+    {
+        if tokio::fs::try_exists(ancestor).await? {
+            break;
+        }
+        missing.push(ancestor);
+    }
+    tokio::fs::create_dir_all(dir).await?;
+    for created in missing.into_iter().rev() {
+        apply(created, mode, owner).await?;
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn user_id(user: &str) -> Result<u32, String> {
+    if let Ok(uid) = user.parse() {
+        return Ok(uid);
+    }
+    let name = std::ffi::CString::new(user).map_err(|_| format!("no user {:?}", user))?;
+    // SAFETY: the entry is read before anything else looks users up
+    let entry = unsafe { libc::getpwnam(name.as_ptr()) };
+    match entry.is_null() {
+        true => Err(format!("no user {:?}", user)),
+        false => Ok(unsafe { (*entry).pw_uid }),
+    }
+}
+
+#[cfg(unix)]
+fn group_id(group: &str) -> Result<u32, String> {
+    if let Ok(gid) = group.parse() {
+        return Ok(gid);
+    }
+    let name = std::ffi::CString::new(group).map_err(|_| format!("no group {:?}", group))?;
+    // SAFETY: as for users
+    let entry = unsafe { libc::getgrnam(name.as_ptr()) };
+    match entry.is_null() {
+        true => Err(format!("no group {:?}", group)),
+        false => Ok(unsafe { (*entry).gr_gid }),
+    }
+}
+
+#[cfg(not(unix))]
+fn user_id(user: &str) -> Result<u32, String> {
+    user.parse().map_err(|_| format!("no user {:?}", user))
+}
+
+#[cfg(not(unix))]
+fn group_id(group: &str) -> Result<u32, String> {
+    group.parse().map_err(|_| format!("no group {:?}", group))
+}
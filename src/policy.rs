@@ -0,0 +1,264 @@
+//! [`Options::url_policy`](crate::Options::url_policy): keeping downloads away
+//! from internal networks when the urls come from someone else, like the queue
+//! of a hosted deployment.
+//!
+//! Urls are checked before the first request and on every redirect. Their
+//! hosts are matched against the allowed and denied patterns, and host names
+//! are checked by the resolver, which leaves out blocked addresses before
+//! reqwest connects to what it returns, so a name can't resolve to a public
+//! address when checked and to an internal one when connected to.
+
+use crate::{glob, trace::TimedResolver};
+use hyper::client::connect::dns::Name;
+use reqwest::{
+    dns::{Addrs, Resolve, Resolving},
+    Url,
+};
+use std::{net::IpAddr, sync::Arc};
+
+/// Addresses that are no one's business but the host's own network: loopback,
+/// private, carrier-grade NAT, link-local (where cloud metadata endpoints like
+/// `169.254.169.254` live), benchmarking, multicast, unique local and
+/// unspecified ones, and the NAT64 prefix that reaches any IPv4 address.
+const INTERNAL: &[&str] = &[
+    "0.0.0.0/8",
+    "10.0.0.0/8",
+    "100.64.0.0/10",
+    "127.0.0.0/8",
+    "169.254.0.0/16",
+    "172.16.0.0/12",
+    "192.168.0.0/16",
+    "198.18.0.0/15",
+    "224.0.0.0/4",
+    "::/128",
+    "::1/128",
+    "64:ff9b::/96",
+    "fc00::/7",
+    "fe80::/10",
+    "ff00::/8",
+];
+
+/// Where downloads may go.
+#[derive(Debug, Clone)]
+pub struct UrlPolicy {
+    /// Schemes urls may use, like `https`.
+    pub schemes: Vec<String>,
+    /// Addresses never connected to.
+    pub blocked: Vec<IpRange>,
+    /// Globs like `*.example.com`; when there are any, urls must have a host
+    /// matching one of them.
+    pub allow_hosts: Vec<String>,
+    /// Globs of hosts never downloaded from, before `allow_hosts`.
+    pub deny_hosts: Vec<String>,
+}
+
+impl UrlPolicy {
+    /// `http` and `https`, to anything but internal addresses.
+    pub fn public() -> UrlPolicy {
+        UrlPolicy {
+            schemes: vec!["http".to_string(), "https".to_string()],
+            blocked: IpRange::internal(),
+            allow_hosts: Vec::new(),
+            deny_hosts: Vec::new(),
+        }
+    }
+
+    /// Why `url` may not be downloaded, if it may not.
+    pub fn check(&self, url: &str) -> Result<(), String> {
+        let url = Url::parse(url).map_err(|err| err.to_string())?;
+        if !self.schemes.iter().any(|scheme| scheme == url.scheme()) {
+            return Err(format!("scheme {} isn't allowed", url.scheme()));
+        }
+        let host = url.host_str().unwrap_or_default();
+        let name = host.to_ascii_lowercase();
+        if self.deny_hosts.iter().any(|pattern| glob(pattern, &name)) {
+            return Err(format!("host {} is denied", host));
+        }
+        if !self.allow_hosts.is_empty()
+            && !self.allow_hosts.iter().any(|pattern| glob(pattern, &name))
+        {
+            return Err(format!("host {} isn't allowed", host));
+        }
+        // names are checked as they resolve, addresses right away
+        let Ok(addr) = host.trim_start_matches('[').trim_end_matches(']').parse() else {
+            return Ok(());
+        };
+        match self.blocks(addr) {
+            true => Err(format!("address {} is blocked", addr)),
+            false => Ok(()),
+        }
+    }
+
+    fn blocks(&self, addr: IpAddr) -> bool {
+        // an IPv4 address can hide in an IPv6 one, mapped (`::ffff:a.b.c.d`) or
+        // compatible (`::a.b.c.d`), so both forms are matched
+        let embedded = match addr {
+            IpAddr::V6(v6) => v6.to_ipv4().map(IpAddr::V4),
+            IpAddr::V4(_) => None,
+        };
+        std::iter::once(addr)
+            .chain(embedded)
+            .any(|addr| self.blocked.iter().any(|range| range.contains(addr)))
+    }
+}
+
+/// Whether `host`, in lowercase, matches the glob `pattern`, ignoring case.
+fn glob(pattern: &str, host: &str) -> bool {
+    glob::matches(&pattern.to_ascii_lowercase(), host)
+}
+
+/// A block of addresses in CIDR notation, like `10.0.0.0/8`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IpRange {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl IpRange {
+    /// Parses `10.0.0.0/8` or `fc00::/7`; a bare address is a range of one.
+    pub fn parse(s: &str) -> Option<IpRange> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr.parse::<IpAddr>().ok()?, Some(prefix.parse().ok()?)),
+            None => (s.parse::<IpAddr>().ok()?, None),
+        };
+        let bits = match addr {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+        let prefix = prefix.unwrap_or(bits);
+        (prefix <= bits).then_some(IpRange { addr, prefix })
+    }
+
+    /// The ranges [`UrlPolicy::public`] blocks.
+    pub fn internal() -> Vec<IpRange> {
+        INTERNAL
+            .iter()
+            .map(|range| IpRange::parse(range).expect("valid range"))
+            .collect()
+    }
+
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        let (mask, start, addr) = match (self.addr, addr) {
+            (IpAddr::V4(start), IpAddr::V4(addr)) => (
+                u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0) as u128,
+                u32::from(start) as u128,
+                u32::from(addr) as u128,
+            ),
+            (IpAddr::V6(start), IpAddr::V6(addr)) => (
+                u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0),
+                u128::from(start),
+                u128::from(addr),
+            ),
+            _ => return false,
+        };
+        start & mask == addr & mask
+    }
+}
+
+/// Resolves with `inner` or the system resolver and leaves out the addresses
+/// `policy` blocks, failing when none are left.
+pub(crate) struct GuardedResolver {
+    policy: Arc<UrlPolicy>,
+    inner: Option<TimedResolver>,
+}
+
+impl GuardedResolver {
+    pub(crate) fn new(policy: &UrlPolicy, inner: Option<TimedResolver>) -> GuardedResolver {
+        GuardedResolver {
+            policy: Arc::new(policy.clone()),
+            inner,
+        }
+    }
+}
+
+impl Resolve for GuardedResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let (policy, inner) = (self.policy.clone(), self.inner);
+        Box::pin(async move {
+            let host = name.as_str().to_string();
+            let addrs: Vec<_> = match inner {
+                Some(inner) => inner.resolve(name).await?.collect(),
+                None => tokio::net::lookup_host((host.as_str(), 0)).await?.collect(),
+            };
+            let allowed = addrs
+                .into_iter()
+                .filter(|addr| !policy.blocks(addr.ip()))
+                .collect::<Vec<_>>();
+            if allowed.is_empty() {
+                return Err(format!("every address of {} is blocked", host).into());
+            }
+            Ok(Box::new(allowed.into_iter()) as Addrs)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn range(s: &str) -> IpRange {
+        IpRange::parse(s).expect("valid range")
+    }
+
+    #[test]
+    fn ranges_contain_the_addresses_under_their_prefix() {
+        assert!(range("10.0.0.0/8").contains("10.255.0.1".parse().unwrap()));
+        assert!(!range("10.0.0.0/8").contains("11.0.0.1".parse().unwrap()));
+        assert!(range("fc00::/7").contains("fd12::1".parse().unwrap()));
+        assert!(range("0.0.0.0/0").contains("8.8.8.8".parse().unwrap()));
+        assert!(range("192.0.2.1").contains("192.0.2.1".parse().unwrap()));
+        assert!(!range("192.0.2.1").contains("192.0.2.2".parse().unwrap()));
+        // an IPv4 range holds no IPv6 addresses, mapped or not
+        assert!(!range("0.0.0.0/0").contains("::ffff:8.8.8.8".parse().unwrap()));
+    }
+
+    #[test]
+    fn parse_rejects_prefixes_longer_than_the_address() {
+        assert_eq!(IpRange::parse("10.0.0.0/33"), None);
+        assert_eq!(IpRange::parse("::/129"), None);
+        assert_eq!(IpRange::parse("example.com/8"), None);
+    }
+
+    #[test]
+    fn public_blocks_internal_addresses_however_they_are_written() {
+        let policy = UrlPolicy::public();
+        for url in [
+            "http://127.0.0.1/",
+            "http://169.254.169.254/latest/meta-data/",
+            "http://198.18.0.1/",
+            "http://224.0.0.1/",
+            "http://[::1]/",
+            "http://[::ffff:127.0.0.1]/",
+            "http://[::127.0.0.1]/",
+            "http://[::ffff:169.254.169.254]/",
+            "http://[64:ff9b::a9fe:a9fe]/",
+            "http://[ff02::1]/",
+        ] {
+            assert!(policy.check(url).is_err(), "{} isn't blocked", url);
+        }
+        for url in [
+            "http://93.184.215.14/",
+            "https://[2606:2800:21f:cb07:6820:80da:af6b:8b2c]/",
+            "http://[::ffff:93.184.215.14]/",
+            "https://example.com/",
+        ] {
+            assert_eq!(policy.check(url), Ok(()), "{} is blocked", url);
+        }
+    }
+
+    #[test]
+    fn checks_schemes_and_host_globs() {
+        let policy = UrlPolicy {
+            schemes: vec!["https".to_string()],
+            blocked: Vec::new(),
+            allow_hosts: vec!["*.Example.com".to_string()],
+            deny_hosts: vec!["private.example.com".to_string()],
+        };
+        assert_eq!(policy.check("https://cdn.example.com/a.iso"), Ok(()));
+        assert_eq!(policy.check("https://CDN.EXAMPLE.COM/a.iso"), Ok(()));
+        assert!(policy.check("http://cdn.example.com/a.iso").is_err());
+        assert!(policy.check("https://example.com/a.iso").is_err());
+        assert!(policy.check("https://private.example.com/a.iso").is_err());
+        assert!(policy.check("https://example.org/a.iso").is_err());
+    }
+}
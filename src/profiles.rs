@@ -0,0 +1,152 @@
+//! [`Options::host_profiles`](crate::Options::host_profiles): politeness and
+//! auth settings for the downloads from particular hosts.
+//!
+//! Every profile has its own limits, shared by all downloads from the hosts it
+//! matches: a slot of `max_concurrent` is held for an attempt, `delay` spaces
+//! the starts of attempts, and `rate` paces reading, which slows the server
+//! down through flow control like [`Options::max_memory`](crate::Options::max_memory).
+
+use crate::Image;
+use reqwest::Url;
+use std::{sync::Arc, time::Duration};
+use tokio::{
+    sync::{Mutex, Semaphore, SemaphorePermit},
+    time::Instant,
+};
+
+/// Settings for the downloads from a host.
+#[derive(Debug, Clone, Default)]
+pub struct HostProfile {
+    /// The host it's for, like `cdn.example.com`; `*.example.com` is for
+    /// `example.com` and its subdomains. A url gets the most specific match.
+    pub host: String,
+    /// Downloads from the host at once.
+    pub max_concurrent: Option<usize>,
+    /// Bytes per second, for all downloads from the host together.
+    pub rate: Option<u64>,
+    /// Time between the starts of downloads from the host.
+    pub delay: Option<Duration>,
+    /// Request headers, unless the item sets them itself.
+    pub headers: Vec<(String, String)>,
+}
+
+impl HostProfile {
+    pub fn new(host: impl Into<String>) -> HostProfile {
+        HostProfile {
+            host: host.into(),
+            ..HostProfile::default()
+        }
+    }
+
+    /// How specific the match of `host` is, if it matches: the length of the
+    /// name the profile is for.
+    fn matches(&self, host: &str) -> Option<usize> {
+        let pattern = self.host.to_ascii_lowercase();
+        match pattern.strip_prefix("*.") {
+            Some(domain) => {
+                (host == domain || host.ends_with(&format!(".{}", domain))).then_some(domain.len())
+            }
+            // exact names beat every wildcard
+            None => (host == pattern).then_some(usize::MAX),
+        }
+    }
+
+    /// `image` with the headers of the profile it doesn't set.
+    pub(crate) fn apply(&self, image: &Image) -> Image {
+        let mut image = image.clone();
+        for (name, value) in &self.headers {
+            if !image
+                .headers
+                .iter()
+                .any(|(set, _)| set.eq_ignore_ascii_case(name))
+            {
+                image.headers.push((name.clone(), value.clone()));
+            }
+        }
+        image
+    }
+}
+
+/// The limits of one profile, while a downloader runs.
+pub(crate) struct HostLimit {
+    pub(crate) profile: HostProfile,
+    slots: Option<Semaphore>,
+    /// When the next download may start.
+    next_start: Mutex<Instant>,
+    /// When the bytes read so far are paid for at `rate`.
+    paid_until: std::sync::Mutex<Instant>,
+}
+
+impl HostLimit {
+    pub(crate) fn new(profile: HostProfile) -> HostLimit {
+        let now = Instant::now();
+        HostLimit {
+            slots: profile.max_concurrent.map(|max| Semaphore::new(max.max(1))),
+            next_start: Mutex::new(now),
+            paid_until: std::sync::Mutex::new(now),
+            profile,
+        }
+    }
+
+    /// Waits for a slot and for the delay since the last start, and holds the
+    /// slot until the permit is dropped.
+    pub(crate) async fn start(&self) -> Option<SemaphorePermit<'_>> {
+        // the semaphore is never closed
+        let slot = match &self.slots {
+            Some(slots) => slots.acquire().await.ok(),
+            None => None,
+        };
+        if let Some(delay) = self.profile.delay {
+            let mut next_start = self.next_start.lock().await;
+            tokio::time::sleep_until(*next_start).await;
+            *next_start = Instant::now() + delay;
+        }
+        slot
+    }
+
+    /// Waits until reading `bytes` more keeps to the rate.
+    pub(crate) async fn pace(&self, bytes: usize) {
+        let Some(rate) = self.profile.rate.filter(|&rate| rate > 0) else {
+            return;
+        };
+        let until = {
+            let mut paid_until = self.paid_until.lock().unwrap();
+            let start = (*paid_until).max(Instant::now());
+            *paid_until = start + Duration::from_secs_f64(bytes as f64 / rate as f64);
+            *paid_until
+        };
+        tokio::time::sleep_until(until).await;
+    }
+}
+
+/// The limits of every profile of a downloader.
+#[derive(Clone, Default)]
+pub(crate) struct HostLimits {
+    limits: Arc<Vec<Arc<HostLimit>>>,
+}
+
+impl HostLimits {
+    pub(crate) fn new(profiles: &[HostProfile]) -> HostLimits {
+        let limits = profiles
+            .iter()
+            .map(|profile| Arc::new(HostLimit::new(profile.clone())))
+            .collect();
+        HostLimits {
+            limits: Arc::new(limits),
+        }
+    }
+
+    /// The limits for the host of `url`, if a profile matches it.
+    pub(crate) fn get(&self, url: &str) -> Option<Arc<HostLimit>> {
+        if self.limits.is_empty() {
+            return None;
+        }
+        let url = Url::parse(url).ok()?;
+        let host = url.host_str()?.to_ascii_lowercase();
+        self.limits
+            .iter()
+            .filter_map(|limit| Some((limit.profile.matches(&host)?, limit)))
+            .max_by_key(|(specificity, _)| *specificity)
+            .map(|(_, limit)| limit.clone())
+    }
+}
@@ -0,0 +1,84 @@
+//! The look of the progress bar: an indicatif template and progress characters
+//! from `--progress-template` and `--progress-chars`, or the `[progress]`
+//! table of the config.
+
+use crate::{config::ProgressConfig, eta::Eta};
+use indicatif::{HumanDuration, ProgressBar, ProgressState, ProgressStyle};
+use std::{
+    fmt,
+    io::{self, IsTerminal},
+    time::Duration,
+};
+
+/// indicatif's default bar with the time left.
+const DEFAULT_TEMPLATE: &str = "{wide_bar} {pos}/{len} (eta {eta})";
+
+/// The progress bar of a run of `len` items, whose `{eta}` is that of `eta`.
+/// It's hidden when `hidden` is set or stderr isn't a terminal, where
+/// redrawing it would only clutter logs.
+pub fn bar(
+    len: u64,
+    hidden: bool,
+    template: Option<&str>,
+    chars: Option<&str>,
+    config: &ProgressConfig,
+    eta: &Eta,
+) -> Result<ProgressBar, String> {
+    let style = style(
+        template.or(config.template.as_deref()),
+        chars.or(config.chars.as_deref()),
+    )?;
+    if hidden || !io::stderr().is_terminal() {
+        return Ok(ProgressBar::hidden());
+    }
+    let eta = eta.clone();
+    let style = style.with_key("eta", move |_: &ProgressState, out: &mut dyn fmt::Write| {
+        let _ = match eta.left() {
+            Some(left) => write!(out, "{}", HumanDuration(left)),
+            None => write!(out, "?"),
+        };
+    });
+    Ok(ProgressBar::new(len).with_style(style))
+}
+
+/// A bar for a stage before the downloads start, like parsing the url file or
+/// asking servers for sizes, so a long one doesn't look hung. It counts bytes
+/// with `bytes`, items otherwise, and is hidden like the bar of [`bar`].
+pub fn stage(hidden: bool, message: &'static str, bytes: bool) -> ProgressBar {
+    if hidden || !io::stderr().is_terminal() {
+        return ProgressBar::hidden();
+    }
+    let template = match bytes {
+        true => "{spinner} {msg} {bytes}/{total_bytes}",
+        false => "{spinner} {msg} {pos}/{len}",
+    };
+    let stage = ProgressBar::new(0)
+        .with_style(ProgressStyle::with_template(template).expect("valid template"))
+        .with_message(message);
+    // a stage waiting on one slow server still moves
+    stage.enable_steady_tick(Duration::from_millis(100));
+    stage
+}
+
+/// Turns off the colors of templates like `{bar:.cyan}`, which `NO_COLOR`
+/// and a stderr that isn't a terminal already do.
+pub fn disable_colors() {
+    console::set_colors_enabled(false);
+    console::set_colors_enabled_stderr(false);
+}
+
+fn style(template: Option<&str>, chars: Option<&str>) -> Result<ProgressStyle, String> {
+    let mut style = ProgressStyle::with_template(template.unwrap_or(DEFAULT_TEMPLATE))
+        .map_err(|err| format!("invalid progress template: {}", err))?;
+    if let Some(chars) = chars {
+        // indicatif needs a full and an empty character at least
+        if chars.chars().count() < 2 {
+            return Err(format!(
+                "progress characters need at least two characters, not {:?}",
+                chars
+            ));
+        }
+        style = style.progress_chars(chars);
+    }
+    Ok(style)
+}
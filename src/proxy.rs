@@ -0,0 +1,319 @@
+//! `fast_download proxy`: a local HTTP forward proxy that keeps the responses
+//! passing through it in the content-addressable [`Cache`].
+//!
+//! Point a batch at it with `HTTP_PROXY=http://127.0.0.1:3128` and later runs
+//! are answered from disk. Only plain `http://` GETs without credentials or
+//! cookies that come back `200`, without `Set-Cookie` or `Vary`, are cached. A
+//! cached response is served as is within its `max-age`, and once stale, or
+//! with `no-cache`, after the origin answers its validators with `304`. HTTPS
+//! is tunneled through `CONNECT` untouched, since the proxy can't see inside
+//! it.
+
+use crate::cache::{Cache, Entry};
+use hyper::{
+    header::{self, HeaderName, HeaderValue},
+    service::{make_service_fn, service_fn},
+    upgrade, Body, Method, Request, Response, Server, StatusCode,
+};
+use std::{
+    convert::Infallible,
+    net::SocketAddr,
+    path::Path,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::{fs, net::TcpStream};
+use tokio_util::io::ReaderStream;
+
+pub const DEFAULT_ADDR: &str = "127.0.0.1:3128";
+
+/// Headers that describe a single connection and are never forwarded.
+const HOP_BY_HOP: &[&str] = &[
+    "connection",
+    "proxy-connection",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+];
+
+/// Response headers that belong to one client and are never stored.
+const PRIVATE: &[&str] = &["set-cookie", "set-cookie2"];
+
+struct Shared {
+    cache: Cache,
+    client: reqwest::Client,
+}
+
+pub async fn run(cache_dir: &Path, addr: SocketAddr) -> Result<(), String> {
+    let cache = Cache::open(cache_dir)
+        .await
+        .map_err(|err| format!("failed to open cache {}: {}", cache_dir.display(), err))?;
+    // redirects go back to the client, and the proxy must not proxy itself
+    let client = reqwest::Client::builder()
+        .redirect(reqwest::redirect::Policy::none())
+        .no_proxy()
+        .build()
+        .map_err(|err| err.to_string())?;
+    let shared = Arc::new(Shared { cache, client });
+    let make_service = make_service_fn(move |_| {
+        let shared = shared.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request| {
+                let shared = shared.clone();
+                async move { Ok::<_, Infallible>(respond(shared, request).await) }
+            }))
+        }
+    });
+    let server = Server::try_bind(&addr)
+        .map_err(|err| format!("failed to listen on {}: {}", addr, err))?
+        .serve(make_service);
+    eprintln!(
+        "caching proxy on http://{}/, cache in {}",
+        server.local_addr(),
+        cache_dir.display()
+    );
+    server
+        .with_graceful_shutdown(async {
+            let _ = tokio::signal::ctrl_c().await;
+        })
+        .await
+        .map_err(|err| err.to_string())
+}
+
+async fn respond(shared: Arc<Shared>, request: Request<Body>) -> Response<Body> {
+    if request.method() == Method::CONNECT {
+        return tunnel(request);
+    }
+    if request.uri().scheme_str() != Some("http") {
+        return status(StatusCode::BAD_REQUEST, "expected an absolute http:// url");
+    }
+    let url = request.uri().to_string();
+    let cacheable = request.method() == Method::GET
+        && !request.headers().contains_key(header::RANGE)
+        && !request.headers().contains_key(header::AUTHORIZATION)
+        && !request.headers().contains_key(header::COOKIE);
+    // a client sending validators of its own gets the origin's answer to them
+    let conditional = request.headers().contains_key(header::IF_NONE_MATCH)
+        || request.headers().contains_key(header::IF_MODIFIED_SINCE);
+    let mut stale = None;
+    if cacheable {
+        if let Some((entry, blob)) = shared.cache.get(&url).await {
+            if entry.fresh_until.is_some_and(|until| until > unix_now()) {
+                if let Some(response) = hit(&entry, &blob, "HIT").await {
+                    return response;
+                }
+            } else if !conditional && !validators(&entry).is_empty() {
+                stale = Some((entry, blob));
+            }
+        }
+    }
+    let (parts, body) = request.into_parts();
+    let body = match hyper::body::to_bytes(body).await {
+        Ok(body) => body,
+        Err(err) => return status(StatusCode::BAD_REQUEST, &err.to_string()),
+    };
+    let mut forwarded = shared.client.request(parts.method, &url).body(body);
+    for (name, value) in &parts.headers {
+        if !is_hop_by_hop(name) && *name != header::HOST {
+            forwarded = forwarded.header(name, value);
+        }
+    }
+    if let Some((entry, _)) = &stale {
+        for (name, value) in validators(entry) {
+            forwarded = forwarded.header(name, value);
+        }
+    }
+    let mut origin = match forwarded.send().await {
+        Ok(origin) => origin,
+        Err(err) => return status(StatusCode::BAD_GATEWAY, &err.to_string()),
+    };
+    if let (Some((mut entry, blob)), reqwest::StatusCode::NOT_MODIFIED) = (stale, origin.status()) {
+        entry.fresh_until = origin
+            .headers()
+            .get(header::CACHE_CONTROL)
+            .and_then(|value| value.to_str().ok())
+            .and_then(fresh_until);
+        let _ = shared.cache.save(&entry).await;
+        return match hit(&entry, &blob, "REVALIDATED").await {
+            Some(response) => response,
+            None => status(StatusCode::BAD_GATEWAY, "the cached body is gone"),
+        };
+    }
+    let headers = origin
+        .headers()
+        .iter()
+        .filter(|(name, _)| !is_hop_by_hop(name))
+        .filter_map(|(name, value)| Some((name.to_string(), value.to_str().ok()?.to_string())))
+        .collect::<Vec<_>>();
+    let mut response = Response::builder().status(origin.status().as_u16());
+    for (name, value) in origin.headers() {
+        if !is_hop_by_hop(name) {
+            response = response.header(name, value);
+        }
+    }
+    let store = cacheable && origin.status() == reqwest::StatusCode::OK && storable(&headers);
+    let (mut sender, body) = Body::channel();
+    tokio::spawn(async move {
+        let writer = match store {
+            true => shared.cache.writer().await.ok(),
+            false => None,
+        };
+        let Some(mut writer) = writer else {
+            // without the cache the response is only passed on
+            loop {
+                match origin.chunk().await {
+                    Ok(Some(chunk)) => {
+                        if sender.send_data(chunk).await.is_err() {
+                            return;
+                        }
+                    }
+                    Ok(None) => return,
+                    Err(_) => return sender.abort(),
+                }
+            }
+        };
+        // a client that goes away doesn't stop the cache from being filled
+        let mut client_gone = false;
+        loop {
+            match origin.chunk().await {
+                Ok(Some(chunk)) => {
+                    if writer.write(&chunk).await.is_err() {
+                        writer.discard().await;
+                        return;
+                    }
+                    if !client_gone {
+                        client_gone = sender.send_data(chunk).await.is_err();
+                    }
+                }
+                Ok(None) => break,
+                Err(_) => {
+                    sender.abort();
+                    writer.discard().await;
+                    return;
+                }
+            }
+        }
+        let fresh_until = headers
+            .iter()
+            .find(|(name, _)| name == "cache-control")
+            .and_then(|(_, value)| fresh_until(value));
+        let headers = headers
+            .into_iter()
+            .filter(|(name, _)| name != "content-length" && !PRIVATE.contains(&name.as_str()))
+            .collect();
+        let _ = shared
+            .cache
+            .commit(writer, &url, 200, headers, fresh_until)
+            .await;
+    });
+    response
+        .header("x-cache", "MISS")
+        .body(body)
+        .expect("origin headers are valid")
+}
+
+/// Whether the origin allows keeping the response for every client: it sets
+/// no cookies and doesn't vary with request headers the cache doesn't key on.
+fn storable(headers: &[(String, String)]) -> bool {
+    !headers.iter().any(|(name, value)| {
+        let value = value.to_ascii_lowercase();
+        (name == "cache-control" && (value.contains("no-store") || value.contains("private")))
+            || name == "vary"
+            || PRIVATE.contains(&name.as_str())
+    })
+}
+
+/// When a response with `cache_control` goes stale, `None` when it has to be
+/// revalidated before every use; `s-maxage` wins over `max-age`, as the proxy
+/// is a shared cache.
+fn fresh_until(cache_control: &str) -> Option<u64> {
+    let (mut max_age, mut shared_max_age) = (None, None);
+    for directive in cache_control.split(',') {
+        let directive = directive.trim().to_ascii_lowercase();
+        match directive.split_once('=') {
+            _ if directive == "no-cache" => return None,
+            Some(("max-age", seconds)) => max_age = seconds.trim_matches('"').parse::<u64>().ok(),
+            Some(("s-maxage", seconds)) => {
+                shared_max_age = seconds.trim_matches('"').parse::<u64>().ok()
+            }
+            _ => {}
+        }
+    }
+    shared_max_age
+        .or(max_age)
+        .map(|max_age| unix_now() + max_age)
+}
+
+/// The `If-None-Match` and `If-Modified-Since` headers that revalidate
+/// `entry`.
+fn validators(entry: &Entry) -> Vec<(HeaderName, &str)> {
+    entry
+        .headers
+        .iter()
+        .filter_map(|(name, value)| match name.as_str() {
+            "etag" => Some((header::IF_NONE_MATCH, value.as_str())),
+            "last-modified" => Some((header::IF_MODIFIED_SINCE, value.as_str())),
+            _ => None,
+        })
+        .collect()
+}
+
+/// The cached response `entry` with the body in `blob`, unless the blob can't
+/// be opened.
+async fn hit(entry: &Entry, blob: &Path, x_cache: &'static str) -> Option<Response<Body>> {
+    let file = fs::File::open(blob).await.ok()?;
+    let mut response = Response::builder().status(entry.status);
+    for (name, value) in &entry.headers {
+        response = response.header(name, value);
+    }
+    Some(
+        response
+            .header(header::CONTENT_LENGTH, entry.size)
+            .header("x-cache", x_cache)
+            .body(Body::wrap_stream(ReaderStream::new(file)))
+            .expect("cached headers are valid"),
+    )
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or(Duration::ZERO)
+        .as_secs()
+}
+
+/// Relays the bytes of a `CONNECT` tunnel between the client and the target.
+fn tunnel(request: Request<Body>) -> Response<Body> {
+    let Some(target) = request
+        .uri()
+        .authority()
+        .map(|authority| authority.to_string())
+    else {
+        return status(StatusCode::BAD_REQUEST, "CONNECT needs host:port");
+    };
+    tokio::spawn(async move {
+        let Ok(mut upgraded) = upgrade::on(request).await else {
+            return;
+        };
+        if let Ok(mut server) = TcpStream::connect(&target).await {
+            let _ = tokio::io::copy_bidirectional(&mut upgraded, &mut server).await;
+        }
+    });
+    Response::new(Body::empty())
+}
+
+fn is_hop_by_hop(name: &HeaderName) -> bool {
+    HOP_BY_HOP.contains(&name.as_str())
+}
+
+fn status(status: StatusCode, message: &str) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header(header::CONTENT_TYPE, HeaderValue::from_static("text/plain"))
+        .body(Body::from(format!("{}: {}\n", status, message)))
+        .expect("valid response")
+}
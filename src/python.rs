@@ -0,0 +1,247 @@
+//! Python bindings, built with `maturin build` which enables the `python`
+//! feature.
+//!
+//! ```python
+//! import fast_download
+//!
+//! downloader = fast_download.Downloader(max_concurrent_downloads=20, retries=2)
+//! batch = downloader.submit([("https://example.com/a.jpg", "images/a.jpg")])
+//! for event in batch:
+//!     if event["event"] == "failed":
+//!         print(event["id"], event["error"])
+//! report = batch.report()
+//! print(report.downloaded, report.skipped, report.failed)
+//! ```
+
+// the code pyo3 generates for methods returning `PyResult` trips this lint
+#![allow(clippy::useless_conversion)]
+
+use crate::{DownloadCompleted, Downloader, Event, Image, Options, Order, Outcome};
+use futures::StreamExt;
+use pyo3::{
+    exceptions::PyRuntimeError,
+    prelude::*,
+    types::{IntoPyDict, PyDict},
+};
+use std::{path::PathBuf, sync::Arc};
+use tokio::{runtime::Runtime, sync::mpsc, task::JoinHandle};
+
+#[pyclass(name = "Downloader")]
+struct PyDownloader {
+    options: Options,
+    runtime: Arc<Runtime>,
+}
+
+/// A list of downloads running in the background. Iterating it yields event
+/// dicts until every download has finished.
+#[pyclass]
+struct Batch {
+    runtime: Arc<Runtime>,
+    events: Option<mpsc::UnboundedReceiver<Event>>,
+    task: Option<JoinHandle<Vec<Outcome>>>,
+    report: Option<Report>,
+}
+
+#[pyclass(get_all)]
+#[derive(Clone)]
+struct Report {
+    downloaded: usize,
+    skipped: usize,
+    failed: usize,
+    /// `(url, path, status, error)` for every item in submission order.
+    items: Vec<(String, String, &'static str, Option<String>)>,
+}
+
+#[pymethods]
+impl PyDownloader {
+    #[new]
+    #[pyo3(signature = (max_concurrent_downloads=20, force_redownload=false, fsync=false, temp_dir=None, retries=0))]
+    fn new(
+        max_concurrent_downloads: usize,
+        force_redownload: bool,
+        fsync: bool,
+        temp_dir: Option<PathBuf>,
+        retries: u32,
+    ) -> PyResult<PyDownloader> {
+        let runtime = Runtime::new().map_err(|err| PyRuntimeError::new_err(err.to_string()))?;
+        Ok(PyDownloader {
+            options: Options {
+                max_concurrent_downloads,
+                force_redownload,
+                fsync,
+                temp_dir,
+                retries,
+                ..Options::default()
+            },
+            runtime: Arc::new(runtime),
+        })
+    }
+
+    /// Starts downloading `(url, path)` pairs. With `events=False` the batch
+    /// doesn't keep events around for iteration, only the report.
+    #[pyo3(signature = (items, events=true))]
+    fn submit(&self, items: Vec<(String, String)>, events: bool) -> PyResult<Batch> {
+        // every batch gets its own downloader, so its events end with it
+        let downloader = Downloader::new(self.options.clone())
+            .map_err(|err| PyRuntimeError::new_err(err.to_string()))?;
+        let events = events.then(|| downloader.subscribe());
+        let images = items
+            .into_iter()
+            .map(|(url, path)| Image::new(url, path))
+            .collect::<Vec<_>>();
+        let task = self.runtime.spawn(async move {
+            downloader
+                .stream(images, Order::Submission)
+                .collect::<Vec<_>>()
+                .await
+        });
+        Ok(Batch {
+            runtime: self.runtime.clone(),
+            events,
+            task: Some(task),
+            report: None,
+        })
+    }
+}
+
+#[pymethods]
+impl Batch {
+    fn __iter__(slf: PyRef<'_, Self>) -> PyRef<'_, Self> {
+        slf
+    }
+
+    fn __next__(&mut self, py: Python<'_>) -> PyResult<Option<Py<PyDict>>> {
+        let Some(events) = &mut self.events else {
+            return Ok(None);
+        };
+        match py.allow_threads(|| events.blocking_recv()) {
+            Some(event) => event_dict(py, event).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Waits for every download to finish.
+    fn report(&mut self, py: Python<'_>) -> PyResult<Report> {
+        if let Some(task) = self.task.take() {
+            let runtime = &self.runtime;
+            let outcomes = py
+                .allow_threads(|| runtime.block_on(task))
+                .map_err(|err| PyRuntimeError::new_err(err.to_string()))?;
+            self.report = Some(Report::new(outcomes));
+        }
+        self.report
+            .clone()
+            .ok_or_else(|| PyRuntimeError::new_err("the batch was stopped"))
+    }
+}
+
+#[pymethods]
+impl Report {
+    fn __repr__(&self) -> String {
+        format!(
+            "Report(downloaded={}, skipped={}, failed={})",
+            self.downloaded, self.skipped, self.failed
+        )
+    }
+}
+
+impl Report {
+    fn new(outcomes: Vec<Outcome>) -> Report {
+        let mut report = Report {
+            downloaded: 0,
+            skipped: 0,
+            failed: 0,
+            items: Vec::with_capacity(outcomes.len()),
+        };
+        for Outcome { image, result, .. } in outcomes {
+            let (status, error) = match result {
+                Ok(DownloadCompleted::Success) => {
+                    report.downloaded += 1;
+                    ("downloaded", None)
+                }
+                Ok(DownloadCompleted::Skipped) => {
+                    report.skipped += 1;
+                    ("skipped", None)
+                }
+                Err(err) => {
+                    report.failed += 1;
+                    ("failed", Some(err.to_string()))
+                }
+            };
+            report
+                .items
+                .push((image.url, image.file_name, status, error));
+        }
+        report
+    }
+}
+
+fn event_dict(py: Python<'_>, event: Event) -> PyResult<Py<PyDict>> {
+    let dict = PyDict::new_bound(py);
+    match event {
+        Event::ItemStarted {
+            id,
+            url,
+            file_name,
+            item_id,
+            meta,
+        } => {
+            dict.set_item("event", "started")?;
+            dict.set_item("id", id)?;
+            dict.set_item("url", url)?;
+            dict.set_item("path", file_name)?;
+            dict.set_item("item_id", item_id)?;
+            dict.set_item("meta", meta.into_py_dict_bound(py))?;
+        }
+        Event::ChunkReceived {
+            id,
+            bytes,
+            total,
+            copied,
+        } => {
+            dict.set_item("event", "chunk")?;
+            dict.set_item("id", id)?;
+            dict.set_item("bytes", bytes)?;
+            dict.set_item("total", total)?;
+            dict.set_item("copied", copied)?;
+        }
+        Event::ItemFinished { id, outcome } => {
+            dict.set_item("event", "finished")?;
+            dict.set_item("id", id)?;
+            let outcome = match outcome {
+                DownloadCompleted::Success => "downloaded",
+                DownloadCompleted::Skipped => "skipped",
+            };
+            dict.set_item("outcome", outcome)?;
+        }
+        Event::ItemFailed {
+            id,
+            error,
+            attempt,
+            will_retry,
+        } => {
+            dict.set_item("event", "failed")?;
+            dict.set_item("id", id)?;
+            dict.set_item("error", error)?;
+            dict.set_item("attempt", attempt)?;
+            dict.set_item("will_retry", will_retry)?;
+        }
+        Event::Request { id, record } => {
+            dict.set_item("event", "request")?;
+            dict.set_item("id", id)?;
+            dict.set_item("url", &record.url)?;
+            dict.set_item("status", record.status)?;
+            dict.set_item("bytes", record.bytes)?;
+            dict.set_item("error", &record.error)?;
+        }
+    }
+    Ok(dict.unbind())
+}
+
+#[pymodule]
+fn fast_download(module: &Bound<'_, PyModule>) -> PyResult<()> {
+    module.add_class::<PyDownloader>()?;
+    module.add_class::<Batch>()?;
+    module.add_class::<Report>()?;
+    Ok(())
+}
@@ -0,0 +1,167 @@
+//! The `--report` summary written when a run ends.
+
+use crate::Stats;
+use fast_download::{Image, RequestRecord, Timings};
+use serde_json::json;
+use std::{collections::BTreeMap, fs, io, path::Path, time::Duration};
+
+struct Failure {
+    id: String,
+    url: String,
+    path: String,
+    error: String,
+    meta: Vec<(String, String)>,
+    tags: Vec<String>,
+    source: Option<String>,
+}
+
+struct Request {
+    url: String,
+    status: Option<u16>,
+    bytes: u64,
+    timings: Timings,
+}
+
+#[derive(Default)]
+pub struct Report {
+    failures: Vec<Failure>,
+    requests: Vec<Request>,
+    /// The outcomes of the entries of every tag.
+    tags: BTreeMap<String, Stats>,
+}
+
+impl Report {
+    pub fn failed(&mut self, image: &Image, error: &str) {
+        self.failures.push(Failure {
+            id: image.id(),
+            url: image.url.clone(),
+            path: image.file_name.clone(),
+            error: error.to_string(),
+            meta: image.meta.clone(),
+            tags: image.tags.clone(),
+            source: image.source.clone(),
+        });
+    }
+
+    /// Counts an outcome of `image` for each of its tags, the way `count`
+    /// counts it for the run.
+    pub fn tally(&mut self, image: &Image, count: impl Fn(&mut Stats)) {
+        for tag in &image.tags {
+            count(self.tags.entry(tag.clone()).or_default());
+        }
+    }
+
+    pub fn request(&mut self, record: &RequestRecord) {
+        self.requests.push(Request {
+            url: record.url.clone(),
+            status: record.status,
+            bytes: record.bytes,
+            timings: record.timings.clone(),
+        });
+    }
+
+    /// Writes the report as JSON to `path`, or to stdout when `path` is `-`.
+    /// `error` is what stopped the run, if anything did.
+    pub fn write(
+        &self,
+        path: &Path,
+        stats: &Stats,
+        total: u64,
+        elapsed: Duration,
+        error: Option<&str>,
+    ) -> io::Result<()> {
+        let failures = self
+            .failures
+            .iter()
+            .map(|failure| {
+                json!({
+                    "id": failure.id,
+                    "url": failure.url,
+                    "path": failure.path,
+                    "error": failure.error,
+                    "source": failure.source,
+                    "meta": failure.meta.iter().cloned().collect::<BTreeMap<_, _>>(),
+                    "tags": failure.tags,
+                })
+            })
+            .collect::<Vec<_>>();
+        let tags = self
+            .tags
+            .iter()
+            .map(|(tag, counts)| {
+                let counts = json!({
+                    "downloaded": counts.downloaded,
+                    "verified": stats.verified.map(|_| counts.verified.unwrap_or(0)),
+                    "skipped": counts.skipped,
+                    "failed": counts.failed,
+                });
+                (tag.clone(), counts)
+            })
+            .collect::<serde_json::Map<_, _>>();
+        let millis = |duration: Option<Duration>| duration.map(|d| d.as_secs_f64() * 1000.0);
+        let requests = self
+            .requests
+            .iter()
+            .map(|request| {
+                json!({
+                    "url": request.url,
+                    "status": request.status,
+                    "bytes": request.bytes,
+                    "dns_ms": millis(request.timings.dns),
+                    "ttfb_ms": millis(request.timings.ttfb),
+                    "transfer_ms": millis(request.timings.transfer),
+                })
+            })
+            .collect::<Vec<_>>();
+        let percentiles = |timing: fn(&Timings) -> Option<Duration>| {
+            percentiles(self.requests.iter().filter_map(|r| timing(&r.timings)))
+        };
+        let report = json!({
+            "result": if error.is_some() { "failed" } else { "finished" },
+            "error": error,
+            "total": total,
+            "downloaded": stats.downloaded,
+            "verified": stats.verified,
+            "skipped": stats.skipped,
+            "failed": stats.failed,
+            "elapsed_secs": elapsed.as_secs_f64(),
+            "failures": failures,
+            "tags": tags,
+            // ttfb includes connecting and the TLS handshake, which aren't
+            // timed separately
+            "timings": {
+                "dns": percentiles(|timings| timings.dns),
+                "ttfb": percentiles(|timings| timings.ttfb),
+                "transfer": percentiles(|timings| timings.transfer),
+            },
+            "requests": requests,
+        });
+        let report = serde_json::to_string_pretty(&report)? + "\n";
+        if path == Path::new("-") {
+            print!("{}", report);
+            Ok(())
+        } else {
+            fs::write(path, report)
+        }
+    }
+}
+
+/// Nearest rank percentiles in milliseconds, `null` without samples.
+fn percentiles(samples: impl Iterator<Item = Duration>) -> serde_json::Value {
+    let mut samples = samples.collect::<Vec<_>>();
+    if samples.is_empty() {
+        return serde_json::Value::Null;
+    }
+    samples.sort();
+    let at = |percent: usize| {
+        let rank = (samples.len() * percent).div_ceil(100).max(1);
+        samples[rank - 1].as_secs_f64() * 1000.0
+    };
+    json!({
+        "count": samples.len(),
+        "p50_ms": at(50),
+        "p90_ms": at(90),
+        "p99_ms": at(99),
+        "max_ms": at(100),
+    })
+}
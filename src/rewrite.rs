@@ -0,0 +1,59 @@
+//! Config driven url rewriting, so mirrors or CDNs can be swapped in without
+//! regenerating url files.
+
+use crate::config::RewriteConfig;
+use regex::Regex;
+use std::collections::HashMap;
+
+#[derive(Debug, Default)]
+pub struct Rewriter {
+    rules: Vec<(Regex, String)>,
+    hosts: HashMap<String, String>,
+    https: bool,
+}
+
+impl Rewriter {
+    pub fn new(config: &RewriteConfig) -> Result<Rewriter, String> {
+        let rules = config
+            .rules
+            .iter()
+            .map(|rule| {
+                Regex::new(&rule.pattern)
+                    .map(|regex| (regex, rule.replace.clone()))
+                    .map_err(|err| format!("invalid rewrite pattern {}: {}", rule.pattern, err))
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Rewriter {
+            rules,
+            hosts: config.hosts.clone(),
+            https: config.https,
+        })
+    }
+
+    /// Applies the regex rules in order, then the host remapping, then the
+    /// https upgrade.
+    pub fn rewrite(&self, url: &str) -> String {
+        let mut url = self
+            .rules
+            .iter()
+            .fold(url.to_string(), |url, (regex, replace)| {
+                regex.replace(&url, replace.as_str()).into_owned()
+            });
+        if self.hosts.is_empty() && !self.https {
+            return url;
+        }
+        // urls that don't parse are left for the download to report
+        if let Ok(mut parsed) = reqwest::Url::parse(&url) {
+            if let Some(host) = parsed.host_str().and_then(|host| self.hosts.get(host)) {
+                if parsed.set_host(Some(&host.clone())).is_err() {
+                    return url;
+                }
+            }
+            if self.https && parsed.scheme() == "http" {
+                let _ = parsed.set_scheme("https");
+            }
+            url = parsed.to_string();
+        }
+        url
+    }
+}
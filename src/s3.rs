@@ -0,0 +1,358 @@
+//! [`Options::s3`](crate::Options::s3): uploading downloads straight to an S3
+//! bucket instead of writing them to disk.
+//!
+//! A download is collected into 8 MiB parts in memory and sent with a
+//! multipart upload as each fills, or with a single PutObject when it fits in
+//! one. Credentials come from `AWS_ACCESS_KEY_ID`, `AWS_SECRET_ACCESS_KEY` and
+//! optionally `AWS_SESSION_TOKEN`, the region from `AWS_REGION`. Setting
+//! `AWS_ENDPOINT_URL` sends path-style requests to another S3 compatible
+//! service.
+
+use crate::sigv4::{self, hex, Credentials};
+use bytes::{Bytes, BytesMut};
+use chrono::Utc;
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use sha2::{Digest, Sha256};
+use std::{
+    env, fmt, io,
+    path::{Component, Path},
+    sync::Arc,
+};
+
+/// Size of the parts of a multipart upload, S3 wants at least 5 MiB.
+const PART_SIZE: usize = 8 << 20;
+
+/// What SigV4 leaves unencoded in paths and query strings.
+const UNRESERVED: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'_')
+    .remove(b'.')
+    .remove(b'~');
+
+/// Where uploads go: `s3://bucket/prefix/` and the credentials to get there.
+#[derive(Clone)]
+pub struct S3Dest {
+    bucket: String,
+    prefix: String,
+    /// Set with `AWS_ENDPOINT_URL`, otherwise AWS itself.
+    endpoint: Option<String>,
+    region: String,
+    credentials: Arc<Credentials>,
+}
+
+impl fmt::Debug for S3Dest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "s3://{}/{}", self.bucket, self.prefix)
+    }
+}
+
+impl S3Dest {
+    /// Parses `s3://bucket/prefix/`, taking the credentials and region from
+    /// the environment.
+    pub fn parse(url: &str) -> Result<S3Dest, String> {
+        let (bucket, prefix) = url
+            .strip_prefix("s3://")
+            .map(|rest| rest.split_once('/').unwrap_or((rest, "")))
+            .filter(|(bucket, _)| !bucket.is_empty())
+            .ok_or_else(|| format!("expected s3://bucket/prefix/, not {}", url))?;
+        Ok(S3Dest {
+            bucket: bucket.to_string(),
+            prefix: prefix.trim_start_matches('/').to_string(),
+            endpoint: env::var("AWS_ENDPOINT_URL")
+                .ok()
+                .map(|endpoint| endpoint.trim_end_matches('/').to_string()),
+            region: env::var("AWS_REGION")
+                .or_else(|_| env::var("AWS_DEFAULT_REGION"))
+                .unwrap_or_else(|_| "us-east-1".to_string()),
+            credentials: Arc::new(Credentials::from_env("S3")?),
+        })
+    }
+
+    /// The key of the file that would be written to `path`, under the prefix.
+    pub fn key(&self, path: &Path) -> String {
+        let parts = path.components().filter_map(|component| match component {
+            Component::Normal(part) => Some(part.to_string_lossy()),
+            _ => None,
+        });
+        let mut key = self.prefix.clone();
+        for part in parts {
+            if !(key.is_empty() || key.ends_with('/')) {
+                key.push('/');
+            }
+            key.push_str(&part);
+        }
+        key
+    }
+
+    /// The size of the object at `key`, `None` if there is none.
+    pub(crate) async fn size(
+        &self,
+        client: &reqwest::Client,
+        key: &str,
+    ) -> io::Result<Option<u64>> {
+        let response = self
+            .send(client, reqwest::Method::HEAD, key, &[], Bytes::new())
+            .await?;
+        match response.status() {
+            reqwest::StatusCode::NOT_FOUND => Ok(None),
+            status if status.is_success() => Ok(response.content_length()),
+            status => Err(io::Error::other(format!(
+                "HEAD {} answered {}",
+                key, status
+            ))),
+        }
+    }
+
+    pub(crate) fn upload(&self, client: &reqwest::Client, key: String) -> Upload {
+        Upload {
+            dest: self.clone(),
+            client: client.clone(),
+            key,
+            buffer: BytesMut::new(),
+            upload_id: None,
+            parts: Vec::new(),
+            finished: false,
+        }
+    }
+
+    /// Sends a signed request for `key` with `query` parameters.
+    async fn send(
+        &self,
+        client: &reqwest::Client,
+        method: reqwest::Method,
+        key: &str,
+        query: &[(&str, &str)],
+        body: Bytes,
+    ) -> io::Result<reqwest::Response> {
+        let path = key
+            .split('/')
+            .map(|part| utf8_percent_encode(part, UNRESERVED).to_string())
+            .collect::<Vec<_>>()
+            .join("/");
+        let (base, path) = match &self.endpoint {
+            Some(endpoint) => (endpoint.clone(), format!("/{}/{}", self.bucket, path)),
+            None => (
+                format!("https://{}.s3.{}.amazonaws.com", self.bucket, self.region),
+                format!("/{}", path),
+            ),
+        };
+        let mut query = query
+            .iter()
+            .map(|(name, value)| {
+                format!(
+                    "{}={}",
+                    utf8_percent_encode(name, UNRESERVED),
+                    utf8_percent_encode(value, UNRESERVED)
+                )
+            })
+            .collect::<Vec<_>>();
+        query.sort();
+        let query = query.join("&");
+        let url = reqwest::Url::parse(&format!("{}{}", base, path))
+            .map_err(|err| io::Error::other(format!("invalid S3 url: {}", err)))?;
+        let host = match url.port() {
+            Some(port) => format!("{}:{}", url.host_str().unwrap_or_default(), port),
+            None => url.host_str().unwrap_or_default().to_string(),
+        };
+        let amz_date = Utc::now().format("%Y%m%dT%H%M%SZ").to_string();
+        let payload_hash = hex(&Sha256::digest(&body));
+        let mut headers = vec![
+            ("host", host),
+            ("x-amz-content-sha256", payload_hash.clone()),
+            ("x-amz-date", amz_date.clone()),
+        ];
+        if let Some(token) = &self.credentials.session_token {
+            headers.push(("x-amz-security-token", token.clone()));
+        }
+        let authorization = self.credentials.authorization(
+            &sigv4::Request {
+                method: method.as_str(),
+                path: &path,
+                query: &query,
+                headers: &headers,
+                payload_hash: &payload_hash,
+            },
+            &self.region,
+            "s3",
+            &amz_date,
+        );
+        let url = match query.is_empty() {
+            true => format!("{}{}", base, path),
+            false => format!("{}{}?{}", base, path, query),
+        };
+        let mut request = client.request(method, url).body(body);
+        for (name, value) in &headers {
+            if *name != "host" {
+                request = request.header(*name, value);
+            }
+        }
+        request
+            .header(reqwest::header::AUTHORIZATION, authorization)
+            .send()
+            .await
+            .map_err(io::Error::other)
+    }
+}
+
+/// An object being uploaded. Dropped before it is finished, a multipart
+/// upload is aborted so its parts don't linger in the bucket.
+pub(crate) struct Upload {
+    dest: S3Dest,
+    client: reqwest::Client,
+    key: String,
+    buffer: BytesMut,
+    /// Set once the first part is sent.
+    upload_id: Option<String>,
+    /// The ETags of the parts sent so far.
+    parts: Vec<String>,
+    finished: bool,
+}
+
+impl Upload {
+    pub async fn write(&mut self, chunk: &[u8]) -> io::Result<()> {
+        self.buffer.extend_from_slice(chunk);
+        while self.buffer.len() >= PART_SIZE {
+            let part = self.buffer.split_to(PART_SIZE).freeze();
+            self.send_part(part).await?;
+        }
+        Ok(())
+    }
+
+    /// Sends what is left and completes the object.
+    pub async fn finish(mut self) -> io::Result<()> {
+        let rest = self.buffer.split().freeze();
+        if self.upload_id.is_none() {
+            let response = self
+                .dest
+                .send(&self.client, reqwest::Method::PUT, &self.key, &[], rest)
+                .await?;
+            check("PutObject", response).await?;
+            self.finished = true;
+            return Ok(());
+        }
+        if !rest.is_empty() {
+            self.send_part(rest).await?;
+        }
+        let parts = self
+            .parts
+            .iter()
+            .enumerate()
+            .map(|(index, etag)| {
+                format!(
+                    "<Part><PartNumber>{}</PartNumber><ETag>{}</ETag></Part>",
+                    index + 1,
+                    etag
+                )
+            })
+            .collect::<String>();
+        let body = format!(
+            "<CompleteMultipartUpload>{}</CompleteMultipartUpload>",
+            parts
+        );
+        let upload_id = self.upload_id.clone().expect("multipart upload");
+        let response = self
+            .dest
+            .send(
+                &self.client,
+                reqwest::Method::POST,
+                &self.key,
+                &[("uploadId", &upload_id)],
+                Bytes::from(body),
+            )
+            .await?;
+        // an error can also come with 200 OK once the upload was accepted
+        let text = check("CompleteMultipartUpload", response).await?;
+        if text.contains("<Error>") {
+            return Err(io::Error::other(format!(
+                "CompleteMultipartUpload failed: {}",
+                text.trim()
+            )));
+        }
+        self.finished = true;
+        Ok(())
+    }
+
+    async fn send_part(&mut self, part: Bytes) -> io::Result<()> {
+        let upload_id = match &self.upload_id {
+            Some(upload_id) => upload_id.clone(),
+            None => {
+                let response = self
+                    .dest
+                    .send(
+                        &self.client,
+                        reqwest::Method::POST,
+                        &self.key,
+                        &[("uploads", "")],
+                        Bytes::new(),
+                    )
+                    .await?;
+                let text = check("CreateMultipartUpload", response).await?;
+                let upload_id = element(&text, "UploadId").ok_or_else(|| {
+                    io::Error::other("CreateMultipartUpload answered without an UploadId")
+                })?;
+                self.upload_id = Some(upload_id.to_string());
+                upload_id.to_string()
+            }
+        };
+        let number = (self.parts.len() + 1).to_string();
+        let response = self
+            .dest
+            .send(
+                &self.client,
+                reqwest::Method::PUT,
+                &self.key,
+                &[("partNumber", &number), ("uploadId", &upload_id)],
+                part,
+            )
+            .await?;
+        let etag = response
+            .headers()
+            .get(reqwest::header::ETAG)
+            .and_then(|etag| etag.to_str().ok())
+            .map(str::to_string);
+        check("UploadPart", response).await?;
+        self.parts
+            .push(etag.ok_or_else(|| io::Error::other("UploadPart answered without an ETag"))?);
+        Ok(())
+    }
+}
+
+impl Drop for Upload {
+    fn drop(&mut self) {
+        let Some(upload_id) = self.upload_id.take().filter(|_| !self.finished) else {
+            return;
+        };
+        let (dest, client, key) = (self.dest.clone(), self.client.clone(), self.key.clone());
+        // at worst the parts wait for the bucket's lifecycle rules
+        if let Ok(runtime) = tokio::runtime::Handle::try_current() {
+            runtime.spawn(async move {
+                let query = [("uploadId", upload_id.as_str())];
+                let _ = dest
+                    .send(&client, reqwest::Method::DELETE, &key, &query, Bytes::new())
+                    .await;
+            });
+        }
+    }
+}
+
+/// The body of a successful response, or its status and body as an error.
+async fn check(action: &str, response: reqwest::Response) -> io::Result<String> {
+    let status = response.status();
+    let text = response.text().await.map_err(io::Error::other)?;
+    match status.is_success() {
+        true => Ok(text),
+        false => Err(io::Error::other(format!(
+            "{} answered {}: {}",
+            action,
+            status,
+            text.trim()
+        ))),
+    }
+}
+
+/// The text of the first `<name>` element of an XML response.
+fn element<'a>(xml: &'a str, name: &str) -> Option<&'a str> {
+    let start = xml.find(&format!("<{}>", name))? + name.len() + 2;
+    let end = start + xml[start..].find(&format!("</{}>", name))?;
+    Some(&xml[start..end])
+}
@@ -0,0 +1,186 @@
+//! Rhai scripts that rewrite or veto items as the url file is parsed.
+//!
+//! A script given with `--script` must define `fn transform(item)`. `item` is a
+//! map with `url`, `path`, `headers` (a map of header name to value),
+//! `mirrors` (an array of urls), `copies` (an array of paths), `meta` (a map of
+//! the `meta.<key>=` fields), `tags` (an array of the `tag=` fields) and
+//! `sha256`, `sha256tree` and `size` (the expected checksums and size, `()`
+//! when the url file has none). The function returns the item, modified as it
+//! sees fit, or `false`/`()` to drop it:
+//!
+//! ```rhai
+//! fn transform(item) {
+//!     if item.url.ends_with(".gif") { return false; }
+//!     item.url.replace("http://", "https://");
+//!     item.headers["Referer"] = "https://example.com/";
+//!     item
+//! }
+//! ```
+
+use fast_download::Image;
+use rhai::{Dynamic, Engine, Map, Scope, AST};
+use std::path::Path;
+
+pub struct Script {
+    engine: Engine,
+    ast: AST,
+}
+
+impl Script {
+    pub fn load(path: &Path) -> Result<Script, String> {
+        let engine = Engine::new();
+        let ast = engine
+            .compile_file(path.to_path_buf())
+            .map_err(|err| err.to_string())?;
+        if !ast
+            .iter_functions()
+            .any(|f| f.name == "transform" && f.params.len() == 1)
+        {
+            return Err("script doesn't define fn transform(item)".to_string());
+        }
+        Ok(Script { engine, ast })
+    }
+
+    /// Returns `None` when the script drops the item.
+    pub fn transform(&self, image: Image) -> Result<Option<Image>, String> {
+        let mut headers = Map::new();
+        for (name, value) in image.headers {
+            headers.insert(name.into(), value.into());
+        }
+        let mut meta = Map::new();
+        for (key, value) in image.meta {
+            meta.insert(key.into(), value.into());
+        }
+        let mut item = Map::new();
+        item.insert("url".into(), image.url.into());
+        item.insert("path".into(), image.file_name.into());
+        item.insert("headers".into(), headers.into());
+        item.insert("meta".into(), meta.into());
+        item.insert(
+            "mirrors".into(),
+            image
+                .mirrors
+                .into_iter()
+                .map(Dynamic::from)
+                .collect::<rhai::Array>()
+                .into(),
+        );
+        item.insert(
+            "copies".into(),
+            image
+                .copies
+                .into_iter()
+                .map(Dynamic::from)
+                .collect::<rhai::Array>()
+                .into(),
+        );
+        item.insert(
+            "tags".into(),
+            image
+                .tags
+                .into_iter()
+                .map(Dynamic::from)
+                .collect::<rhai::Array>()
+                .into(),
+        );
+        item.insert(
+            "sha256".into(),
+            image.sha256.map_or(Dynamic::UNIT, Dynamic::from),
+        );
+        item.insert(
+            "sha256tree".into(),
+            image.sha256_tree.map_or(Dynamic::UNIT, Dynamic::from),
+        );
+        item.insert(
+            "size".into(),
+            image
+                .size
+                .map_or(Dynamic::UNIT, |size| Dynamic::from(size as i64)),
+        );
+        let result: Dynamic = self
+            .engine
+            .call_fn(&mut Scope::new(), &self.ast, "transform", (item,))
+            .map_err(|err| err.to_string())?;
+        if result.is_unit() || result.as_bool() == Ok(false) {
+            return Ok(None);
+        }
+        let mut item = result
+            .try_cast::<Map>()
+            .ok_or("transform must return the item, false or ()")?;
+        let mut field = |name: &str| {
+            item.remove(name)
+                .and_then(|value| value.into_string().ok())
+                .ok_or_else(|| format!("transform result has no string `{}`", name))
+        };
+        let url = field("url")?;
+        let file_name = field("path")?;
+        let headers = match item.remove("headers") {
+            None => Vec::new(),
+            Some(headers) => headers
+                .try_cast::<Map>()
+                .ok_or("transform result `headers` must be a map")?
+                .into_iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect(),
+        };
+        let mirrors = match item.remove("mirrors") {
+            None => Vec::new(),
+            Some(mirrors) => mirrors
+                .try_cast::<rhai::Array>()
+                .ok_or("transform result `mirrors` must be an array")?
+                .into_iter()
+                .map(|mirror| mirror.to_string())
+                .collect(),
+        };
+        let copies = match item.remove("copies") {
+            None => Vec::new(),
+            Some(copies) => copies
+                .try_cast::<rhai::Array>()
+                .ok_or("transform result `copies` must be an array")?
+                .into_iter()
+                .map(|copy| copy.to_string())
+                .collect(),
+        };
+        let tags = match item.remove("tags") {
+            None => Vec::new(),
+            Some(tags) => tags
+                .try_cast::<rhai::Array>()
+                .ok_or("transform result `tags` must be an array")?
+                .into_iter()
+                .map(|tag| tag.to_string())
+                .collect(),
+        };
+        let meta = match item.remove("meta") {
+            None => Vec::new(),
+            Some(meta) => meta
+                .try_cast::<Map>()
+                .ok_or("transform result `meta` must be a map")?
+                .into_iter()
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .collect(),
+        };
+        let sha256 = item
+            .remove("sha256")
+            .and_then(|value| value.into_string().ok());
+        let sha256_tree = item
+            .remove("sha256tree")
+            .and_then(|value| value.into_string().ok());
+        let size = item
+            .remove("size")
+            .and_then(|value| value.as_int().ok())
+            .and_then(|size| u64::try_from(size).ok());
+        Ok(Some(Image {
+            url,
+            file_name,
+            headers,
+            sha256,
+            sha256_tree,
+            size,
+            mirrors,
+            copies,
+            meta,
+            tags,
+            source: None,
+        }))
+    }
+}
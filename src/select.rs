@@ -0,0 +1,27 @@
+//! `--interactive` picking of the items to download.
+
+use fast_download::Image;
+use inquire::MultiSelect;
+
+/// Shows a fuzzy searchable list of the items and returns the picked ones.
+pub fn pick(images: Vec<Image>) -> Result<Vec<Image>, String> {
+    let labels = images
+        .iter()
+        .map(|image| format!("{}  {}", image.file_name, image.url))
+        .collect::<Vec<_>>();
+    let picked = MultiSelect::new("files to download:", labels)
+        .with_help_message("type to search, space to select, → all, ← none, enter to start")
+        .with_page_size(15)
+        .with_formatter(&|picked| format!("{} selected", picked.len()))
+        .raw_prompt()
+        .map_err(|err| err.to_string())?;
+    let mut keep = vec![false; images.len()];
+    for option in picked {
+        keep[option.index] = true;
+    }
+    Ok(images
+        .into_iter()
+        .zip(keep)
+        .filter_map(|(image, keep)| keep.then_some(image))
+        .collect())
+}
@@ -0,0 +1,215 @@
+//! `fast_download serve-files <dir>`: a small HTTP server over a download
+//! directory, so finished files can be fetched from other machines right away.
+//!
+//! Files are served with GET and HEAD, directories as an HTML index. With
+//! `--auth user:password` every request needs those basic auth credentials.
+
+use base64::Engine;
+use hyper::{
+    header,
+    service::{make_service_fn, service_fn},
+    Body, Method, Request, Response, Server, StatusCode,
+};
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, CONTROLS};
+use sha2::{Digest, Sha256};
+use std::{
+    convert::Infallible,
+    net::SocketAddr,
+    path::{Component, Path, PathBuf},
+    sync::Arc,
+};
+use tokio::fs;
+use tokio_util::io::ReaderStream;
+
+pub const DEFAULT_ADDR: &str = "0.0.0.0:8000";
+
+/// What has to be escaped in the links of directory indexes.
+const PATH: &AsciiSet = &CONTROLS
+    .add(b' ')
+    .add(b'"')
+    .add(b'#')
+    .add(b'%')
+    .add(b'<')
+    .add(b'>')
+    .add(b'?')
+    .add(b'\\');
+
+struct Shared {
+    root: PathBuf,
+    /// The `Authorization` header value requests must send.
+    authorization: Option<String>,
+}
+
+/// Serves `root` on `addr` until interrupted.
+pub async fn run(root: &Path, addr: SocketAddr, auth: Option<&str>) -> Result<(), String> {
+    if !root.is_dir() {
+        return Err(format!("{} is not a directory", root.display()));
+    }
+    let shared = Arc::new(Shared {
+        root: root.to_path_buf(),
+        authorization: auth.map(|auth| {
+            format!(
+                "Basic {}",
+                base64::engine::general_purpose::STANDARD.encode(auth)
+            )
+        }),
+    });
+    let make_service = make_service_fn(move |_| {
+        let shared = shared.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request| {
+                let shared = shared.clone();
+                async move { Ok::<_, Infallible>(respond(&shared, request).await) }
+            }))
+        }
+    });
+    let server = Server::try_bind(&addr)
+        .map_err(|err| format!("failed to listen on {}: {}", addr, err))?
+        .serve(make_service);
+    eprintln!(
+        "serving {} on http://{}/",
+        root.display(),
+        server.local_addr()
+    );
+    server
+        .with_graceful_shutdown(async {
+            let _ = tokio::signal::ctrl_c().await;
+        })
+        .await
+        .map_err(|err| err.to_string())
+}
+
+async fn respond(shared: &Shared, request: Request<Body>) -> Response<Body> {
+    if let Some(expected) = &shared.authorization {
+        let sent = request.headers().get(header::AUTHORIZATION);
+        if !sent.is_some_and(|value| same(value.as_bytes(), expected.as_bytes())) {
+            return Response::builder()
+                .status(StatusCode::UNAUTHORIZED)
+                .header(header::WWW_AUTHENTICATE, "Basic realm=\"fast_download\"")
+                .body(Body::empty())
+                .expect("valid response");
+        }
+    }
+    if !matches!(*request.method(), Method::GET | Method::HEAD) {
+        return status(StatusCode::METHOD_NOT_ALLOWED);
+    }
+    let Some(relative) = relative_path(request.uri().path()) else {
+        return status(StatusCode::BAD_REQUEST);
+    };
+    let path = shared.root.join(&relative);
+    let Ok(metadata) = fs::metadata(&path).await else {
+        return status(StatusCode::NOT_FOUND);
+    };
+    let head = request.method() == Method::HEAD;
+    if metadata.is_dir() {
+        // relative links in the index only resolve under a trailing slash;
+        // the location is built from the normalized path, as the raw one could
+        // start with `//` and send the client to another host
+        if !request.uri().path().ends_with('/') {
+            let location = relative
+                .iter()
+                .map(|part| utf8_percent_encode(&part.to_string_lossy(), PATH).to_string())
+                .collect::<Vec<_>>()
+                .join("/");
+            return Response::builder()
+                .status(StatusCode::MOVED_PERMANENTLY)
+                .header(header::LOCATION, format!("/{}/", location))
+                .body(Body::empty())
+                .expect("valid response");
+        }
+        return match index(&path, request.uri().path()).await {
+            Ok(html) => Response::builder()
+                .header(header::CONTENT_TYPE, "text/html; charset=utf-8")
+                .header(header::CONTENT_LENGTH, html.len())
+                .body(if head {
+                    Body::empty()
+                } else {
+                    Body::from(html)
+                })
+                .expect("valid response"),
+            Err(_) => status(StatusCode::FORBIDDEN),
+        };
+    }
+    let body = if head {
+        Body::empty()
+    } else {
+        match fs::File::open(&path).await {
+            Ok(file) => Body::wrap_stream(ReaderStream::new(file)),
+            Err(_) => return status(StatusCode::FORBIDDEN),
+        }
+    };
+    Response::builder()
+        .header(header::CONTENT_TYPE, "application/octet-stream")
+        .header(header::CONTENT_LENGTH, metadata.len())
+        .body(body)
+        .expect("valid response")
+}
+
+/// Whether `sent` and `expected` are equal, in a time that doesn't tell how
+/// much of them is: their SHA-256s are compared without stopping early.
+pub(crate) fn same(sent: &[u8], expected: &[u8]) -> bool {
+    let (sent, expected) = (Sha256::digest(sent), Sha256::digest(expected));
+    sent.iter()
+        .zip(expected.iter())
+        .fold(0, |difference, (a, b)| difference | (a ^ b))
+        == 0
+}
+
+/// The decoded request path relative to the root, or `None` if it would leave
+/// the root.
+fn relative_path(path: &str) -> Option<PathBuf> {
+    let decoded = percent_decode_str(path).decode_utf8().ok()?;
+    let mut relative = PathBuf::new();
+    for component in Path::new(decoded.trim_start_matches('/')).components() {
+        match component {
+            Component::Normal(part) => relative.push(part),
+            Component::CurDir => {}
+            _ => return None,
+        }
+    }
+    Some(relative)
+}
+
+async fn index(dir: &Path, url_path: &str) -> std::io::Result<String> {
+    let mut names = Vec::new();
+    let mut entries = fs::read_dir(dir).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let mut name = entry.file_name().to_string_lossy().into_owned();
+        if entry.file_type().await?.is_dir() {
+            name.push('/');
+        }
+        names.push(name);
+    }
+    names.sort();
+    let title = escape(&percent_decode_str(url_path).decode_utf8_lossy());
+    let mut html = format!(
+        "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><title>{0}</title></head>\n<body><h1>{0}</h1>\n<ul>\n",
+        title
+    );
+    if url_path != "/" {
+        html.push_str("<li><a href=\"../\">../</a></li>\n");
+    }
+    for name in names {
+        html.push_str(&format!(
+            "<li><a href=\"{}\">{}</a></li>\n",
+            utf8_percent_encode(&name, PATH),
+            escape(&name)
+        ));
+    }
+    html.push_str("</ul></body></html>\n");
+    Ok(html)
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn status(status: StatusCode) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .body(Body::from(format!("{}\n", status)))
+        .expect("valid response")
+}
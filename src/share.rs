@@ -0,0 +1,132 @@
+//! [`Options::share_links`](crate::Options::share_links): the public share
+//! links of Google Drive and Dropbox, which lead to a preview page rather than
+//! the file, in ordinary url files.
+//!
+//! The links are rewritten to the direct download urls of the same files
+//! before the first request. Google Drive answers those for files too large
+//! to scan for viruses with a warning page instead, whose confirm form is sent
+//! on to get the file.
+
+use regex::Regex;
+use reqwest::Url;
+
+/// What is read of a warning page at most.
+const MAX_PAGE_SIZE: usize = 1 << 20;
+
+/// The direct download url of a share link, if `url` is one that needs it.
+pub(crate) fn direct(url: &str) -> Option<String> {
+    let mut parsed = Url::parse(url).ok()?;
+    match parsed.host_str()? {
+        "drive.google.com" => {
+            let path = parsed.path().to_string();
+            let segments = path
+                .split('/')
+                .filter(|s| !s.is_empty())
+                .collect::<Vec<_>>();
+            let query_id = || {
+                parsed
+                    .query_pairs()
+                    .find(|(key, _)| key == "id")
+                    .map(|(_, id)| id.into_owned())
+            };
+            let id = match segments.as_slice() {
+                // /file/d/<id>/view, /file/d/<id>/edit, /file/d/<id>
+                ["file", "d", id, ..] => id.to_string(),
+                ["open"] | ["uc"] => query_id()?,
+                _ => return None,
+            };
+            Some(format!(
+                "https://drive.usercontent.google.com/download?id={}&export=download",
+                id
+            ))
+        }
+        "dropbox.com" | "www.dropbox.com" => {
+            // already direct
+            if parsed
+                .query_pairs()
+                .any(|(key, value)| (key == "dl" && value == "1") || key == "raw")
+            {
+                return None;
+            }
+            let pairs = parsed
+                .query_pairs()
+                .filter(|(key, _)| key != "dl")
+                .map(|(key, value)| (key.into_owned(), value.into_owned()))
+                .collect::<Vec<_>>();
+            parsed
+                .query_pairs_mut()
+                .clear()
+                .extend_pairs(pairs)
+                .append_pair("dl", "1");
+            Some(parsed.to_string())
+        }
+        _ => None,
+    }
+}
+
+/// Whether `response` is Google Drive's page asking to confirm the download
+/// of a file it couldn't scan, rather than the file, which is sent as an
+/// attachment even when it's HTML.
+pub(crate) fn is_warning(response: &reqwest::Response) -> bool {
+    let drive = matches!(
+        response.url().host_str(),
+        Some("drive.usercontent.google.com" | "drive.google.com")
+    );
+    let header = |name| {
+        response
+            .headers()
+            .get(name)
+            .and_then(|value: &reqwest::header::HeaderValue| value.to_str().ok())
+    };
+    let html =
+        header(reqwest::header::CONTENT_TYPE).is_some_and(|value| value.starts_with("text/html"));
+    let attachment = header(reqwest::header::CONTENT_DISPOSITION)
+        .is_some_and(|value| value.trim_start().starts_with("attachment"));
+    drive && html && !attachment
+}
+
+/// The url the confirm form of a warning page sends, if it has one.
+pub(crate) async fn confirm_url(mut response: reqwest::Response) -> Option<String> {
+    let page_url = response.url().clone();
+    let mut body = Vec::new();
+    while let Some(chunk) = response.chunk().await.ok()? {
+        body.extend_from_slice(&chunk);
+        if body.len() > MAX_PAGE_SIZE {
+            return None;
+        }
+    }
+    let html = String::from_utf8_lossy(&body);
+    let form = Regex::new(r#"(?s)<form[^>]*id="download-form"[^>]*>(.*?)</form>"#)
+        .expect("valid regex")
+        .captures(&html)?;
+    let action = Regex::new(r#"action="([^"]*)""#)
+        .expect("valid regex")
+        .captures(&form[0])?;
+    let mut url = page_url.join(&unescape(&action[1])).ok()?;
+    let input = Regex::new(r#"<input[^>]*type="hidden"[^>]*>"#).expect("valid regex");
+    let (name, value) = (
+        Regex::new(r#"name="([^"]*)""#).expect("valid regex"),
+        Regex::new(r#"value="([^"]*)""#).expect("valid regex"),
+    );
+    let fields = input
+        .find_iter(&form[1])
+        .filter_map(|input| {
+            let name = name.captures(input.as_str())?;
+            let value = value.captures(input.as_str());
+            Some((
+                unescape(&name[1]),
+                value.map_or(String::new(), |value| unescape(&value[1])),
+            ))
+        })
+        .collect::<Vec<_>>();
+    url.query_pairs_mut().clear().extend_pairs(fields);
+    Some(url.to_string())
+}
+
+fn unescape(text: &str) -> String {
+    text.replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&amp;", "&")
+}
@@ -0,0 +1,47 @@
+//! Control signals for long runs: SIGHUP reloads the url file and SIGUSR1
+//! prints a status snapshot. Other platforms never receive either.
+
+use std::io;
+
+pub enum Signal {
+    Reload,
+    Status,
+}
+
+#[cfg(unix)]
+pub struct Signals {
+    hangup: tokio::signal::unix::Signal,
+    user1: tokio::signal::unix::Signal,
+}
+
+#[cfg(unix)]
+impl Signals {
+    pub fn new() -> io::Result<Signals> {
+        use tokio::signal::unix::{signal, SignalKind};
+        Ok(Signals {
+            hangup: signal(SignalKind::hangup())?,
+            user1: signal(SignalKind::user_defined1())?,
+        })
+    }
+
+    pub async fn recv(&mut self) -> Signal {
+        tokio::select! {
+            _ = self.hangup.recv() => Signal::Reload,
+            _ = self.user1.recv() => Signal::Status,
+        }
+    }
+}
+
+#[cfg(not(unix))]
+pub struct Signals;
+
+#[cfg(not(unix))]
+impl Signals {
+    pub fn new() -> io::Result<Signals> {
+        Ok(Signals)
+    }
+
+    pub async fn recv(&mut self) -> Signal {
+        std::future::pending().await
+    }
+}
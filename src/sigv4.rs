@@ -0,0 +1,139 @@
+//! AWS Signature Version 4, which signs the S3 requests of
+//! [`Options::s3`](crate::Options::s3) and the SQS requests of
+//! `fast_download daemon`, and the lowercase hex that it and the checksums of
+//! url files are written in.
+
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+use std::env;
+
+/// The keys requests are signed with.
+pub struct Credentials {
+    pub access_key: String,
+    pub secret_key: String,
+    pub session_token: Option<String>,
+}
+
+/// What of a request is signed, as it is sent.
+pub struct Request<'a> {
+    pub method: &'a str,
+    pub path: &'a str,
+    /// Encoded and sorted.
+    pub query: &'a str,
+    /// Sorted by name, all of them signed.
+    pub headers: &'a [(&'a str, String)],
+    /// The SHA-256 of the body in hex.
+    pub payload_hash: &'a str,
+}
+
+impl Credentials {
+    /// Takes the credentials from `AWS_ACCESS_KEY_ID`, `AWS_SECRET_ACCESS_KEY`
+    /// and optionally `AWS_SESSION_TOKEN`, `service` naming what needs them.
+    pub fn from_env(service: &str) -> Result<Credentials, String> {
+        let var = |name: &str| {
+            env::var(name).map_err(|_| format!("{} is not set, needed for {}", name, service))
+        };
+        Ok(Credentials {
+            access_key: var("AWS_ACCESS_KEY_ID")?,
+            secret_key: var("AWS_SECRET_ACCESS_KEY")?,
+            session_token: env::var("AWS_SESSION_TOKEN").ok(),
+        })
+    }
+
+    /// The `Authorization` header of `request` to `service` in `region`, sent
+    /// at `amz_date`, its `x-amz-date`.
+    pub fn authorization(
+        &self,
+        request: &Request,
+        region: &str,
+        service: &str,
+        amz_date: &str,
+    ) -> String {
+        let date = &amz_date[..8];
+        let signed_headers = request
+            .headers
+            .iter()
+            .map(|(name, _)| *name)
+            .collect::<Vec<_>>()
+            .join(";");
+        let canonical_headers = request
+            .headers
+            .iter()
+            .map(|(name, value)| format!("{}:{}\n", name, value.trim()))
+            .collect::<String>();
+        let canonical_request = format!(
+            "{}\n{}\n{}\n{}\n{}\n{}",
+            request.method,
+            request.path,
+            request.query,
+            canonical_headers,
+            signed_headers,
+            request.payload_hash
+        );
+        let scope = format!("{}/{}/{}/aws4_request", date, region, service);
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+            amz_date,
+            scope,
+            hex(&Sha256::digest(canonical_request))
+        );
+        let key = [region, service, "aws4_request"].iter().fold(
+            hmac(format!("AWS4{}", self.secret_key).as_bytes(), date),
+            |key, part| hmac(&key, part),
+        );
+        format!(
+            "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+            self.access_key,
+            scope,
+            signed_headers,
+            hex(&hmac(&key, &string_to_sign))
+        )
+    }
+}
+
+fn hmac(key: &[u8], data: &str) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("hmac takes any key length");
+    mac.update(data.as_bytes());
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// `bytes` in lowercase hex.
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn signs_the_get_vanilla_example_of_the_aws_test_suite() {
+        let credentials = Credentials {
+            access_key: "AKIDEXAMPLE".to_string(),
+            secret_key: "wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY".to_string(),
+            session_token: None,
+        };
+        let headers = [
+            ("host", "example.amazonaws.com".to_string()),
+            ("x-amz-date", "20150830T123600Z".to_string()),
+        ];
+        let request = Request {
+            method: "GET",
+            path: "/",
+            query: "",
+            headers: &headers,
+            payload_hash: &hex(&Sha256::digest(b"")),
+        };
+        assert_eq!(
+            credentials.authorization(&request, "us-east-1", "service", "20150830T123600Z"),
+            "AWS4-HMAC-SHA256 Credential=AKIDEXAMPLE/20150830/us-east-1/service/aws4_request, \
+             SignedHeaders=host;x-amz-date, \
+             Signature=5fa00fa31553b73ebf1942676e86291e8372ff2a2260956d9b8aae1d763fbf31"
+        );
+    }
+
+    #[test]
+    fn hex_is_lowercase_and_padded() {
+        assert_eq!(hex(&[0x00, 0x0f, 0xab]), "000fab");
+    }
+}
@@ -0,0 +1,83 @@
+//! [`Options::reject_error_pages`](crate::Options::reject_error_pages) and
+//! [`Options::soft_404`](crate::Options::soft_404): telling the HTML "access
+//! denied", login or "not found" page a server sent with a 200 from the file
+//! that was asked for.
+
+use std::path::Path;
+
+/// How much of the start of a body is looked at.
+pub(crate) const HEAD_LEN: usize = 512;
+
+/// How much of the start of a body a soft 404 signature is matched against.
+pub(crate) const SIGNATURE_LEN: usize = 64 * 1024;
+
+/// Bodies starting like an HTML page only count as one up to this size, a
+/// large file that happens to start with `<html` is more likely the file.
+/// Larger bodies aren't hashed for [`Options::soft_404_sha256`] either.
+///
+/// [`Options::soft_404_sha256`]: crate::Options::soft_404_sha256
+pub(crate) const PAGE_MAX_SIZE: u64 = 1 << 20;
+
+/// Extensions whose files are expected to be markup.
+const MARKUP: &[&str] = &[
+    "html", "htm", "xhtml", "shtml", "php", "asp", "aspx", "jsp", "xml", "svg", "rss", "atom",
+];
+
+/// The first bytes of files of these extensions.
+const MAGIC: &[(&[&str], &[u8])] = &[
+    (&["png"], b"\x89PNG"),
+    (&["jpg", "jpeg"], b"\xff\xd8\xff"),
+    (&["gif"], b"GIF8"),
+    (&["webp", "wav", "avi"], b"RIFF"),
+    (&["pdf"], b"%PDF"),
+    (
+        &["zip", "jar", "apk", "docx", "xlsx", "pptx", "whl"],
+        b"PK\x03\x04",
+    ),
+    (&["gz", "tgz"], b"\x1f\x8b"),
+    (&["zst"], b"\x28\xb5\x2f\xfd"),
+    (&["xz"], b"\xfd7zXZ\x00"),
+    (&["bz2"], b"BZh"),
+    (&["7z"], b"7z\xbc\xaf\x27\x1c"),
+    (&["parquet"], b"PAR1"),
+];
+
+/// Whether the body of `file_name`, starting with `head` and `size` bytes
+/// long, is most likely an error page: the server called a file with an
+/// extension HTML, it starts like an HTML page and is small, or it starts
+/// with markup where the extension calls for a binary format. Files named
+/// like markup are never error pages.
+pub(crate) fn error_page(
+    file_name: &str,
+    content_type: Option<&str>,
+    head: &[u8],
+    size: u64,
+) -> bool {
+    let extension = Path::new(file_name)
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .map(str::to_ascii_lowercase);
+    let extension = extension.as_deref();
+    if extension.is_some_and(|extension| MARKUP.contains(&extension)) {
+        return false;
+    }
+    let html_type = extension.is_some()
+        && content_type.is_some_and(|content_type| {
+            let mime = content_type.split(';').next().unwrap_or_default().trim();
+            mime.eq_ignore_ascii_case("text/html")
+                || mime.eq_ignore_ascii_case("application/xhtml+xml")
+        });
+    // a byte order mark and whitespace may come first
+    let text = head.strip_prefix(b"\xef\xbb\xbf").unwrap_or(head);
+    let text = &text[text.iter().take_while(|b| b.is_ascii_whitespace()).count()..];
+    let starts_with = |prefix: &[u8]| {
+        text.len() >= prefix.len() && text[..prefix.len()].eq_ignore_ascii_case(prefix)
+    };
+    let html = starts_with(b"<!doctype html") || starts_with(b"<html");
+    let magic = MAGIC
+        .iter()
+        .find(|(extensions, _)| extension.is_some_and(|extension| extensions.contains(&extension)))
+        .map(|(_, magic)| *magic);
+    let wrong_magic = magic.is_some_and(|magic| !head.starts_with(magic)) && starts_with(b"<");
+    html_type || html && size <= PAGE_MAX_SIZE || wrong_magic
+}
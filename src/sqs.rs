@@ -0,0 +1,176 @@
+//! A minimal Amazon SQS client over its JSON protocol, just what the daemon
+//! needs to receive, hold and delete messages.
+//!
+//! Credentials come from `AWS_ACCESS_KEY_ID`, `AWS_SECRET_ACCESS_KEY` and
+//! optionally `AWS_SESSION_TOKEN`. The region is taken from the queue url
+//! (`https://sqs.<region>.amazonaws.com/...`), or `AWS_REGION` for other
+//! endpoints such as local test servers.
+
+use chrono::Utc;
+use fast_download::sigv4::{self, hex, Credentials};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use std::{env, time::Duration};
+
+/// The longest SQS holds a receive open waiting for messages.
+const WAIT_SECS: u64 = 20;
+
+pub struct Queue {
+    client: reqwest::Client,
+    queue_url: String,
+    endpoint: reqwest::Url,
+    region: String,
+    credentials: Credentials,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Message {
+    #[serde(rename = "MessageId")]
+    pub id: String,
+    #[serde(rename = "ReceiptHandle")]
+    pub receipt: String,
+    #[serde(rename = "Body")]
+    pub body: String,
+}
+
+#[derive(Deserialize)]
+struct Received {
+    #[serde(rename = "Messages", default)]
+    messages: Vec<Message>,
+}
+
+impl Queue {
+    pub fn new(queue_url: &str) -> Result<Queue, String> {
+        let url = reqwest::Url::parse(queue_url)
+            .map_err(|err| format!("invalid queue url {}: {}", queue_url, err))?;
+        let host = url
+            .host_str()
+            .ok_or_else(|| format!("invalid queue url {}", queue_url))?;
+        let region = match host
+            .strip_prefix("sqs.")
+            .and_then(|rest| rest.split_once('.'))
+        {
+            Some((region, _)) => region.to_string(),
+            None => env::var("AWS_REGION")
+                .or_else(|_| env::var("AWS_DEFAULT_REGION"))
+                .unwrap_or_else(|_| "us-east-1".to_string()),
+        };
+        let credentials = Credentials::from_env("SQS")?;
+        let mut endpoint = url.clone();
+        endpoint.set_path("/");
+        endpoint.set_query(None);
+        Ok(Queue {
+            client: reqwest::Client::builder()
+                .timeout(Duration::from_secs(WAIT_SECS + 10))
+                .build()
+                .map_err(|err| err.to_string())?,
+            queue_url: queue_url.to_string(),
+            endpoint,
+            region,
+            credentials,
+        })
+    }
+
+    /// Waits up to 20 seconds for at most `max` messages, which stay hidden
+    /// from other consumers for `visibility`.
+    pub async fn receive(&self, max: usize, visibility: Duration) -> Result<Vec<Message>, String> {
+        let received = self
+            .call(
+                "ReceiveMessage",
+                json!({
+                    "QueueUrl": self.queue_url,
+                    "MaxNumberOfMessages": max.clamp(1, 10),
+                    "WaitTimeSeconds": WAIT_SECS,
+                    "VisibilityTimeout": visibility.as_secs().max(1),
+                }),
+            )
+            .await?;
+        serde_json::from_value::<Received>(received)
+            .map(|received| received.messages)
+            .map_err(|err| format!("invalid ReceiveMessage response: {}", err))
+    }
+
+    /// Keeps `message` hidden for another `visibility` from now.
+    pub async fn extend(&self, message: &Message, visibility: Duration) -> Result<(), String> {
+        self.call(
+            "ChangeMessageVisibility",
+            json!({
+                "QueueUrl": self.queue_url,
+                "ReceiptHandle": message.receipt,
+                "VisibilityTimeout": visibility.as_secs().max(1),
+            }),
+        )
+        .await
+        .map(drop)
+    }
+
+    /// Acknowledges `message` so it isn't delivered again.
+    pub async fn delete(&self, message: &Message) -> Result<(), String> {
+        self.call(
+            "DeleteMessage",
+            json!({
+                "QueueUrl": self.queue_url,
+                "ReceiptHandle": message.receipt,
+            }),
+        )
+        .await
+        .map(drop)
+    }
+
+    async fn call(&self, action: &str, body: Value) -> Result<Value, String> {
+        let body = body.to_string();
+        let target = format!("AmazonSQS.{}", action);
+        let now = Utc::now();
+        let amz_date = now.format("%Y%m%dT%H%M%SZ").to_string();
+        let host = match self.endpoint.port() {
+            Some(port) => format!("{}:{}", self.endpoint.host_str().unwrap_or_default(), port),
+            None => self.endpoint.host_str().unwrap_or_default().to_string(),
+        };
+        let mut headers = vec![
+            ("content-type", "application/x-amz-json-1.0".to_string()),
+            ("host", host),
+            ("x-amz-date", amz_date.clone()),
+        ];
+        if let Some(token) = &self.credentials.session_token {
+            headers.push(("x-amz-security-token", token.clone()));
+        }
+        headers.push(("x-amz-target", target));
+        let authorization = self.credentials.authorization(
+            &sigv4::Request {
+                method: "POST",
+                path: "/",
+                query: "",
+                headers: &headers,
+                payload_hash: &hex(&Sha256::digest(&body)),
+            },
+            &self.region,
+            "sqs",
+            &amz_date,
+        );
+        let mut request = self.client.post(self.endpoint.clone()).body(body);
+        for (name, value) in &headers {
+            if *name != "host" {
+                request = request.header(*name, value);
+            }
+        }
+        let response = request
+            .header(reqwest::header::AUTHORIZATION, authorization)
+            .send()
+            .await
+            .map_err(|err| format!("{} failed: {}", action, err))?;
+        let status = response.status();
+        let text = response
+            .text()
+            .await
+            .map_err(|err| format!("{} failed: {}", action, err))?;
+        if !status.is_success() {
+            return Err(format!("{} answered {}: {}", action, status, text.trim()));
+        }
+        match text.trim() {
+            "" => Ok(Value::Null),
+            text => serde_json::from_str(text)
+                .map_err(|err| format!("invalid {} response: {}", action, err)),
+        }
+    }
+}
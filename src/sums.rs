@@ -0,0 +1,141 @@
+//! `--discover-sums`: finds the checksums of entries without `sha256=` in the
+//! files projects publish next to their artifacts, so they are verified
+//! without editing the url file.
+//!
+//! Every directory the urls are in is asked for a `SHA256SUMS` once, and the
+//! entries it doesn't list for their `<url>.sha256`. Both the GNU
+//! (`<hex>  <name>`) and the BSD (`SHA256 (<name>) = <hex>`) formats are read.
+
+use crate::check;
+use fast_download::Image;
+use futures::{stream, StreamExt};
+use indicatif::ProgressBar;
+use reqwest::{Method, Url};
+use std::collections::HashMap;
+
+/// What is read of a checksum file at most; real ones are far smaller.
+const MAX_SUMS_SIZE: usize = 1 << 20;
+
+/// Sets the `sha256` of the entries that have none and returns how many were
+/// found.
+pub async fn discover(
+    images: &mut [Image],
+    client: &check::Client,
+    max_concurrent: usize,
+    progress: &ProgressBar,
+) -> usize {
+    let unsummed = images
+        .iter()
+        .enumerate()
+        .filter(|(_, image)| image.sha256.is_none())
+        .filter_map(|(index, image)| {
+            let url = Url::parse(&image.url).ok()?;
+            let name = file_name(&url)?;
+            Some((index, url.join("SHA256SUMS").ok()?, name))
+        })
+        .collect::<Vec<_>>();
+    progress.set_length(unsummed.len() as u64);
+
+    let mut dirs = unsummed
+        .iter()
+        .map(|(_, sums, _)| sums.clone())
+        .collect::<Vec<_>>();
+    dirs.sort();
+    dirs.dedup();
+    let listed = stream::iter(dirs)
+        .map(|sums| async move {
+            let listed = fetch(client, sums.as_str()).await.map(|text| parse(&text));
+            (sums, listed.unwrap_or_default())
+        })
+        .buffer_unordered(max_concurrent.max(1))
+        .collect::<HashMap<_, _>>()
+        .await;
+
+    let found = stream::iter(unsummed)
+        .map(|(index, sums, name)| {
+            let (listed, url) = (&listed, &images[index].url);
+            async move {
+                let sha256 = match listed[&sums].get(&name) {
+                    Some(sha256) => Some(sha256.clone()),
+                    None => fetch(client, &format!("{}.sha256", url))
+                        .await
+                        .and_then(|text| single(&text, &name)),
+                };
+                progress.inc(1);
+                (index, sha256)
+            }
+        })
+        .buffer_unordered(max_concurrent.max(1))
+        .collect::<Vec<_>>()
+        .await;
+    let mut count = 0;
+    for (index, sha256) in found {
+        if let Some(sha256) = sha256 {
+            images[index].sha256 = Some(sha256);
+            count += 1;
+        }
+    }
+    count
+}
+
+/// The body of `url` as text, if the server has it.
+async fn fetch(client: &check::Client, url: &str) -> Option<String> {
+    let mut response = client.send(Method::GET, url, &[]).await.ok()?;
+    if !response.status().is_success() {
+        return None;
+    }
+    let mut body = Vec::new();
+    while let Some(chunk) = response.chunk().await.ok()? {
+        body.extend_from_slice(&chunk);
+        if body.len() > MAX_SUMS_SIZE {
+            return None;
+        }
+    }
+    String::from_utf8(body).ok()
+}
+
+/// The checksums of a `SHA256SUMS`, by file name.
+fn parse(text: &str) -> HashMap<String, String> {
+    text.lines().filter_map(line).collect()
+}
+
+/// The checksum of `name` in a `.sha256`, which often holds just the hex.
+fn single(text: &str, name: &str) -> Option<String> {
+    let mut lines = text.lines().filter(|line| !line.trim().is_empty());
+    let first = lines.next()?.trim();
+    match is_sha256(first) {
+        true => Some(first.to_ascii_lowercase()),
+        false => parse(text).remove(name),
+    }
+}
+
+/// The file name and checksum of a line in either format.
+fn line(line: &str) -> Option<(String, String)> {
+    let line = line.trim();
+    let (name, hex) = match line.strip_prefix("SHA256 (") {
+        Some(rest) => {
+            let (name, hex) = rest.rsplit_once(") = ")?;
+            (name, hex.trim())
+        }
+        None => {
+            let (hex, name) = line.split_once(char::is_whitespace)?;
+            // a `*` marks a file hashed in binary mode
+            (name.trim_start().trim_start_matches('*'), hex)
+        }
+    };
+    let name = name.rsplit('/').next()?;
+    (is_sha256(hex) && !name.is_empty()).then(|| (name.to_string(), hex.to_ascii_lowercase()))
+}
+
+fn is_sha256(hex: &str) -> bool {
+    hex.len() == 64 && hex.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+/// The last segment of the path of `url`, decoded, if it's not empty.
+fn file_name(url: &Url) -> Option<String> {
+    let name = url.path_segments()?.next_back()?;
+    let name = percent_encoding::percent_decode_str(name)
+        .decode_utf8()
+        .ok()?;
+    (!name.is_empty()).then(|| name.into_owned())
+}
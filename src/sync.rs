@@ -0,0 +1,65 @@
+//! `--sync <dir>`: makes `<dir>` mirror the url file by deleting the files
+//! under it that the url file doesn't list.
+
+use std::{
+    collections::HashSet,
+    fs, io,
+    path::{Component, Path, PathBuf},
+};
+
+/// An absolute form of `path` with `.` and `..` resolved without touching the
+/// filesystem, so listed paths compare equal to the walked ones.
+pub fn normalize(path: &Path) -> PathBuf {
+    let path = std::env::current_dir().unwrap_or_default().join(path);
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            component => normalized.push(component),
+        }
+    }
+    normalized
+}
+
+/// The files under `root` that aren't in `listed`, which must be normalized.
+/// Symlinked directories aren't followed.
+pub fn extraneous(root: &Path, listed: &HashSet<PathBuf>) -> io::Result<Vec<PathBuf>> {
+    let mut extraneous = Vec::new();
+    let mut dirs = vec![normalize(root)];
+    while let Some(dir) = dirs.pop() {
+        for entry in fs::read_dir(&dir)? {
+            let entry = entry?;
+            let path = entry.path();
+            if entry.file_type()?.is_dir() {
+                dirs.push(path);
+            } else if !listed.contains(&path) {
+                extraneous.push(path);
+            }
+        }
+    }
+    extraneous.sort();
+    Ok(extraneous)
+}
+
+/// Deletes `files`, then the directories under `root` that deleting them left
+/// empty.
+pub fn remove(root: &Path, files: &[PathBuf]) -> io::Result<()> {
+    let root = normalize(root);
+    let mut dirs = HashSet::new();
+    for file in files {
+        fs::remove_file(file)?;
+        dirs.extend(file.ancestors().skip(1).take_while(|dir| *dir != root));
+    }
+    // the deepest directories first, so their parents can be empty in turn
+    let mut dirs = dirs.into_iter().collect::<Vec<_>>();
+    dirs.sort_by_key(|dir| std::cmp::Reverse(dir.components().count()));
+    for dir in dirs {
+        if fs::read_dir(dir)?.next().is_none() {
+            fs::remove_dir(dir)?;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,204 @@
+//! Records of the HTTP request behind every download attempt, turned on with
+//! [`Options::record_requests`](crate::Options::record_requests).
+//!
+//! DNS lookups and redirects happen inside reqwest, so the client gets a timing
+//! resolver and a redirect policy that report into a task local scoped around
+//! each request.
+
+use hyper::client::connect::dns::Name;
+use openssl::x509::{X509NameRef, X509};
+use reqwest::{
+    dns::{Addrs, Resolve, Resolving},
+    redirect, ClientBuilder, RequestBuilder, Response,
+};
+use std::{
+    cell::RefCell,
+    net::SocketAddr,
+    time::{Duration, Instant, SystemTime},
+};
+
+#[derive(Debug, Clone)]
+pub struct RequestRecord {
+    pub method: String,
+    pub url: String,
+    /// When the request was sent.
+    pub started: SystemTime,
+    pub request_headers: Vec<(String, String)>,
+    /// Redirects followed before the final response, in order.
+    pub redirects: Vec<Redirect>,
+    /// The url of the final response, after redirects.
+    pub final_url: Option<String>,
+    pub status: Option<u16>,
+    pub http_version: Option<String>,
+    pub response_headers: Vec<(String, String)>,
+    pub remote_addr: Option<SocketAddr>,
+    pub tls: Option<TlsDetails>,
+    pub timings: Timings,
+    /// Body bytes received.
+    pub bytes: u64,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Redirect {
+    pub status: u16,
+    pub location: String,
+}
+
+/// The server certificate of an https request.
+#[derive(Debug, Clone)]
+pub struct TlsDetails {
+    pub subject: String,
+    pub issuer: String,
+    pub not_after: String,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Timings {
+    /// `None` when there was nothing to look up, for ip addresses and reused
+    /// connections.
+    pub dns: Option<Duration>,
+    /// From sending the request until the response headers arrived. This
+    /// includes connecting and the TLS handshake, which reqwest doesn't time
+    /// separately.
+    pub ttfb: Option<Duration>,
+    /// From the response headers until the last body byte.
+    pub transfer: Option<Duration>,
+}
+
+#[derive(Default)]
+struct Pending {
+    dns: Option<Duration>,
+    redirects: Vec<Redirect>,
+}
+
+tokio::task_local! {
+    static PENDING: RefCell<Pending>;
+}
+
+/// A resolver that reports how long lookups take.
+#[derive(Clone, Copy)]
+pub(crate) struct TimedResolver;
+
+impl Resolve for TimedResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        Box::pin(async move {
+            let start = Instant::now();
+            let addrs = tokio::net::lookup_host((name.as_str(), 0))
+                .await?
+                .collect::<Vec<_>>();
+            let _ = PENDING.try_with(|pending| pending.borrow_mut().dns = Some(start.elapsed()));
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
+
+/// Makes `builder` report certificates. DNS timings and redirects are
+/// reported by [`TimedResolver`] and [`record_redirect`], which the client sets
+/// up along with the rest of its resolver and redirect policy.
+pub(crate) fn instrument(builder: ClientBuilder) -> ClientBuilder {
+    builder.tls_info(true)
+}
+
+/// Notes the redirect `attempt` in the record of the request.
+pub(crate) fn record_redirect(attempt: &redirect::Attempt) {
+    let _ = PENDING.try_with(|pending| {
+        pending.borrow_mut().redirects.push(Redirect {
+            status: attempt.status().as_u16(),
+            location: attempt.url().to_string(),
+        })
+    });
+}
+
+impl RequestRecord {
+    fn new(method: &str, url: &str) -> RequestRecord {
+        RequestRecord {
+            method: method.to_string(),
+            url: url.to_string(),
+            started: SystemTime::now(),
+            request_headers: Vec::new(),
+            redirects: Vec::new(),
+            final_url: None,
+            status: None,
+            http_version: None,
+            response_headers: Vec::new(),
+            remote_addr: None,
+            tls: None,
+            timings: Timings::default(),
+            bytes: 0,
+            error: None,
+        }
+    }
+}
+
+/// Sends `request`, recording everything up to the response headers.
+pub(crate) async fn send(
+    client: &reqwest::Client,
+    request: RequestBuilder,
+    record: &mut Option<RequestRecord>,
+) -> reqwest::Result<Response> {
+    let request = request.build()?;
+    let record = record.insert(RequestRecord::new(
+        request.method().as_str(),
+        request.url().as_str(),
+    ));
+    record.request_headers = headers(request.headers());
+    let start = Instant::now();
+    let (response, pending) = PENDING
+        .scope(RefCell::new(Pending::default()), async {
+            let response = client.execute(request).await;
+            (response, PENDING.with(|pending| pending.take()))
+        })
+        .await;
+    record.timings.dns = pending.dns;
+    record.redirects = pending.redirects;
+    match &response {
+        Ok(response) => {
+            record.timings.ttfb = Some(start.elapsed());
+            record.final_url = Some(response.url().to_string());
+            record.status = Some(response.status().as_u16());
+            record.http_version = Some(format!("{:?}", response.version()));
+            record.response_headers = headers(response.headers());
+            record.remote_addr = response.remote_addr();
+            record.tls = response
+                .extensions()
+                .get::<reqwest::tls::TlsInfo>()
+                .and_then(|info| info.peer_certificate())
+                .and_then(|der| X509::from_der(der).ok())
+                .map(|cert| TlsDetails {
+                    subject: name(cert.subject_name()),
+                    issuer: name(cert.issuer_name()),
+                    not_after: cert.not_after().to_string(),
+                });
+        }
+        Err(err) => record.error = Some(err.to_string()),
+    }
+    response
+}
+
+fn headers(headers: &reqwest::header::HeaderMap) -> Vec<(String, String)> {
+    headers
+        .iter()
+        .map(|(name, value)| {
+            (
+                name.to_string(),
+                String::from_utf8_lossy(value.as_bytes()).into_owned(),
+            )
+        })
+        .collect()
+}
+
+fn name(name: &X509NameRef) -> String {
+    name.entries()
+        .map(|entry| {
+            let key = entry.object().nid().short_name().unwrap_or("?");
+            let value = entry
+                .data()
+                .as_utf8()
+                .map(|value| value.to_string())
+                .unwrap_or_default();
+            format!("{}={}", key, value)
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
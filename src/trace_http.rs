@@ -0,0 +1,60 @@
+//! `--trace-http` output: the request and response headers, redirects,
+//! certificate and timings of every request.
+
+use fast_download::RequestRecord;
+use indicatif::HumanBytes;
+use std::fmt::Write;
+
+pub fn format(record: &RequestRecord) -> String {
+    let mut out = String::new();
+    let _ = writeln!(out, "> {} {}", record.method, record.url);
+    for (name, value) in &record.request_headers {
+        let _ = writeln!(out, "> {}: {}", name, value);
+    }
+    for redirect in &record.redirects {
+        let _ = writeln!(
+            out,
+            "* redirect {} -> {}",
+            redirect.status, redirect.location
+        );
+    }
+    if let Some(status) = record.status {
+        let _ = write!(
+            out,
+            "< {} {}",
+            record.http_version.as_deref().unwrap_or("HTTP"),
+            status
+        );
+        if let Some(addr) = record.remote_addr {
+            let _ = write!(out, " from {}", addr);
+        }
+        out.push('\n');
+    }
+    for (name, value) in &record.response_headers {
+        let _ = writeln!(out, "< {}: {}", name, value);
+    }
+    if let Some(tls) = &record.tls {
+        let _ = writeln!(
+            out,
+            "* tls: subject {}; issuer {}; expires {}",
+            tls.subject, tls.issuer, tls.not_after
+        );
+    }
+    let timings = &record.timings;
+    let mut timing = Vec::new();
+    if let Some(dns) = timings.dns {
+        timing.push(format!("dns {:.1?}", dns));
+    }
+    if let Some(ttfb) = timings.ttfb {
+        timing.push(format!("ttfb {:.1?} (includes connect and tls)", ttfb));
+    }
+    if let Some(transfer) = timings.transfer {
+        timing.push(format!("transfer {:.1?}", transfer));
+    }
+    timing.push(HumanBytes(record.bytes).to_string());
+    let _ = writeln!(out, "* {}", timing.join(", "));
+    if let Some(error) = &record.error {
+        let _ = writeln!(out, "* error: {}", error);
+    }
+    out
+}
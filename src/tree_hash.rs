@@ -0,0 +1,205 @@
+//! SHA-256 tree hashes, the `sha256tree=` of url files: the SHA-256 of every
+//! MiB of a file, hashed pairwise up to a single root, as S3 Glacier computes
+//! them. A plain SHA-256 has to go through a file in order on one core, while
+//! the leaves of a tree hash don't depend on each other, so a multi-gigabyte
+//! file is checked on every core, and downloads hand their leaves to blocking
+//! threads as the data arrives instead of hashing it in between reads.
+
+use crate::sigv4::hex;
+use sha2::{Digest, Sha256};
+use std::{
+    collections::VecDeque,
+    io::{self, Read, Seek, SeekFrom},
+    path::Path,
+};
+use tokio::task::JoinHandle;
+
+/// The size of a leaf, all but the last of a file are this long.
+pub const LEAF_SIZE: usize = 1 << 20;
+
+type Hash = [u8; 32];
+
+fn leaf(data: &[u8]) -> Hash {
+    Sha256::digest(data).into()
+}
+
+/// The root of `leaves`, in the order of the file: pairs are hashed together
+/// level by level, an odd one out moving up as it is.
+fn root(mut leaves: Vec<Hash>) -> Hash {
+    if leaves.is_empty() {
+        return leaf(&[]);
+    }
+    while leaves.len() > 1 {
+        leaves = leaves
+            .chunks(2)
+            .map(|pair| match pair {
+                [left, right] => Sha256::new()
+                    .chain_update(left)
+                    .chain_update(right)
+                    .finalize()
+                    .into(),
+                [single] => *single,
+                _ => unreachable!("chunks of two"),
+            })
+            .collect();
+    }
+    leaves[0]
+}
+
+/// Leaves hashed at once, the rest of the data waits for them.
+fn parallelism() -> usize {
+    std::thread::available_parallelism().map_or(4, |n| n.get())
+}
+
+/// Hashes a stream like a download's body, each full leaf on a blocking
+/// thread, up to [`parallelism`] of them at a time.
+pub(crate) struct TreeHasher {
+    pending: Vec<u8>,
+    hashing: VecDeque<JoinHandle<Hash>>,
+    leaves: Vec<Hash>,
+}
+
+impl TreeHasher {
+    pub(crate) fn new() -> TreeHasher {
+        TreeHasher {
+            pending: Vec::new(),
+            hashing: VecDeque::new(),
+            leaves: Vec::new(),
+        }
+    }
+
+    /// The hasher after the first `len` bytes of the file at `path`, for a
+    /// resumed download.
+    pub(crate) async fn of_prefix(path: &Path, len: u64) -> io::Result<TreeHasher> {
+        let full = len / LEAF_SIZE as u64;
+        let leaves = file_leaves(path, full).await?;
+        let path = path.to_path_buf();
+        let pending = tokio::task::spawn_blocking(move || {
+            let mut file = std::fs::File::open(path)?;
+            file.seek(SeekFrom::Start(full * LEAF_SIZE as u64))?;
+            let mut pending = Vec::new();
+            file.take(len % LEAF_SIZE as u64)
+                .read_to_end(&mut pending)?;
+            Ok::<_, io::Error>(pending)
+        })
+        .await??;
+        Ok(TreeHasher {
+            pending,
+            hashing: VecDeque::new(),
+            leaves,
+        })
+    }
+
+    pub(crate) async fn update(&mut self, mut data: &[u8]) -> io::Result<()> {
+        while !data.is_empty() {
+            let take = (LEAF_SIZE - self.pending.len()).min(data.len());
+            self.pending.extend_from_slice(&data[..take]);
+            data = &data[take..];
+            if self.pending.len() < LEAF_SIZE {
+                break;
+            }
+            if self.hashing.len() >= parallelism() {
+                let oldest = self.hashing.pop_front().expect("hashing some");
+                self.leaves.push(oldest.await?);
+            }
+            let full = std::mem::replace(&mut self.pending, Vec::with_capacity(LEAF_SIZE));
+            self.hashing
+                .push_back(tokio::task::spawn_blocking(move || leaf(&full)));
+        }
+        Ok(())
+    }
+
+    /// The tree hash in lowercase hex.
+    pub(crate) async fn finish(mut self) -> io::Result<String> {
+        for hashing in self.hashing {
+            self.leaves.push(hashing.await?);
+        }
+        // an empty file is a single empty leaf
+        if !self.pending.is_empty() || self.leaves.is_empty() {
+            self.leaves.push(leaf(&self.pending));
+        }
+        Ok(hex(&root(self.leaves)))
+    }
+}
+
+/// A [`TreeHasher`] for blocking code, hashing every leaf in place.
+pub(crate) struct BlockingTreeHasher {
+    pending: Vec<u8>,
+    leaves: Vec<Hash>,
+}
+
+impl BlockingTreeHasher {
+    pub(crate) fn new() -> BlockingTreeHasher {
+        BlockingTreeHasher {
+            pending: Vec::new(),
+            leaves: Vec::new(),
+        }
+    }
+
+    pub(crate) fn finish(mut self) -> String {
+        if !self.pending.is_empty() || self.leaves.is_empty() {
+            self.leaves.push(leaf(&self.pending));
+        }
+        hex(&root(self.leaves))
+    }
+}
+
+impl io::Write for BlockingTreeHasher {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        let take = (LEAF_SIZE - self.pending.len()).min(data.len());
+        self.pending.extend_from_slice(&data[..take]);
+        if self.pending.len() == LEAF_SIZE {
+            self.leaves.push(leaf(&self.pending));
+            self.pending.clear();
+        }
+        Ok(take)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// The tree hash of the file at `path` in lowercase hex, as
+/// [`Image::sha256_tree`](crate::Image::sha256_tree) has it. Its leaves are
+/// read and hashed on a blocking thread per core.
+pub async fn file_sha256_tree(path: &Path) -> io::Result<String> {
+    let len = tokio::fs::metadata(path).await?.len();
+    let leaves = file_leaves(path, len.div_ceil(LEAF_SIZE as u64)).await?;
+    Ok(hex(&root(leaves)))
+}
+
+/// The first `count` leaves of the file at `path`, split in runs of leaves
+/// hashed on a blocking thread each.
+async fn file_leaves(path: &Path, count: u64) -> io::Result<Vec<Hash>> {
+    let per_task = count.div_ceil(parallelism() as u64).max(1);
+    let tasks = (0..count)
+        .step_by(per_task as usize)
+        .map(|first| {
+            let path = path.to_path_buf();
+            let last = (first + per_task).min(count);
+            tokio::task::spawn_blocking(move || {
+                let mut file = std::fs::File::open(path)?;
+                file.seek(SeekFrom::Start(first * LEAF_SIZE as u64))?;
+                let mut buffer = vec![0; LEAF_SIZE];
+                let mut leaves = Vec::new();
+                for _ in first..last {
+                    let mut filled = 0;
+                    while filled < LEAF_SIZE {
+                        match file.read(&mut buffer[filled..])? {
+                            0 => break,
+                            read => filled += read,
+                        }
+                    }
+                    leaves.push(leaf(&buffer[..filled]));
+                }
+                Ok::<_, io::Error>(leaves)
+            })
+        })
+        .collect::<Vec<_>>();
+    let mut leaves = Vec::new();
+    for task in tasks {
+        leaves.extend(task.await??);
+    }
+    Ok(leaves)
+}
@@ -0,0 +1,155 @@
+//! `fast_download self-update`: replaces the running binary with the latest
+//! GitHub release.
+//!
+//! Releases carry a binary per platform named `fast_download-<arch>-<os>`
+//! (`.exe` on Windows) next to a `<name>.sha256` checksum file. The binary is
+//! fetched with the download engine itself and only installed when its SHA-256
+//! matches. Releases aren't signed, so the checksum is all that is verified.
+
+use fast_download::{sigv4::hex, Downloader, Image, Options};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::{
+    env,
+    path::{Path, PathBuf},
+};
+use tokio::fs;
+
+const RELEASES_URL: &str =
+    "https://api.github.com/repos/heshanpadmasiri/fast_download/releases/latest";
+
+#[derive(Deserialize)]
+struct Release {
+    tag_name: String,
+    assets: Vec<Asset>,
+}
+
+#[derive(Deserialize)]
+struct Asset {
+    name: String,
+    browser_download_url: String,
+}
+
+/// Installs the latest release if it is newer, or with `check_only` just
+/// reports whether there is one.
+pub async fn run(check_only: bool) -> Result<(), String> {
+    // lets mirrors and tests stand in for GitHub
+    let url = env::var("FAST_DOWNLOAD_RELEASES_URL").unwrap_or_else(|_| RELEASES_URL.to_string());
+    let client = reqwest::Client::new();
+    let release = client
+        .get(&url)
+        .header(reqwest::header::USER_AGENT, env!("CARGO_PKG_NAME"))
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .map_err(|err| format!("failed to get the latest release: {}", err))?
+        .json::<Release>()
+        .await
+        .map_err(|err| format!("failed to read the latest release: {}", err))?;
+    let current = env!("CARGO_PKG_VERSION");
+    let latest = release.tag_name.trim_start_matches('v');
+    if version(latest) <= version(current) {
+        println!("fast_download {} is up to date", current);
+        return Ok(());
+    }
+    if check_only {
+        println!(
+            "fast_download {} is available, {} is installed",
+            latest, current
+        );
+        return Ok(());
+    }
+    let name = format!(
+        "fast_download-{}-{}{}",
+        env::consts::ARCH,
+        env::consts::OS,
+        env::consts::EXE_SUFFIX
+    );
+    let asset = |name: &str| {
+        release
+            .assets
+            .iter()
+            .find(|asset| asset.name == name)
+            .ok_or_else(|| format!("release {} has no {}", release.tag_name, name))
+    };
+    let binary = asset(&name)?;
+    let checksum = asset(&format!("{}.sha256", name))?;
+    let expected = client
+        .get(&checksum.browser_download_url)
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .map_err(|err| format!("failed to get {}: {}", checksum.name, err))?
+        .text()
+        .await
+        .map_err(|err| format!("failed to read {}: {}", checksum.name, err))?;
+    let expected = expected
+        .split_whitespace()
+        .next()
+        .ok_or_else(|| format!("{} is empty", checksum.name))?
+        .to_ascii_lowercase();
+
+    let exe =
+        env::current_exe().map_err(|err| format!("failed to find the running binary: {}", err))?;
+    let new = with_suffix(&exe, ".new");
+    let downloader = Downloader::new(Options {
+        force_redownload: true,
+        ..Options::default()
+    })
+    .map_err(|err| err.to_string())?;
+    downloader
+        .download(
+            0,
+            &Image::new(&binary.browser_download_url, new.to_string_lossy()),
+        )
+        .await
+        .map_err(|err| format!("failed to download {}: {}", binary.name, err))?;
+    let contents = fs::read(&new)
+        .await
+        .map_err(|err| format!("failed to read {}: {}", new.display(), err))?;
+    let actual = hex(&Sha256::digest(&contents));
+    if actual != expected {
+        let _ = fs::remove_file(&new).await;
+        return Err(format!(
+            "checksum mismatch for {}: expected {}, got {}",
+            binary.name, expected, actual
+        ));
+    }
+    install(&new, &exe)
+        .await
+        .map_err(|err| format!("failed to replace {}: {}", exe.display(), err))?;
+    println!("updated fast_download {} -> {}", current, latest);
+    Ok(())
+}
+
+/// `1.10.0` sorts after `1.9.3`; anything that isn't a number counts as 0.
+fn version(version: &str) -> Vec<u64> {
+    version
+        .split(['.', '-', '+'])
+        .map(|part| part.parse().unwrap_or(0))
+        .collect()
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+#[cfg(unix)]
+async fn install(new: &Path, exe: &Path) -> std::io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    fs::set_permissions(new, std::fs::Permissions::from_mode(0o755)).await?;
+    // replacing the file keeps the running process on the old inode
+    fs::rename(new, exe).await
+}
+
+#[cfg(not(unix))]
+async fn install(new: &Path, exe: &Path) -> std::io::Result<()> {
+    // a running executable can't be overwritten on Windows, but it can be
+    // renamed out of the way
+    let old = with_suffix(exe, ".old");
+    let _ = fs::remove_file(&old).await;
+    fs::rename(exe, &old).await?;
+    fs::rename(new, exe).await
+}
@@ -0,0 +1,227 @@
+//! io_uring backed file writing, enabled with the `io-uring` feature on Linux.
+//!
+//! tokio-uring drives its own single threaded runtime, so files are written on
+//! a dedicated thread and download tasks stream their chunks to it over a
+//! channel.
+
+use bytes::Bytes;
+use std::{
+    io,
+    path::{Path, PathBuf},
+    thread,
+};
+use tokio::sync::{mpsc, oneshot, OwnedSemaphorePermit};
+
+/// Number of chunks a download may have queued before it waits for the writer.
+const CHUNK_QUEUE_LEN: usize = 16;
+
+enum Op {
+    Preallocate(u64),
+    /// The permit holds the chunk's share of the memory budget until written.
+    Write(Bytes, Option<OwnedSemaphorePermit>),
+    Sync(oneshot::Sender<io::Result<()>>),
+    Finish,
+}
+
+enum Job {
+    Write {
+        path: PathBuf,
+        /// Where to continue an existing file, instead of creating it.
+        offset: Option<u64>,
+        ops: mpsc::Receiver<Op>,
+        done: oneshot::Sender<io::Result<()>>,
+    },
+    Rename {
+        from: PathBuf,
+        to: PathBuf,
+        done: oneshot::Sender<io::Result<()>>,
+    },
+}
+
+/// Handle to the io_uring writer thread.
+pub struct Writer {
+    jobs: mpsc::UnboundedSender<Job>,
+}
+
+/// A file being written by the io_uring thread.
+pub struct FileWriter {
+    ops: mpsc::Sender<Op>,
+    done: oneshot::Receiver<io::Result<()>>,
+}
+
+impl Writer {
+    /// Starts the writer thread, failing if the kernel doesn't allow io_uring.
+    pub fn spawn() -> io::Result<Writer> {
+        let (jobs, mut rx) = mpsc::unbounded_channel::<Job>();
+        let (ready_tx, ready_rx) = std::sync::mpsc::channel();
+        thread::Builder::new()
+            .name("io-uring-writer".to_string())
+            .spawn(move || {
+                // tokio_uring::start panics if the ring can't be created, in which
+                // case `ready_tx` is dropped without sending
+                tokio_uring::start(async move {
+                    let _ = ready_tx.send(());
+                    while let Some(job) = rx.recv().await {
+                        tokio_uring::spawn(async move {
+                            match job {
+                                Job::Write {
+                                    path,
+                                    offset,
+                                    ops,
+                                    done,
+                                } => {
+                                    let _ = done.send(write_file(&path, offset, ops).await);
+                                }
+                                Job::Rename { from, to, done } => {
+                                    let _ = done.send(tokio_uring::fs::rename(from, to).await);
+                                }
+                            }
+                        });
+                    }
+                });
+            })?;
+        ready_rx
+            .recv()
+            .map_err(|_| io::Error::new(io::ErrorKind::Unsupported, "io_uring is not available"))?;
+        Ok(Writer { jobs })
+    }
+
+    /// Creates (or truncates) the file at `path` on the writer thread.
+    pub fn create(&self, path: PathBuf) -> FileWriter {
+        self.open(path, None)
+    }
+
+    /// Continues writing the existing file at `path` from `offset` on.
+    pub fn open_at(&self, path: PathBuf, offset: u64) -> FileWriter {
+        self.open(path, Some(offset))
+    }
+
+    fn open(&self, path: PathBuf, offset: Option<u64>) -> FileWriter {
+        let (ops, rx) = mpsc::channel(CHUNK_QUEUE_LEN);
+        let (done_tx, done) = oneshot::channel();
+        let job = Job::Write {
+            path,
+            offset,
+            ops: rx,
+            done: done_tx,
+        };
+        // if the writer thread is gone `done` resolves to an error in `finish`
+        let _ = self.jobs.send(job);
+        FileWriter { ops, done }
+    }
+
+    pub async fn rename(&self, from: PathBuf, to: PathBuf) -> io::Result<()> {
+        let (done, rx) = oneshot::channel();
+        if self.jobs.send(Job::Rename { from, to, done }).is_err() {
+            return Err(writer_gone());
+        }
+        rx.await.unwrap_or_else(|_| Err(writer_gone()))
+    }
+}
+
+impl FileWriter {
+    pub async fn write(
+        &mut self,
+        chunk: Bytes,
+        reserved: Option<OwnedSemaphorePermit>,
+    ) -> io::Result<()> {
+        if self.ops.send(Op::Write(chunk, reserved)).await.is_err() {
+            // the writer stopped early, `done` holds the reason
+            return Err(self.result().await.err().unwrap_or_else(writer_gone));
+        }
+        Ok(())
+    }
+
+    pub async fn preallocate(&mut self, len: u64) -> io::Result<()> {
+        if self.ops.send(Op::Preallocate(len)).await.is_err() {
+            return Err(self.result().await.err().unwrap_or_else(writer_gone));
+        }
+        Ok(())
+    }
+
+    /// Waits until everything sent so far is synced to disk.
+    pub async fn sync(&mut self) -> io::Result<()> {
+        let (tx, rx) = oneshot::channel();
+        if self.ops.send(Op::Sync(tx)).await.is_err() {
+            return Err(self.result().await.err().unwrap_or_else(writer_gone));
+        }
+        match rx.await {
+            Ok(result) => result,
+            Err(_) => Err(self.result().await.err().unwrap_or_else(writer_gone)),
+        }
+    }
+
+    pub async fn finish(mut self) -> io::Result<()> {
+        if self.ops.send(Op::Finish).await.is_err() {
+            return Err(self.result().await.err().unwrap_or_else(writer_gone));
+        }
+        self.result().await
+    }
+
+    async fn result(&mut self) -> io::Result<()> {
+        (&mut self.done)
+            .await
+            .unwrap_or_else(|_| Err(writer_gone()))
+    }
+}
+
+fn writer_gone() -> io::Error {
+    io::Error::new(io::ErrorKind::BrokenPipe, "io_uring writer stopped")
+}
+
+async fn write_file(
+    path: &Path,
+    offset: Option<u64>,
+    mut ops: mpsc::Receiver<Op>,
+) -> io::Result<()> {
+    let file = match offset {
+        Some(_) => {
+            tokio_uring::fs::OpenOptions::new()
+                .write(true)
+                .open(path)
+                .await?
+        }
+        None => tokio_uring::fs::File::create(path).await?,
+    };
+    let mut pos = offset.unwrap_or(0);
+    let mut finished = false;
+    let mut result = Ok(());
+    while let Some(op) = ops.recv().await {
+        match op {
+            Op::Preallocate(len) => match file.fallocate(0, len, 0).await {
+                Err(err) if err.raw_os_error() != Some(libc::EOPNOTSUPP) => {
+                    result = Err(err);
+                    break;
+                }
+                _ => {}
+            },
+            Op::Write(chunk, reserved) => {
+                let len = chunk.len() as u64;
+                let (res, _) = file.write_all_at(chunk, pos).await;
+                drop(reserved);
+                if let Err(err) = res {
+                    result = Err(err);
+                    break;
+                }
+                pos += len;
+            }
+            Op::Sync(done) => {
+                let _ = done.send(file.sync_all().await);
+            }
+            Op::Finish => {
+                finished = true;
+                break;
+            }
+        }
+    }
+    file.close().await?;
+    if result.is_ok() && !finished {
+        // the download was dropped before finishing, don't leave a partial file
+        let _ = tokio_uring::fs::remove_file(path).await;
+        result = Err(io::Error::new(
+            io::ErrorKind::Interrupted,
+            "download cancelled",
+        ));
+    }
+    result
+}
@@ -0,0 +1,69 @@
+//! Checking the urls of a url file as it's parsed, so a broken one is reported
+//! with its line up front instead of failing deep inside a request.
+
+use reqwest::Url;
+
+/// The schemes downloads can be made over.
+const SCHEMES: &[&str] = &["http", "https"];
+
+/// `url` in the form it's requested in: an internationalized host in punycode,
+/// characters urls can't hold, like spaces and other unicode, percent-encoded
+/// and a stray `%` escaped, while existing escapes are kept. Errors say why
+/// it can't be downloaded.
+pub fn normalize(url: &str) -> Result<String, String> {
+    let parsed = Url::parse(&escape_percents(url)).map_err(|err| err.to_string())?;
+    if !SCHEMES.contains(&parsed.scheme()) {
+        return Err(format!("unsupported scheme {}", parsed.scheme()));
+    }
+    if parsed.host_str().is_none_or(str::is_empty) {
+        return Err("no host".to_string());
+    }
+    Ok(parsed.into())
+}
+
+/// `url` with every `%` that doesn't start an escape written as `%25`.
+fn escape_percents(url: &str) -> String {
+    let bytes = url.as_bytes();
+    let mut escaped = String::with_capacity(url.len());
+    for (i, c) in url.char_indices() {
+        let is_escape = c == '%'
+            && bytes.get(i + 1).is_some_and(u8::is_ascii_hexdigit)
+            && bytes.get(i + 2).is_some_and(u8::is_ascii_hexdigit);
+        match c {
+            '%' if !is_escape => escaped.push_str("%25"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::normalize;
+
+    #[test]
+    fn encodes_hosts_in_punycode_and_paths_in_percents() {
+        assert_eq!(
+            normalize("https://bücher.example/a file ü.txt").as_deref(),
+            Ok("https://xn--bcher-kva.example/a%20file%20%C3%BC.txt")
+        );
+    }
+
+    #[test]
+    fn escapes_stray_percents_and_keeps_escapes() {
+        assert_eq!(
+            normalize("http://example.com/100%/50%25?q=%zz").as_deref(),
+            Ok("http://example.com/100%25/50%25?q=%25zz")
+        );
+    }
+
+    #[test]
+    fn rejects_other_schemes_and_urls_without_a_host() {
+        assert_eq!(
+            normalize("ftp://example.com/a.iso"),
+            Err("unsupported scheme ftp".to_string())
+        );
+        assert!(normalize("http://").is_err());
+        assert!(normalize("not a url").is_err());
+    }
+}
@@ -0,0 +1,96 @@
+//! The ETag and Last-Modified of downloaded files, kept in
+//! [`Options::validators`](crate::Options::validators) to revalidate the files
+//! of urls that change over time.
+//!
+//! Every url and destination has an entry `<sha256 of both>.json`.
+
+use crate::sigv4::hex;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    env, io,
+    path::{Path, PathBuf},
+};
+use tokio::fs;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct Validators {
+    pub url: String,
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+    /// The size of the file as downloaded, a file changed since isn't
+    /// revalidated.
+    pub size: u64,
+}
+
+impl Validators {
+    /// The validators of `response` to `url`, if it has any.
+    pub fn of(url: &str, response: &reqwest::Response, size: u64) -> Option<Validators> {
+        let header = |name| {
+            response
+                .headers()
+                .get(name)
+                .and_then(|value: &reqwest::header::HeaderValue| value.to_str().ok())
+                .map(str::to_string)
+        };
+        let etag = header(reqwest::header::ETAG);
+        let last_modified = header(reqwest::header::LAST_MODIFIED);
+        (etag.is_some() || last_modified.is_some()).then(|| Validators {
+            url: url.to_string(),
+            etag,
+            last_modified,
+            size,
+        })
+    }
+
+    /// The validator an `If-Range` request can carry: a strong ETag, or else
+    /// the Last-Modified date.
+    pub fn if_range(&self) -> Option<&str> {
+        self.etag
+            .as_deref()
+            .filter(|etag| !etag.starts_with("W/"))
+            .or(self.last_modified.as_deref())
+    }
+
+    /// The conditional request headers that revalidate the file.
+    pub fn headers(&self) -> Vec<(reqwest::header::HeaderName, String)> {
+        let mut headers = Vec::new();
+        if let Some(etag) = &self.etag {
+            headers.push((reqwest::header::IF_NONE_MATCH, etag.clone()));
+        }
+        if let Some(last_modified) = &self.last_modified {
+            headers.push((reqwest::header::IF_MODIFIED_SINCE, last_modified.clone()));
+        }
+        headers
+    }
+}
+
+pub(crate) async fn load(dir: &Path, url: &str, path: &Path) -> Option<Validators> {
+    let json = fs::read(entry_path(dir, url, path)).await.ok()?;
+    serde_json::from_slice(&json).ok()
+}
+
+pub(crate) async fn store(
+    dir: &Path,
+    url: &str,
+    path: &Path,
+    validators: &Validators,
+) -> io::Result<()> {
+    fs::create_dir_all(dir).await?;
+    let entry = entry_path(dir, url, path);
+    let staged = entry.with_extension("json.part");
+    let json = serde_json::to_vec_pretty(validators).expect("validators serialize");
+    fs::write(&staged, json).await?;
+    fs::rename(&staged, &entry).await
+}
+
+fn entry_path(dir: &Path, url: &str, path: &Path) -> PathBuf {
+    // absolute, as runs may start in different directories
+    let path = env::current_dir().unwrap_or_default().join(path);
+    let mut hasher = Sha256::new();
+    hasher.update(url);
+    hasher.update([0]);
+    hasher.update(path.to_string_lossy().as_bytes());
+    let name = hex(&hasher.finalize());
+    dir.join(format!("{}.json", name))
+}
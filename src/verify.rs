@@ -0,0 +1,285 @@
+//! `fast_download verify`: checks the files of a url file against the sizes
+//! and checksums it lists, without touching the network.
+
+use crate::check;
+use fast_download::{decompressed_digest, file_sha256, file_sha256_tree, Image, Options};
+use futures::{stream, StreamExt};
+use indicatif::ProgressBar;
+use serde_json::json;
+use std::{
+    fs::OpenOptions,
+    io::{self, Write},
+    path::Path,
+};
+use tokio::fs;
+
+#[derive(Debug, PartialEq)]
+pub enum Status {
+    Ok,
+    Missing,
+    /// Empty although the url file expects no particular size.
+    Empty,
+    WrongSize(u64),
+    WrongSha256(String),
+    WrongSha256Tree(String),
+    Unreadable(String),
+}
+
+impl Status {
+    fn name(&self) -> &'static str {
+        match self {
+            Status::Ok => "ok",
+            Status::Missing => "missing",
+            Status::Empty => "empty",
+            Status::WrongSize(_) => "size",
+            Status::WrongSha256(_) => "sha256",
+            Status::WrongSha256Tree(_) => "sha256tree",
+            Status::Unreadable(_) => "error",
+        }
+    }
+
+    fn detail(&self, image: &Image) -> Option<String> {
+        match self {
+            Status::WrongSize(actual) => Some(format!(
+                "{} bytes, expected {}",
+                actual,
+                image.size.unwrap_or_default()
+            )),
+            Status::WrongSha256(actual) => Some(format!(
+                "{}, expected {}",
+                actual,
+                image.sha256.as_deref().unwrap_or_default()
+            )),
+            Status::WrongSha256Tree(actual) => Some(format!(
+                "{}, expected {}",
+                actual,
+                image.sha256_tree.as_deref().unwrap_or_default()
+            )),
+            Status::Unreadable(err) => Some(err.clone()),
+            _ => None,
+        }
+    }
+}
+
+/// Checks that the file of `image` exists at its
+/// [output path](Options::output_path), has the expected size and then the
+/// expected checksum, hashing only files of the right size. Files written with
+/// `--compress` are compared by what they decompress to.
+pub async fn status(image: &Image, options: &Options) -> Status {
+    let path = options.output_path(&image.file_name);
+    let len = match fs::metadata(&path).await {
+        Ok(metadata) => metadata.len(),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Status::Missing,
+        Err(err) => return Status::Unreadable(err.to_string()),
+    };
+    let decompressed = match options.compress {
+        Some(compression) => {
+            match decompressed_digest(&path, compression, image.sha256_tree.is_some()).await {
+                Ok(digest) => Some(digest),
+                Err(err) => return Status::Unreadable(err.to_string()),
+            }
+        }
+        None => None,
+    };
+    let len = decompressed.as_ref().map_or(len, |(size, _, _)| *size);
+    match image.size {
+        Some(size) if size != len => return Status::WrongSize(len),
+        None if len == 0 => return Status::Empty,
+        _ => {}
+    }
+    if let Some(expected) = &image.sha256 {
+        let actual = match &decompressed {
+            Some((_, sha256, _)) => Ok(sha256.clone()),
+            None => file_sha256(&path).await,
+        };
+        match actual {
+            Ok(actual) if &actual != expected => return Status::WrongSha256(actual),
+            Ok(_) => {}
+            Err(err) => return Status::Unreadable(err.to_string()),
+        }
+    }
+    if let Some(expected) = &image.sha256_tree {
+        let actual = match &decompressed {
+            Some((_, _, Some(sha256_tree))) => Ok(sha256_tree.clone()),
+            _ => file_sha256_tree(&path).await,
+        };
+        match actual {
+            Ok(actual) if &actual != expected => return Status::WrongSha256Tree(actual),
+            Ok(_) => {}
+            Err(err) => return Status::Unreadable(err.to_string()),
+        }
+    }
+    Status::Ok
+}
+
+/// Prints the files that aren't ok, every file with `verbose`, and returns the
+/// bad ones. With `enqueue` they are appended to that url file.
+pub async fn run(
+    images: &[Image],
+    options: &Options,
+    max_concurrent: usize,
+    json: bool,
+    verbose: bool,
+    enqueue: Option<&Path>,
+) -> Result<Vec<Image>, String> {
+    let checked = stream::iter(images)
+        .map(|image| async move { (image, status(image, options).await) })
+        .buffered(max_concurrent.max(1))
+        .collect::<Vec<_>>()
+        .await;
+    if json {
+        let items = checked
+            .iter()
+            .map(|(image, status)| {
+                json!({
+                    "url": image.url,
+                    "path": options.output_path(&image.file_name),
+                    "status": status.name(),
+                    "detail": status.detail(image),
+                })
+            })
+            .collect::<Vec<_>>();
+        println!("{}", serde_json::to_string_pretty(&items).unwrap());
+    } else {
+        for (image, status) in &checked {
+            if *status == Status::Ok && !verbose {
+                continue;
+            }
+            let mut line = format!(
+                "{:<8} {}",
+                status.name(),
+                options.output_path(&image.file_name).display()
+            );
+            if let Some(detail) = status.detail(image) {
+                line.push_str(&format!(" ({})", detail));
+            }
+            println!("{}", line);
+        }
+    }
+    let ok = checked
+        .iter()
+        .filter(|(_, status)| *status == Status::Ok)
+        .count();
+    let missing = checked
+        .iter()
+        .filter(|(_, status)| *status == Status::Missing)
+        .count();
+    let unverified = checked
+        .iter()
+        .filter(|(image, status)| {
+            *status == Status::Ok
+                && image.size.is_none()
+                && image.sha256.is_none()
+                && image.sha256_tree.is_none()
+        })
+        .count();
+    eprintln!(
+        "{} ok ({} without a size or sha256), {} missing, {} corrupt",
+        ok,
+        unverified,
+        missing,
+        checked.len() - ok - missing
+    );
+    let bad = checked
+        .into_iter()
+        .filter(|(_, status)| *status != Status::Ok)
+        .map(|(image, _)| image.clone())
+        .collect::<Vec<_>>();
+    if let Some(url_file) = enqueue {
+        let text = bad
+            .iter()
+            .map(|image| format!("{}\n", line(image)))
+            .collect::<String>();
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(url_file)
+            .and_then(|mut file| file.write_all(text.as_bytes()))
+            .map_err(|err| format!("failed to write {}: {}", url_file.display(), err))?;
+        eprintln!("enqueued {} files in {}", bad.len(), url_file.display());
+    }
+    Ok(bad)
+}
+
+/// Picks the entries of `images` whose files need downloading for `--repair`:
+/// missing files, and files that are empty, truncated or don't match their
+/// checksum. Files without a `size=` or `sha256=` are compared with the size
+/// the server reports. Returns them with how many files were fine.
+pub async fn repair(
+    images: Vec<Image>,
+    options: &Options,
+    client: &check::Client,
+    max_concurrent: usize,
+    verbose: bool,
+    progress: &ProgressBar,
+) -> (Vec<Image>, usize) {
+    progress.set_length(images.len() as u64);
+    let checked = stream::iter(images)
+        .map(|mut image| {
+            async move {
+                let mut status = status(&image, options).await;
+                let unchecked =
+                    image.size.is_none() && image.sha256.is_none() && image.sha256_tree.is_none();
+                if matches!(status, Status::Ok | Status::Empty) && unchecked {
+                    if let Some(size) = check::remote_size(client, &image).await {
+                        // the download is then held to the size, and the
+                        // existing file replaced unless it has it
+                        image.size = Some(size);
+                        status = self::status(&image, options).await;
+                    }
+                }
+                (image, status)
+            }
+        })
+        .buffered(max_concurrent.max(1))
+        .inspect(|_| progress.inc(1))
+        .collect::<Vec<_>>()
+        .await;
+    let mut ok = 0;
+    let mut repair = Vec::new();
+    for (image, status) in checked {
+        match status {
+            Status::Ok => {
+                ok += 1;
+                continue;
+            }
+            // nothing says how big the file should be, so only removing it
+            // gets it downloaded again
+            Status::Empty => {
+                let _ = fs::remove_file(options.output_path(&image.file_name)).await;
+            }
+            _ => {}
+        }
+        if verbose && status != Status::Missing {
+            let detail = status
+                .detail(&image)
+                .map_or(String::new(), |detail| format!(" ({})", detail));
+            progress.suspend(|| {
+                eprintln!("repairing {}: {}{}", image.file_name, status.name(), detail)
+            });
+        }
+        repair.push(image);
+    }
+    (repair, ok)
+}
+
+/// `image` as a url file line, with its fields.
+pub fn line(image: &Image) -> String {
+    let mut line = format!("{} {}", image.url, image.file_name);
+    if let Some(sha256) = &image.sha256 {
+        line.push_str(&format!(" sha256={}", sha256));
+    }
+    if let Some(sha256_tree) = &image.sha256_tree {
+        line.push_str(&format!(" sha256tree={}", sha256_tree));
+    }
+    if let Some(size) = image.size {
+        line.push_str(&format!(" size={}", size));
+    }
+    for mirror in &image.mirrors {
+        line.push_str(&format!(" mirror={}", mirror));
+    }
+    for copy in &image.copies {
+        line.push_str(&format!(" copy={}", copy));
+    }
+    line
+}
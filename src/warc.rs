@@ -0,0 +1,289 @@
+//! [`Options::warc`](crate::Options::warc): every response a download reads,
+//! with its request, appended to a WARC 1.1 file for web archiving.
+//!
+//! Bodies are spooled to a file next to the archive while they download, so
+//! concurrent downloads don't interleave, and appended with their request once
+//! complete. An archive named `.gz` gets every record as its own gzip member,
+//! the way replay tools expect `.warc.gz` files.
+
+use chrono::{SecondsFormat, Utc};
+use flate2::write::GzEncoder;
+use sha2::{Digest, Sha256};
+use std::{
+    io::{self, Read, Write},
+    net::IpAddr,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::SystemTime,
+};
+use tokio::{fs, io::AsyncWriteExt};
+
+/// An open WARC file shared by the downloads of a downloader.
+pub(crate) struct Archive {
+    path: PathBuf,
+    file: Mutex<std::fs::File>,
+    gzip: bool,
+    /// Numbers spool files and record ids.
+    next: AtomicU64,
+}
+
+/// The response of one download on its way into the archive. Dropping it
+/// before [`finish`](Capture::finish) leaves the response out.
+pub(crate) struct Capture {
+    archive: Arc<Archive>,
+    spool: PathBuf,
+    body: Option<fs::File>,
+    url: String,
+    date: String,
+    ip: Option<IpAddr>,
+    request: Vec<u8>,
+    /// The status line and headers of the response.
+    head: Vec<u8>,
+}
+
+/// The WARC headers of a record, without `Content-Length`, and its block.
+struct Record<'a> {
+    headers: &'a [(&'a str, String)],
+    block: &'a mut dyn Read,
+    len: u64,
+}
+
+impl Archive {
+    /// Opens `path` to append to, starting with a `warcinfo` record.
+    pub fn open(path: &Path) -> io::Result<Arc<Archive>> {
+        let file = std::fs::File::options()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|err| {
+                io::Error::new(
+                    err.kind(),
+                    format!("failed to open WARC {}: {}", path.display(), err),
+                )
+            })?;
+        let archive = Arc::new(Archive {
+            path: path.to_path_buf(),
+            file: Mutex::new(file),
+            gzip: path.extension().is_some_and(|ext| ext == "gz"),
+            next: AtomicU64::new(0),
+        });
+        let info = format!(
+            "software: fast_download/{}\r\nformat: WARC File Format 1.1\r\n",
+            env!("CARGO_PKG_VERSION")
+        );
+        let headers = [
+            ("WARC-Type", "warcinfo".to_string()),
+            ("WARC-Record-ID", archive.record_id()),
+            ("WARC-Date", warc_date(SystemTime::now())),
+            ("WARC-Filename", file_name(path)),
+            ("Content-Type", "application/warc-fields".to_string()),
+        ];
+        archive.append(&mut [Record {
+            headers: &headers,
+            block: &mut info.as_bytes(),
+            len: info.len() as u64,
+        }])?;
+        Ok(archive)
+    }
+
+    /// A fresh `<urn:uuid:...>`, random enough to be unique across runs.
+    fn record_id(&self) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(self.next.fetch_add(1, Ordering::Relaxed).to_le_bytes());
+        hasher.update(std::process::id().to_le_bytes());
+        let nanos = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos();
+        hasher.update(nanos.to_le_bytes());
+        hasher.update(self.path.as_os_str().as_encoded_bytes());
+        let mut bytes = hasher.finalize();
+        // a version 4 uuid
+        bytes[6] = (bytes[6] & 0x0f) | 0x40;
+        bytes[8] = (bytes[8] & 0x3f) | 0x80;
+        let hex = crate::sigv4::hex(&bytes[..16]);
+        format!(
+            "<urn:uuid:{}-{}-{}-{}-{}>",
+            &hex[..8],
+            &hex[8..12],
+            &hex[12..16],
+            &hex[16..20],
+            &hex[20..]
+        )
+    }
+
+    /// Writes `records` one after the other.
+    fn append(&self, records: &mut [Record<'_>]) -> io::Result<()> {
+        let mut file = self.file.lock().unwrap();
+        for record in records {
+            let mut head = String::from("WARC/1.1\r\n");
+            for (name, value) in record.headers {
+                head.push_str(&format!("{}: {}\r\n", name, value));
+            }
+            head.push_str(&format!("Content-Length: {}\r\n\r\n", record.len));
+            let mut write = |out: &mut dyn Write| {
+                out.write_all(head.as_bytes())?;
+                let copied = io::copy(&mut Read::take(&mut *record.block, record.len), out)?;
+                if copied != record.len {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "record body shorter than recorded",
+                    ));
+                }
+                out.write_all(b"\r\n\r\n")
+            };
+            if self.gzip {
+                let mut encoder = GzEncoder::new(&mut *file, flate2::Compression::default());
+                write(&mut encoder)?;
+                encoder.finish()?;
+            } else {
+                write(&mut *file)?;
+            }
+        }
+        file.flush()
+    }
+}
+
+impl Capture {
+    /// Starts recording `response`, the answer to a `GET` with
+    /// `request_headers`.
+    pub async fn start(
+        archive: &Arc<Archive>,
+        request_headers: &[(String, String)],
+        response: &reqwest::Response,
+    ) -> io::Result<Capture> {
+        let url = response.url();
+        let version = format!("{:?}", response.version());
+        let mut target = url.path().to_string();
+        if let Some(query) = url.query() {
+            target.push('?');
+            target.push_str(query);
+        }
+        let mut request = format!("GET {} {}\r\n", target, version);
+        if !request_headers
+            .iter()
+            .any(|(name, _)| name.eq_ignore_ascii_case("host"))
+        {
+            let host = match url.port() {
+                Some(port) => format!("{}:{}", url.host_str().unwrap_or_default(), port),
+                None => url.host_str().unwrap_or_default().to_string(),
+            };
+            request.push_str(&format!("Host: {}\r\n", host));
+        }
+        for (name, value) in request_headers {
+            request.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        request.push_str("\r\n");
+        let status = response.status();
+        let mut head = format!(
+            "{} {} {}\r\n",
+            version,
+            status.as_u16(),
+            status.canonical_reason().unwrap_or("")
+        )
+        .into_bytes();
+        for (name, value) in response.headers() {
+            head.extend_from_slice(name.as_str().as_bytes());
+            head.extend_from_slice(b": ");
+            head.extend_from_slice(value.as_bytes());
+            head.extend_from_slice(b"\r\n");
+        }
+        head.extend_from_slice(b"\r\n");
+        let n = archive.next.fetch_add(1, Ordering::Relaxed);
+        let mut spool = archive.path.clone().into_os_string();
+        spool.push(format!(".{}.{}.body", std::process::id(), n));
+        let spool = PathBuf::from(spool);
+        Ok(Capture {
+            archive: archive.clone(),
+            body: Some(fs::File::create(&spool).await?),
+            spool,
+            url: url.to_string(),
+            date: warc_date(SystemTime::now()),
+            ip: response.remote_addr().map(|addr| addr.ip()),
+            request: request.into_bytes(),
+            head,
+        })
+    }
+
+    /// Records a chunk of the body as it came off the network.
+    pub async fn write(&mut self, chunk: &[u8]) -> io::Result<()> {
+        match &mut self.body {
+            Some(body) => body.write_all(chunk).await,
+            None => Ok(()),
+        }
+    }
+
+    /// Appends the request and the complete response to the archive.
+    pub async fn finish(mut self) -> io::Result<()> {
+        if let Some(mut body) = self.body.take() {
+            body.flush().await?;
+        }
+        tokio::task::spawn_blocking(move || {
+            let response_id = self.archive.record_id();
+            let request_id = self.archive.record_id();
+            let mut response = self
+                .head
+                .as_slice()
+                .chain(std::fs::File::open(&self.spool)?);
+            let response_len = self.head.len() as u64 + std::fs::metadata(&self.spool)?.len();
+            let mut response_headers = vec![
+                ("WARC-Type", "response".to_string()),
+                ("WARC-Record-ID", response_id.clone()),
+                ("WARC-Date", self.date.clone()),
+                ("WARC-Target-URI", self.url.clone()),
+                (
+                    "Content-Type",
+                    "application/http;msgtype=response".to_string(),
+                ),
+            ];
+            if let Some(ip) = self.ip {
+                response_headers.push(("WARC-IP-Address", ip.to_string()));
+            }
+            let request_headers = [
+                ("WARC-Type", "request".to_string()),
+                ("WARC-Record-ID", request_id),
+                ("WARC-Date", self.date.clone()),
+                ("WARC-Target-URI", self.url.clone()),
+                ("WARC-Concurrent-To", response_id),
+                (
+                    "Content-Type",
+                    "application/http;msgtype=request".to_string(),
+                ),
+            ];
+            self.archive.append(&mut [
+                Record {
+                    headers: &response_headers,
+                    block: &mut response,
+                    len: response_len,
+                },
+                Record {
+                    headers: &request_headers,
+                    block: &mut self.request.as_slice(),
+                    len: self.request.len() as u64,
+                },
+            ])
+        })
+        .await?
+    }
+}
+
+impl Drop for Capture {
+    fn drop(&mut self) {
+        // a blocking call, but the spool file is closed or never read again
+        let _ = std::fs::remove_file(&self.spool);
+    }
+}
+
+fn warc_date(time: SystemTime) -> String {
+    chrono::DateTime::<Utc>::from(time).to_rfc3339_opts(SecondsFormat::Secs, true)
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .into_owned()
+}
@@ -0,0 +1,167 @@
+//! Batch lifecycle events POSTed to `--notify-url`.
+//!
+//! Events are delivered in order by a background task so a slow endpoint never
+//! holds up the downloads.
+
+use crate::Stats;
+use indicatif::HumanDuration;
+use serde_json::json;
+use std::time::Duration;
+use tokio::{sync::mpsc, task::JoinHandle};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Json,
+    Slack,
+    Ntfy,
+}
+
+pub enum Lifecycle {
+    Started,
+    Progress(u64),
+    Finished,
+    Failed(String),
+    /// The daemon's queue got jobs after being empty.
+    QueueBusy,
+    /// The daemon's queue drained.
+    QueueEmpty,
+}
+
+struct Payload {
+    title: String,
+    body: String,
+    content_type: &'static str,
+}
+
+pub struct Webhook {
+    format: Format,
+    payloads: mpsc::UnboundedSender<Payload>,
+    task: JoinHandle<()>,
+}
+
+impl Format {
+    pub fn parse(s: &str) -> Option<Format> {
+        match s {
+            "json" => Some(Format::Json),
+            "slack" => Some(Format::Slack),
+            "ntfy" => Some(Format::Ntfy),
+            _ => None,
+        }
+    }
+
+    /// Picks the payload shape from well known webhook hosts.
+    pub fn detect(url: &str) -> Format {
+        let host = reqwest::Url::parse(url)
+            .ok()
+            .and_then(|url| url.host_str().map(str::to_string))
+            .unwrap_or_default();
+        if host == "hooks.slack.com" {
+            Format::Slack
+        } else if host == "ntfy.sh" || host.starts_with("ntfy.") {
+            Format::Ntfy
+        } else {
+            Format::Json
+        }
+    }
+}
+
+impl Webhook {
+    pub fn new(url: String, format: Format) -> Webhook {
+        let (payloads, mut rx) = mpsc::unbounded_channel::<Payload>();
+        let task = tokio::spawn(async move {
+            let client = reqwest::Client::new();
+            while let Some(payload) = rx.recv().await {
+                let mut request = client
+                    .post(&url)
+                    .header(reqwest::header::CONTENT_TYPE, payload.content_type)
+                    .body(payload.body);
+                if format == Format::Ntfy {
+                    request = request.header("Title", payload.title);
+                }
+                let sent = request.send().await.and_then(|r| r.error_for_status());
+                if let Err(err) = sent {
+                    eprintln!("failed to send notification to {}: {}", url, err);
+                }
+            }
+        });
+        Webhook {
+            format,
+            payloads,
+            task,
+        }
+    }
+
+    pub fn send(&self, event: Lifecycle, stats: &Stats, total: u64, elapsed: Duration) {
+        let (name, title) = match &event {
+            Lifecycle::Started => ("started", format!("fast_download started: {} files", total)),
+            Lifecycle::Progress(percent) => {
+                ("progress", format!("fast_download {}% done", percent))
+            }
+            Lifecycle::Finished => (
+                "finished",
+                format!("fast_download finished in {}", HumanDuration(elapsed)),
+            ),
+            Lifecycle::Failed(_) => (
+                "failed",
+                format!("fast_download failed after {}", HumanDuration(elapsed)),
+            ),
+            Lifecycle::QueueBusy => (
+                "queue_busy",
+                "fast_download got jobs from the queue".to_string(),
+            ),
+            Lifecycle::QueueEmpty => (
+                "queue_empty",
+                format!(
+                    "fast_download drained the queue: {} files in {}",
+                    total,
+                    HumanDuration(elapsed)
+                ),
+            ),
+        };
+        let mut details = stats.to_string();
+        if let Lifecycle::Failed(err) = &event {
+            details.push('\n');
+            details.push_str(err);
+        }
+        let payload = match self.format {
+            Format::Json => Payload {
+                title,
+                body: json!({
+                    "event": name,
+                    "total": total,
+                    "downloaded": stats.downloaded,
+                    "skipped": stats.skipped,
+                    "failed": stats.failed,
+                    "elapsed_secs": elapsed.as_secs_f64(),
+                    "percent": match &event {
+                        Lifecycle::Progress(percent) => Some(*percent),
+                        _ => None,
+                    },
+                    "error": match &event {
+                        Lifecycle::Failed(err) => Some(err),
+                        _ => None,
+                    },
+                })
+                .to_string(),
+                content_type: "application/json",
+            },
+            Format::Slack => Payload {
+                body: json!({ "text": format!("{}\n{}", title, details) }).to_string(),
+                title,
+                content_type: "application/json",
+            },
+            Format::Ntfy => Payload {
+                title,
+                body: details,
+                content_type: "text/plain",
+            },
+        };
+        let _ = self.payloads.send(payload);
+    }
+
+    /// Waits until every event has been delivered.
+    pub async fn finish(self) {
+        drop(self.payloads);
+        let _ = self.task.await;
+    }
+}
@@ -0,0 +1,69 @@
+//! `--active-hours`: the daily window downloads are allowed to run in.
+
+use chrono::{DateTime, Duration, Local, NaiveTime, TimeZone};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ActiveHours {
+    start: NaiveTime,
+    end: NaiveTime,
+}
+
+impl ActiveHours {
+    /// Parses `HH:MM-HH:MM` in local time. A window past midnight, like
+    /// `22:00-06:00`, runs into the next day.
+    pub fn parse(text: &str) -> Option<ActiveHours> {
+        let (start, end) = text.split_once('-')?;
+        let time = |text: &str| NaiveTime::parse_from_str(text.trim(), "%H:%M").ok();
+        Some(ActiveHours {
+            start: time(start)?,
+            end: time(end)?,
+        })
+    }
+
+    pub fn contains(&self, time: DateTime<Local>) -> bool {
+        let time = time.time();
+        match self.start <= self.end {
+            true => self.start <= time && time < self.end,
+            false => time >= self.start || time < self.end,
+        }
+    }
+
+    /// When the window next opens or closes after `now`.
+    pub fn next_change(&self, now: DateTime<Local>) -> DateTime<Local> {
+        [self.start, self.end]
+            .into_iter()
+            .map(|time| {
+                let mut date = now.date_naive();
+                if date.and_time(time) <= now.naive_local() {
+                    date = date.succ_opt().unwrap_or(date);
+                }
+                // a time skipped by a DST change is treated as an hour later
+                let at = date.and_time(time);
+                Local
+                    .from_local_datetime(&at)
+                    .earliest()
+                    .or_else(|| {
+                        Local
+                            .from_local_datetime(&(at + Duration::hours(1)))
+                            .earliest()
+                    })
+                    .unwrap_or(now + Duration::hours(1))
+            })
+            .min()
+            .expect("two times")
+    }
+
+    pub fn start(&self) -> NaiveTime {
+        self.start
+    }
+}
+
+/// Waits until `hours` next opens or closes, forever without a window.
+pub async fn next_change(hours: Option<ActiveHours>) {
+    let Some(hours) = hours else {
+        return std::future::pending().await;
+    };
+    let now = Local::now();
+    let wait = (hours.next_change(now) - now).to_std().unwrap_or_default();
+    tokio::time::sleep(wait).await;
+}
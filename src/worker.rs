@@ -0,0 +1,195 @@
+//! `fast_download worker --queue <url>`: downloads the items a
+//! `fast_download coordinator` leases out, see the coordinator module for the
+//! protocol.
+
+use crate::Stats;
+use fast_download::{DownloadCompleted, Downloader, Image};
+use futures::{stream::FuturesUnordered, StreamExt};
+use serde::Deserialize;
+use serde_json::json;
+use std::time::Duration;
+
+/// How long to wait before asking again when every item is leased.
+const IDLE_POLL: Duration = Duration::from_secs(1);
+
+#[derive(Deserialize)]
+struct Lease {
+    lease: String,
+    lease_secs: f64,
+    item: Item,
+}
+
+#[derive(Deserialize)]
+struct Item {
+    url: String,
+    path: String,
+    #[serde(default)]
+    headers: Vec<(String, String)>,
+    sha256: Option<String>,
+    #[serde(default)]
+    sha256tree: Option<String>,
+    size: Option<u64>,
+    #[serde(default)]
+    mirrors: Vec<String>,
+    #[serde(default)]
+    copies: Vec<String>,
+    #[serde(default)]
+    meta: Vec<(String, String)>,
+    #[serde(default)]
+    tags: Vec<String>,
+    source: Option<String>,
+}
+
+/// Works off the queue at `queue` until the coordinator is done, running
+/// `max_concurrent` downloads at once. `token` is sent with every request when
+/// the coordinator needs one.
+pub async fn run(
+    queue: &str,
+    token: Option<&str>,
+    downloader: &Downloader,
+    max_concurrent: usize,
+    verbose: bool,
+) -> Result<Stats, String> {
+    let queue = match queue.strip_suffix('/') {
+        Some(queue) => queue,
+        None => queue,
+    };
+    if !(queue.starts_with("http://") || queue.starts_with("https://")) {
+        return Err(format!(
+            "unsupported queue {}, expected the http:// url of a fast_download coordinator",
+            queue
+        ));
+    }
+    let mut headers = reqwest::header::HeaderMap::new();
+    if let Some(token) = token {
+        let mut authorization =
+            reqwest::header::HeaderValue::from_str(&format!("Bearer {}", token))
+                .map_err(|_| "invalid --token".to_string())?;
+        authorization.set_sensitive(true);
+        headers.insert(reqwest::header::AUTHORIZATION, authorization);
+    }
+    let client = reqwest::Client::builder()
+        .default_headers(headers)
+        .build()
+        .map_err(|err| format!("failed to build http client: {}", err))?;
+    let mut stats = Stats::default();
+    let mut running = FuturesUnordered::new();
+    let mut done = false;
+    let mut next_id = 0;
+    loop {
+        while !done && running.len() < max_concurrent.max(1) {
+            let response = client
+                .post(format!("{}/lease", queue))
+                .send()
+                .await
+                .map_err(|err| format!("failed to reach the coordinator: {}", err))?;
+            match response.status() {
+                reqwest::StatusCode::GONE => done = true,
+                reqwest::StatusCode::NO_CONTENT => break,
+                status if !status.is_success() => {
+                    return Err(format!("the coordinator answered {}", status));
+                }
+                _ => {
+                    let lease = response
+                        .json::<Lease>()
+                        .await
+                        .map_err(|err| format!("invalid lease: {}", err))?;
+                    let id = next_id;
+                    next_id += 1;
+                    running.push(work(&client, queue, downloader, id, lease));
+                }
+            }
+        }
+        if running.is_empty() {
+            if done {
+                return Ok(stats);
+            }
+            tokio::time::sleep(IDLE_POLL).await;
+            continue;
+        }
+        // with free slots, look for newly queued items now and then
+        let finished = if running.len() < max_concurrent.max(1) && !done {
+            match tokio::time::timeout(IDLE_POLL, running.next()).await {
+                Ok(finished) => finished,
+                Err(_) => continue,
+            }
+        } else {
+            running.next().await
+        };
+        let Some((image, result)) = finished else {
+            continue;
+        };
+        match result {
+            Ok(DownloadCompleted::Success) => {
+                stats.downloaded += 1;
+                if verbose {
+                    println!("downloaded: {}", image.file_name);
+                }
+            }
+            Ok(DownloadCompleted::Skipped) => {
+                stats.skipped += 1;
+                if verbose {
+                    println!("skipped: {}", image.file_name);
+                }
+            }
+            Err(err) => {
+                stats.failed += 1;
+                println!(
+                    "{}error : {} url: {} file_name: {} id: {}",
+                    crate::located(&image),
+                    err,
+                    image.url,
+                    image.file_name,
+                    image.id()
+                );
+            }
+        }
+    }
+}
+
+/// Downloads a leased item, renewing the lease until it is acknowledged.
+async fn work(
+    client: &reqwest::Client,
+    queue: &str,
+    downloader: &Downloader,
+    id: usize,
+    lease: Lease,
+) -> (Image, Result<DownloadCompleted, String>) {
+    let mut image = Image::new(lease.item.url, lease.item.path);
+    image.headers = lease.item.headers;
+    image.sha256 = lease.item.sha256;
+    image.sha256_tree = lease.item.sha256tree;
+    image.size = lease.item.size;
+    image.mirrors = lease.item.mirrors;
+    image.copies = lease.item.copies;
+    image.meta = lease.item.meta;
+    image.tags = lease.item.tags;
+    image.source = lease.item.source;
+    let renew_every = Duration::from_secs_f64((lease.lease_secs / 3.0).max(1.0));
+    let result = {
+        let download = downloader.download(id, &image);
+        tokio::pin!(download);
+        loop {
+            tokio::select! {
+                result = &mut download => break result.map_err(|err| err.to_string()),
+                _ = tokio::time::sleep(renew_every) => {
+                    // a lost lease only means another worker may get the item too
+                    let _ = client
+                        .post(format!("{}/renew/{}", queue, lease.lease))
+                        .send()
+                        .await;
+                }
+            }
+        }
+    };
+    let ack = match &result {
+        Ok(_) => json!({ "ok": true }),
+        Err(err) => json!({ "ok": false, "error": err }),
+    };
+    let _ = client
+        .post(format!("{}/ack/{}", queue, lease.lease))
+        .json(&ack)
+        .send()
+        .await;
+    (image, result)
+}
@@ -0,0 +1,257 @@
+//! A pool of dedicated disk writer threads, the `threads` write backend.
+//!
+//! Download tasks hand their chunks to a writer thread and go back to reading
+//! the network, so a slow disk such as NFS or an SD card doesn't hold up the
+//! connections until the servers time out. Every file sticks to one thread,
+//! which writes its chunks in order; a file has at most a few chunks queued
+//! before its download waits.
+
+use bytes::Bytes;
+use std::{
+    collections::HashMap,
+    io::{self, Write},
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+};
+use tokio::sync::{mpsc, oneshot, OwnedSemaphorePermit, Semaphore};
+
+/// Number of chunks a download may have queued before it waits for the writer.
+const CHUNK_QUEUE_LEN: usize = 16;
+
+/// Where a writer thread reports the first error of a file.
+type ErrorSlot = Arc<Mutex<Option<io::Error>>>;
+
+enum Op {
+    Preallocate(u64),
+    /// With the chunk's place in the file's queue, and its share of the memory
+    /// budget, both held until written.
+    Write(Bytes, OwnedSemaphorePermit, Option<OwnedSemaphorePermit>),
+    Sync(oneshot::Sender<()>),
+    /// Closes the file, the reply comes once it is.
+    Close(Option<oneshot::Sender<()>>),
+}
+
+enum Job {
+    Create {
+        id: u64,
+        path: PathBuf,
+        error: ErrorSlot,
+    },
+    /// Writes on to an already open file.
+    Adopt {
+        id: u64,
+        file: std::fs::File,
+        error: ErrorSlot,
+    },
+    File {
+        id: u64,
+        op: Op,
+    },
+    Rename {
+        from: PathBuf,
+        to: PathBuf,
+        done: oneshot::Sender<io::Result<()>>,
+    },
+}
+
+/// Handle to the writer threads.
+pub struct Pool {
+    threads: Vec<mpsc::UnboundedSender<Job>>,
+    next_id: AtomicU64,
+}
+
+/// A file being written by one of the pool's threads.
+pub struct FileWriter {
+    id: u64,
+    thread: mpsc::UnboundedSender<Job>,
+    queue: Arc<Semaphore>,
+    /// The first error writing the file, after which its ops are ignored.
+    error: ErrorSlot,
+    closed: bool,
+}
+
+impl Pool {
+    pub fn spawn(threads: usize) -> io::Result<Pool> {
+        let threads = (0..threads.max(1))
+            .map(|index| {
+                let (jobs, rx) = mpsc::unbounded_channel();
+                thread::Builder::new()
+                    .name(format!("disk-writer-{}", index))
+                    .spawn(move || run(rx))?;
+                Ok(jobs)
+            })
+            .collect::<io::Result<Vec<_>>>()?;
+        Ok(Pool {
+            threads,
+            next_id: AtomicU64::new(0),
+        })
+    }
+
+    /// Creates (or truncates) the file at `path` on the next thread in turn.
+    pub fn create(&self, path: PathBuf) -> FileWriter {
+        self.open(|id, error| Job::Create { id, path, error })
+    }
+
+    /// Continues writing `file` from its current position.
+    pub fn adopt(&self, file: std::fs::File) -> FileWriter {
+        self.open(|id, error| Job::Adopt { id, file, error })
+    }
+
+    fn open(&self, job: impl FnOnce(u64, ErrorSlot) -> Job) -> FileWriter {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let thread = self.threads[id as usize % self.threads.len()].clone();
+        let writer = FileWriter {
+            id,
+            thread,
+            queue: Arc::new(Semaphore::new(CHUNK_QUEUE_LEN)),
+            error: Arc::new(Mutex::new(None)),
+            closed: false,
+        };
+        // if the thread is gone the writer's ops fail
+        let _ = writer.thread.send(job(id, writer.error.clone()));
+        writer
+    }
+
+    pub async fn rename(&self, from: PathBuf, to: PathBuf) -> io::Result<()> {
+        let (done, rx) = oneshot::channel();
+        let turn = self.next_id.fetch_add(1, Ordering::Relaxed) as usize;
+        let thread = &self.threads[turn % self.threads.len()];
+        if thread.send(Job::Rename { from, to, done }).is_err() {
+            return Err(writer_gone());
+        }
+        rx.await.unwrap_or_else(|_| Err(writer_gone()))
+    }
+}
+
+impl FileWriter {
+    pub async fn write(
+        &mut self,
+        chunk: Bytes,
+        reserved: Option<OwnedSemaphorePermit>,
+    ) -> io::Result<()> {
+        self.check()?;
+        let queued = self
+            .queue
+            .clone()
+            .acquire_owned()
+            .await
+            .map_err(|_| writer_gone())?;
+        self.send(Op::Write(chunk, queued, reserved));
+        Ok(())
+    }
+
+    pub async fn preallocate(&mut self, len: u64) -> io::Result<()> {
+        self.send(Op::Preallocate(len));
+        self.check()
+    }
+
+    /// Waits until everything sent so far is synced to disk.
+    pub async fn sync(&mut self) -> io::Result<()> {
+        let (tx, rx) = oneshot::channel();
+        self.send(Op::Sync(tx));
+        rx.await.map_err(|_| writer_gone())?;
+        self.check()
+    }
+
+    /// Waits until everything is written and the file is closed.
+    pub async fn finish(mut self) -> io::Result<()> {
+        let (tx, rx) = oneshot::channel();
+        self.closed = true;
+        self.send(Op::Close(Some(tx)));
+        rx.await.map_err(|_| writer_gone())?;
+        self.check()
+    }
+
+    fn send(&self, op: Op) {
+        // a stopped thread drops the reply senders, which callers see
+        let _ = self.thread.send(Job::File { id: self.id, op });
+    }
+
+    fn check(&self) -> io::Result<()> {
+        if self.thread.is_closed() {
+            return Err(writer_gone());
+        }
+        match &*self.error.lock().expect("not poisoned") {
+            Some(err) => Err(io::Error::new(err.kind(), err.to_string())),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for FileWriter {
+    fn drop(&mut self) {
+        // a dropped download only needs its file closed, the download removes it
+        if !self.closed {
+            self.send(Op::Close(None));
+        }
+    }
+}
+
+fn writer_gone() -> io::Error {
+    io::Error::new(io::ErrorKind::BrokenPipe, "disk writer stopped")
+}
+
+/// The loop of one writer thread.
+fn run(mut jobs: mpsc::UnboundedReceiver<Job>) {
+    let mut files = HashMap::<u64, (Option<std::fs::File>, ErrorSlot)>::new();
+    while let Some(job) = jobs.blocking_recv() {
+        let (id, op) = match job {
+            Job::File { id, op } => (id, op),
+            Job::Create { id, path, error } => {
+                let file = match std::fs::File::create(path) {
+                    Ok(file) => Some(file),
+                    Err(err) => {
+                        *error.lock().expect("not poisoned") = Some(err);
+                        None
+                    }
+                };
+                files.insert(id, (file, error));
+                continue;
+            }
+            Job::Adopt { id, file, error } => {
+                files.insert(id, (Some(file), error));
+                continue;
+            }
+            Job::Rename { from, to, done } => {
+                let _ = done.send(std::fs::rename(from, to));
+                continue;
+            }
+        };
+        let Some((file, error)) = files.get_mut(&id) else {
+            continue;
+        };
+        let result = match (op, file.as_mut()) {
+            (Op::Preallocate(len), Some(file)) => crate::download::preallocate_file(file, len),
+            (Op::Write(chunk, queued, reserved), Some(file)) => {
+                let result = file.write_all(&chunk);
+                drop((queued, reserved));
+                result
+            }
+            (Op::Sync(done), file) => {
+                if let Some(Err(err)) = file.map(|file| file.sync_all()) {
+                    error.lock().expect("not poisoned").get_or_insert(err);
+                }
+                let _ = done.send(());
+                continue;
+            }
+            (Op::Close(done), _) => {
+                files.remove(&id);
+                if let Some(done) = done {
+                    let _ = done.send(());
+                }
+                continue;
+            }
+            // the rest of the ops of a failed file are skipped
+            (_, None) => Ok(()),
+        };
+        if let Err(err) = result {
+            // closes the file
+            *file = None;
+            error.lock().expect("not poisoned").get_or_insert(err);
+        }
+    }
+}
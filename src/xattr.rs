@@ -0,0 +1,49 @@
+//! [`Options::xattr`](crate::Options::xattr): where a file came from, in
+//! extended attributes that move with it, named like curl and browsers name
+//! them.
+
+use std::{io, path::Path};
+
+/// The attributes of a file downloaded from `url`, `etag` and `mime_type`
+/// being what the server sent with it.
+pub(crate) fn origin(
+    url: &str,
+    etag: Option<&str>,
+    mime_type: Option<&str>,
+) -> Vec<(&'static str, String)> {
+    let mut attributes = vec![
+        ("user.xdg.origin.url", url.to_string()),
+        ("user.creator", "fast_download".to_string()),
+        ("user.download.time", chrono::Utc::now().to_rfc3339()),
+    ];
+    if let Some(etag) = etag {
+        attributes.push(("user.etag", etag.to_string()));
+    }
+    if let Some(mime_type) = mime_type {
+        attributes.push(("user.mime_type", mime_type.to_string()));
+    }
+    attributes
+}
+
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+pub(crate) fn set(path: &Path, name: &str, value: &str) -> io::Result<()> {
+    use std::{ffi::CString, os::unix::ffi::OsStrExt};
+    let invalid = |_| io::Error::from(io::ErrorKind::InvalidInput);
+    let path = CString::new(path.as_os_str().as_bytes()).map_err(invalid)?;
+    let name = CString::new(name).map_err(invalid)?;
+    let (value, len) = (value.as_ptr().cast(), value.len());
+    // SAFETY: both strings are nul terminated and value is len bytes long
+    #[cfg(target_os = "linux")]
+    let res = unsafe { libc::setxattr(path.as_ptr(), name.as_ptr(), value, len, 0) };
+    #[cfg(target_os = "macos")]
+    let res = unsafe { libc::setxattr(path.as_ptr(), name.as_ptr(), value, len, 0, 0) };
+    match res {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+pub(crate) fn set(_: &Path, _: &str, _: &str) -> io::Result<()> {
+    Err(io::ErrorKind::Unsupported.into())
+}
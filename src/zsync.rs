@@ -0,0 +1,568 @@
+//! [`Options::zsync`](crate::Options::zsync): downloading only the blocks of
+//! a file that changed since the copy on disk, as listed by the `.zsync`
+//! control file the server publishes next to it.
+//!
+//! The control file has the size, SHA-1 and a rolling checksum and MD4 of
+//! every block of the file. Blocks found anywhere in the old copy are copied
+//! from it, the rest is fetched with range requests, and the result is only
+//! kept if its SHA-1 matches.
+
+use crate::{sigv4::hex, Image};
+use sha1::{Digest, Sha1};
+use std::{
+    collections::HashMap,
+    io::{self, Seek, SeekFrom, Write},
+    path::Path,
+};
+use tokio::io::{AsyncSeekExt, AsyncWriteExt};
+
+/// Missing blocks closer than this are fetched in one request, with the
+/// blocks between them.
+const MERGE_GAP: u64 = 64 * 1024;
+
+/// A file rebuilt from the old copy into the part file.
+pub(crate) struct Delta {
+    pub length: u64,
+    /// Seconds since the epoch, from the control file's `MTime`.
+    pub mtime: Option<i64>,
+}
+
+struct Control {
+    block_size: usize,
+    length: u64,
+    mtime: Option<i64>,
+    sha1: String,
+    seq_matches: usize,
+    rsum_bytes: usize,
+    checksum_bytes: usize,
+    blocks: Vec<Block>,
+}
+
+#[derive(Clone, Copy)]
+struct Block {
+    rsum: u32,
+    checksum: [u8; 16],
+}
+
+/// Rebuilds the file of `image` in `part` from `old` and the ranges missing
+/// from it. `Ok(None)` if the server has no control file for it.
+pub(crate) async fn fetch(
+    client: &reqwest::Client,
+    image: &Image,
+    old: &Path,
+    part: &Path,
+    mut on_chunk: impl FnMut(usize, Option<u64>),
+) -> io::Result<Option<Delta>> {
+    let get = |url: &str| {
+        let mut request = client.get(url);
+        for (name, value) in &image.headers {
+            request = request.header(name, value);
+        }
+        request
+    };
+    let response = get(&format!("{}.zsync", image.url))
+        .send()
+        .await
+        .map_err(io::Error::other)?;
+    if !response.status().is_success() {
+        return Ok(None);
+    }
+    let body = response.bytes().await.map_err(io::Error::other)?;
+    let control = parse(&body)?;
+    let (old, part_path) = (old.to_path_buf(), part.to_path_buf());
+    let (control, missing) = tokio::task::spawn_blocking(move || {
+        let found = match_blocks(&control, &old)?;
+        copy_found(&control, &found, &old, &part_path)?;
+        let missing = missing_ranges(&control, &found);
+        io::Result::Ok((control, missing))
+    })
+    .await??;
+    let mut file = tokio::fs::OpenOptions::new().write(true).open(part).await?;
+    for (start, end) in missing {
+        let response = get(&image.url)
+            .header(
+                reqwest::header::RANGE,
+                format!("bytes={}-{}", start, end - 1),
+            )
+            .send()
+            .await
+            .map_err(io::Error::other)?;
+        let expected = format!("bytes {}-{}/", start, end - 1);
+        let content_range = response
+            .headers()
+            .get(reqwest::header::CONTENT_RANGE)
+            .and_then(|value| value.to_str().ok());
+        if response.status() != reqwest::StatusCode::PARTIAL_CONTENT
+            || !content_range.is_some_and(|range| range.starts_with(&expected))
+        {
+            return Err(io::Error::other("server doesn't support range requests"));
+        }
+        file.seek(SeekFrom::Start(start)).await?;
+        let mut response = response;
+        let mut received = 0;
+        while let Some(chunk) = response.chunk().await.map_err(io::Error::other)? {
+            received += chunk.len() as u64;
+            if received > end - start {
+                return Err(io::Error::other("range is longer than requested"));
+            }
+            file.write_all(&chunk).await?;
+            on_chunk(chunk.len(), Some(control.length));
+        }
+        if received != end - start {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+    }
+    file.set_len(control.length).await?;
+    file.flush().await?;
+    drop(file);
+    let part = part.to_path_buf();
+    let sha1 = tokio::task::spawn_blocking(move || {
+        let mut hasher = Sha1::new();
+        io::copy(&mut std::fs::File::open(part)?, &mut hasher)?;
+        io::Result::Ok(hasher.finalize())
+    })
+    .await??;
+    let sha1 = hex(&sha1);
+    if sha1 != control.sha1 {
+        return Err(io::Error::other(
+            "rebuilt file doesn't match the zsync SHA-1",
+        ));
+    }
+    Ok(Some(Delta {
+        length: control.length,
+        mtime: control.mtime,
+    }))
+}
+
+fn parse(body: &[u8]) -> io::Result<Control> {
+    let invalid = |what: &str| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("invalid zsync file: {}", what),
+        )
+    };
+    let end = body
+        .windows(2)
+        .position(|window| window == b"\n\n")
+        .ok_or_else(|| invalid("no end of headers"))?;
+    let headers = std::str::from_utf8(&body[..end]).map_err(|_| invalid("headers"))?;
+    let mut fields = HashMap::new();
+    for line in headers.lines() {
+        if let Some((name, value)) = line.split_once(':') {
+            fields.insert(name.trim(), value.trim());
+        }
+    }
+    // the blocks of compressed files are of the compressed stream
+    if fields.contains_key("Z-Map2") {
+        return Err(invalid("compressed files aren't supported"));
+    }
+    let number = |name: &str| {
+        fields
+            .get(name)
+            .and_then(|value| value.parse::<u64>().ok())
+            .ok_or_else(|| invalid(name))
+    };
+    let block_size = number("Blocksize")? as usize;
+    let length = number("Length")?;
+    let lengths = fields
+        .get("Hash-Lengths")
+        .map(|value| {
+            value
+                .split(',')
+                .filter_map(|value| value.parse::<usize>().ok())
+                .collect::<Vec<_>>()
+        })
+        .unwrap_or_else(|| vec![1, 4, 16]);
+    let [seq_matches, rsum_bytes, checksum_bytes] = lengths[..] else {
+        return Err(invalid("Hash-Lengths"));
+    };
+    if !block_size.is_power_of_two()
+        || !(1..=2).contains(&seq_matches)
+        || !(1..=4).contains(&rsum_bytes)
+        || !(3..=16).contains(&checksum_bytes)
+    {
+        return Err(invalid("block or hash lengths"));
+    }
+    let sha1 = fields
+        .get("SHA-1")
+        .ok_or_else(|| invalid("SHA-1"))?
+        .to_lowercase();
+    let count = length.div_ceil(block_size as u64) as usize;
+    let table = &body[end + 2..];
+    let entry = rsum_bytes + checksum_bytes;
+    if table.len() < count * entry {
+        return Err(invalid("truncated block table"));
+    }
+    let blocks = table
+        .chunks_exact(entry)
+        .take(count)
+        .map(|entry| {
+            let mut rsum = [0u8; 4];
+            rsum[4 - rsum_bytes..].copy_from_slice(&entry[..rsum_bytes]);
+            let mut checksum = [0u8; 16];
+            checksum[..checksum_bytes].copy_from_slice(&entry[rsum_bytes..]);
+            Block {
+                rsum: u32::from_be_bytes(rsum),
+                checksum,
+            }
+        })
+        .collect();
+    Ok(Control {
+        block_size,
+        length,
+        mtime: fields
+            .get("MTime")
+            .and_then(|value| chrono::DateTime::parse_from_rfc2822(value).ok())
+            .map(|mtime| mtime.timestamp()),
+        sha1,
+        seq_matches,
+        rsum_bytes,
+        checksum_bytes,
+        blocks,
+    })
+}
+
+/// zsync's rolling checksum of a block: the sum of its bytes and the sum of
+/// those sums, each in 16 bits.
+#[derive(Clone, Copy)]
+struct Rsum {
+    a: u16,
+    b: u16,
+}
+
+impl Rsum {
+    fn of(block: &[u8]) -> Rsum {
+        let (mut a, mut b) = (0u16, 0u16);
+        for &byte in block {
+            a = a.wrapping_add(byte as u16);
+            b = b.wrapping_add(a);
+        }
+        Rsum { a, b }
+    }
+
+    /// Moves the window a byte on, from `old` to `new`.
+    fn roll(&mut self, old: u8, new: u8, shift: u32) {
+        self.a = self.a.wrapping_add(new as u16).wrapping_sub(old as u16);
+        self.b = self
+            .b
+            .wrapping_add(self.a)
+            .wrapping_sub((old as u16).wrapping_shl(shift));
+    }
+
+    fn masked(self, mask: u32) -> u32 {
+        (((self.a as u32) << 16) | self.b as u32) & mask
+    }
+}
+
+/// The offset in the old copy of every block of the new file found in it.
+fn match_blocks(control: &Control, old: &Path) -> io::Result<Vec<Option<u64>>> {
+    let mut found = vec![None; control.blocks.len()];
+    let file = std::fs::File::open(old)?;
+    // SAFETY: the old copy isn't written while the new one is built
+    let old = unsafe { memmap2::Mmap::map(&file)? };
+    let size = control.block_size;
+    // a run of seq_matches blocks has to follow for a match
+    let window = size * control.seq_matches;
+    if old.len() < size {
+        return Ok(found);
+    }
+    let mask = match control.rsum_bytes {
+        4 => u32::MAX,
+        bytes => (1 << (8 * bytes)) - 1,
+    };
+    let mut by_rsum = HashMap::<u32, Vec<usize>>::new();
+    for (index, block) in control.blocks.iter().enumerate() {
+        by_rsum.entry(block.rsum).or_default().push(index);
+    }
+    let shift = size.trailing_zeros();
+    // past its end the old copy reads as zeros, as zsync pads it, so its last
+    // block can still be followed by the next one
+    let byte = |offset: usize| old.get(offset).copied().unwrap_or(0);
+    let fresh = |offset: usize| {
+        let mut next = vec![0; size];
+        let tail = old.get(offset + size..).unwrap_or_default();
+        let len = tail.len().min(size);
+        next[..len].copy_from_slice(&tail[..len]);
+        [Rsum::of(&old[offset..offset + size]), Rsum::of(&next)]
+    };
+    let mut offset = 0;
+    let mut sums = fresh(0);
+    while offset + size <= old.len() {
+        let mut matched = false;
+        for &index in by_rsum.get(&sums[0].masked(mask)).into_iter().flatten() {
+            let next = control.blocks.get(index + 1);
+            // the block after a match only needs itself to match
+            let follows =
+                index > 0 && offset >= size && found[index - 1] == Some((offset - size) as u64);
+            if control.seq_matches == 2
+                && !follows
+                && next.is_some_and(|next| next.rsum != sums[1].masked(mask))
+            {
+                continue;
+            }
+            if found[index].is_none()
+                && md4(&old[offset..offset + size])[..control.checksum_bytes]
+                    == control.blocks[index].checksum[..control.checksum_bytes]
+            {
+                found[index] = Some(offset as u64);
+                matched = true;
+            }
+        }
+        if matched && offset + 2 * size <= old.len() {
+            // blocks of a file don't overlap
+            offset += size;
+            sums = fresh(offset);
+            continue;
+        }
+        if offset + size >= old.len() {
+            break;
+        }
+        sums[0].roll(old[offset], old[offset + size], shift);
+        if control.seq_matches == 2 {
+            sums[1].roll(byte(offset + size), byte(offset + window), shift);
+        }
+        offset += 1;
+    }
+    Ok(found)
+}
+
+/// Writes the blocks found in `old` to a new `part` of the new file's length.
+fn copy_found(control: &Control, found: &[Option<u64>], old: &Path, part: &Path) -> io::Result<()> {
+    let mut old = std::fs::File::open(old)?;
+    let mut new = std::fs::File::create(part)?;
+    new.set_len(control.length)?;
+    let mut block = vec![0u8; control.block_size];
+    for (index, offset) in found.iter().enumerate() {
+        let Some(offset) = offset else {
+            continue;
+        };
+        let start = (index * control.block_size) as u64;
+        let len = (control.length - start).min(control.block_size as u64) as usize;
+        old.seek(SeekFrom::Start(*offset))?;
+        io::Read::read_exact(&mut old, &mut block[..len])?;
+        new.seek(SeekFrom::Start(start))?;
+        new.write_all(&block[..len])?;
+    }
+    new.flush()
+}
+
+/// The byte ranges of the new file that weren't found, end exclusive, with
+/// gaps below [`MERGE_GAP`] merged.
+fn missing_ranges(control: &Control, found: &[Option<u64>]) -> Vec<(u64, u64)> {
+    let mut ranges = Vec::<(u64, u64)>::new();
+    for (index, _) in found
+        .iter()
+        .enumerate()
+        .filter(|(_, found)| found.is_none())
+    {
+        let start = (index * control.block_size) as u64;
+        let end = (start + control.block_size as u64).min(control.length);
+        match ranges.last_mut() {
+            Some((_, last)) if start - *last <= MERGE_GAP => *last = end,
+            _ => ranges.push((start, end)),
+        }
+    }
+    ranges
+}
+
+/// MD4, which zsync checksums blocks with.
+fn md4(data: &[u8]) -> [u8; 16] {
+    let mut state = [0x67452301u32, 0xefcdab89, 0x98badcfe, 0x10325476];
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend_from_slice(&((data.len() as u64) * 8).to_le_bytes());
+    for chunk in message.chunks_exact(64) {
+        let x = std::array::from_fn::<u32, 16, _>(|i| {
+            u32::from_le_bytes(chunk[4 * i..4 * i + 4].try_into().expect("4 bytes"))
+        });
+        let [mut a, mut b, mut c, mut d] = state;
+        let f = |x: u32, y: u32, z: u32| (x & y) | (!x & z);
+        let g = |x: u32, y: u32, z: u32| (x & y) | (x & z) | (y & z);
+        let h = |x: u32, y: u32, z: u32| x ^ y ^ z;
+        for &i in &[0, 4, 8, 12] {
+            a = a.wrapping_add(f(b, c, d)).wrapping_add(x[i]).rotate_left(3);
+            d = d
+                .wrapping_add(f(a, b, c))
+                .wrapping_add(x[i + 1])
+                .rotate_left(7);
+            c = c
+                .wrapping_add(f(d, a, b))
+                .wrapping_add(x[i + 2])
+                .rotate_left(11);
+            b = b
+                .wrapping_add(f(c, d, a))
+                .wrapping_add(x[i + 3])
+                .rotate_left(19);
+        }
+        for &i in &[0, 1, 2, 3] {
+            let k = 0x5a827999u32;
+            a = a
+                .wrapping_add(g(b, c, d))
+                .wrapping_add(x[i])
+                .wrapping_add(k)
+                .rotate_left(3);
+            d = d
+                .wrapping_add(g(a, b, c))
+                .wrapping_add(x[i + 4])
+                .wrapping_add(k)
+                .rotate_left(5);
+            c = c
+                .wrapping_add(g(d, a, b))
+                .wrapping_add(x[i + 8])
+                .wrapping_add(k)
+                .rotate_left(9);
+            b = b
+                .wrapping_add(g(c, d, a))
+                .wrapping_add(x[i + 12])
+                .wrapping_add(k)
+                .rotate_left(13);
+        }
+        for &i in &[0, 2, 1, 3] {
+            let k = 0x6ed9eba1u32;
+            a = a
+                .wrapping_add(h(b, c, d))
+                .wrapping_add(x[i])
+                .wrapping_add(k)
+                .rotate_left(3);
+            d = d
+                .wrapping_add(h(a, b, c))
+                .wrapping_add(x[i + 8])
+                .wrapping_add(k)
+                .rotate_left(9);
+            c = c
+                .wrapping_add(h(d, a, b))
+                .wrapping_add(x[i + 4])
+                .wrapping_add(k)
+                .rotate_left(11);
+            b = b
+                .wrapping_add(h(c, d, a))
+                .wrapping_add(x[i + 12])
+                .wrapping_add(k)
+                .rotate_left(15);
+        }
+        for (value, new) in state.iter_mut().zip([a, b, c, d]) {
+            *value = value.wrapping_add(new);
+        }
+    }
+    let mut digest = [0u8; 16];
+    for (bytes, value) in digest.chunks_exact_mut(4).zip(state) {
+        bytes.copy_from_slice(&value.to_le_bytes());
+    }
+    digest
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Bytes that don't repeat within a few blocks.
+    fn data(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 31 + i / 7) as u8).collect()
+    }
+
+    /// A control file for `data` with full sums and checksums.
+    fn control_file(data: &[u8], block_size: usize, seq_matches: usize) -> Vec<u8> {
+        let mut body = format!(
+            "zsync: 0.6.2\nBlocksize: {}\nLength: {}\nHash-Lengths: {},4,16\nSHA-1: {}\n\n",
+            block_size,
+            data.len(),
+            seq_matches,
+            hex(&Sha1::digest(data))
+        )
+        .into_bytes();
+        for block in data.chunks(block_size) {
+            let mut padded = block.to_vec();
+            padded.resize(block_size, 0);
+            body.extend_from_slice(&Rsum::of(&padded).masked(u32::MAX).to_be_bytes());
+            body.extend_from_slice(&md4(&padded));
+        }
+        body
+    }
+
+    #[test]
+    fn rolling_the_sum_matches_summing_the_window() {
+        let data = data(200);
+        let size = 16;
+        let mut sum = Rsum::of(&data[..size]);
+        for offset in 1..=data.len() - size {
+            sum.roll(
+                data[offset - 1],
+                data[offset - 1 + size],
+                size.trailing_zeros(),
+            );
+            let fresh = Rsum::of(&data[offset..offset + size]);
+            assert_eq!((sum.a, sum.b), (fresh.a, fresh.b), "offset {}", offset);
+        }
+    }
+
+    #[test]
+    fn md4_matches_the_rfc_1320_test_suite() {
+        let cases: [(&[u8], &str); 7] = [
+            (b"", "31d6cfe0d16ae931b73c59d7e0c089c0"),
+            (b"a", "bde52cb31de33e46245e05fbdbd6fb24"),
+            (b"abc", "a448017aaf21d8525fc10ae87aa6729d"),
+            (b"message digest", "d9130a8164549fe818874806e1c7014b"),
+            (
+                b"abcdefghijklmnopqrstuvwxyz",
+                "d79e1c308aa5bbcdeea8ed63df412da9",
+            ),
+            (
+                b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789",
+                "043f8582f241db351ce627e153e7f0e4",
+            ),
+            (
+                b"12345678901234567890123456789012345678901234567890123456789012345678901234567890",
+                "e33b4ddc9c38f2199c3e7b164fcc0536",
+            ),
+        ];
+        for (input, expected) in cases {
+            assert_eq!(hex(&md4(input)), expected);
+        }
+    }
+
+    #[test]
+    fn finds_the_blocks_of_a_shifted_copy() {
+        let new = data(16 * 10);
+        // the old copy has 5 bytes in front and lacks the new file's 4th block
+        let mut old = vec![0xff; 5];
+        old.extend_from_slice(&new[..16 * 3]);
+        old.extend_from_slice(&new[16 * 4..]);
+        let path = std::env::temp_dir().join(format!("fast_download-zsync-{}", std::process::id()));
+        std::fs::write(&path, &old).unwrap();
+        for seq_matches in [1, 2] {
+            let control = parse(&control_file(&new, 16, seq_matches)).unwrap();
+            let found = match_blocks(&control, &path).unwrap();
+            let expected = (0..10)
+                .map(|index| match index {
+                    0..3 => Some(5 + 16 * index),
+                    3 => None,
+                    _ => Some(5 + 16 * (index - 1)),
+                })
+                .collect::<Vec<_>>();
+            assert_eq!(found, expected, "seq_matches {}", seq_matches);
+            assert_eq!(missing_ranges(&control, &found), [(48, 64)]);
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn rejects_malformed_control_files() {
+        let valid = control_file(&data(100), 32, 1);
+        assert!(parse(&valid).is_ok());
+        let headers = |text: &str| parse(format!("{}\n\n", text).as_bytes()).map(|_| ());
+        // no blank line after the headers
+        assert!(parse(b"Blocksize: 32\nLength: 0\nSHA-1: 00").is_err());
+        assert!(headers("Length: 0\nSHA-1: 00").is_err());
+        assert!(headers("Blocksize: 30\nLength: 0\nSHA-1: 00").is_err());
+        assert!(headers("Blocksize: 32\nLength: x\nSHA-1: 00").is_err());
+        assert!(headers("Blocksize: 32\nLength: 0\nHash-Lengths: 3,4,16\nSHA-1: 00").is_err());
+        assert!(headers("Blocksize: 32\nLength: 0").is_err());
+        assert!(headers("Blocksize: 32\nLength: 0\nSHA-1: 00\nZ-Map2: 1").is_err());
+        assert!(headers("Blocksize: 32\nLength: 0\nSHA-1: 00").is_ok());
+        // four blocks listed, the last one cut short
+        assert!(parse(&valid[..valid.len() - 1]).is_err());
+    }
+}